@@ -0,0 +1,59 @@
+//! Removing ANSI CSI sequences (`ESC [ ... final`) from device output (--strip-ansi): a loader's
+//! colors are fine in a terminal but garbage in a transcript.
+//!
+//! Sequences can be cut across reads, the stripper keeps its place between them. Other escape
+//! sequences go through untouched.
+
+const ESC: u8 = 0x1b;
+/// Longest CSI sequence taken as one, a longer one is dropped up to there and the rest shown
+const MAX_SEQUENCE: usize = 64;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Text,
+    /// After an ESC
+    Escape,
+    /// Inside a CSI sequence, this many bytes after the `[`
+    Sequence(usize),
+}
+
+/// Strips the CSI sequences from a stream of bytes
+#[derive(Debug, Default)]
+pub struct AnsiStripper {
+    state: State,
+}
+
+impl AnsiStripper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `bytes` without the CSI sequences, holding back the start of one cut short
+    pub fn strip(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut text = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            self.state = match (self.state, byte) {
+                (State::Text, ESC) => State::Escape,
+                (State::Text, _) => {
+                    text.push(byte);
+                    State::Text
+                },
+                (State::Escape, b'[') => State::Sequence(0),
+                (State::Escape, ESC) => {
+                    text.push(ESC);
+                    State::Escape
+                },
+                (State::Escape, _) => {
+                    text.extend_from_slice(&[ESC, byte]);
+                    State::Text
+                },
+                // the final byte
+                (State::Sequence(_), 0x40..=0x7e) => State::Text,
+                (State::Sequence(length), _) if length + 1 >= MAX_SEQUENCE => State::Text,
+                (State::Sequence(length), _) => State::Sequence(length + 1),
+            };
+        }
+        text
+    }
+}
@@ -0,0 +1,69 @@
+//! Autobaud training: ROM loaders that detect the host's baud rate from its first bytes expect a
+//! training character repeated until they answer.
+
+use std::time::{Duration, Instant};
+use anyhow::{Result, bail};
+
+use crate::pattern::RollingMatcher;
+use crate::output;
+use crate::protocol;
+use crate::transport::Transport;
+use crate::status;
+
+/// How to train the device's baud rate detection
+#[derive(Debug, Clone)]
+pub struct Training {
+    /// Byte sent repeatedly, 0x55 has a bit transition on every bit
+    pub byte: u8,
+    /// Pause between two training bytes
+    pub interval: Duration,
+    /// What the device answers once it locked on the baud rate
+    pub response: Vec<u8>,
+    /// Give up if the device didn't answer by then
+    pub timeout: Duration,
+}
+
+impl Default for Training {
+    fn default() -> Self {
+        Self {
+            byte: 0x55,
+            interval: Duration::from_millis(10),
+            response: b"OK".to_vec(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Send the training byte until the response is seen.
+/// Sending stops as soon as the response arrives so nothing is left to confuse what comes next.
+/// Device output received while training is shown as usual.
+pub fn train(serial_device: &mut dyn Transport, training: &Training) -> Result<()> {
+    status!("[PUSHER] Training the device's baud rate with {:#04x}", training.byte);
+    let deadline = Instant::now() + training.timeout;
+    let mut matcher = RollingMatcher::new(training.response.clone());
+    let mut sent: u64 = 0;
+    let mut waiter = protocol::ReadableWaiter::new(serial_device)?;
+    loop {
+        protocol::write_byte(serial_device, training.byte)?;
+        sent += 1;
+        let next_byte = Instant::now() + training.interval;
+        // wait out the interval, checking every byte that arrives meanwhile
+        loop {
+            let now = Instant::now();
+            if now >= next_byte {
+                break;
+            }
+            if waiter.wait(Some(next_byte - now))? {
+                let received = serial_device.read_all()?;
+                output::device_bytes(&received);
+                if matcher.feed(&received).is_some() {
+                    status!("\r\n[PUSHER] Device answered after {} training bytes", sent);
+                    return Ok(());
+                }
+            }
+        }
+        if Instant::now() >= deadline {
+            bail!("Device didn't answer the baud rate training within {:?}", training.timeout);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Finding the device when none was given: the one USB serial adapter plugged in
+//! (--auto-device), or the adapter with a profile's USB serial number (--profile). With none or
+//! several it's an error listing what was found, so a bench with two adapters never gets the
+//! wrong one. The other way around, the serial number of a device's adapter picks its kernel
+//! from the config's `[boards]`.
+//!
+//! Ports are listed by `serialport`, which knows each platform's naming. macOS lists every
+//! adapter twice, as `/dev/cu.*` and `/dev/tty.*`, only the `cu` one is kept: opening the `tty`
+//! one waits for carrier detect.
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow, bail};
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+use crate::config::Profile;
+
+/// The USB serial port to use: the only one there is
+pub fn select() -> Result<PathBuf> {
+    let (usb, other): (Vec<_>, Vec<_>) = ports()?.into_iter()
+        .partition(|port| matches!(port.port_type, SerialPortType::UsbPort(_)));
+    match usb.as_slice() {
+        [port] => Ok(PathBuf::from(&port.port_name)),
+        [] if other.is_empty() => bail!("--auto-device found no serial port, give the device"),
+        [] => bail!("--auto-device found no USB serial port, give the device. Other ports:\n{}", list(&other)),
+        _ => bail!("--auto-device found {} USB serial ports, give the device:\n{}", usb.len(), list(&usb))
+    }
+}
+
+/// The device of the board `profile` (named `name`) describes: its `device`, or the port of
+/// the adapter with its `usb_serial`
+pub fn profile_device(name: &str, profile: &Profile) -> Result<String> {
+    let serial = match (&profile.device, &profile.usb_serial) {
+        (Some(_), Some(_)) => bail!("Profile \"{}\" gives both a device and a usb_serial, keep one", name),
+        (Some(device), None) => return Ok(device.clone()),
+        (None, Some(serial)) => serial,
+        (None, None) => bail!("Profile \"{}\" has no device or usb_serial", name)
+    };
+    let ports = ports()?;
+    let found: Vec<&SerialPortInfo> = ports.iter().filter(|port| usb_serial(port) == Some(serial)).collect();
+    match found.as_slice() {
+        [port] => Ok(port.port_name.clone()),
+        [] => bail!("No port has the adapter with serial {} of profile \"{}\". Ports:\n{}", serial, name,
+            list(&ports)),
+        _ => bail!("Several ports have an adapter with serial {} (profile \"{}\"):\n{}", serial, name, list(&found))
+    }
+}
+
+/// The profile of the board plugged in, found by its adapter's serial number, with its port.
+/// Fails unless exactly one port has an adapter one of `profiles` names.
+pub fn select_profile(profiles: &BTreeMap<String, Profile>) -> Result<(&str, PathBuf)> {
+    let ports = ports()?;
+    let mut matches = Vec::new();
+    for port in &ports {
+        for (name, profile) in profiles {
+            if usb_serial(port).is_some() && usb_serial(port) == profile.usb_serial.as_deref() {
+                matches.push((name.as_str(), port));
+            }
+        }
+    }
+    match matches.as_slice() {
+        [(name, port)] => Ok((name, PathBuf::from(&port.port_name))),
+        [] => {
+            let serials: Vec<String> = profiles.iter()
+                .filter_map(|(name, profile)| Some(format!("  {}: {}", name, profile.usb_serial.as_ref()?)))
+                .collect();
+            if serials.is_empty() {
+                bail!("--profile auto needs profiles with a usb_serial, there are none");
+            }
+            bail!("--profile auto found no adapter with a profile's usb_serial. Ports:\n{}\nProfiles:\n{}",
+                if ports.is_empty() { "  none".to_string() } else { list(&ports) }, serials.join("\n"))
+        },
+        _ => {
+            let lines: Vec<String> = matches.iter().map(|(name, port)| format!("  {}: {}", name, list(&[*port]).trim()))
+                .collect();
+            bail!("--profile auto found {} boards with profiles, pick one with --profile <name>:\n{}", matches.len(),
+                lines.join("\n"))
+        }
+    }
+}
+
+/// The serial number of the USB adapter of the port at `path`, if it's one that has a serial
+pub fn port_usb_serial(path: &Path) -> Option<String> {
+    let path = fs::canonicalize(path).ok()?;
+    ports().ok()?.iter()
+        .find(|port| fs::canonicalize(&port.port_name).is_ok_and(|name| name == path))
+        .and_then(|port| usb_serial(port).map(str::to_string))
+}
+
+/// The serial ports there are, with the macOS twins left out
+fn ports() -> Result<Vec<SerialPortInfo>> {
+    let ports = serialport::available_ports().map_err(|err| anyhow!("Couldn't list the serial ports: {}", err))?;
+    Ok(dedup(ports))
+}
+
+/// The serial number of the port's USB adapter, if it has one
+fn usb_serial(port: &SerialPortInfo) -> Option<&str> {
+    match &port.port_type {
+        SerialPortType::UsbPort(UsbPortInfo { serial_number, .. }) => serial_number.as_deref(),
+        _ => None
+    }
+}
+
+/// One line per port, with what's known of its adapter
+fn list(ports: &[impl Borrow<SerialPortInfo>]) -> String {
+    let lines: Vec<String> = ports.iter().map(Borrow::borrow).map(|port| match &port.port_type {
+        SerialPortType::UsbPort(usb) => {
+            let mut line = format!("  {} ({:04x}:{:04x}", port.port_name, usb.vid, usb.pid);
+            for detail in [&usb.manufacturer, &usb.product].into_iter().flatten() {
+                line += &format!(" {}", detail);
+            }
+            if let Some(serial) = &usb.serial_number {
+                line += &format!(", serial {}", serial);
+            }
+            line + ")"
+        },
+        _ => format!("  {}", port.port_name)
+    }).collect();
+    lines.join("\n")
+}
+
+/// Drop the `/dev/tty.*` twin of each `/dev/cu.*` port
+#[cfg(target_os = "macos")]
+fn dedup(ports: Vec<SerialPortInfo>) -> Vec<SerialPortInfo> {
+    let callout: Vec<String> = ports.iter().filter_map(|port| port.port_name.strip_prefix("/dev/cu.")).map(str::to_string)
+        .collect();
+    ports.into_iter()
+        .filter(|port| port.port_name.strip_prefix("/dev/tty.").is_none_or(|name| !callout.iter().any(|cu| cu == name)))
+        .collect()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn dedup(ports: Vec<SerialPortInfo>) -> Vec<SerialPortInfo> {
+    ports
+}
@@ -0,0 +1,226 @@
+//! Measuring what the link can do (`pusher bench`), to tell a slow link from a slow pusher.
+//!
+//! The bytes go out through the same path as a pushed kernel, `CHUNK_SIZE` at a time, so
+//! changes to it show up in the throughput. Against a loopback jumper or a loader echoing what
+//! it receives, the round trip of a single byte is timed too and what comes back is compared
+//! to what was sent.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{Result, bail};
+use serde_json::{Value, json};
+
+use crate::output;
+use crate::protocol::{self, ReadableWaiter};
+use crate::push::CHUNK_SIZE;
+use crate::transport::Transport;
+use crate::status;
+
+/// Single byte round trips timed
+const ROUND_TRIPS: usize = 8;
+/// Sent for the round trips
+const ROUND_TRIP_BYTE: u8 = b'U';
+/// How long an echo may take, no echo after that means there's nothing echoing
+const ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What the benchmark sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    Random,
+    Zeros,
+}
+
+impl Pattern {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "random" => Ok(Pattern::Random),
+            "zeros" => Ok(Pattern::Zeros),
+            _ => bail!("Unknown pattern \"{}\", expected random or zeros", name)
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Pattern::Random => "random",
+            Pattern::Zeros => "zeros",
+        }
+    }
+
+    /// `size` bytes of the pattern
+    fn generate(self, size: usize) -> Vec<u8> {
+        match self {
+            Pattern::Random => {
+                // xorshift64, good enough to keep a link from compressing or a jumper from hiding errors
+                let mut state = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64 | 1;
+                (0..size).map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                }).collect()
+            },
+            Pattern::Zeros => vec![0; size],
+        }
+    }
+}
+
+/// What came back from an echoing device
+#[derive(Debug, Clone, Copy)]
+pub struct Echo {
+    pub received: usize,
+    /// Bytes that came back different
+    pub corrupted: usize,
+    /// Bytes that never came back
+    pub lost: usize,
+}
+
+/// The result of a benchmark
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub size: usize,
+    pub pattern: Pattern,
+    /// 0 for links without one, like sockets
+    pub baud_rate: u32,
+    /// How long writing all the bytes took
+    pub elapsed: Duration,
+    /// Shortest and average single byte round trip, if anything echoed
+    pub round_trip: Option<(Duration, Duration)>,
+    pub echo: Option<Echo>,
+}
+
+impl Report {
+    /// Sustained bytes written per second
+    pub fn throughput(&self) -> f64 {
+        self.size as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The throughput as a percentage of what the baud rate allows with 8N1 framing (10 bits a byte)
+    pub fn line_rate_percent(&self) -> Option<f64> {
+        (self.baud_rate > 0).then(|| self.throughput() * 100.0 / (self.baud_rate as f64 / 10.0))
+    }
+
+    /// Corrupted and lost bytes over the bytes sent, if anything echoed
+    pub fn error_rate(&self) -> Option<f64> {
+        self.echo.map(|echo| (echo.corrupted + echo.lost) as f64 / self.size.max(1) as f64)
+    }
+
+    pub fn print(&self) {
+        status!("[PUSHER] Benchmark of {} {} bytes{}", self.size, self.pattern.name(),
+            if self.baud_rate > 0 { format!(" at {} baud", self.baud_rate) } else { String::new() });
+        status!("[PUSHER] Throughput: {:.0} bytes/s ({:.1} kbit/s) over {:.2}s{}", self.throughput(),
+            self.throughput() * 8.0 / 1000.0, self.elapsed.as_secs_f64(),
+            self.line_rate_percent().map_or(String::new(), |percent| format!(", {:.0}% of the line rate", percent)));
+        let (Some((min, average)), Some(echo)) = (self.round_trip, self.echo) else {
+            status!("[PUSHER] Nothing echoed, round trips and errors weren't measured (they need a loopback \
+                jumper or an echoing loader)");
+            return;
+        };
+        status!("[PUSHER] Round trip: {:.2}ms shortest, {:.2}ms average over {} bytes", millis(min), millis(average),
+            ROUND_TRIPS);
+        status!("[PUSHER] Echo: {} bytes back, {} corrupted, {} lost, error rate {:.2e}", echo.received,
+            echo.corrupted, echo.lost, self.error_rate().unwrap_or_default());
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "size": self.size,
+            "pattern": self.pattern.name(),
+            "baud_rate": self.baud_rate,
+            "seconds": self.elapsed.as_secs_f64(),
+            "bytes_per_second": self.throughput(),
+            "line_rate_percent": self.line_rate_percent(),
+            "round_trip_ms": self.round_trip.map(|(min, average)| json!({ "min": millis(min), "average": millis(average) })),
+            "echo": self.echo.map(|echo| json!({
+                "received": echo.received,
+                "corrupted": echo.corrupted,
+                "lost": echo.lost,
+                "error_rate": self.error_rate(),
+            })),
+        })
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Time single byte round trips, then send `size` bytes of `pattern` and compare what echoes
+/// back, if anything echoed the round trips
+pub fn bench(serial_device: &mut dyn Transport, baud_rate: u32, size: usize, pattern: Pattern) -> Result<Report> {
+    serial_device.clear_input()?;
+    let mut waiter = protocol::ReadableWaiter::new(serial_device)?;
+    let round_trip = time_round_trips(serial_device, &mut waiter)?;
+    let data = pattern.generate(size);
+    let mut received = Vec::new();
+    let started = Instant::now();
+    for (index, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        protocol::write_bytes(serial_device, chunk)?;
+        if round_trip.is_some() {
+            received.extend(serial_device.read_all()?);
+        }
+        print_progress(index * CHUNK_SIZE + chunk.len(), size);
+    }
+    let elapsed = started.elapsed();
+    status!();
+    let echo = match round_trip {
+        Some(_) => {
+            // the rest of the echo, until it's all back or stops coming
+            while received.len() < size && waiter.wait(Some(ECHO_TIMEOUT))? {
+                received.extend(serial_device.read_all()?);
+            }
+            let (corrupted, lost) = compare(&data, &received);
+            Some(Echo { received: received.len(), corrupted, lost })
+        },
+        None => None
+    };
+    Ok(Report { size, pattern, baud_rate, elapsed, round_trip, echo })
+}
+
+/// The shortest and average time for a byte to come back, `None` if the first doesn't
+fn time_round_trips(serial_device: &mut dyn Transport, waiter: &mut ReadableWaiter)
+    -> Result<Option<(Duration, Duration)>>
+{
+    let mut times = Vec::with_capacity(ROUND_TRIPS);
+    for _ in 0..ROUND_TRIPS {
+        let sent = Instant::now();
+        protocol::write_byte(serial_device, ROUND_TRIP_BYTE)?;
+        let mut echoed = false;
+        while !echoed && waiter.wait(Some(ECHO_TIMEOUT))? {
+            // whatever else the device prints isn't an echo
+            echoed = serial_device.read_all()?.contains(&ROUND_TRIP_BYTE);
+        }
+        if !echoed {
+            if times.is_empty() {
+                return Ok(None);
+            }
+            bail!("The device echoed {} bytes and then stopped", times.len());
+        }
+        times.push(sent.elapsed());
+    }
+    let min = times.iter().min().copied().unwrap_or_default();
+    let average = times.iter().sum::<Duration>() / times.len() as u32;
+    Ok(Some((min, average)))
+}
+
+/// Corrupted and lost bytes of `received` against `sent`. A byte missing from the echo is
+/// counted lost rather than shifting everything after it into corrupted.
+fn compare(sent: &[u8], received: &[u8]) -> (usize, usize) {
+    let (mut corrupted, mut lost) = (0, 0);
+    let (mut i, mut j) = (0, 0);
+    while i < sent.len() && j < received.len() {
+        if sent[i] == received[j] {
+            j += 1;
+        } else if sent.get(i + 1) == Some(&received[j]) && sent.get(i + 2) == received.get(j + 1) {
+            lost += 1;
+        } else {
+            corrupted += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    (corrupted, lost + sent.len() - i)
+}
+
+fn print_progress(sent: usize, size: usize) {
+    let percent = (sent * 100).checked_div(size).unwrap_or(100);
+    output::status_text(&format!("\r[PUSHER] Sent {}/{} bytes ({}%)", sent, size, percent));
+}
@@ -0,0 +1,78 @@
+//! Showing a raw capture (--capture-raw, or a --record journal) as a timestamped hex dump, with
+//! `pusher capdump`: what went which way and when, for the bytes the console never shows.
+
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Result, bail};
+
+use crate::journal::{Direction, Entry, JournalReader};
+
+/// Bytes per row
+const WIDTH: usize = 16;
+
+/// The records of a capture to show
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Filter {
+    /// Only the ones going this way
+    pub direction: Option<Direction>,
+    /// Only the ones from this long after the capture started
+    pub from: Option<Duration>,
+    /// Only the ones until this long after the capture started
+    pub to: Option<Duration>,
+}
+
+impl Filter {
+    /// The direction named by --direction
+    pub fn parse_direction(name: &str) -> Result<Direction> {
+        match name {
+            "rx" => Ok(Direction::Rx),
+            "tx" => Ok(Direction::Tx),
+            _ => bail!("Unknown direction \"{}\", expected rx or tx", name)
+        }
+    }
+
+    fn matches(&self, direction: Direction, time: Duration) -> bool {
+        self.direction.is_none_or(|wanted| wanted == direction)
+            && self.from.is_none_or(|from| time >= from)
+            && self.to.is_none_or(|to| time <= to)
+    }
+}
+
+/// Print the records of the capture at `path` that `filter` lets through, each a line with its
+/// time, direction and size followed by its bytes in rows. Stops quietly when stdout is closed
+/// (piped into `head`).
+pub fn dump(path: &Path, filter: &Filter) -> Result<()> {
+    let mut reader = JournalReader::open(path)?;
+    let mut out = io::stdout().lock();
+    while let Some(entry) = reader.next_entry()? {
+        if !filter.matches(entry.direction, entry.time) {
+            continue;
+        }
+        match write_entry(&mut out, &entry) {
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => return Ok(()),
+            result => result?
+        }
+    }
+    Ok(())
+}
+
+fn write_entry(out: &mut impl Write, entry: &Entry) -> io::Result<()> {
+    let direction = match entry.direction {
+        Direction::Rx => "RX",
+        Direction::Tx => "TX",
+    };
+    let time = format!("+{}.{:06}", entry.time.as_secs(), entry.time.subsec_micros());
+    if entry.data.is_empty() {
+        return writeln!(out, "{} {} link closed", time, direction);
+    }
+    writeln!(out, "{} {} {} bytes", time, direction, entry.data.len())?;
+    for (row, bytes) in entry.data.chunks(WIDTH).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = bytes.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        writeln!(out, "  {:08x}  {:<width$}  |{}|", row * WIDTH, hex.join(" "), ascii, width = WIDTH * 3 - 1)?;
+    }
+    Ok(())
+}
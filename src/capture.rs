@@ -0,0 +1,52 @@
+//! Device output received while a transfer is going on, held back so it doesn't interleave
+//! with pusher's own progress output and shown in one block afterwards.
+
+use crate::output;
+use crate::status;
+
+/// Console output collected during a transfer, up to a size limit
+pub struct Capture {
+    buffer: Vec<u8>,
+    limit: usize,
+    dropped: usize,
+}
+
+impl Capture {
+    /// Keep at most `limit` bytes, anything beyond is only counted
+    pub fn new(limit: usize) -> Self {
+        Self { buffer: Vec::new(), limit, dropped: 0 }
+    }
+
+    /// Add received bytes
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let kept = bytes.len().min(self.limit - self.buffer.len());
+        self.buffer.extend_from_slice(&bytes[..kept]);
+        self.dropped += bytes.len() - kept;
+    }
+
+    /// Hand over what was captured and how many bytes didn't fit, for callers showing it
+    /// their own way
+    pub fn take(&mut self) -> (Vec<u8>, usize) {
+        let dropped = self.dropped;
+        self.dropped = 0;
+        (std::mem::take(&mut self.buffer), dropped)
+    }
+
+    /// Print everything captured in a delimited block, nothing if the device stayed quiet
+    pub fn replay(&mut self) {
+        if self.buffer.is_empty() && self.dropped == 0 {
+            return;
+        }
+        status!("[PUSHER] Output received during transfer:");
+        output::device_bytes(&self.buffer);
+        if !self.buffer.ends_with(b"\n") {
+            output::device("\n");
+        }
+        if self.dropped > 0 {
+            status!("[PUSHER] ({} more bytes truncated)", self.dropped);
+        }
+        status!("[PUSHER] End of output received during transfer");
+        self.buffer.clear();
+        self.dropped = 0;
+    }
+}
@@ -0,0 +1,3491 @@
+//! The `pusher` command: its command line, and the session it runs with the device.
+
+use std::ffi::CString;
+use std::{fmt, fs};
+use std::os::unix::prelude::{OsStrExt, RawFd};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use std::{env, io};
+use std::path::{PathBuf, Path};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use anyhow::{Result, anyhow, bail};
+use libc::{isatty, open, 
+    O_RDWR, // read-write
+    O_NONBLOCK, // non blocking
+    O_NOCTTY // open as non-controlling terminal
+};
+
+use mio::{Poll, Events, Interest};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2, SIGWINCH};
+use signal_hook_mio::v0_8::Signals;
+use serde_json::{json, Value};
+use regex::bytes::Regex;
+use crate::{autobaud, autodevice, bench, cobs, commands, control, defmt, demux, elf, fanout, fetch, output, pattern, probe,
+    protocol, pull, simulate, trace, verify};
+use crate::capdump;
+use crate::capture::Capture;
+use crate::output::Output;
+use crate::console::ConsoleServer;
+use crate::control::{ControlServer, Request};
+use crate::events::{self, Event, EventLog, Rotation};
+use crate::fetch::RemoteImage;
+use crate::push::{self, BoardChoice, BoardKernels, ImageDigest, PushOptions, Step, Transfer};
+use crate::statushttp::{Endpoint, StatusServer};
+use crate::statusline::StatusLine;
+use crate::stats::{PushRecord, SessionStats};
+use crate::hotplug::{Hotplug, HotplugMonitor, Selector};
+use crate::idle::IdleWatch;
+use crate::mirror::Mirror;
+use crate::tokens::{Source, Tokens};
+use crate::uarterrors::ErrorWatch;
+use crate::lines::{self, LineWatch};
+use crate::lock::{self, DeviceLock};
+use crate::enter::{EnterSends, EnterTranslator};
+use crate::power::{self, HubPort, PowerCycle};
+use crate::reset::{self, BoardReset, GpioReset, GpioSpec};
+use crate::sendfile::{self, FileSend};
+use crate::autobaud::Training;
+use crate::readyprobe::{Prober, ReadyProbe};
+use crate::hexview::{self, HexView};
+use crate::bench::Pattern;
+use crate::completions::{self, Dynamic, Shell};
+use crate::config::{Config, UnknownBoard};
+use crate::pattern::RollingMatcher;
+use crate::protocol::{Checksum, Protocol};
+use crate::symbols::Symbolizer;
+use crate::transport::{ErrorCounts, Transport, UnixSocketDevice, WireTap};
+use crate::journal::{self, JournalWriter, Recorder};
+use crate::tty::{self, SerialDevice, StdinDevice};
+use crate::status;
+
+#[cfg(feature = "banner")]
+const PUSHER_LOGO: &str = r#"
+__________             .__                  
+\______   \__ __  _____|  |__   ___________ 
+ |     ___/  |  \/  ___/  |  \_/ __ \_  __ \
+ |    |   |  |  /\___ \|   Y  \  ___/|  | \/
+ |____|   |____//____  >___|  /\___  >__|   
+                     \/     \/     \/       
+"#;
+const USAGE: &str = "Usage: pusher [push] [options] <device> <baudrate> [<kernel>]
+       pusher push --list-dtbs
+       pusher pull <device> <baudrate> <output>
+       pusher verify <device> <baudrate> <kernel>
+       pusher replay [--speed <factor>|--lockstep] [options] <journal> <kernel>
+       pusher bench [--size <n>] [--pattern random|zeros] [--json] <device> <baudrate>
+       pusher lines [--watch-lines] <device> [<baudrate>]
+       pusher capdump [--direction rx|tx] [--from <secs>] [--to <secs>] <capture>
+       pusher ctl <socket> status|push|set-kernel <path>|reset-board|quit
+       pusher completions bash|zsh|fish
+
+<device> is a tty or unix:<socket_path>, <kernel> a file or an http(s):// URL. Without one pusher is
+just a console (--interactive-only)
+
+Press Ctrl-A m to read device memory, Ctrl-A w to write it, Ctrl-A e to toggle local echo,
+Ctrl-A Enter to switch what Enter sends (CR, LF, CR LF or as typed),
+Ctrl-A s to show the session statistics, Ctrl-A c to cancel a push, Ctrl-A f to push right away,
+Ctrl-A P to push another file once, Ctrl-A b to reset the board, Ctrl-A p to power cycle it,
+Ctrl-A d / Ctrl-A r to toggle DTR / RTS, Ctrl-A D / Ctrl-A R to pulse them, Ctrl-A l to show the lines,
+Ctrl-A t to toggle the status line, Ctrl-A u to send a text file line by line (Esc stops it),
+Ctrl-A x to exit, Ctrl-A Ctrl-A to send Ctrl-A
+
+Options:
+    --flush-input         discard stale RX data before starting
+    --wake <pattern>      bytes sent on connecting, before waiting for the ready signal
+    --config <path>       config file to use instead of ./pusher.toml
+    --dtb <name|path>     send a device tree blob after the kernel
+    --list-dtbs           list the DTB variants defined in the config
+    --dump-kernel-info    print the kernel's path, size, CRC32 and first and last bytes, and exit
+    --push-on-connect     push immediately instead of waiting for the break sequence
+    --symbols <elf>       annotate addresses printed by the device using the kernel's symbols
+    --symbol-pattern <re> regex matching addresses to annotate (group 1 is the address)
+    --defmt <elf>         decode defmt log frames using the firmware's ELF
+    --checksum <alg>      append a crc16, crc32 or no (none) checksum after the image
+    --negotiate           let the loader pick the checksum, --checksum if it doesn't answer
+    --identify            ask the loader who it is after its ready signal and report it
+    --verify-after-send   ask the loader for the CRC32 of what it stored after each push
+    --nak-token <pattern> the loader's answer asking for the size again instead of OK
+    --ack-status          read a status after the loader's OK (`OK 2`) and report it
+    --retries <n>         size headers sent again on --nak-token at most (default 3)
+    --demux               split framed device output into channels, channel 0 is the console
+    --channel <n>=<path>  file or FIFO receiving channel n when demuxing
+    --autobaud-train [0xNN] [interval_ms]
+                          repeat a training byte until the device answers
+    --autobaud-response <pattern>
+                          the device's answer to training (default OK)
+    --autobaud-timeout <ms>
+                          give up training after this long (default 10000)
+    --trigger-pattern <pattern>
+                          ready signal to wait for instead of \x03\x03\x03
+    --probe-bauds <rates> try each comma separated baud rate until the device is understood
+    --probe-pattern <pattern>
+                          output confirming a probed rate, besides the ready signal
+    --probe-after <secs>  poke the loader when no ready signal came for this long
+    --probe-string <pattern>
+                          what --probe-after sends (default \n)
+    --probe-response <pattern>
+                          the loader's answer to the probe, taken as the ready signal
+    --probe-limit <n>     probes sent at most, each after twice the wait (default 5)
+    --ready-timeout <secs>
+                          exit when no ready signal came for this long
+    --break-reset-timeout <ms>
+                          forget a partial ready signal whose rest doesn't follow within this long
+    --local-echo          show typed characters locally (toggle with Ctrl-A e)
+    --enter-sends <mode>  what Enter sends: cr, lf, crlf or raw (the default, as typed)
+    --forward-stdin       send what's piped to stdin to the device, e.g. echo boot | pusher ...
+    --char-delay <us>     pause after each typed byte sent to the device
+    --send-file-on-connect <path>
+                          send a text file to the console line by line on connecting
+    --line-delay <ms>     pause after each line of a file sent to the console
+    --line-prompt <re>    wait for this regex in the output before sending a file's next line
+    --char-delay-push     pace the pushed kernel with --char-delay too
+    --pre-send-wait <ms>  pause once between the loader's OK and the first kernel byte
+    --write-timeout <ms>  fail a push when the device takes no byte for this long
+    --event-log <file>    append a timestamped line per event to <file>
+    --log-rotate-size <bytes>
+                          rotate the --event-log into <file>.1, <file>.2... when it gets this big
+    --log-rotate-keep <n> rotated logs kept, older ones are deleted (default 5)
+    --no-drain            don't drain pending output before sending the size
+    --echo-received-to-stderr
+                          write the device output to stderr, status lines to stdout
+    --strip-ansi          show the device output without its ANSI color and cursor sequences
+    --print-wire          hex dump every byte sent and received to stderr
+    --record <path>       journal every byte sent and received, for pusher replay
+    --capture-raw <path>  capture every read and write of the device, buffered, for pusher capdump
+    --direction <rx|tx>   show only what pusher capdump's capture received or sent
+    --from <secs>         show pusher capdump's records from this many seconds in
+    --to <secs>           show pusher capdump's records until this many seconds in
+    --speed <factor>      replay that many times as fast as recorded, 0 for no waiting
+    --lockstep            replay the device's output in step with what's sent, not the clock
+    --size <n>            bytes sent by pusher bench (default 16384)
+    --pattern <name>      what pusher bench sends, random (the default) or zeros
+    --json                print pusher bench's report as JSON
+    --verbose             more status lines about the link
+    --trace               time each push's handshake, transfer and verify on stderr (RUST_LOG filters)
+    --status-line         keep pusher's state in a line at the bottom of the terminal
+    --raw                 send the kernel bytes alone, no size header or handshake
+    --no-handshake        --raw right away on connecting, without waiting for the ready signal
+    --poll-events <n>     readiness events taken per wakeup (default 1024)
+    --skip-unchanged      don't push the same image twice in a session (Ctrl-A f forces it)
+    --skip-signal <pattern>
+                          sent to the device instead of a skipped push
+    --exit-on <pattern>   exit once the device prints this, e.g. the end of a test run
+    --once                exit once the kernel is pushed
+    --interactive-only    just the console, no kernel (the same as leaving the kernel out)
+    --runner              be cargo's runner, pushing to the [runner] device of pusher.toml
+    --kernel <path|url>   the kernel to push, URLs are downloaded first
+    --header <\"Name: value\">
+                          extra HTTP header when downloading the kernel
+    --expected-sha256 <hex>
+                          refuse a downloaded kernel with another SHA-256
+    --refetch-each-push   download the kernel again before every push if it changed
+    --cache-dir <dir>     keep downloaded kernels there for the next runs
+    --kernel-offset <n>   skip the first <n> bytes of the kernel file
+    --kernel-length <n>   send only <n> bytes of the kernel file
+    --control-socket <path>
+                          take requests from `pusher ctl` on a Unix domain socket
+    --reset-gpio <chip:line[:active_low]>
+                          reset the board through a GPIO line instead of DTR
+    --reset-pulse <ms>    how long a reset holds the line (default 100)
+    --power-cycle-cmd <command>
+                          command power cycling the board when pushes keep failing
+    --hub-location <hub:port=n>
+                          power cycle by switching a USB hub port, like uhubctl
+    --power-cycle-after <n>
+                          failed pushes in a row before power cycling (default 3)
+    --device <[name=]device>
+                          the device, repeat it to push to several boards at once
+    --auto-device         without a device, use the only USB serial adapter plugged in
+    --profile <name|auto> push to a board of the config's [profiles], auto finds it by USB serial
+    --steal-lock          take the device's lockfile even if its holder looks alive
+    --open-retries <n>    try opening a busy device again that many times (default 0)
+    --open-retry-delay <ms>
+                          wait before the first retry, doubled for each next one (default 500)
+    --console-server <addr:port>
+                          share the console with TCP clients
+    --console-server-writable
+                          let console clients type to the device
+    --status-addr <addr:port>
+                          serve /status (JSON) and /metrics (Prometheus) over HTTP
+    --no-banner           don't print the logo on start
+    --keepalive <ms>      send a byte the loader ignores when the link was idle this long
+    --idle-warn <secs>    warn when the booted kernel printed nothing for this long
+    --idle-warn-always    with --idle-warn, also watch while waiting for the first ready signal
+    --watch-lines         print each change of the modem control lines (CTS, DSR, DCD, RI...)
+    --mirror <device:baudrate>
+                          copy every byte received from the device to this second port
+    --keepalive-byte <byte>
+                          the byte --keepalive sends (default 0x00)
+    --daemon              run unattended: no terminal, reopen the device when it goes away
+                          (SIGUSR1 dumps the statistics, SIGUSR2 toggles --verbose)
+    --apply-hard          on SIGHUP, open the device again to apply a new device or baud rate
+    --udev                notice the device being plugged in and out through udev
+    --udev-match <vid:pid|serial=serial>
+                          --udev, following the tty with these USB IDs or serial
+    --cobs                show console output as hex dumps of COBS packets
+    --hex                 show console output as a hex dump
+    --hex-width <n>       bytes per row of the --hex dump (default 16)
+    --hex-diff            highlight bytes that changed since the row or COBS packet before";
+/// Readiness events taken per wakeup of the event loop, by default
+const DEFAULT_POLL_EVENTS: usize = 1024;
+/// Bytes sent by `pusher bench`, by default
+const DEFAULT_BENCH_SIZE: usize = 16384;
+/// Rate `pusher lines` opens the tty at, unless given. The lines don't care.
+const DEFAULT_LINES_BAUD_RATE: u32 = 115200;
+/// Size headers sent again when the loader answers one with --nak-token, by default
+const DEFAULT_SIZE_RETRIES: u32 = 3;
+/// Most device output kept while a transfer is going on
+const CAPTURE_LIMIT: usize = 64 * 1024;
+/// How long a power cycled board has to show up again, unless running as a daemon
+const POWER_CYCLE_GRACE: Duration = Duration::from_secs(30);
+/// Shortest and longest wait before a daemon opens a device that went away again
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+/// How long each rate is listened to when probing
+const PROBE_DWELL: Duration = Duration::from_millis(1500);
+/// Wait before trying again at opening a busy device, unless --open-retry-delay says
+const DEFAULT_OPEN_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Run pusher with the command line it was started with, showing what it shows on `output`
+pub fn run(output: Output) -> Result<()> {
+    output::with(output, run_command)
+}
+
+fn run_command() -> Result<()> {
+    let (command, globals) = parse_input(None)?;
+    // the answer is all a script talking to the control socket wants to read
+    if let Command::Ctl(options) = command {
+        let response = control::send_request(&options.socket_path, &options.request)?;
+        status!("{}", response);
+        if response["ok"] != true {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    // printed for a shell to read, nothing else goes with it
+    match &command {
+        Command::Completions(shell) => {
+            output::status_text(&completions::script(*shell, USAGE));
+            return Ok(());
+        },
+        Command::Complete(dynamic, config) => {
+            for value in dynamic.values(config) {
+                status!("{}", value);
+            }
+            return Ok(());
+        },
+        Command::Capdump(path, filter) => return capdump::dump(path, filter),
+        // the report alone goes to the device output
+        Command::Bench(options) if options.json => output::set_status_to_stderr(true),
+        _ => {}
+    }
+    print_banner(globals.show_banner);
+    lock::set_steal(globals.steal_lock);
+    tty::set_open_retries(globals.open_retries, globals.open_retry_delay);
+    let wire_log = WireLog::open(&globals)?;
+    status!("[PUSHER] Pusher is waiting...");
+    let mut options = match command {
+        Command::Push(options) => options,
+        Command::Ctl(_) | Command::Completions(_) | Command::Complete(..) | Command::Capdump(..) => {
+            unreachable!("handled above")
+        },
+        Command::Pull(options) => {
+            let mut device = open_device(options.target, options.baud_rate, &wire_log)?;
+            return pull::pull(device.as_mut(), &options.protocol, &options.output_path);
+        }
+        Command::Verify(options) => {
+            let mut device = open_device(options.target, options.baud_rate, &wire_log)?;
+            return verify::verify(device.as_mut(), &options.protocol, &options.kernel_path);
+        }
+        Command::Bench(options) => {
+            let mut device = open_device(options.target, options.baud_rate, &wire_log)?;
+            let report = bench::bench(device.as_mut(), options.baud_rate, options.size, options.pattern)?;
+            match options.json {
+                true => output::device(&format!("{}\n", report.to_json())),
+                false => report.print()
+            }
+            return Ok(());
+        }
+        Command::Lines(options) => {
+            let mut device = open_device(options.target, options.baud_rate, &wire_log)?;
+            return lines::show(device.as_mut(), options.watch);
+        }
+        Command::Simulate(options) => {
+            let mut device = open_device(options.target, options.baud_rate, &wire_log)?;
+            return simulate::simulate(device.as_mut(), &options.protocol, options.expected_crc);
+        }
+        Command::ListDtbs(config) => {
+            config.list_dtbs();
+            return Ok(());
+        }
+    };
+    // before the remote image is taken, so a reload can tell whether its URL changed
+    let loaded = settings(&options);
+    output::set_device_to_stderr(options.device_to_stderr);
+    output::set_strip_ansi(options.strip_ansi);
+    output::set_verbose(options.verbose);
+    if options.trace {
+        trace::init()?;
+    }
+    let mut remote = options.remote.take();
+    if let Some(remote) = &mut remote {
+        remote.fetch()?;
+    }
+    if options.dump_kernel_info {
+        let kernel_path = options.kernel_path.as_ref().ok_or_else(|| anyhow!("--dump-kernel-info needs a kernel"))?;
+        status!("[PUSHER] {}", push::kernel_info(kernel_path, options.kernel_offset, options.kernel_length)?);
+        return Ok(());
+    }
+    // a window that doesn't fit should fail now, not when the device asks for the kernel
+    match &options.kernel_path {
+        Some(kernel_path) => {
+            let file_size = fs::metadata(kernel_path)?.len();
+            let window = push::kernel_window(file_size, options.kernel_offset, options.kernel_length)?;
+            if options.kernel_length.is_some_and(|length| length > window.end - window.start) {
+                status!("[PUSHER] Warning: --kernel-length goes past the end of {}, sending the {} bytes left",
+                    kernel_path.display(), window.end - window.start);
+            }
+        },
+        None => status!("[PUSHER] No kernel given, just the console (Ctrl-A P pushes a file)")
+    }
+    exit_on_second_signal()?;
+    // a daemon has no terminal to set up, even if started from one
+    if !options.boards.is_empty() {
+        return fan_out(&options, &wire_log);
+    }
+    let mut stdin_device = if options.daemon {
+        StdinDevice::detached()
+    } else {
+        match StdinDevice::init() {
+            Ok(stdin) => stdin,
+            Err(err) => bail!("Failed initializing stdin: {}", err)
+        }
+    };
+    let event_log = match &options.event_log_path {
+        Some(path) => match EventLog::open(path, &session_context(&options), options.log_rotation) {
+            Ok(event_log) => event_log,
+            Err(err) => bail!("Couldn't open event log {}: {}", path.display(), err)
+        },
+        None => EventLog::disabled()
+    };
+    let mut tokens = Tokens::new();
+    let control_server = match &options.control_socket {
+        Some(path) => match ControlServer::bind(path, tokens.allocate(Source::ControlClient)) {
+            Ok(server) => Some(server),
+            Err(err) => bail!("Couldn't listen on {}: {}", path.display(), err)
+        },
+        None => None
+    };
+    let console_server = match &options.console_server {
+        Some(address) => match ConsoleServer::bind(address, options.console_server_writable) {
+            Ok(server) => Some(server),
+            Err(err) => bail!("Couldn't listen on {}: {}", address, err)
+        },
+        None => None
+    };
+    let status_server = match &options.status_addr {
+        Some(address) => {
+            let server = StatusServer::bind(address)?;
+            status!("[PUSHER] Serving /status and /metrics on http://{}", server.address());
+            Some(server)
+        },
+        None => None
+    };
+    // udev is a nicety, polling for the device still works without it
+    let hotplug = match options.udev.clone().map(HotplugMonitor::open) {
+        Some(Ok(monitor)) => Some(monitor),
+        Some(Err(err)) => {
+            status!("[PUSHER] Warning: {}, polling for the device instead", err);
+            None
+        },
+        None => None
+    };
+    let reset_gpio = match &options.reset_gpio {
+        Some(spec) => Some(GpioReset::open(spec)?),
+        None => None
+    };
+    let mirror = match &options.mirror {
+        Some((target, baud_rate)) => {
+            let device = open_link(target.clone(), *baud_rate)
+                .map_err(|err| anyhow!("Couldn't open the --mirror port: {}", err))?;
+            Some(Mirror::new(device, target.to_string()))
+        },
+        None => None
+    };
+    let mut state = SessionState {
+        poll: Poll::new()?,
+        // Ctrl-C and process managers stopping us end the session cleanly, restoring the terminal
+        // SIGWINCH moves the status line to the terminal's new bottom, SIGUSR1 dumps the
+        // statistics, SIGUSR2 toggles the verbose status lines and SIGHUP reloads the configuration
+        signals: Signals::new([SIGINT, SIGTERM, SIGWINCH, SIGUSR1, SIGUSR2, SIGHUP])?,
+        control_server,
+        console_server,
+        status_server,
+        tokens,
+        event_log,
+        remote,
+        last_pushed: None,
+        board_reset: BoardReset::new(reset_gpio, options.reset_pulse),
+        failed_pushes: 0,
+        hotplug,
+        status_line: StatusLine::new(options.status_line),
+        stats: SessionStats::new(),
+        stopped_by: None,
+        stdin_open: false,
+        push_started: None,
+        pushing: None,
+        kernel_digest: None,
+        mirror,
+        uart_errors: None,
+        raw_capture: wire_log.capture.clone(),
+        dump_stats: false,
+        reload: false,
+        loaded,
+    };
+    if state.board_reset.has_gpio() {
+        status!("[PUSHER] Resetting the board");
+        state.board_reset.reset(None)?;
+    }
+    // without a terminal (CI, scripts) there are no keystrokes to forward, only the console to
+    // show, and what's piped in if asked to
+    if stdin_device.is_interactive() {
+        let token = state.tokens.allocate(Source::Stdin);
+        state.poll.registry().register(&mut stdin_device, token, Interest::READABLE)?;
+    } else if options.forward_stdin {
+        let token = state.tokens.allocate(Source::Stdin);
+        // regular files and /dev/null can't be polled
+        match stdin_device.watch_pipe()
+            .and_then(|()| state.poll.registry().register(&mut stdin_device, token, Interest::READABLE))
+        {
+            Ok(()) => state.stdin_open = true,
+            Err(err) => status!("[PUSHER] Warning: can't watch stdin ({}), not forwarding it", err)
+        }
+    }
+    let token = state.tokens.allocate(Source::Signals);
+    state.poll.registry().register(&mut state.signals, token, Interest::READABLE)?;
+    if let Some(server) = &state.control_server {
+        server.register(state.poll.registry(), state.tokens.allocate(Source::Control))?;
+    }
+    if let Some(server) = &state.console_server {
+        server.register(state.poll.registry(), state.tokens.allocate(Source::Console))?;
+    }
+    if let Some(server) = &state.status_server {
+        server.register(state.poll.registry(), state.tokens.allocate(Source::Status))?;
+    }
+    if let Some(monitor) = &mut state.hotplug {
+        monitor.register(state.poll.registry(), state.tokens.allocate(Source::Hotplug))?;
+    }
+    let serial_token = state.tokens.allocate(Source::Serial);
+
+    // shared by every connection and the waits between them
+    let mut events = Events::with_capacity(options.poll_events);
+    let mut backoff = RECONNECT_MIN;
+    // when the board was last power cycled, its device is waited for even without --daemon
+    let mut power_cycled: Option<Instant> = None;
+    let result = loop {
+        // a tty picked by its USB IDs may come back under another name
+        if let Some(path) = state.hotplug.as_ref().filter(|_| follows_udev(&options)).and_then(HotplugMonitor::find) {
+            options.target = Target::Serial(path);
+        }
+        let err = match open_device(options.target.clone(), options.baud_rate, &wire_log) {
+            Ok(mut device) => {
+                backoff = RECONNECT_MIN;
+                power_cycled = None;
+                device.set_write_timeout(options.write_timeout);
+                if options.flush_input {
+                    device.clear_input()?;
+                }
+                state.poll.registry().register(device.as_mut(), serial_token, Interest::READABLE)?;
+                let result = run_connected(device.as_mut(), &mut stdin_device, &mut options, &mut state, &mut events);
+                // however the connection ended, the old device is closed before it's opened again, or the
+                // reopen could find it busy. A lost device may not deregister, closing it does anyway
+                let _ = state.poll.registry().deregister(device.as_mut());
+                drop(device);
+                match result {
+                    Err(err) if err.is::<PowerCycled>() => {
+                        power_cycled = Some(Instant::now());
+                        err
+                    },
+                    Err(err) if matches!(options.target, Target::Replay { .. }) && is_replay_end(&err) => {
+                        status!("\r\n[PUSHER] End of the replay");
+                        break Ok(());
+                    },
+                    Err(err) if err.is::<DeviceLost>() => err,
+                    // a reload changed how it's opened
+                    Err(err) if err.is::<Reopen>() => continue,
+                    result => break result
+                }
+            },
+            Err(err) => err
+        };
+        // unattended boards get unplugged and power cycled, wait for the device to come back
+        if !options.daemon && power_cycled.is_none_or(|time| time.elapsed() >= POWER_CYCLE_GRACE) {
+            break Err(err);
+        }
+        status!("\r\n[PUSHER] {}, retrying in {}s", err, backoff.as_secs());
+        state.stats.on_reconnect();
+        state.event_log.record(Event::Disconnected(err.to_string()))?;
+        if !wait_for_device(&mut state, &mut options, &mut events, backoff)? {
+            break Ok(());
+        }
+        backoff = (backoff * 2).min(RECONNECT_MAX);
+    };
+    state.event_log.record(match &result {
+        Ok(()) => Event::Exit,
+        Err(err) => Event::Error(err.to_string())
+    })?;
+    let stopped_by = state.stopped_by;
+    // exiting skips destructors, the terminal, the control socket and the rest go first
+    drop(state);
+    drop(stdin_device);
+    result?;
+    if let Some(signal) = stopped_by {
+        wire_log.flush();
+        std::process::exit(128 + signal);
+    }
+    Ok(())
+}
+
+/// Once SIGINT or SIGTERM started the shutdown, a second one exits right away with the same
+/// code, for a shutdown stuck on a blocking wait (power cycle command, device that won't close)
+fn exit_on_second_signal() -> io::Result<()> {
+    let stopping = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        // checked before it's set, so only the second signal finds it set
+        signal_hook::flag::register_conditional_shutdown(signal, 128 + signal, Arc::clone(&stopping))?;
+        signal_hook::flag::register(signal, Arc::clone(&stopping))?;
+    }
+    Ok(())
+}
+
+/// Print the logo, unless it was compiled out (the `banner` feature) or turned off with --no-banner
+fn print_banner(show: bool) {
+    #[cfg(feature = "banner")]
+    if show {
+        status!("{}", PUSHER_LOGO);
+    }
+    #[cfg(not(feature = "banner"))]
+    let _ = show;
+}
+
+/// Open the link to the device, through `wire_log`
+fn open_device(target: Target, baud_rate: u32, wire_log: &WireLog) -> Result<Box<dyn Transport>> {
+    wire_log.tap(open_link(target, baud_rate)?)
+}
+
+/// Open the tty or the socket
+fn open_link(target: Target, baud_rate: u32) -> Result<Box<dyn Transport>> {
+    match target {
+        Target::Serial(path) => {
+            let port = SerialDevice::probe(&path).map_err(|err| anyhow!("Error opening serial device: {}", err))?;
+            // before opening, so a port in use says who has it
+            let lock = DeviceLock::acquire(&path)?;
+            match tty::retry_open(&path, || SerialDevice::init(open_tty(&path)?, baud_rate)) {
+                Ok(device) => {
+                    output::verbose(&format!("Opened {}", port));
+                    Ok(Box::new(device.with_lock(lock)))
+                },
+                Err(err) if err.kind() == io::ErrorKind::ResourceBusy => Err(port_busy(&path, &err)),
+                Err(err) => bail!("Error opening serial device: {}", err)
+            }
+        },
+        Target::UnixSocket(path) => match UnixSocketDevice::connect(&path) {
+            Ok(device) => Ok(Box::new(device)),
+            Err(err) => bail!("Error connecting to {}: {}", path.display(), err)
+        },
+        Target::Replay { journal, lockstep: true, .. } => Ok(Box::new(journal::Lockstep::open(&journal)?)),
+        Target::Replay { journal, speed, .. } => Ok(Box::new(journal::replay(&journal, speed)?))
+    }
+}
+
+/// Whether `err` is a replayed journal ending: the link closing, noticed by the session or by a push
+fn is_replay_end(err: &anyhow::Error) -> bool {
+    err.is::<DeviceLost>() || err.chain().any(|cause| cause.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::UnexpectedEof))
+}
+
+/// What's done with the bytes on the wire besides carrying them
+struct WireLog {
+    /// Hex dump them to stderr, --print-wire
+    print: bool,
+    /// Journal them, --record
+    journal: Option<JournalWriter>,
+    /// Journal them buffered, --capture-raw
+    capture: Option<JournalWriter>,
+}
+
+impl WireLog {
+    fn open(globals: &GlobalOptions) -> Result<Self> {
+        let journal = match &globals.record_path {
+            Some(path) => Some(JournalWriter::create(path)?),
+            None => None
+        };
+        let capture = match &globals.capture_raw_path {
+            Some(path) => Some(JournalWriter::buffered(path)?),
+            None => None
+        };
+        Ok(Self { print: globals.print_wire, journal, capture })
+    }
+
+    /// Wrap a link that was just opened
+    fn tap(&self, mut device: Box<dyn Transport>) -> Result<Box<dyn Transport>> {
+        for journal in [&self.journal, &self.capture].into_iter().flatten() {
+            device = Box::new(Recorder::new(device, journal.clone()));
+        }
+        Ok(if self.print { Box::new(WireTap::new(device)) } else { device })
+    }
+
+    /// Write out the buffered capture, for exits that skip destructors
+    fn flush(&self) {
+        if let Some(capture) = &self.capture {
+            if let Err(err) = capture.flush() {
+                status!("[PUSHER] Couldn't write the --capture-raw file: {}", err);
+            }
+        }
+    }
+}
+
+/// Serve one connection to the device, until asked to exit or the device goes away
+/// (a `DeviceLost` error). The device is registered with a `Source::Serial` token by the caller
+fn run_connected(serial_device: &mut dyn Transport, stdin_device: &mut StdinDevice, options: &mut Options,
+    state: &mut SessionState, events: &mut Events) -> Result<()>
+{
+
+    let mut symbolizer = match &options.symbols_path {
+        Some(path) => match Symbolizer::load(path, &options.symbol_patterns) {
+            Ok(symbolizer) => Some(symbolizer),
+            Err(err) => bail!("Couldn't load symbols from {}: {}", path.display(), err)
+        },
+        None => None
+    };
+
+    let mut defmt_decoder = match &options.defmt_path {
+        Some(path) => match defmt::Decoder::load(path) {
+            Ok(decoder) => Some(decoder),
+            Err(err) => bail!("Couldn't load defmt table from {}: {}", path.display(), err)
+        },
+        None => None
+    };
+
+    let mut cobs_reader = match (options.cobs, options.hex_diff) {
+        (true, true) => Some(cobs::CobsReader::diffing()),
+        (true, false) => Some(cobs::CobsReader::new()),
+        (false, _) => None
+    };
+    let mut hex_view = options.hex.map(|width| HexView::new(width, options.hex_diff));
+    let mut demux = if options.demux { Some(demux::Demux::open(&options.channels)?) } else { None };
+
+    // look for the rate the loader talks at first, it may already be waiting for us
+    let mut ready_while_probing = false;
+    if !options.probe_bauds.is_empty() {
+        let (baud_rate, matched) = probe::probe_bauds(serial_device, &options.probe_bauds, PROBE_DWELL,
+            &options.protocol.ready_signal, options.probe_pattern.as_deref())?;
+        status!("[PUSHER] Using {} baud", baud_rate);
+        ready_while_probing = matched == probe::ProbeMatch::ReadySignal;
+    }
+
+    // ROM loaders detecting the baud rate need training before anything else
+    if let Some(training) = &options.autobaud {
+        autobaud::train(serial_device, training)?;
+    }
+    if let Some(wake) = &options.wake {
+        protocol::write_bytes(serial_device, wake)?;
+        state.stats.on_sent(wake.len() as u64);
+    }
+
+    // emulated loaders are ready as soon as we're connected, so don't wait for breaks
+    let mut phase = Phase::Waiting;
+    if options.push_on_connect || ready_while_probing {
+        phase = Phase::Triggered { skip_unchanged: false };
+    } else {
+        state.event_log.record(Event::Waiting)?;
+    }
+
+    let mut capture = Capture::new(CAPTURE_LIMIT);
+    let mut ready_signal = RollingMatcher::new(options.protocol.ready_signal.clone());
+    // when the last byte of the ready signal seen so far came, for --break-reset-timeout
+    let mut ready_seen_at: Option<Instant> = None;
+    let mut exit_on = options.exit_on.clone().map(RollingMatcher::new);
+    let mut escape_pending = false;
+    let mut overrun_warning = output::OverrunWarning::new();
+    let mut session = commands::Session { local_echo: options.local_echo, enter_sends: options.enter_sends,
+        modem_lines: serial_device.modem_lines().ok(), pulse: options.reset_pulse, ..Default::default() };
+    let mut typed_enter = EnterTranslator::default();
+    let mut piped_enter = EnterTranslator::default();
+    // piped in but not sent yet: while pushing, or what came while disconnected
+    let mut piped_input = Vec::new();
+    if state.stdin_open {
+        read_piped(stdin_device, state, &mut piped_input)?;
+    }
+    // when a byte last went either way, for --keepalive
+    let mut last_traffic = Instant::now();
+    let mut idle_watch = options.idle_warn.map(|period| IdleWatch::new(period, options.idle_warn_always));
+    state.uart_errors = ErrorWatch::new(serial_device);
+    if state.uart_errors.is_some() {
+        state.stats.on_uart_errors(ErrorCounts::default());
+    }
+    let mut line_watch = None;
+    if options.watch_lines {
+        match LineWatch::new(serial_device) {
+            Ok(watch) => line_watch = Some(watch),
+            Err(err) => status!("[PUSHER] Not watching the lines: {}", err)
+        }
+    }
+    // both only until the first push
+    let mut prober = options.ready_probe.clone().filter(|_| matches!(phase, Phase::Waiting)).map(Prober::new);
+    let mut ready_deadline = options.ready_timeout.filter(|_| matches!(phase, Phase::Waiting))
+        .map(|timeout| Instant::now() + timeout);
+    let mut file_send = options.send_file_on_connect.as_deref()
+        .and_then(|path| start_file_send(path, options));
+    // pushed instead of the kernel by the next push, asked for with Ctrl-A P
+    let mut one_off: Option<PathBuf> = None;
+    loop {
+        if let Phase::Triggered { skip_unchanged } = phase {
+            prober = None;
+            ready_deadline = None;
+            let file = one_off.take();
+            // the loader won't have the kernel anymore, so the next push sends it whatever --skip-unchanged says
+            let digest = match (&file, &options.kernel_path) {
+                (None, Some(kernel_path)) => prepare_push(&mut state.remote, options, kernel_path)?,
+                _ => None
+            };
+            phase = if file.is_none() && options.kernel_path.is_none() {
+                status!("\r\n[PUSHER] The device is ready for a kernel but none was given (Ctrl-A P pushes a file)");
+                Phase::Waiting
+            } else if skip_unchanged && digest.is_some() && digest == state.last_pushed {
+                skip_push(serial_device, options, state)?;
+                if options.once {
+                    return Ok(());
+                }
+                if let Some(idle_watch) = &mut idle_watch {
+                    idle_watch.arm();
+                }
+                Phase::Waiting
+            } else {
+                // what was seen of the ready signal so far can't join up with what comes after the push
+                ready_signal.reset();
+                ready_seen_at = None;
+                // the rest of the file was meant for the loader, not for what gets pushed
+                if let Some(send) = file_send.take() {
+                    let (sent, total) = send.progress();
+                    status!("\r\n[PUSHER] Stopped sending {} after line {} of {}, a push is starting",
+                        send.path().display(), sent, total);
+                }
+                match start_push(serial_device, &options.protocol, options, file, state)? {
+                    Some(transfer) => Phase::Sending { transfer: Box::new(transfer), digest },
+                    None => Phase::Waiting
+                }
+            };
+        }
+
+        let mut connection = match (phase.transfer().map(Transfer::progress), file_send.as_ref().map(FileSend::progress)) {
+            (Some((sent, total)), _) => format!("pushing {}%", (sent * 100).checked_div(total).unwrap_or(100)),
+            (None, Some((sent, total))) => format!("sending file, line {}/{}", sent, total),
+            (None, None) => "waiting".to_string()
+        };
+        if let Some(lines) = session.modem_lines {
+            connection += &format!(", {}", lines.outputs());
+        }
+        update_status_line(options, state, &connection);
+
+        // a push in progress is moved along between events, without blocking them, and an idle
+        // link wakes us up in time for the keepalive
+        let timeout = match phase.transfer() {
+            Some(transfer) => transfer.timeout(),
+            None => options.keepalive.map(|interval| interval.saturating_sub(last_traffic.elapsed()))
+        };
+        let waiting = matches!(phase, Phase::Waiting);
+        let timeout = [
+            timeout,
+            file_send.as_ref().filter(|_| waiting).map(FileSend::timeout),
+            idle_watch.as_ref().filter(|_| waiting).and_then(IdleWatch::timeout),
+            prober.as_ref().filter(|_| waiting).and_then(Prober::timeout),
+            ready_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            options.break_reset_timeout.zip(ready_seen_at)
+                .map(|(timeout, seen_at)| timeout.saturating_sub(seen_at.elapsed())),
+            line_watch.as_ref().map(LineWatch::timeout),
+            state.uart_errors.as_ref().map(ErrorWatch::timeout),
+            state.raw_capture.as_ref().and_then(JournalWriter::flush_timeout),
+        ].into_iter().flatten().min();
+        match state.poll.poll(events, timeout) {
+            // a signal arrived, it's handled through its token
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => result?
+        }
+        // the device hung up after sending something, which is shown first
+        let mut hung_up = false;
+        for event in events.iter() {
+            match state.tokens.source(event.token()) {
+                Some(Source::Serial) => {
+                    let output_bytes = match serial_device.read_all() {
+                        Ok(output_bytes) => output_bytes,
+                        Err(err) => return Err(DeviceLost(err).into())
+                    };
+                    // a hung up tty reads as empty rather than failing
+                    if output_bytes.is_empty() && (event.is_read_closed() || event.is_error()) {
+                        return Err(DeviceLost(io::Error::new(io::ErrorKind::BrokenPipe, "hung up")).into());
+                    }
+                    hung_up = event.is_read_closed();
+                    if !output_bytes.is_empty() {
+                        last_traffic = Instant::now();
+                        if let Some(event) = idle_watch.as_mut().and_then(IdleWatch::on_output) {
+                            state.event_log.record(event)?;
+                        }
+                    }
+                    state.stats.on_received(output_bytes.len());
+                    if let Some(mirror) = &mut state.mirror {
+                        mirror.write(&output_bytes);
+                    }
+                    if let Phase::Sending { transfer, .. } = &mut phase {
+                        let retries = transfer.retries();
+                        output::device_bytes(&transfer.on_received(&output_bytes, &mut state.event_log, &mut capture)?);
+                        state.stats.on_retries(transfer.retries() - retries);
+                        continue;
+                    }
+                    if let Some(send) = &mut file_send {
+                        send.on_received(&output_bytes);
+                    }
+                    let display_start = Instant::now();
+                    let console_bytes = match &mut demux {
+                        Some(demux) => demux.feed(&output_bytes),
+                        None => output_bytes.clone()
+                    };
+                    if let Some(cobs_reader) = &mut cobs_reader {
+                        for line in cobs_reader.feed(&console_bytes) {
+                            output::device(&format!("{}\r\n", line));
+                        }
+                    } else if let Some(hex_view) = &mut hex_view {
+                        output::device(&hex_view.feed(&console_bytes));
+                    } else if let Some(decoder) = &mut defmt_decoder {
+                        output::device(&decoder.feed(&console_bytes));
+                    } else {
+                        output::device_bytes(&console_bytes);
+                    }
+                    if let Some(symbolizer) = &mut symbolizer {
+                        for annotation in symbolizer.feed(&console_bytes) {
+                            status!("{}", annotation);
+                        }
+                    }
+                    overrun_warning.check(display_start.elapsed());
+                    state.status_line.refresh();
+                    if let Some(server) = &mut state.console_server {
+                        server.broadcast(state.poll.registry(), &mut state.tokens, &console_bytes)?;
+                    }
+                    if exit_on.as_mut().is_some_and(|exit_on| exit_on.feed(&console_bytes).is_some()) {
+                        status!("\r\n[PUSHER] The device printed the --exit-on pattern, exiting");
+                        return Ok(());
+                    }
+
+                    if options.push_on_connect || !matches!(phase, Phase::Waiting) {
+                        continue;
+                    }
+                    let triggered = ready_signal.feed(&output_bytes).is_some();
+                    // a partial match ends with the last byte read
+                    ready_seen_at = (ready_signal.partial_match() > 0).then(Instant::now);
+                    if triggered {
+                        phase = Phase::Triggered { skip_unchanged: true };
+                    } else if prober.as_mut().is_some_and(|prober| prober.feed(&output_bytes)) {
+                        status!("\r\n[PUSHER] The loader answered the probe");
+                        phase = Phase::Triggered { skip_unchanged: true };
+                    }
+                },
+                Some(Source::Stdin) if !stdin_device.is_interactive() => read_piped(stdin_device, state, &mut piped_input)?,
+                Some(Source::Stdin) => {
+                    // read from stdin and write to serial, unless it's a local command
+                    session.push_progress = phase.transfer().map(Transfer::progress);
+                    for byte in stdin_device.read_pending()? {
+                        if escape_pending {
+                            escape_pending = false;
+                            commands::run_escape_command(byte, serial_device, stdin_device, &mut session)?;
+                            continue;
+                        }
+                        if byte == commands::ESCAPE_BYTE {
+                            escape_pending = true;
+                            continue;
+                        }
+                        if let Some(send) = file_send.as_ref() {
+                            if byte == sendfile::ABORT_BYTE {
+                                let (sent, total) = send.progress();
+                                status!("\r\n[PUSHER] Stopped sending {} after line {} of {}", send.path().display(),
+                                    sent, total);
+                                file_send = None;
+                            }
+                            // keystrokes would land in the middle of the file's lines
+                            continue;
+                        }
+                        // keystrokes would end up in the payload
+                        if !matches!(phase, Phase::Waiting) {
+                            continue;
+                        }
+                        let sent = match typed_enter.translate(session.enter_sends, byte) {
+                            Some(line_ending) => line_ending,
+                            None => std::slice::from_ref(&byte)
+                        };
+                        for &sent_byte in sent {
+                            protocol::write_byte(serial_device, sent_byte)?;
+                            state.stats.on_sent(1);
+                        }
+                        last_traffic = Instant::now();
+                        // what was typed, a CR LF pair shows as one line break
+                        if session.local_echo && !sent.is_empty() {
+                            stdin_device.echo(byte);
+                        }
+                        // pasted text arrives back to back, keep the bytes apart
+                        if !options.char_delay.is_zero() {
+                            sleep(options.char_delay);
+                        }
+                    }
+                },
+                Some(Source::Signals) => {
+                    if !stop_signal(state) {
+                        continue;
+                    }
+                    if let Some(transfer) = phase.stop() {
+                        cancel_push(transfer, &mut capture, state)?;
+                    }
+                    return Ok(());
+                },
+                Some(Source::Control) => {
+                    if let Some(server) = &mut state.control_server {
+                        server.accept(state.poll.registry())?;
+                    }
+                },
+                Some(Source::ControlClient) => {
+                    let Some(server) = &mut state.control_server else {
+                        continue;
+                    };
+                    for request in server.read_requests(state.poll.registry())? {
+                        let response = match request {
+                            Ok(Request::Quit) => {
+                                server.respond(&json!({ "ok": true }))?;
+                                status!("\r\n[PUSHER] Asked to quit over the control socket, exiting");
+                                if let Some(transfer) = phase.stop() {
+                                    cancel_push(transfer, &mut capture, state)?;
+                                }
+                                return Ok(());
+                            },
+                            Ok(request) => {
+                                session.push_progress = phase.transfer().map(Transfer::progress);
+                                session.ready_signal = Some((ready_signal.partial_match(), ready_signal.pattern().len()));
+                                control_request(request, Some(&mut *serial_device), options, &mut state.remote,
+                                    &state.stats, &mut state.board_reset, &mut session)
+                            },
+                            Err(err) => json!({ "ok": false, "error": err })
+                        };
+                        server.respond(&response)?;
+                    }
+                },
+                Some(Source::Console) => {
+                    if let Some(server) = &mut state.console_server {
+                        server.accept(state.poll.registry(), &mut state.tokens)?;
+                    }
+                },
+                Some(Source::Status) => {
+                    if let Some(server) = &mut state.status_server {
+                        server.accept(state.poll.registry(), &mut state.tokens)?;
+                    }
+                },
+                Some(Source::StatusClient) => {
+                    let Some(server) = &mut state.status_server else {
+                        continue;
+                    };
+                    if let Some(endpoint) = server.on_client_event(state.poll.registry(), &mut state.tokens, event.token())? {
+                        session.push_progress = phase.transfer().map(Transfer::progress);
+                        session.ready_signal = Some((ready_signal.partial_match(), ready_signal.pattern().len()));
+                        let (content_type, body) = status_page(endpoint, true, options, &state.stats, &session);
+                        server.respond(state.poll.registry(), &mut state.tokens, event.token(), 200, content_type, &body)?;
+                    }
+                },
+                Some(Source::Hotplug) => {
+                    let Some(monitor) = &mut state.hotplug else {
+                        continue;
+                    };
+                    // it'd only fail reading later, udev says why
+                    if monitor.events().iter().any(|hotplug| matches!(hotplug, Hotplug::Removed(_))) {
+                        return Err(DeviceLost(io::Error::new(io::ErrorKind::NotFound, "device removed (udev)")).into());
+                    }
+                },
+                Some(Source::ConsoleClient) => {
+                    let Some(server) = &mut state.console_server else {
+                        continue;
+                    };
+                    let input = server.on_client_event(state.poll.registry(), &mut state.tokens, event.token())?;
+                    // like keystrokes, it would end up in the payload
+                    if matches!(phase, Phase::Waiting) && !input.is_empty() {
+                        protocol::write_paced(serial_device, &input, options.char_delay)?;
+                        state.stats.on_sent(input.len() as u64);
+                        last_traffic = Instant::now();
+                    }
+                },
+                // a console client that left while handling an earlier event of this batch
+                None => {}
+            }
+        }
+        if hung_up {
+            return Err(DeviceLost(io::Error::new(io::ErrorKind::BrokenPipe, "hung up")).into());
+        }
+
+        if session.quit {
+            status!("\r\n[PUSHER] Exiting");
+            if let Some(transfer) = phase.stop() {
+                cancel_push(transfer, &mut capture, state)?;
+            }
+            return Ok(());
+        }
+        if session.force_push {
+            session.force_push = false;
+            phase = Phase::Triggered { skip_unchanged: false };
+        }
+        if let Some(path) = session.push_file.take() {
+            one_off = Some(path);
+            phase = Phase::Triggered { skip_unchanged: false };
+        }
+        if session.cancel_push {
+            session.cancel_push = false;
+            if let Some(transfer) = phase.stop() {
+                cancel_push(transfer, &mut capture, state)?;
+            }
+        }
+        if session.reset_board {
+            session.reset_board = false;
+            // whatever the loader was receiving is gone with the reset
+            if let Some(transfer) = phase.stop() {
+                cancel_push(transfer, &mut capture, state)?;
+            }
+            match state.board_reset.reset(Some(serial_device)) {
+                Ok(()) => status!("\r\n[PUSHER] Board reset"),
+                Err(err) => status!("\r\n[PUSHER] Couldn't reset the board: {}", err)
+            }
+            // a DTR reset leaves DTR released, whatever it was before
+            session.modem_lines = serial_device.modem_lines().ok();
+        }
+        if session.power_cycle {
+            session.power_cycle = false;
+            if options.power_cycle.is_none() {
+                status!("\r\n[PUSHER] No way to power cycle the board, see --power-cycle-cmd and --hub-location");
+            } else {
+                if let Some(transfer) = phase.stop() {
+                    cancel_push(transfer, &mut capture, state)?;
+                }
+                power_cycle("asked for with Ctrl-A p", options, state)?;
+            }
+        }
+        if session.toggle_status_line {
+            session.toggle_status_line = false;
+            if !state.status_line.toggle() {
+                status!("\r\n[PUSHER] No status line, stdout isn't a terminal");
+            }
+        }
+        if let Some(path) = session.send_file.take() {
+            if file_send.is_some() {
+                status!("\r\n[PUSHER] Already sending a file, Esc stops it");
+            } else {
+                file_send = start_file_send(&path, options);
+            }
+        }
+        if session.show_stats || state.dump_stats {
+            let ready = match phase.transfer() {
+                Some(transfer) => {
+                    let (sent, total) = transfer.progress();
+                    format!("pushing, sent {}/{} bytes", sent, total)
+                },
+                None => format!("waiting, {}/{} bytes of the ready signal seen", ready_signal.partial_match(),
+                    ready_signal.pattern().len())
+            };
+            if std::mem::take(&mut session.show_stats) {
+                state.stats.report(&ready);
+                if let Ok(lines) = serial_device.modem_lines() {
+                    session.modem_lines = Some(lines);
+                    status!("[PUSHER]   {}", lines);
+                }
+            }
+            if std::mem::take(&mut state.dump_stats) {
+                dump_stats(state, &ready)?;
+            }
+        }
+        // a push goes on with what it started with, the reload waits for it
+        if state.reload && matches!(phase, Phase::Waiting) {
+            state.reload = false;
+            if let Some(reload) = reload_config(options, state, true)? {
+                if !reload.reopened.is_empty() {
+                    status!("[PUSHER] Opening the device again");
+                    return Err(Reopen.into());
+                }
+                if reload.applied.contains(&"--write-timeout") {
+                    serial_device.set_write_timeout(options.write_timeout);
+                }
+                if reload.applied.contains(&"protocol options") {
+                    ready_signal = RollingMatcher::new(options.protocol.ready_signal.clone());
+                    ready_seen_at = None;
+                }
+                if reload.applied.contains(&"--exit-on") {
+                    exit_on = options.exit_on.clone().map(RollingMatcher::new);
+                }
+            }
+        }
+        if let Some(uart_errors) = &mut state.uart_errors {
+            let new = uart_errors.check(serial_device, phase.transfer().is_some());
+            state.stats.on_uart_errors(new);
+        }
+        if let Phase::Sending { transfer, digest } = &mut phase {
+            let before = transfer.progress().0;
+            let step = transfer.step(serial_device, &mut state.event_log, Some(&mut print_progress));
+            state.stats.on_sent(transfer.progress().0 - before);
+            match step {
+                Ok(Step::Pending) => {},
+                Ok(Step::Done) => {
+                    state.failed_pushes = 0;
+                    state.last_pushed = digest.take();
+                    let ack_status = transfer.ack_status().map(str::to_string);
+                    let identity = transfer.identity().map(str::to_string);
+                    let board = transfer.board().cloned();
+                    let transfer_stats = transfer.stats();
+                    phase = Phase::Waiting;
+                    let file = state.pushing.take();
+                    status!();
+                    capture.replay();
+                    match (&board, file.as_ref().filter(|file| Some(*file) != options.kernel_path.as_ref())) {
+                        (Some(BoardChoice { board: Some(board), kernel }), _) => {
+                            status!("[PUSHER] Done! Pushed {} for board {}, booting now\n\n", kernel.display(), board);
+                        },
+                        (Some(BoardChoice { board: None, kernel }), _) => {
+                            status!("[PUSHER] Done! Pushed the default {} to a board not in [boards], booting now\n\n",
+                                kernel.display());
+                        },
+                        (None, Some(file)) => status!("[PUSHER] Done! Pushed {} instead of the kernel, booting now\n\n",
+                            file.display()),
+                        (None, None) => status!("[PUSHER] Done! booting now\n\n")
+                    }
+                    // what was pushed is the board's kernel
+                    let file = board.as_ref().map(|board| board.kernel.clone()).or(file);
+                    let uart_errors = push_uart_errors(state);
+                    state.push_started = None;
+                    state.stats.on_push(PushRecord::new("ok", None).with_transfer(transfer_stats).of(file)
+                        .with_uart_errors(uart_errors).with_ack_status(ack_status.as_deref())
+                        .with_identity(identity.as_deref()).with_board(board));
+                    if options.once {
+                        return Ok(());
+                    }
+                    if let Some(idle_watch) = &mut idle_watch {
+                        idle_watch.arm();
+                    }
+                },
+                Err(err) => {
+                    phase = Phase::Waiting;
+                    status!();
+                    // the loader may be explaining why the push failed
+                    capture.replay();
+                    push_failed(err, serial_device, options, state)?;
+                }
+            }
+        }
+        if let Some(send) = file_send.as_mut().filter(|_| matches!(phase, Phase::Waiting)) {
+            let before = send.bytes_sent();
+            let step = send.step(serial_device);
+            state.stats.on_sent(send.bytes_sent() - before);
+            if send.bytes_sent() != before {
+                last_traffic = Instant::now();
+            }
+            match step {
+                Ok(Step::Pending) => {},
+                Ok(Step::Done) => {
+                    status!("\r\n[PUSHER] Sent {}", send.path().display());
+                    file_send = None;
+                },
+                Err(err) => {
+                    status!("\r\n[PUSHER] {}", err);
+                    file_send = None;
+                }
+            }
+        }
+        // like keystrokes, it would end up in the payload
+        if matches!(phase, Phase::Waiting) && !piped_input.is_empty() {
+            let input = piped_enter.translate_all(session.enter_sends, &piped_input);
+            protocol::write_paced(serial_device, &input, options.char_delay)?;
+            state.stats.on_sent(input.len() as u64);
+            piped_input.clear();
+            last_traffic = Instant::now();
+        }
+        if let Some(event) = idle_watch.as_mut().filter(|_| matches!(phase, Phase::Waiting)).and_then(IdleWatch::check) {
+            state.event_log.record(event)?;
+        }
+        if let Some(prober) = prober.as_mut().filter(|_| matches!(phase, Phase::Waiting)) {
+            if let Some((event, sent)) = prober.step(serial_device)? {
+                state.event_log.record(event)?;
+                state.stats.on_sent(sent as u64);
+                last_traffic = Instant::now();
+            }
+        }
+        if let Some(watch) = &mut line_watch {
+            match watch.check(serial_device) {
+                Ok(Some(lines)) => session.modem_lines = Some(lines),
+                Ok(None) => {},
+                Err(err) => {
+                    status!("\r\n[PUSHER] Stopped watching the lines: {}", err);
+                    line_watch = None;
+                }
+            }
+        }
+        if let Some(capture) = state.raw_capture.as_ref().filter(|capture| capture.flush_timeout() == Some(Duration::ZERO)) {
+            capture.flush()?;
+        }
+        if let Some(timeout) = options.break_reset_timeout {
+            if ready_seen_at.is_some_and(|seen_at| seen_at.elapsed() >= timeout) {
+                output::verbose(&format!("Forgetting {}/{} bytes of the ready signal, the rest didn't come within {}ms",
+                    ready_signal.partial_match(), ready_signal.pattern().len(), timeout.as_millis()));
+                ready_signal.reset();
+                ready_seen_at = None;
+            }
+        }
+        if ready_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            bail!("No ready signal within {}s (--ready-timeout)", options.ready_timeout.unwrap_or_default().as_secs());
+        }
+        if let Some(interval) = options.keepalive {
+            if !matches!(phase, Phase::Waiting) {
+                // the push is traffic enough
+                last_traffic = Instant::now();
+            } else if last_traffic.elapsed() >= interval {
+                protocol::write_byte(serial_device, options.keepalive_byte)?;
+                state.stats.on_sent(1);
+                last_traffic = Instant::now();
+            }
+        }
+    }
+}
+
+/// Where a connection is with pushing the kernel. It goes Waiting → Triggered → Sending and back
+/// to Waiting once the push is done, failed or was cancelled.
+enum Phase {
+    /// Watching the device output for the ready signal
+    Waiting,
+    /// A push is due, it starts at the top of the next loop iteration. `skip_unchanged` when the
+    /// device asked for it, so --skip-unchanged applies, a push asked for by hand sends anyway.
+    Triggered { skip_unchanged: bool },
+    /// Pushing the kernel, `digest` being its digest with --skip-unchanged
+    Sending { transfer: Box<Transfer>, digest: Option<ImageDigest> },
+}
+
+impl Phase {
+    /// The push in progress
+    fn transfer(&self) -> Option<&Transfer> {
+        match self {
+            Phase::Sending { transfer, .. } => Some(transfer.as_ref()),
+            _ => None
+        }
+    }
+
+    /// Go back to waiting, giving up a push that's due or in progress. Returns the transfer to cancel.
+    fn stop(&mut self) -> Option<Transfer> {
+        match std::mem::replace(self, Phase::Waiting) {
+            Phase::Sending { transfer, .. } => Some(*transfer),
+            _ => None
+        }
+    }
+}
+
+/// Start sending the text file at `path` to the console, saying why not if it can't be read
+fn start_file_send(path: &Path, options: &Options) -> Option<FileSend> {
+    match FileSend::open(path, options.char_delay, options.line_delay, options.line_prompt.clone()) {
+        Ok(send) => {
+            status!("[PUSHER] Sending {}, {} lines (Esc stops it)", path.display(), send.progress().1);
+            Some(send)
+        },
+        Err(err) => {
+            status!("[PUSHER] {}", err);
+            None
+        }
+    }
+}
+
+/// Take what came through the piped stdin, to be forwarded. Once it ended it's not watched anymore.
+fn read_piped(stdin_device: &mut StdinDevice, state: &mut SessionState, piped_input: &mut Vec<u8>) -> Result<()> {
+    let (bytes, ended) = stdin_device.read_piped()?;
+    piped_input.extend_from_slice(&bytes);
+    if ended {
+        state.stdin_open = false;
+        state.poll.registry().deregister(stdin_device)?;
+        output::verbose("stdin ended, not forwarding it anymore");
+    }
+    Ok(())
+}
+
+/// The device and the kernel, for the event log's header
+fn session_context(options: &Options) -> String {
+    let kernel = match &options.kernel_path {
+        Some(kernel_path) => kernel_path.display().to_string(),
+        None => "no kernel".to_string()
+    };
+    match &options.target {
+        Target::Serial(_) => format!("device {} @ {}, kernel {}", options.target, options.baud_rate, kernel),
+        target => format!("device {}, kernel {}", target, kernel)
+    }
+}
+
+/// Say in the status line, if it's shown, where the device and the kernel are and how the
+/// pushes went. `connection` is what's happening with the device.
+fn update_status_line(options: &Options, state: &mut SessionState, connection: &str) {
+    if !state.status_line.is_shown() {
+        return;
+    }
+    let device = match &options.target {
+        Target::Serial(_) => format!("{} @ {}", options.target, options.baud_rate),
+        target => target.to_string()
+    };
+    let kernel = match &options.kernel_path {
+        Some(kernel_path) => {
+            if state.kernel_digest.as_ref().is_none_or(|(path, _)| path != kernel_path) {
+                state.kernel_digest = push::image_digest(kernel_path).ok()
+                    .map(|digest| (kernel_path.clone(), digest));
+            }
+            let digest: String = match &state.kernel_digest {
+                Some((_, digest)) => digest[..4].iter().map(|byte| format!("{:02x}", byte)).collect(),
+                None => "unreadable".to_string()
+            };
+            format!("{} {}", kernel_path.display(), digest)
+        },
+        None => "no kernel".to_string()
+    };
+    let last_push = match state.stats.last_push() {
+        Some(PushRecord { result, duration: Some(duration), .. }) => {
+            format!("last {} in {:.1}s", result, duration.as_secs_f64())
+        },
+        Some(record) => format!("last {}", record.result),
+        None => "no push yet".to_string()
+    };
+    state.status_line.set(format!(" {} | {} | {} | {} pushed | {}", device, connection, kernel,
+        state.stats.pushed(), last_push));
+}
+
+/// Start pushing the kernel, or all of `file` instead if given, the device just signaled it's ready.
+/// There must be one or the other.
+/// Returns `None` if a daemon couldn't start the push, see `push_failed`.
+fn start_push(serial_device: &mut dyn Transport, protocol: &Protocol, options: &Options, file: Option<PathBuf>,
+    state: &mut SessionState) -> Result<Option<Transfer>>
+{
+    state.event_log.record(Event::Trigger)?;
+    state.push_started = Some(Instant::now());
+    // the kernel was likely rebuilt since
+    state.kernel_digest = None;
+    let push_options = match (file, &options.kernel_path) {
+        (Some(file), _) => {
+            status!("[PUSHER] Sending {} instead of the kernel, this push only", file.display());
+            // the window and the DTB are the kernel's
+            PushOptions { kernel_offset: 0, kernel_length: None, dtb_path: None, boards: None,
+                ..push_options(options, &file) }
+        },
+        (None, Some(kernel_path)) => {
+            status!("[PUSHER] Sending kernel!");
+            push_options(options, kernel_path)
+        },
+        (None, None) => bail!("No kernel to push")
+    };
+    state.pushing = Some(push_options.kernel_path.clone());
+    match Transfer::start(serial_device, protocol, &push_options, &mut state.event_log) {
+        Ok(transfer) => Ok(Some(transfer)),
+        Err(err) => push_failed(err, serial_device, options, state).map(|()| None)
+    }
+}
+
+/// What the library needs to know to push the kernel at `kernel_path`
+fn push_options(options: &Options, kernel_path: &Path) -> PushOptions {
+    PushOptions {
+        kernel_path: kernel_path.to_path_buf(),
+        kernel_offset: options.kernel_offset,
+        kernel_length: options.kernel_length,
+        dtb_path: options.dtb_path.clone(),
+        byte_delay: if options.char_delay_push { options.char_delay } else { Duration::ZERO },
+        pre_send_wait: options.pre_send_wait,
+        size_retries: options.size_retries,
+        drain: !options.no_drain,
+        raw: options.raw,
+        boards: options.board_kernels.clone(),
+    }
+}
+
+/// Push to every board given with --device. Boards that can't be opened are left out.
+fn fan_out(options: &Options, wire_log: &WireLog) -> Result<()> {
+    let mut boards = Vec::new();
+    for (name, target) in &options.boards {
+        match open_device(target.clone(), options.baud_rate, wire_log) {
+            Ok(mut device) => {
+                device.set_write_timeout(options.write_timeout);
+                boards.push(fanout::Board::new(name, device, &options.protocol));
+            },
+            Err(err) => status!("[PUSHER] [{}] {}, leaving it out", name, err)
+        }
+    }
+    if boards.is_empty() {
+        bail!("None of the boards could be opened");
+    }
+    let kernel_path = options.kernel_path.as_deref().ok_or_else(|| anyhow!("Pushing to several --device needs a kernel"))?;
+    let stopped_by = fanout::fan_out(&mut boards, &options.protocol, &push_options(options, kernel_path),
+        options.poll_events)?;
+    drop(boards);
+    if let Some(signal) = stopped_by {
+        wire_log.flush();
+        std::process::exit(128 + signal);
+    }
+    Ok(())
+}
+
+/// A push that went wrong ends the session, but a daemon or a session that can power cycle the
+/// board logs it and waits for the device to ask again. After --power-cycle-after failures in a
+/// row the board is power cycled, otherwise it's reset if it has a reset GPIO so the loader
+/// starts over
+fn push_failed(err: anyhow::Error, serial_device: &mut dyn Transport, options: &Options, state: &mut SessionState)
+    -> Result<()>
+{
+    if !options.daemon && options.power_cycle.is_none() {
+        return Err(err);
+    }
+    status!("[PUSHER] Push failed: {}", err);
+    state.event_log.record(Event::Error(err.to_string()))?;
+    let uart_errors = push_uart_errors(state);
+    state.stats.on_push(PushRecord::new("failed", Some(err.to_string())).took(state.push_started.take())
+        .of(state.pushing.take()).with_uart_errors(uart_errors));
+    state.failed_pushes += 1;
+    if options.power_cycle.is_some() && state.failed_pushes >= options.power_cycle_after {
+        state.failed_pushes = 0;
+        return power_cycle(&format!("{} failed pushes in a row", options.power_cycle_after), options, state);
+    }
+    if state.board_reset.has_gpio() {
+        status!("[PUSHER] Resetting the board before trying again");
+        state.board_reset.reset(Some(serial_device))?;
+    }
+    Ok(())
+}
+
+/// Power cycle the board, loudly since it loses whatever it was doing. Fails with `PowerCycled`
+/// for the caller to wait for the device to come back. A power cycle that didn't work is only
+/// reported, the session goes on with the device as it is.
+fn power_cycle(reason: &str, options: &Options, state: &mut SessionState) -> Result<()> {
+    let Some(power_cycle) = &options.power_cycle else {
+        return Ok(());
+    };
+    status!("\r\n[PUSHER] ***** Power cycling the board ({}), {} *****", reason, power_cycle);
+    state.event_log.record(Event::PowerCycle(format!("{}, {}", reason, power_cycle)))?;
+    match power_cycle.run() {
+        Ok(()) => Err(PowerCycled.into()),
+        Err(err) => {
+            status!("[PUSHER] ***** Power cycle failed: {} *****", err);
+            state.event_log.record(Event::Error(format!("power cycle failed: {}", err)))?;
+            Ok(())
+        }
+    }
+}
+
+/// Get the kernel at `kernel_path` ready for a push: download it again if asked to, and return
+/// its digest to compare with the last pushed one (only with --skip-unchanged)
+fn prepare_push(remote: &mut Option<RemoteImage>, options: &Options, kernel_path: &Path) -> Result<Option<ImageDigest>> {
+    if let Some(remote) = remote.as_mut().filter(|_| options.refetch_each_push) {
+        remote.fetch()?;
+    }
+    if !options.skip_unchanged {
+        return Ok(None);
+    }
+    match &options.board_kernels {
+        Some(board_kernels) => Ok(Some(board_kernels.digest(kernel_path)?)),
+        None => Ok(Some(push::image_digest(kernel_path)?))
+    }
+}
+
+/// Don't push the same image again, telling the loader to boot what it has if it knows how
+fn skip_push(serial_device: &mut dyn Transport, options: &Options, state: &mut SessionState) -> Result<()> {
+    state.event_log.record(Event::Skipped)?;
+    state.stats.on_push(PushRecord::new("skipped", None));
+    status!("[PUSHER] Image unchanged since the last push, not sending it again (Ctrl-A f to push anyway)");
+    if let Some(skip_signal) = &options.skip_signal {
+        protocol::write_bytes(serial_device, skip_signal)?;
+    }
+    Ok(())
+}
+
+/// Carry out a request from the control socket. `serial_device` is `None` while a daemon waits
+/// for the device to come back, `session.push_progress` tells if a push is in progress.
+/// Quitting is up to the caller.
+fn control_request(request: Request, serial_device: Option<&mut dyn Transport>, options: &mut Options,
+    remote: &mut Option<RemoteImage>, stats: &SessionStats, board_reset: &mut BoardReset,
+    session: &mut commands::Session) -> Value
+{
+    let pushing = session.push_progress;
+    match request {
+        Request::Status => session_status(serial_device.is_some(), options, stats, session),
+        Request::Push if serial_device.is_none() => json!({ "ok": false, "error": "The device is disconnected" }),
+        Request::Push if pushing.is_some() => json!({ "ok": false, "error": "Already pushing" }),
+        Request::Push => {
+            session.force_push = true;
+            json!({ "ok": true })
+        },
+        Request::SetKernel { path } => {
+            if !path.is_file() {
+                return json!({ "ok": false, "error": format!("{} doesn't exist", path.display()) });
+            }
+            status!("\r\n[PUSHER] Kernel is now {}", path.display());
+            options.kernel_path = Some(path);
+            // a local file replaces a downloaded one
+            *remote = None;
+            json!({ "ok": true })
+        },
+        Request::ResetBoard => match board_reset.reset(serial_device) {
+            Ok(()) => json!({ "ok": true }),
+            Err(err) => json!({ "ok": false, "error": format!("Couldn't reset the board: {}", err) })
+        },
+        Request::Quit => json!({ "ok": true }),
+    }
+}
+
+/// Whether pusher is waiting or pushing and how far, the kernel and the counters, for the
+/// control socket's status request and /status
+fn session_status(connected: bool, options: &Options, stats: &SessionStats, session: &commands::Session) -> Value {
+    let pushing = session.push_progress;
+    let mut status = match (connected, pushing) {
+        (false, _) => json!({ "ok": true, "state": "disconnected" }),
+        (true, Some((sent, total))) => json!({ "ok": true, "state": "pushing", "sent": sent, "total": total }),
+        (true, None) => json!({ "ok": true, "state": "waiting" })
+    };
+    status["kernel"] = json!(options.kernel_path);
+    status["last_push"] = stats.last_push().map_or(Value::Null, PushRecord::to_json);
+    status["stats"] = stats.to_json();
+    if let (Some((seen, length)), true, None) = (session.ready_signal, connected, pushing) {
+        status["ready_signal"] = json!({ "seen": seen, "length": length });
+    }
+    status
+}
+
+/// The content type and body answering a --status-addr client
+fn status_page(endpoint: Endpoint, connected: bool, options: &Options, stats: &SessionStats,
+    session: &commands::Session) -> (&'static str, String)
+{
+    match endpoint {
+        Endpoint::Status => {
+            let mut status = session_status(connected, options, stats, session);
+            status["device"] = json!(options.target.to_string());
+            ("application/json", format!("{}\n", status))
+        },
+        Endpoint::Metrics => ("text/plain; version=0.0.4", stats.to_metrics(connected)),
+    }
+}
+
+/// What the UART counted during the push that just ended, reported if it counted anything
+fn push_uart_errors(state: &mut SessionState) -> Option<ErrorCounts> {
+    let uart_errors = state.uart_errors.as_mut()?.end_push();
+    if !uart_errors.is_zero() {
+        status!("[PUSHER] UART errors during the push: {}", uart_errors);
+    }
+    Some(uart_errors)
+}
+
+/// Give up on a push, showing what the device said meanwhile
+fn cancel_push(transfer: Transfer, capture: &mut Capture, state: &mut SessionState) -> Result<()> {
+    let (sent, total) = transfer.progress();
+    transfer.cancel(&mut state.event_log)?;
+    status!("\r\n[PUSHER] Push cancelled after {}/{} bytes, reset the device before pushing again", sent, total);
+    let uart_errors = push_uart_errors(state);
+    state.stats.on_push(PushRecord::new("cancelled", None).of(state.pushing.take()).with_uart_errors(uart_errors));
+    capture.replay();
+    Ok(())
+}
+
+/// Whether SIGINT or SIGTERM arrived, saying which and keeping it for the exit code. SIGWINCH
+/// only gets the status line fitted to the terminal again, SIGUSR1 asks for the statistics (see
+/// `dump_stats`), SIGUSR2 toggles the verbose status lines and SIGHUP asks for the configuration
+/// to be reloaded (see `reload_config`).
+fn stop_signal(state: &mut SessionState) -> bool {
+    for signal in state.signals.pending() {
+        match signal {
+            SIGWINCH => state.status_line.resize(),
+            SIGUSR1 => state.dump_stats = true,
+            SIGHUP => state.reload = true,
+            SIGUSR2 => {
+                output::set_verbose(!output::is_verbose());
+                status!("\r\n[PUSHER] Got SIGUSR2, verbose status lines {}", if output::is_verbose() { "on" } else { "off" });
+            },
+            signal => state.stopped_by = Some(signal)
+        }
+    }
+    let Some(signal) = state.stopped_by else {
+        return false;
+    };
+    status!("\r\n[PUSHER] Got {}, exiting", if signal == SIGTERM { "SIGTERM" } else { "SIGINT" });
+    true
+}
+
+/// Wait `delay` before a daemon opens the device again, still answering signals and the
+/// control socket. Returns false if asked to exit meanwhile.
+fn wait_for_device(state: &mut SessionState, options: &mut Options, events: &mut Events, delay: Duration)
+    -> Result<bool>
+{
+    let deadline = Instant::now() + delay;
+    let mut session = commands::Session::default();
+    update_status_line(options, state, "disconnected");
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(true);
+        }
+        match state.poll.poll(events, Some(deadline - now)) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => result?
+        }
+        for event in events.iter() {
+            match state.tokens.source(event.token()) {
+                Some(Source::Signals) => {
+                    if stop_signal(state) {
+                        return Ok(false);
+                    }
+                },
+                Some(Source::Control) => {
+                    if let Some(server) = &mut state.control_server {
+                        server.accept(state.poll.registry())?;
+                    }
+                },
+                Some(Source::ControlClient) => {
+                    let Some(server) = &mut state.control_server else {
+                        continue;
+                    };
+                    for request in server.read_requests(state.poll.registry())? {
+                        let response = match request {
+                            Ok(Request::Quit) => {
+                                server.respond(&json!({ "ok": true }))?;
+                                status!("[PUSHER] Asked to quit over the control socket, exiting");
+                                return Ok(false);
+                            },
+                            Ok(request) => control_request(request, None, options, &mut state.remote, &state.stats,
+                                &mut state.board_reset, &mut session),
+                            Err(err) => json!({ "ok": false, "error": err })
+                        };
+                        server.respond(&response)?;
+                    }
+                },
+                Some(Source::Console) => {
+                    if let Some(server) = &mut state.console_server {
+                        server.accept(state.poll.registry(), &mut state.tokens)?;
+                    }
+                },
+                Some(Source::Hotplug) => {
+                    let Some(monitor) = &mut state.hotplug else {
+                        continue;
+                    };
+                    for hotplug in monitor.events() {
+                        if let Hotplug::Added(path) = hotplug {
+                            status!("[PUSHER] {} plugged in (udev)", path.display());
+                            if follows_udev(options) {
+                                options.target = Target::Serial(path);
+                            }
+                            return Ok(true);
+                        }
+                    }
+                },
+                Some(Source::Status) => {
+                    if let Some(server) = &mut state.status_server {
+                        server.accept(state.poll.registry(), &mut state.tokens)?;
+                    }
+                },
+                Some(Source::StatusClient) => {
+                    let Some(server) = &mut state.status_server else {
+                        continue;
+                    };
+                    if let Some(endpoint) = server.on_client_event(state.poll.registry(), &mut state.tokens, event.token())? {
+                        let (content_type, body) = status_page(endpoint, false, options, &state.stats, &session);
+                        server.respond(state.poll.registry(), &mut state.tokens, event.token(), 200, content_type, &body)?;
+                    }
+                },
+                Some(Source::ConsoleClient) => {
+                    // nothing to type into, but clients leaving still have to be noticed
+                    if let Some(server) = &mut state.console_server {
+                        server.on_client_event(state.poll.registry(), &mut state.tokens, event.token())?;
+                    }
+                },
+                // there's no device to type into
+                Some(Source::Stdin) | Some(Source::Serial) | None => {}
+            }
+        }
+        if std::mem::take(&mut state.dump_stats) {
+            dump_stats(state, "disconnected, waiting to open the device again")?;
+        }
+        if std::mem::take(&mut state.reload) {
+            reload_config(options, state, false)?;
+        }
+    }
+}
+
+/// Write the statistics and the history of the session's pushes to stderr and the event log,
+/// for SIGUSR1. `ready` says where the device is at.
+fn dump_stats(state: &mut SessionState, ready: &str) -> Result<()> {
+    let mut lines = state.stats.summary(ready);
+    lines.extend(state.stats.history().map(|push| format!("push {}", push)));
+    eprint!("\r\n[PUSHER] Session statistics (SIGUSR1):\r\n");
+    for line in lines {
+        eprint!("[PUSHER]   {}\r\n", line);
+        state.event_log.record(Event::Stats(line))?;
+    }
+    Ok(())
+}
+
+/// What a reload changed, by when it took effect
+#[derive(Debug, Default)]
+struct Reload {
+    /// Applied, from now or the next push
+    applied: Vec<&'static str>,
+    /// Applied, the device is opened again for them (--apply-hard)
+    reopened: Vec<&'static str>,
+    /// Left as they were, they're only read on opening the device
+    need_reopen: Vec<&'static str>,
+    /// Left as they were, they're only read on start
+    need_restart: Vec<&'static str>,
+}
+
+impl Reload {
+    fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.reopened.is_empty() && self.need_reopen.is_empty() && self.need_restart.is_empty()
+    }
+}
+
+/// Reload the configuration for SIGHUP and say what changed. `connected` is whether the device
+/// is open. A configuration that doesn't read is reported and leaves everything as it was, `None`
+/// is returned then.
+fn reload_config(options: &mut Options, state: &mut SessionState, connected: bool) -> Result<Option<Reload>> {
+    let reload = match reload(options, state, connected) {
+        Ok(reload) => reload,
+        Err(err) => {
+            status!("\r\n[PUSHER] Couldn't reload the configuration, keeping the current one: {:#}", err);
+            return Ok(None);
+        }
+    };
+    if reload.is_empty() {
+        status!("\r\n[PUSHER] Got SIGHUP, the configuration didn't change");
+        return Ok(Some(reload));
+    }
+    status!("\r\n[PUSHER] Reloaded the configuration (SIGHUP)");
+    let mut changes = Vec::new();
+    for (names, what) in [
+        (&reload.applied, "applied"),
+        (&reload.reopened, "applied, opening the device again"),
+        (&reload.need_reopen, "need the device opened again, see --apply-hard"),
+        (&reload.need_restart, "need a restart"),
+    ] {
+        if !names.is_empty() {
+            status!("[PUSHER]   {}: {}", what, names.join(", "));
+            changes.push(format!("{} ({})", names.join(", "), what));
+        }
+    }
+    state.event_log.record(Event::Reloaded(changes.join(", ")))?;
+    Ok(Some(reload))
+}
+
+/// Parse the command line and the config again and apply the settings that changed since they
+/// were last read, as far as they can be while running. What's only read on opening the device
+/// is applied when it isn't open, or with --apply-hard.
+fn reload(options: &mut Options, state: &mut SessionState, connected: bool) -> Result<Reload> {
+    let (Command::Push(new), _) = parse_input(Some(options))? else {
+        bail!("the command line doesn't start a session anymore");
+    };
+    let new_settings = settings(&new);
+    let changed = |name: &str| state.loaded.iter().zip(&new_settings)
+        .any(|((loaded, _, old), (_, _, value))| *loaded == name && old != value);
+    // the kernel is then the download's cache, which the session's remote image still fills
+    let kernel_url_changed = changed("kernel URL");
+    let mut reload = Reload::default();
+    for (name, takes, _) in state.loaded.iter().filter(|(name, ..)| changed(name)) {
+        match takes {
+            Takes::Now if *name == "kernel" && kernel_url_changed => reload.need_restart.push(name),
+            Takes::Now => reload.applied.push(name),
+            Takes::Reopen if !connected => reload.applied.push(name),
+            Takes::Reopen if new.apply_hard => reload.reopened.push(name),
+            Takes::Reopen => reload.need_reopen.push(name),
+            Takes::Restart => reload.need_restart.push(name),
+        }
+    }
+    // the last thing that can fail, nothing is changed before it
+    let log_changed = ["--event-log", "--log-rotate-size"].iter().any(|name| reload.applied.contains(name));
+    let event_log = match &new.event_log_path {
+        Some(path) if log_changed => Some(EventLog::open(path, &session_context(&new), new.log_rotation)
+            .map_err(|err| anyhow!("Couldn't open event log {}: {}", path.display(), err))?),
+        None if log_changed => Some(EventLog::disabled()),
+        _ => None
+    };
+    if let Some(event_log) = event_log {
+        state.event_log = event_log;
+    }
+    let applied: Vec<&'static str> = reload.applied.iter().chain(&reload.reopened).copied().collect();
+    apply_settings(options, *new, &applied);
+    for (loaded, new) in state.loaded.iter_mut().zip(new_settings) {
+        if applied.contains(&loaded.0) {
+            *loaded = new;
+        }
+    }
+    if applied.contains(&"--echo-received-to-stderr") {
+        output::set_device_to_stderr(options.device_to_stderr);
+    }
+    if applied.contains(&"--strip-ansi") {
+        output::set_strip_ansi(options.strip_ansi);
+    }
+    if applied.contains(&"--verbose") {
+        output::set_verbose(options.verbose);
+    }
+    Ok(reload)
+}
+
+/// Whether the device is whichever tty udev finds, rather than a path given by the user
+fn follows_udev(options: &Options) -> bool {
+    !matches!(options.udev, None | Some(Selector::Path(_)))
+}
+
+fn print_progress(sent: u64, total: u64) {
+    let percent = (sent * 100).checked_div(total).unwrap_or(100);
+    output::status_text(&format!("\r[PUSHER] Sent {}/{} bytes ({}%)", sent, total, percent));
+}
+
+/// Everything that outlives a connection to the device, which a daemon opens again and again
+struct SessionState {
+    /// Polls stdin, signals and the control socket, and the device while connected
+    poll: Poll,
+    signals: Signals,
+    control_server: Option<ControlServer>,
+    console_server: Option<ConsoleServer>,
+    /// Serves /status and /metrics, with --status-addr
+    status_server: Option<StatusServer>,
+    /// What each token registered with the poll is for
+    tokens: Tokens,
+    event_log: EventLog,
+    remote: Option<RemoteImage>,
+    /// Digest of the last image that made it, for --skip-unchanged
+    last_pushed: Option<ImageDigest>,
+    board_reset: BoardReset,
+    /// Pushes that failed since the last one that made it
+    failed_pushes: u32,
+    /// Tells when the device comes and goes, with --udev
+    hotplug: Option<HotplugMonitor>,
+    status_line: StatusLine,
+    /// Counters and how the last push ended, for Ctrl-A s and the control socket's status
+    stats: SessionStats,
+    /// The signal that ended the session, pusher exits with 128 + its number
+    stopped_by: Option<i32>,
+    /// A piped stdin is forwarded and hasn't ended yet, with --forward-stdin
+    stdin_open: bool,
+    /// When the push in progress started
+    push_started: Option<Instant>,
+    /// The file the push in progress sends
+    pushing: Option<PathBuf>,
+    /// Digest of the kernel for the status line, with the path it's of
+    kernel_digest: Option<(PathBuf, ImageDigest)>,
+    /// Gets a copy of the device output, with --mirror
+    mirror: Option<Mirror>,
+    /// Reads the error counters of the connection's UART, if it counts them
+    uart_errors: Option<ErrorWatch>,
+    /// Flushed on a timer, with --capture-raw
+    raw_capture: Option<JournalWriter>,
+    /// SIGUSR1 asked for the statistics, they're dumped by the loop waiting on the device
+    dump_stats: bool,
+    /// SIGHUP asked for the configuration to be reloaded, once no push is going on
+    reload: bool,
+    /// The settings as last read, from the start or a reload, see `settings`
+    loaded: Vec<(&'static str, Takes, String)>,
+}
+
+/// The device stopped answering (unplugged, emulator gone), a daemon opens it again
+#[derive(Debug)]
+struct DeviceLost(io::Error);
+
+impl fmt::Display for DeviceLost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Lost the device: {}", self.0)
+    }
+}
+
+impl std::error::Error for DeviceLost {}
+
+/// The board was power cycled, its device has to be opened again once it's back
+#[derive(Debug)]
+struct PowerCycled;
+
+impl fmt::Display for PowerCycled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Power cycled the board")
+    }
+}
+
+impl std::error::Error for PowerCycled {}
+
+/// A reload (SIGHUP with --apply-hard) changed how the device is opened, it's opened again
+#[derive(Debug)]
+struct Reopen;
+
+impl fmt::Display for Reopen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reopening the device for the reloaded configuration")
+    }
+}
+
+impl std::error::Error for Reopen {}
+
+/// What pusher was asked to do
+enum Command {
+    /// Wait for the device and push the kernel (the default)
+    Push(Box<Options>),
+    /// Print the DTB variants defined in the config and exit
+    ListDtbs(Config),
+    /// Receive a file from the device
+    Pull(PullOptions),
+    /// Compare the image the device holds with a local kernel
+    Verify(VerifyOptions),
+    /// Measure the link's throughput, round trip and errors
+    Bench(BenchOptions),
+    /// Show the modem control lines
+    Lines(LinesOptions),
+    /// Play the loader's role, hidden from the usage
+    Simulate(SimulateOptions),
+    /// Send a request to a running pusher's control socket
+    Ctl(CtlOptions),
+    /// Print a shell's completion script
+    Completions(Shell),
+    /// Print values to complete, for the completion scripts, hidden from the usage
+    Complete(Dynamic, Config),
+    /// Print a raw capture as a hex dump
+    Capdump(PathBuf, capdump::Filter),
+}
+
+/// Where the device is connected
+#[derive(Debug, Clone)]
+enum Target {
+    /// A tty, opened when connecting
+    Serial(PathBuf),
+    /// A UNIX domain socket, given as `unix:/path/to/socket`
+    UnixSocket(PathBuf),
+    /// A journal played back by `pusher replay`, `speed` times as fast as it was recorded or in
+    /// step with what's sent
+    Replay { journal: PathBuf, speed: f64, lockstep: bool },
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Serial(path) => write!(f, "{}", path.display()),
+            Target::UnixSocket(path) => write!(f, "unix:{}", path.display()),
+            Target::Replay { journal, .. } => write!(f, "replay of {}", journal.display())
+        }
+    }
+}
+
+/// Options that apply whatever the command
+struct GlobalOptions {
+    show_banner: bool,
+    /// Hex dump the bytes on the wire to stderr
+    print_wire: bool,
+    /// Journal the bytes on the wire to this file
+    record_path: Option<PathBuf>,
+    /// Journal them buffered to this file, --capture-raw
+    capture_raw_path: Option<PathBuf>,
+    /// Take the lockfile of a port whoever holds it
+    steal_lock: bool,
+    /// Tries again at opening a busy port, and the wait before the first one
+    open_retries: u32,
+    open_retry_delay: Duration,
+}
+
+/// Options for `pusher pull`
+struct PullOptions {
+    target: Target,
+    baud_rate: u32,
+    protocol: Protocol,
+    output_path: PathBuf,
+}
+
+/// Options for `pusher verify`
+struct VerifyOptions {
+    target: Target,
+    baud_rate: u32,
+    protocol: Protocol,
+    kernel_path: PathBuf,
+}
+
+/// Options for `pusher bench`
+struct BenchOptions {
+    target: Target,
+    baud_rate: u32,
+    size: usize,
+    pattern: Pattern,
+    /// Print the report as JSON
+    json: bool,
+}
+
+/// Options for `pusher lines`
+struct LinesOptions {
+    target: Target,
+    baud_rate: u32,
+    /// Print the transitions until interrupted
+    watch: bool,
+}
+
+/// Options for `pusher ctl`
+struct CtlOptions {
+    socket_path: PathBuf,
+    request: Request,
+}
+
+/// Options for `pusher simulate`
+struct SimulateOptions {
+    target: Target,
+    baud_rate: u32,
+    protocol: Protocol,
+    /// CRC32 the received image must have
+    expected_crc: Option<u32>,
+}
+
+/// Options supplied on the command line
+struct Options {
+    target: Target,
+    baud_rate: u32,
+    /// Wire format, built from the defaults and the protocol options
+    protocol: Protocol,
+    /// `None` with --interactive-only, nothing is pushed then but what Ctrl-A P asks for
+    kernel_path: Option<PathBuf>,
+    /// Discard whatever is sitting in the serial input buffer before starting
+    flush_input: bool,
+    /// Device tree blob sent after the kernel
+    dtb_path: Option<PathBuf>,
+    /// Kernels picked by board, from the config's `[boards]`
+    board_kernels: Option<BoardKernels>,
+    /// Push right after connecting instead of waiting for breaks, then just monitor
+    push_on_connect: bool,
+    /// Kernel ELF used to annotate addresses the device prints
+    symbols_path: Option<PathBuf>,
+    /// Regexes finding addresses in device output, see `Symbolizer`
+    symbol_patterns: Vec<String>,
+    /// Firmware ELF used to decode defmt frames in device output
+    defmt_path: Option<PathBuf>,
+    /// Split device output into channels, see `Demux`
+    demux: bool,
+    /// Where each non console channel goes
+    channels: Vec<(u8, PathBuf)>,
+    /// Console output is COBS packets delimited by zeros, shown as hex
+    cobs: bool,
+    /// Show console output as a hex dump with rows this wide
+    hex: Option<usize>,
+    /// Highlight bytes that changed since the row or COBS packet before
+    hex_diff: bool,
+    /// Train the device's baud rate detection before waiting for it
+    autobaud: Option<Training>,
+    /// Poke the loader when its ready signal doesn't come
+    ready_probe: Option<ReadyProbe>,
+    /// Give up when no ready signal came for this long
+    ready_timeout: Option<Duration>,
+    /// Forget the start of the ready signal when the rest doesn't follow within this long
+    break_reset_timeout: Option<Duration>,
+    /// Baud rates to try until the device says something we recognize
+    probe_bauds: Vec<u32>,
+    /// Output that confirms a probed rate, besides the ready signal
+    probe_pattern: Option<Vec<u8>>,
+    /// Show typed bytes locally, toggled at runtime with Ctrl-A e
+    local_echo: bool,
+    /// What the Enter key sends
+    enter_sends: EnterSends,
+    /// Send what's piped to stdin to the device
+    forward_stdin: bool,
+    /// Pause after each byte sent to the device
+    char_delay: Duration,
+    /// Pace the pushed payload with `char_delay` too, not only keystrokes
+    char_delay_push: bool,
+    /// Pause between the ack and the payload
+    pre_send_wait: Duration,
+    /// Size headers sent again when the loader NAKs one
+    size_retries: u32,
+    /// Longest wait for the device to take a byte, forever if `None`
+    write_timeout: Option<Duration>,
+    /// Text file sent to the console on connecting
+    send_file_on_connect: Option<PathBuf>,
+    /// Pause after each line of a file sent to the console
+    line_delay: Duration,
+    /// Output to wait for after each line of a file sent to the console
+    line_prompt: Option<Regex>,
+    /// Where to append the timeline of events
+    event_log_path: Option<PathBuf>,
+    /// When to rotate the event log
+    log_rotation: Option<Rotation>,
+    /// Print what a push would send and exit
+    dump_kernel_info: bool,
+    /// Don't read what's pending before sending the size header
+    no_drain: bool,
+    /// Push the kernel bytes alone, no size header and no handshake
+    raw: bool,
+    /// Device output goes to stderr, status lines stay on stdout
+    device_to_stderr: bool,
+    /// Show the device output without its CSI sequences
+    strip_ansi: bool,
+    /// Show what's done with the link, like opening and closing it
+    verbose: bool,
+    /// Print the spans of each push's phases
+    trace: bool,
+    /// Keep a status line at the bottom of the terminal
+    status_line: bool,
+    /// Readiness events taken per wakeup of the event loop
+    poll_events: usize,
+    /// Don't push an image identical to the last one pushed this session
+    skip_unchanged: bool,
+    /// Sent instead of the size header when a push is skipped, for loaders that can boot
+    /// the image they already have
+    skip_signal: Option<Vec<u8>>,
+    /// End the session once the device prints this
+    exit_on: Option<Vec<u8>>,
+    /// End the session after the first push
+    once: bool,
+    /// Sent on connecting, to wake the loader up
+    wake: Option<Vec<u8>>,
+    /// Where to download the kernel from, `kernel_path` is then its local copy
+    remote: Option<RemoteImage>,
+    /// Download the kernel again (if it changed) before every push
+    refetch_each_push: bool,
+    /// Bytes at the start of the kernel file not to send
+    kernel_offset: u64,
+    /// How much of the kernel file to send from `kernel_offset`, everything left if not set
+    kernel_length: Option<u64>,
+    /// Where to listen for control requests
+    control_socket: Option<PathBuf>,
+    /// Boards to push to at once, with their names. Empty unless --device was given more than
+    /// once, `target` is then the first one
+    boards: Vec<(String, Target)>,
+    /// GPIO line wired to the board's reset, used instead of DTR
+    reset_gpio: Option<GpioSpec>,
+    /// How long a reset holds the line
+    reset_pulse: Duration,
+    /// How to power cycle the board, if it can be
+    power_cycle: Option<PowerCycle>,
+    /// Failed pushes in a row that get the board power cycled
+    power_cycle_after: u32,
+    /// Where to share the console over TCP
+    console_server: Option<String>,
+    /// Where to serve /status and /metrics
+    status_addr: Option<String>,
+    /// Forward what console clients send to the device
+    console_server_writable: bool,
+    /// Send `keepalive_byte` when nothing went either way for this long
+    keepalive: Option<Duration>,
+    keepalive_byte: u8,
+    /// Warn when the device printed nothing for this long
+    idle_warn: Option<Duration>,
+    /// Watch the silence before the first push too
+    idle_warn_always: bool,
+    /// Print the modem control lines' transitions
+    watch_lines: bool,
+    /// The second port device output is copied to, and its rate
+    mirror: Option<(Target, u32)>,
+    /// Which tty to watch udev for
+    udev: Option<Selector>,
+    /// Run unattended: leave stdin alone, keep going when a push fails, and open the device
+    /// again when it goes away
+    daemon: bool,
+    /// Open the device again when a reload changed how it's opened
+    apply_hard: bool,
+}
+
+/// When a setting changed by a reload (SIGHUP) takes effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Takes {
+    /// Right away, or from the next push
+    Now,
+    /// On opening the device, which --apply-hard does again
+    Reopen,
+    /// On starting pusher
+    Restart,
+}
+
+/// Every field of `Options`, with its name in a reload's report and when a change to it takes
+/// effect. A field left out doesn't compile.
+macro_rules! settings {
+    ($($field:ident: $name:literal => $takes:ident,)*) => {
+        /// The settings of `options`, formatted to tell whether they changed
+        fn settings(options: &Options) -> Vec<(&'static str, Takes, String)> {
+            vec![$(($name, Takes::$takes, format!("{:?}", options.$field))),*]
+        }
+
+        /// Move the settings named in `names` from `new` to `options`
+        fn apply_settings(options: &mut Options, new: Options, names: &[&'static str]) {
+            let Options { $($field),* } = new;
+            $(if names.contains(&$name) {
+                options.$field = $field;
+            })*
+        }
+    };
+}
+
+settings! {
+    kernel_path: "kernel" => Now,
+    dtb_path: "--dtb" => Now,
+    board_kernels: "[boards]" => Now,
+    protocol: "protocol options" => Now,
+    kernel_offset: "--kernel-offset" => Now,
+    kernel_length: "--kernel-length" => Now,
+    char_delay: "--char-delay" => Now,
+    char_delay_push: "--char-delay-push" => Now,
+    pre_send_wait: "--pre-send-wait" => Now,
+    size_retries: "--retries" => Now,
+    write_timeout: "--write-timeout" => Now,
+    line_delay: "--line-delay" => Now,
+    line_prompt: "--line-prompt" => Now,
+    no_drain: "--no-drain" => Now,
+    raw: "--raw" => Now,
+    skip_unchanged: "--skip-unchanged" => Now,
+    skip_signal: "--skip-signal" => Now,
+    exit_on: "--exit-on" => Now,
+    once: "--once" => Now,
+    keepalive: "--keepalive" => Now,
+    keepalive_byte: "--keepalive-byte" => Now,
+    refetch_each_push: "--refetch-each-push" => Now,
+    power_cycle: "--power-cycle-cmd" => Now,
+    power_cycle_after: "--power-cycle-after" => Now,
+    event_log_path: "--event-log" => Now,
+    log_rotation: "--log-rotate-size" => Now,
+    device_to_stderr: "--echo-received-to-stderr" => Now,
+    strip_ansi: "--strip-ansi" => Now,
+    verbose: "--verbose" => Now,
+    apply_hard: "--apply-hard" => Now,
+    target: "device" => Reopen,
+    baud_rate: "baud rate" => Reopen,
+    flush_input: "--flush-input" => Reopen,
+    push_on_connect: "--push-on-connect" => Reopen,
+    symbols_path: "--symbols" => Reopen,
+    symbol_patterns: "--symbol-pattern" => Reopen,
+    defmt_path: "--defmt" => Reopen,
+    demux: "--demux" => Reopen,
+    channels: "--channel" => Reopen,
+    cobs: "--cobs" => Reopen,
+    hex: "--hex" => Reopen,
+    hex_diff: "--hex-diff" => Reopen,
+    autobaud: "--autobaud-train" => Reopen,
+    ready_probe: "--probe-after" => Reopen,
+    ready_timeout: "--ready-timeout" => Reopen,
+    break_reset_timeout: "--break-reset-timeout" => Now,
+    probe_bauds: "--probe-bauds" => Reopen,
+    probe_pattern: "--probe-pattern" => Reopen,
+    local_echo: "--local-echo" => Reopen,
+    enter_sends: "--enter-sends" => Reopen,
+    send_file_on_connect: "--send-file-on-connect" => Reopen,
+    wake: "--wake" => Reopen,
+    idle_warn: "--idle-warn" => Reopen,
+    idle_warn_always: "--idle-warn-always" => Reopen,
+    watch_lines: "--watch-lines" => Reopen,
+    remote: "kernel URL" => Restart,
+    dump_kernel_info: "--dump-kernel-info" => Restart,
+    forward_stdin: "--forward-stdin" => Restart,
+    trace: "--trace" => Restart,
+    status_line: "--status-line" => Restart,
+    poll_events: "--poll-events" => Restart,
+    control_socket: "--control-socket" => Restart,
+    boards: "--device" => Restart,
+    reset_gpio: "--reset-gpio" => Restart,
+    reset_pulse: "--reset-pulse" => Restart,
+    console_server: "--console-server" => Restart,
+    console_server_writable: "--console-server-writable" => Restart,
+    status_addr: "--status-addr" => Restart,
+    mirror: "--mirror" => Restart,
+    udev: "--udev" => Restart,
+    daemon: "--daemon" => Restart,
+}
+
+/// Parse command line arguments.
+/// Checks if device exists and is a tty and if the kernel image exists
+///
+/// # Usage:
+/// pusher [push] [options] <tty_device> <baudrate> [<kernel_to_push>]
+/// pusher [push] [options] unix:<socket_path> <baudrate> [<kernel_to_push>]
+/// pusher push --list-dtbs
+/// pusher pull <tty_device> <baudrate> <output_path>
+/// pusher verify <tty_device> <baudrate> <kernel>
+/// pusher replay [--speed <factor>|--lockstep] [options] <journal> <kernel>
+/// pusher capdump [--direction <rx|tx>] [--from <secs>] [--to <secs>] <capture>
+/// pusher completions <bash|zsh|fish>
+/// pusher simulate [--verify-after-send] [--expect-crc32 <hex>] <tty_device> <baudrate>
+///
+/// # Options:
+/// --flush-input: discard any RX data already buffered by the OS before pusher started
+/// --wake <pattern>: bytes (e.g. `\x55\xaa`) sent each time the device is opened, for loaders
+///   that only start listening once nudged. They go after the --reset-gpio pulse (pusher's
+///   start), --flush-input, --probe-bauds and the --autobaud-train training, right before
+///   waiting for the ready signal (or pushing, with --push-on-connect)
+/// --config <path>: read settings from `path` instead of `./pusher.toml`. Its `[boards]` table
+///   maps boards to kernels (`rpi4 = "kernel8-rpi4.img"`), each push sends the kernel of the
+///   board the loader names in its --identify answer, or of the adapter's USB serial. Another
+///   board gets the kernel on the command line, or no push with `unknown_board = "refuse"`.
+///   The board is shown when the push is done and kept with it
+/// --dtb <name|path>: send a device tree blob after the kernel, either a variant from the
+///   config's `[dtbs]` table or a path. It is sent as a 4 byte little endian size followed by the blob
+/// --list-dtbs: print the configured DTB variants
+/// --dump-kernel-info: check the right artifact is about to go out: print one line with the
+///   kernel's path, the path it resolves to, and the size, CRC32 and first and last 8 bytes of
+///   what a push sends (--kernel-offset and --kernel-length applied), then exit. The device isn't
+///   opened
+/// --push-on-connect: push as soon as the device is opened without waiting for the break sequence
+///   (the size/OK handshake still happens), then keep monitoring. Meant for emulators
+/// --symbols <elf>: annotate addresses printed by the device with `function+offset (file:line)`
+/// --symbol-pattern <regex>: what an address looks like in the output, may be repeated.
+///   Capture group 1 is the address if the regex has one
+/// --defmt <elf>: decode defmt log frames in device output, plain text is still shown as is
+/// --checksum <crc16|crc32|none>: append a checksum of the image after it, 2 bytes for crc16
+///   (CRC-16/CCITT-FALSE), 4 for crc32 (CRC-32/ISO-HDLC), in the size header's byte order
+/// --negotiate: ask the loader what it supports before each push and use the checksum it selects,
+///   falling back to --checksum if it doesn't answer (see `protocol` for the bytes). For `pusher
+///   simulate`, answer the probe, selecting --checksum if it's offered
+/// --identify: right after the ready signal, ask the loader for its identity (name, version, board
+///   revision, how much it can load, whatever it sends) and show it, log it to the --event-log and
+///   keep it with the push for the status and the SIGUSR1 history. A loader that doesn't answer
+///   within 300ms gets the push as usual, but it must ignore the request byte (see `protocol` for
+///   the bytes). For `pusher simulate`, answer that request
+/// --verify-after-send: once the payload is sent, ask the loader for the CRC32 of the kernel as
+///   it stored it and fail the push if it isn't the local one (see `protocol` for the bytes), so
+///   a daemon or --power-cycle retries it. For `pusher simulate`, answer that request
+/// --nak-token <pattern>: what the loader answers the size header with when it isn't ready for
+///   it, e.g. `NK` or `\x15`. Instead of waiting out the ack timeout the size is sent again right away
+/// --retries <n>: how many times --nak-token gets the size sent again before the push fails, default 3
+/// --ack-status: the loader may follow its OK with a status, e.g. the memory bank it loads into:
+///   spaces or tabs, then the status up to the end of the line, `OK 2\r\n`. It's shown, logged
+///   to the --event-log and kept with the push for the control socket's status and /status. A
+///   bare OK still works, it's taken as one when anything but a space or tab follows it, or
+///   nothing within 100ms (see `protocol` for the details)
+/// --demux: device output is framed as channel byte, length byte, payload. Channel 0 is the console
+/// --channel <number>=<path>: file or FIFO receiving a channel when demuxing, may be repeated
+/// --autobaud-train [0xNN] [interval_ms]: on start, send the training byte (default 0x55) every
+///   interval (default 10ms) until the device answers, then wait for the break sequence as usual
+///   (or push right away with --push-on-connect). The byte is only taken if it starts with 0x,
+///   the interval only if it follows the byte
+/// --autobaud-response <pattern>: the device's answer to training, default `OK`
+/// --autobaud-timeout <ms>: give up training after this long, default 10s
+/// --trigger-pattern <pattern>: what the device sends when it's ready, instead of three 0x03 bytes,
+///   e.g. `<<READY>>`. `\xNN` escapes are supported
+/// --probe-bauds <rate,rate,...>: listen at each rate in turn until the ready signal (which
+///   triggers a push) or the --probe-pattern shows up, and keep that rate
+/// --probe-pattern <pattern>: output known to come from the device at the right rate
+/// --probe-after <secs>: for loaders announcing themselves once at power on, when no ready signal
+///   came this long after connecting, send the --probe-string and count the --probe-response as
+///   the ready signal. Each next probe waits twice as long (up to a minute), and once --probe-limit
+///   probes went the ready signal is waited for as usual. Only until the first push of a connection
+/// --probe-string <pattern>: what --probe-after sends, default a newline
+/// --probe-response <pattern>: the loader's answer to a probe, which triggers a push like the ready
+///   signal. By default only the ready signal does
+/// --probe-limit <n>: how many probes --probe-after sends at most, default 5
+/// --ready-timeout <secs>: fail when no ready signal came this long after connecting (probes
+///   included), rather than waiting forever. Only until the first push of a connection
+/// --break-reset-timeout <ms>: forget the start of the ready signal (one or two of the three
+///   breaks) when the rest doesn't follow within this long, so what a board printed before it
+///   rebooted can't combine with the new boot's into a ready signal. Checked as time goes by,
+///   not only when more output comes. --verbose says when it happens
+/// --local-echo: show what's typed, for devices that don't echo it back. Ctrl-A e toggles it
+/// --enter-sends <cr|lf|crlf|raw>: send Enter to the device as CR, LF or CR LF, whatever the
+///   terminal delivers. Only line endings change: pasted text's newlines (the terminal sends
+///   pastes as typed, there's no bracketed paste) and those of --forward-stdin's input are
+///   translated too. Ctrl-A Enter cycles through the modes. Default raw, sent as is
+/// --forward-stdin: when stdin is a pipe rather than a terminal, send what comes through it to the
+///   device until it ends, paced by --char-delay, and keep showing the console after. Held back
+///   while pushing. Files and /dev/null can't be watched, pipe them in with cat
+/// --char-delay <microseconds>: pause after each typed byte sent, for receivers that drop
+///   bytes arriving back to back
+/// --send-file-on-connect <path>: send a text file to the console each time the device is
+///   opened, line by line with each line ending in `\r` like Enter, as Ctrl-A u does. Paced by
+///   --char-delay, --line-delay and --line-prompt, held back while pushing and stopped by a push
+///   starting or Esc
+/// --line-delay <ms>: pause after each line of a file sent to the console, default none
+/// --line-prompt <regex>: after each line of a file sent to the console, wait (up to 10s) for the
+///   device output to match this, e.g. `=> $` for U-Boot, before sending the next one
+/// --char-delay-push: pace the pushed kernel (and DTB) with --char-delay as well
+/// --pre-send-wait <ms>: wait this long once, after the loader acknowledged the size and before
+///   the first byte of the kernel, for loaders that need a moment to set up their buffers or DMA
+///   and drop what comes meanwhile. Unlike --char-delay it doesn't slow the transfer down. Default 0
+/// --write-timeout <ms>: when the device stops taking bytes (flow control holding the line, a
+///   stuck socket), fail with "transfer stalled" after this long instead of waiting forever. The
+///   failed push is retried like any other with --daemon
+/// --event-log <file>: append a timestamped line per event (waiting, trigger, send start and end,
+///   handshake result, errors) to the file. Each session starts with a `#` line naming the
+///   device, the kernel and the start time
+/// --log-rotate-size <bytes>: for rigs left running for days, move the --event-log to
+///   `<file>.1` (the one before to `<file>.2` and so on) when the next line would take it past
+///   this size, and go on in a new file starting with the session's `#` line. Lines are never split
+/// --log-rotate-keep <n>: how many rotated logs to keep, the oldest beyond are deleted. Default 5,
+///   0 keeps none
+/// --no-drain: send the size header right after the ready signal, without first reading (and
+///   displaying) what the device sent before it
+/// --echo-received-to-stderr: write the device output to stderr, leaving stdout to the
+///   [PUSHER] status lines, so they can be redirected apart
+/// --strip-ansi: remove the ANSI CSI sequences (`ESC [ ... letter`: colors, cursor moves) from
+///   the device output shown, for a clean transcript when it's redirected to a file. --record,
+///   --capture-raw and the --console-server clients still get the bytes as sent
+/// --raw: for loaders that don't speak the protocol yet, the ready signal (or Ctrl-A f, or
+///   --push-on-connect) streams the kernel bytes as they are: no size header, no waiting for OK,
+///   no checksum (--checksum is ignored) and no DTB
+/// --no-handshake: --raw with --push-on-connect, for a loader that has no ready signal either:
+///   the kernel is streamed as soon as the device is opened (paced by --char-delay-push), and
+///   again on Ctrl-A f. With --once it's `cat kernel > tty` with progress and pacing
+/// --print-wire: log every byte sent to and received from the device to stderr as a hex dump,
+///   each line marked TX or RX with the seconds since the device was opened, for debugging the
+///   protocol. Works for every command. Sent bytes are logged in lines of 16, a line being cut
+///   short when the device answers, so the log follows the order things happened in
+/// --record <path>: write every byte sent to and received from the device to a journal (see
+///   `journal` for the format), with when it went. Works for every command, a daemon's
+///   reconnections all go to the same journal. A board's recording kept as a regression fixture
+///   goes by `<name>.pusher-cast`, see --lockstep
+/// --capture-raw <path>: like --record, but the records are buffered and written out every
+///   second and on exit, so capturing a fast push doesn't slow it down. The capture is a journal,
+///   `pusher capdump` shows it as a hex dump with each record's time and direction (and
+///   `pusher replay` plays it back)
+/// --direction <rx|tx>: for `pusher capdump`, show only what was received or sent
+/// --from <secs>, --to <secs>: for `pusher capdump`, show only the records from or until that
+///   many seconds (fractions allowed) after the capture started
+/// --speed <factor>: for `pusher replay`, play the journal back this many times as fast as it was
+///   recorded, default 1, 0 to send it all at once. A replay runs the usual session with the device
+///   output taken from the journal (what the session sends is ignored), so the console options
+///   (--symbols, --demux, --trigger-pattern...) apply and pushes happen when the journal's ready
+///   signal comes. It ends with the journal
+/// --lockstep: for `pusher replay`, play the device's output in step with what the session sends
+///   instead of with the recorded timing: each read comes once as many bytes have been sent as
+///   had been when it was recorded. Ready signal detection and the handshake then go the same way
+///   on every run, for replaying a board's `.pusher-cast` recording (a --record journal) in CI.
+///   Timeouts aren't reproduced, an ack recorded late comes right after the size header
+/// --size <n>: for `pusher bench`, how many bytes to send, default 16384. Like a kernel they go
+///   through the usual write path, so the throughput is what a push gets
+/// --pattern <random|zeros>: for `pusher bench`, the bytes to send, default random
+/// --json: for `pusher bench`, print the report as a JSON object on stdout, the status lines
+///   go to stderr
+/// --verbose: print more status lines about the link, like the serial port being closed
+/// --trace: print a `tracing` span to stderr as each phase of a push (handshake, transfer,
+///   verify) ends, with how long it took and the bytes it sent. `RUST_LOG` filters them as usual,
+///   by default `pusher=info`. Needs a pusher built with `--features trace`
+/// --status-line: keep a line at the bottom of the terminal with the device, whether it's
+///   connected or pushing, the kernel and the start of its SHA-256, the pushes so far and how the
+///   last one went. Ctrl-A t shows or hides it, it's never shown when stdout isn't a terminal
+/// --poll-events <n>: how many readiness events (device, keyboard, sockets...) one wakeup of the
+///   event loop takes, default 1024. Only worth raising with many console clients or boards
+/// --skip-unchanged: when the device asks for a kernel identical to the last one pushed this
+///   session, don't push it again. Ctrl-A f pushes anyway
+/// --skip-signal <pattern>: sent to the device when a push is skipped, for loaders that can
+///   boot their cached image
+/// --exit-on <pattern>: exit, successfully, once the device prints `pattern`
+/// --once: exit, successfully, once a push is done (or skipped by --skip-unchanged). A failed
+///   push exits with an error as usual
+/// --interactive-only: connect and show the console without a kernel to push, as leaving the
+///   kernel argument out does. A ready signal only gets a warning, Ctrl-A P still pushes a file
+///   and `set-kernel` over the control socket gives pusher a kernel after all
+/// --runner: for `runner = "pusher --runner"` in `.cargo/config.toml`, so `cargo run` pushes the
+///   kernel it built. cargo only gives the kernel (then the arguments of `cargo run --`, which are
+///   ignored), the device, baud rate (default 115200) and more options come from the `[runner]`
+///   table of the nearest pusher.toml, in the current directory or a parent like the workspace
+///   root. An ELF kernel is converted to a raw image first, `<kernel>.bin`. A failed push exits
+///   with an error, so `cargo run` fails too, a successful one keeps showing the console until
+///   --exit-on, Ctrl-A x or Ctrl-C
+/// --kernel <path|url>: the kernel to push, instead of the last positional argument.
+///   http:// and https:// URLs are downloaded before pushing
+/// --header <"Name: value">: extra HTTP header when downloading the kernel, e.g. for auth tokens
+/// --expected-sha256 <hex>: refuse a downloaded kernel with another digest
+/// --refetch-each-push: download the kernel again before every push, if the server has a newer one
+/// --cache-dir <dir>: keep downloaded kernels in <dir> with their ETags, so later runs only download
+///   them again if the server has a newer one (or, with --expected-sha256, not at all if the digest
+///   matches). URLs need a build with the `http` feature
+/// --kernel-offset <bytes>: skip the start of the kernel file (a header the loader doesn't expect),
+///   the size header only counts what's sent
+/// --kernel-length <bytes>: send only this many bytes from the offset, e.g. to leave out padding.
+///   Longer than what's left of the file sends the rest, with a warning
+/// --control-socket <path>: listen for JSON requests (status, push, set-kernel, reset-board,
+///   quit) on a Unix domain socket, see `pusher ctl`
+/// --reset-gpio <chip:line[:active_low]>: reset the board through a host GPIO line (e.g.
+///   `gpiochip0:17`) instead of DTR. It's pulsed on start, after a failed push in --daemon mode so
+///   the loader asks again, on Ctrl-A b and on the control socket's reset-board. Needs a build
+///   with the `gpio` feature, on Linux
+/// --reset-pulse <ms>: how long a reset holds the line, GPIO or DTR, default 100ms. Ctrl-A D and
+///   Ctrl-A R pulse DTR and RTS for as long
+/// --power-cycle-cmd <command>: shell command cutting the board's power and giving it back, e.g.
+///   `uhubctl -l 1-1.4 -p 2 -a cycle`. It's run after --power-cycle-after failed pushes in a row
+///   (a failed push doesn't end the session then) and on Ctrl-A p, and the device is waited for
+///   (up to 30s, or forever with --daemon) since its node goes away meanwhile
+/// --hub-location <hub:port=n>: power cycle by switching that USB hub port off for 2s, the way
+///   uhubctl does through sysfs (Linux 6.0 or later, root or a udev rule), e.g. `1-1.4:port=2`
+/// --power-cycle-after <n>: failed pushes in a row that get the board power cycled, default 3
+/// --device <[name=]device>: the device, instead of the first positional argument. Given more
+///   than once, every board is pushed to: each one's output is prefixed with `[name]`, whichever
+///   signals readiness gets the kernel, concurrently with the others, with a progress line every
+///   25%, and a summary of pushes, failures and the last push's time per board is printed at the
+///   end. Keystrokes aren't forwarded then, and only the push options apply (no symbols, demux,
+///   control socket...)
+/// --auto-device: when no device is given (`pusher --auto-device <baudrate> [<kernel>]`), use
+///   the USB serial adapter that's plugged in, saying which. With none or several it fails,
+///   listing the ports found, so a bench with two adapters never gets the wrong one. A device
+///   given (or from the --runner config) is used as usual
+/// --profile <name|auto>: push to the board of the config's `[profiles.<name>]` table
+///   (`pusher --profile rack3`): its device, or the port of the adapter with its `usb_serial`,
+///   its baud rate (default 115200) and options like how it's reset go in front of the command
+///   line, and its kernel is pushed unless one is given. `auto` picks the profile whose
+///   `usb_serial` is plugged in, failing with the ports and serials found unless exactly one is
+/// --steal-lock: take the device's lockfile (`LCK..ttyUSB0` in /var/lock, or /tmp) even if the
+///   process it names is there. Every tty pusher opens is locked that way while it's open, so a
+///   second pusher fails saying who holds it and for how long. A lockfile whose process is gone
+///   is taken over without it, this is for one whose PID was reused by another process
+/// --open-retries <n>: when opening the tty fails because it's busy or not accessible, like
+///   while ModemManager probes an adapter just plugged in or before udev set its permissions,
+///   try again up to <n> times, saying so once. The last error says how many tries were made.
+///   Default 0, failing right away
+/// --open-retry-delay <ms>: the wait before the first retry, default 500. It doubles for each
+///   next one, up to 5s
+/// --console-server <addr:port>: share the console over TCP. Every client gets the device output,
+///   starting with the last 16 KiB of it. Clients too slow to keep up are disconnected
+/// --console-server-writable: what console clients send goes to the device, like keystrokes
+/// --status-addr <addr:port>: serve `GET /status`, the control socket's status as JSON with the
+///   device, and `GET /metrics`, the counters (pushes, bytes, size header retries, reconnects,
+///   UART errors) in the Prometheus text format, for dashboards. Plain HTTP without
+///   authentication, so keep it on 127.0.0.1 or a lab network. Needs a build with the
+///   `status-http` feature
+/// --no-banner: don't print the logo on start (builds without the `banner` feature never do)
+/// --keepalive <ms>: send the --keepalive-byte when nothing was sent or received for this long, for
+///   loaders that give up waiting on a quiet link. The byte must be one the loader safely ignores
+///   while waiting for the ready signal to be answered, or it'll take it as the start of a command
+/// --keepalive-byte <0xNN|n>: the byte --keepalive sends, default 0x00
+/// --idle-warn <secs>: once a kernel was pushed, print a warning (and log an event) when the
+///   device printed nothing for this long, and another line when it prints again. Pushing is
+///   quiet on purpose, so it isn't watched, and the watch starts over after each push
+/// --idle-warn-always: with --idle-warn, watch from the start, a loader waiting for its first
+///   push included
+/// --mirror <device:baudrate>: open a second port (a tty or unix:<socket_path>) and write every
+///   byte received from the device to it, e.g. for an instrument capturing the boot log. The
+///   mirror failing is reported and its output dropped, the session goes on
+/// --watch-lines: look at the modem control lines every 100ms and print each one that changed,
+///   with the time, e.g. a flapping CTS that explains dropped bytes. Also for `pusher lines`,
+///   which shows them once otherwise. Links without the lines (sockets) get a warning
+/// --daemon: run unattended, e.g. under systemd: stdin is never touched, a failed push waits for
+///   the next ready signal and a device that goes away (or isn't there yet) is opened again, backing
+///   off up to a minute between tries. Use --control-socket and --event-log to follow it. SIGTERM
+///   (or SIGINT) stops it cleanly, cancelling a push, with exit code 143 (130), a second one at once.
+///   SIGUSR1 writes the Ctrl-A s statistics and the history of the session's pushes to stderr and
+///   the event log, SIGUSR2 turns the --verbose status lines on or off. SIGHUP reads the
+///   command line's config (pusher.toml, with --runner its `[runner]` options too) again and
+///   applies what changed, see --apply-hard
+/// --apply-hard: when SIGHUP finds that the device, the baud rate or another setting only read
+///   on opening the device changed, open it again with them, instead of saying they need a
+///   restart. Kernel, DTB, protocol, pacing, timeouts, patterns and event log changes apply
+///   without it, from the next push (a reload waits for a push going on to end). What's set up
+///   on start (sockets, servers, --daemon, --mirror, --udev...) always needs a restart, and a
+///   config that doesn't read leaves everything as it was
+/// --udev: listen to udev for the device being plugged in and out, instead of finding out from a
+///   failed read or by trying to open it again and again. With --daemon it's opened as soon as
+///   it's back. Needs a build with the `udev` feature, otherwise (or without udev running) pusher
+///   warns and goes on without it
+/// --udev-match <vid:pid|serial=serial>: --udev, with the device being whichever tty has these USB
+///   IDs (in hex, e.g. 0403:6001) or this serial number, wherever it shows up
+/// --cobs: console output is COBS encoded packets delimited by zero bytes, print them as hex
+/// --hex: print console output as a hex dump, a row (with its offset and printable bytes) once
+///   every --hex-width bytes came
+/// --hex-width <n>: bytes per row of the --hex dump, default 16. Set it to the device's frame size
+///   so each row is a frame
+/// --hex-diff: with --hex (which it turns on) or --cobs, show the bytes that differ from the same
+///   offset in the row or packet before in bold red, to spot the one byte toggling in a stream of
+///   similar frames
+///
+/// `running` is the session's options when the config is read again for SIGHUP. The startup
+/// actions are left out then: the device (or --profile's port) isn't picked or checked again but
+/// taken from `running`, no cache directory is created, no ELF converted and nothing is said.
+///
+/// # Return
+/// The `Command` to run, for a push the device and a path to the kernel image, whether to
+/// print the logo and what to do with the bytes on the wire
+fn parse_input(running: Option<&Options>) -> Result<(Command, GlobalOptions)> {
+    let mut flush_input = false;
+    let mut list_dtbs = false;
+    let mut dump_kernel_info = false;
+    let mut push_on_connect = false;
+    let mut symbols_path = None;
+    let mut symbol_patterns = Vec::new();
+    let mut expected_crc = None;
+    let mut defmt_path = None;
+    let mut demux = false;
+    let mut channels = Vec::new();
+    let mut cobs = false;
+    let mut hex = false;
+    let mut hex_width = hexview::DEFAULT_WIDTH;
+    let mut hex_diff = false;
+    let mut protocol = Protocol::v1();
+    let mut autobaud: Option<Training> = None;
+    let mut autobaud_response = None;
+    let mut autobaud_timeout = None;
+    let mut probe_after = None;
+    let mut probe_string = None;
+    let mut probe_response = None;
+    let mut probe_limit = None;
+    let mut ready_timeout = None;
+    let mut break_reset_timeout = None;
+    let mut probe_bauds = Vec::new();
+    let mut probe_pattern = None;
+    let mut local_echo = false;
+    let mut enter_sends = EnterSends::Raw;
+    let mut forward_stdin = false;
+    let mut char_delay = Duration::ZERO;
+    let mut char_delay_push = false;
+    let mut pre_send_wait = Duration::ZERO;
+    let mut size_retries = None;
+    let mut write_timeout = None;
+    let mut send_file_on_connect = None;
+    let mut line_delay = Duration::ZERO;
+    let mut line_prompt = None;
+    let mut event_log_path = None;
+    let mut log_rotate_size = None;
+    let mut log_rotate_keep = None;
+    let mut no_drain = false;
+    let mut device_to_stderr = false;
+    let mut strip_ansi = false;
+    let mut raw = false;
+    let mut verbose = false;
+    let mut trace = false;
+    let mut status_line = false;
+    let mut poll_events = DEFAULT_POLL_EVENTS;
+    let mut skip_unchanged = false;
+    let mut skip_signal = None;
+    let mut exit_on = None;
+    let mut once = false;
+    let mut interactive_only = false;
+    let mut wake = None;
+    let mut kernel = None;
+    let mut http_headers = Vec::new();
+    let mut expected_sha256 = None;
+    let mut refetch_each_push = false;
+    let mut cache_dir = None;
+    let mut kernel_offset = 0;
+    let mut kernel_length = None;
+    let mut control_socket = None;
+    let mut daemon = false;
+    let mut apply_hard = false;
+    let mut udev = false;
+    let mut udev_match = None;
+    let mut keepalive = None;
+    let mut keepalive_byte = 0;
+    let mut idle_warn = None;
+    let mut idle_warn_always = false;
+    let mut watch_lines = false;
+    let mut mirror = None;
+    let mut boards = Vec::new();
+    let mut reset_gpio = None;
+    let mut reset_pulse = reset::DEFAULT_PULSE;
+    let mut power_cycle = None;
+    let mut power_cycle_after = power::DEFAULT_FAILURES;
+    let mut console_server = None;
+    let mut console_server_writable = false;
+    let mut status_addr = None;
+    let mut auto_device = false;
+    let mut show_banner = true;
+    let mut print_wire = false;
+    let mut record_path = None;
+    let mut capture_raw_path = None;
+    let mut steal_lock = false;
+    let mut open_retries = 0;
+    let mut open_retry_delay = DEFAULT_OPEN_RETRY_DELAY;
+    let mut capdump_filter = capdump::Filter::default();
+    let mut replay_speed = None;
+    let mut lockstep = false;
+    let mut bench_size = None;
+    let mut bench_pattern = None;
+    let mut json = false;
+    let mut config_path = None;
+    let mut dtb = None;
+    let mut supplied_arguments: Vec<String> = Vec::new();
+    let mut arguments: Vec<String> = env::args().collect();
+    let runner = arguments.iter().any(|argument| argument == "--runner");
+    if runner {
+        arguments = runner_arguments(arguments)?;
+    }
+    let profile_kernel = profile_arguments(&mut arguments, runner, running.map(|running| &running.target))?;
+    let mut arguments = arguments.into_iter().peekable();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--flush-input" => flush_input = true,
+            "--list-dtbs" => list_dtbs = true,
+            "--dump-kernel-info" => dump_kernel_info = true,
+            "--push-on-connect" => push_on_connect = true,
+            "--symbols" => symbols_path = Some(PathBuf::from(option_value(&mut arguments, "--symbols")?)),
+            "--symbol-pattern" => symbol_patterns.push(option_value(&mut arguments, "--symbol-pattern")?),
+            "--config" => config_path = Some(PathBuf::from(option_value(&mut arguments, "--config")?)),
+            "--dtb" => dtb = Some(option_value(&mut arguments, "--dtb")?),
+            "--defmt" => defmt_path = Some(PathBuf::from(option_value(&mut arguments, "--defmt")?)),
+            "--checksum" => protocol.checksum = Checksum::parse(&option_value(&mut arguments, "--checksum")?)?,
+            "--negotiate" => protocol.negotiate = true,
+            "--identify" => protocol.identify = true,
+            "--nak-token" => {
+                protocol.size_nak = Some(pattern::parse_pattern(&option_value(&mut arguments, "--nak-token")?)?);
+            },
+            "--retries" => {
+                let retries = option_value(&mut arguments, "--retries")?;
+                size_retries = Some(retries.parse()
+                    .map_err(|_| anyhow!("Invalid --retries \"{}\", expected a count", retries))?);
+            },
+            "--verify-after-send" => protocol.verify_after_send = true,
+            "--ack-status" => protocol.ack_status = true,
+            "--demux" => demux = true,
+            "--cobs" => cobs = true,
+            "--hex" => hex = true,
+            "--hex-width" => {
+                let width = option_value(&mut arguments, "--hex-width")?;
+                hex_width = width.parse::<usize>().ok().filter(|&width| width > 0)
+                    .ok_or_else(|| anyhow!("Invalid --hex-width \"{}\", expected a number of bytes", width))?;
+            },
+            "--hex-diff" => hex_diff = true,
+            "--local-echo" => local_echo = true,
+            "--enter-sends" => enter_sends = EnterSends::parse(&option_value(&mut arguments, "--enter-sends")?)?,
+            "--forward-stdin" => forward_stdin = true,
+            "--char-delay" => {
+                let micros = option_value(&mut arguments, "--char-delay")?;
+                char_delay = Duration::from_micros(micros.parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid --char-delay \"{}\", expected microseconds", micros))?);
+            },
+            "--char-delay-push" => char_delay_push = true,
+            "--pre-send-wait" => {
+                let millis = option_value(&mut arguments, "--pre-send-wait")?;
+                pre_send_wait = Duration::from_millis(millis.parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid --pre-send-wait \"{}\", expected milliseconds", millis))?);
+            },
+            "--write-timeout" => {
+                let millis = option_value(&mut arguments, "--write-timeout")?;
+                write_timeout = Some(Duration::from_millis(millis.parse::<u64>().ok().filter(|&millis| millis > 0)
+                    .ok_or_else(|| anyhow!("Invalid --write-timeout \"{}\", expected milliseconds", millis))?));
+            },
+            "--send-file-on-connect" => {
+                send_file_on_connect = Some(PathBuf::from(option_value(&mut arguments, "--send-file-on-connect")?));
+            },
+            "--line-delay" => {
+                let millis = option_value(&mut arguments, "--line-delay")?;
+                line_delay = Duration::from_millis(millis.parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid --line-delay \"{}\", expected milliseconds", millis))?);
+            },
+            "--line-prompt" => {
+                line_prompt = Some(Regex::new(&option_value(&mut arguments, "--line-prompt")?)
+                    .map_err(|err| anyhow!("Invalid --line-prompt: {}", err))?);
+            },
+            "--no-drain" => no_drain = true,
+            "--echo-received-to-stderr" => device_to_stderr = true,
+            "--strip-ansi" => strip_ansi = true,
+            "--raw" => raw = true,
+            "--no-handshake" => {
+                raw = true;
+                push_on_connect = true;
+            },
+            "--once" => once = true,
+            "--interactive-only" => interactive_only = true,
+            "--verbose" => verbose = true,
+            "--trace" => trace = true,
+            "--status-line" => status_line = true,
+            "--poll-events" => {
+                let count = option_value(&mut arguments, "--poll-events")?;
+                poll_events = match count.parse::<usize>() {
+                    Ok(0) | Err(_) => bail!("Invalid --poll-events \"{}\", expected a number of events", count),
+                    Ok(count) => count
+                };
+            },
+            "--skip-unchanged" => skip_unchanged = true,
+            "--kernel" => kernel = Some(option_value(&mut arguments, "--kernel")?),
+            "--header" => http_headers.push(fetch::parse_header(&option_value(&mut arguments, "--header")?)?),
+            "--expected-sha256" => {
+                expected_sha256 = Some(fetch::parse_sha256(&option_value(&mut arguments, "--expected-sha256")?)?);
+            },
+            "--refetch-each-push" => refetch_each_push = true,
+            "--cache-dir" => cache_dir = Some(PathBuf::from(option_value(&mut arguments, "--cache-dir")?)),
+            "--daemon" => daemon = true,
+            "--apply-hard" => apply_hard = true,
+            "--udev" => udev = true,
+            "--udev-match" => udev_match = Some(Selector::parse(&option_value(&mut arguments, "--udev-match")?)?),
+            "--keepalive" => {
+                let millis = option_value(&mut arguments, "--keepalive")?;
+                keepalive = match millis.parse::<u64>() {
+                    Ok(0) | Err(_) => bail!("Invalid --keepalive \"{}\", expected milliseconds", millis),
+                    Ok(millis) => Some(Duration::from_millis(millis))
+                };
+            },
+            "--idle-warn" => {
+                let secs = option_value(&mut arguments, "--idle-warn")?;
+                idle_warn = match secs.parse::<u64>() {
+                    Ok(0) | Err(_) => bail!("Invalid --idle-warn \"{}\", expected seconds", secs),
+                    Ok(secs) => Some(Duration::from_secs(secs))
+                };
+            },
+            "--idle-warn-always" => idle_warn_always = true,
+            "--watch-lines" => watch_lines = true,
+            "--mirror" => {
+                let mirror_port = option_value(&mut arguments, "--mirror")?;
+                let (device, baud_rate) = mirror_port.rsplit_once(':')
+                    .and_then(|(device, baud_rate)| Some((device, baud_rate.parse::<u32>().ok()?)))
+                    .ok_or_else(|| anyhow!("Invalid --mirror \"{}\", expected <device>:<baudrate>", mirror_port))?;
+                mirror = Some((parse_target(device)?, baud_rate));
+            },
+            "--keepalive-byte" => {
+                let byte = option_value(&mut arguments, "--keepalive-byte")?;
+                keepalive_byte = u8::try_from(commands::parse_number(&byte)?)
+                    .map_err(|_| anyhow!("Invalid --keepalive-byte \"{}\", expected a byte", byte))?;
+            },
+            "--reset-gpio" => reset_gpio = Some(GpioSpec::parse(&option_value(&mut arguments, "--reset-gpio")?)?),
+            "--reset-pulse" => {
+                let millis = option_value(&mut arguments, "--reset-pulse")?;
+                reset_pulse = Duration::from_millis(millis.parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid --reset-pulse \"{}\", expected milliseconds", millis))?);
+            },
+            "--power-cycle-cmd" | "--hub-location" if power_cycle.is_some() => {
+                bail!("Only one of --power-cycle-cmd and --hub-location can be given");
+            },
+            "--power-cycle-cmd" => {
+                power_cycle = Some(PowerCycle::Command(option_value(&mut arguments, "--power-cycle-cmd")?));
+            },
+            "--hub-location" => {
+                power_cycle = Some(PowerCycle::HubPort(HubPort::parse(&option_value(&mut arguments, "--hub-location")?)?));
+            },
+            "--power-cycle-after" => {
+                let count = option_value(&mut arguments, "--power-cycle-after")?;
+                power_cycle_after = match count.parse::<u32>() {
+                    Ok(0) | Err(_) => bail!("Invalid --power-cycle-after \"{}\", expected a number of pushes", count),
+                    Ok(count) => count
+                };
+            },
+            "--device" => boards.push(parse_board(&option_value(&mut arguments, "--device")?)?),
+            "--auto-device" => auto_device = true,
+            "--console-server" => console_server = Some(option_value(&mut arguments, "--console-server")?),
+            "--console-server-writable" => console_server_writable = true,
+            "--status-addr" => status_addr = Some(option_value(&mut arguments, "--status-addr")?),
+            "--no-banner" => show_banner = false,
+            "--steal-lock" => steal_lock = true,
+            "--open-retries" => {
+                let count = option_value(&mut arguments, "--open-retries")?;
+                open_retries = count.parse::<u32>()
+                    .map_err(|_| anyhow!("Invalid --open-retries \"{}\", expected a number of tries", count))?;
+            },
+            "--open-retry-delay" => {
+                let millis = option_value(&mut arguments, "--open-retry-delay")?;
+                open_retry_delay = Duration::from_millis(millis.parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid --open-retry-delay \"{}\", expected milliseconds", millis))?);
+            },
+            "--print-wire" => print_wire = true,
+            "--record" => record_path = Some(PathBuf::from(option_value(&mut arguments, "--record")?)),
+            "--capture-raw" => capture_raw_path = Some(PathBuf::from(option_value(&mut arguments, "--capture-raw")?)),
+            "--direction" => {
+                capdump_filter.direction = Some(capdump::Filter::parse_direction(&option_value(&mut arguments, "--direction")?)?);
+            },
+            "--from" => capdump_filter.from = Some(parse_capture_time(&option_value(&mut arguments, "--from")?)?),
+            "--to" => capdump_filter.to = Some(parse_capture_time(&option_value(&mut arguments, "--to")?)?),
+            "--speed" => {
+                let speed = option_value(&mut arguments, "--speed")?;
+                replay_speed = match speed.parse::<f64>() {
+                    Ok(speed) if speed >= 0.0 && speed.is_finite() => Some(speed),
+                    _ => bail!("Invalid --speed \"{}\", expected a factor like 1 (as recorded), 10 or 0 (no waiting)", speed)
+                };
+            },
+            "--lockstep" => lockstep = true,
+            "--size" => bench_size = Some(commands::parse_number(&option_value(&mut arguments, "--size")?)? as usize),
+            "--pattern" => bench_pattern = Some(Pattern::parse(&option_value(&mut arguments, "--pattern")?)?),
+            "--json" => json = true,
+            "--control-socket" => control_socket = Some(PathBuf::from(option_value(&mut arguments, "--control-socket")?)),
+            "--kernel-offset" => kernel_offset = commands::parse_number(&option_value(&mut arguments, "--kernel-offset")?)?,
+            "--kernel-length" => kernel_length = Some(commands::parse_number(&option_value(&mut arguments, "--kernel-length")?)?),
+            "--skip-signal" => {
+                skip_signal = Some(pattern::parse_pattern(&option_value(&mut arguments, "--skip-signal")?)?);
+            },
+            "--exit-on" => exit_on = Some(pattern::parse_pattern(&option_value(&mut arguments, "--exit-on")?)?),
+            // the config's [runner] options were added already
+            "--runner" => {},
+            "--profile" => {
+                option_value(&mut arguments, "--profile")?;
+            },
+            "--wake" => wake = Some(pattern::parse_pattern(&option_value(&mut arguments, "--wake")?)?),
+            "--event-log" => event_log_path = Some(PathBuf::from(option_value(&mut arguments, "--event-log")?)),
+            "--log-rotate-size" => {
+                let size = option_value(&mut arguments, "--log-rotate-size")?;
+                log_rotate_size = match commands::parse_number(&size) {
+                    Ok(0) | Err(_) => bail!("Invalid --log-rotate-size \"{}\", expected bytes", size),
+                    Ok(size) => Some(size)
+                };
+            },
+            "--log-rotate-keep" => {
+                let keep = option_value(&mut arguments, "--log-rotate-keep")?;
+                log_rotate_keep = Some(keep.parse::<u32>()
+                    .map_err(|_| anyhow!("Invalid --log-rotate-keep \"{}\", expected a number of files", keep))?);
+            },
+            "--probe-bauds" => {
+                probe_bauds = option_value(&mut arguments, "--probe-bauds")?.split(',')
+                    .map(|rate| rate.trim().parse::<u32>().map_err(|_| anyhow!("Invalid baud rate \"{}\"", rate)))
+                    .collect::<Result<_>>()?;
+            },
+            "--probe-pattern" => {
+                probe_pattern = Some(pattern::parse_pattern(&option_value(&mut arguments, "--probe-pattern")?)?);
+            },
+            "--trigger-pattern" => {
+                protocol.ready_signal = pattern::parse_pattern(&option_value(&mut arguments, "--trigger-pattern")?)?;
+            },
+            "--autobaud-train" => {
+                let mut training = Training::default();
+                if let Some(byte) = arguments.next_if(|byte| byte.starts_with("0x")) {
+                    training.byte = u8::from_str_radix(&byte[2..], 16)
+                        .map_err(|_| anyhow!("Invalid --autobaud-train byte \"{}\", expected 0x00 to 0xff", byte))?;
+                    // only a number is the interval, anything else is the next argument
+                    let interval = arguments.next_if(|interval| interval.parse::<u64>().is_ok());
+                    if let Some(millis) = interval.and_then(|interval| interval.parse().ok()) {
+                        training.interval = Duration::from_millis(millis);
+                    }
+                }
+                autobaud = Some(training);
+            },
+            "--autobaud-response" => {
+                autobaud_response = Some(pattern::parse_pattern(&option_value(&mut arguments, "--autobaud-response")?)?);
+            },
+            "--probe-after" => {
+                let secs = option_value(&mut arguments, "--probe-after")?;
+                let secs = secs.parse().map_err(|_| anyhow!("Invalid --probe-after \"{}\", expected seconds", secs))?;
+                probe_after = Some(Duration::from_secs(secs));
+            },
+            "--probe-string" => {
+                probe_string = Some(pattern::parse_pattern(&option_value(&mut arguments, "--probe-string")?)?);
+            },
+            "--probe-response" => {
+                probe_response = Some(pattern::parse_pattern(&option_value(&mut arguments, "--probe-response")?)?);
+            },
+            "--probe-limit" => {
+                let limit = option_value(&mut arguments, "--probe-limit")?;
+                probe_limit = Some(limit.parse()
+                    .map_err(|_| anyhow!("Invalid --probe-limit \"{}\", expected a number of probes", limit))?);
+            },
+            "--ready-timeout" => {
+                let secs = option_value(&mut arguments, "--ready-timeout")?;
+                let secs = secs.parse().map_err(|_| anyhow!("Invalid --ready-timeout \"{}\", expected seconds", secs))?;
+                ready_timeout = Some(Duration::from_secs(secs));
+            },
+            "--break-reset-timeout" => {
+                let millis = option_value(&mut arguments, "--break-reset-timeout")?;
+                break_reset_timeout = match millis.parse::<u64>() {
+                    Ok(0) | Err(_) => bail!("Invalid --break-reset-timeout \"{}\", expected milliseconds", millis),
+                    Ok(millis) => Some(Duration::from_millis(millis))
+                };
+            },
+            "--autobaud-timeout" => {
+                let millis = option_value(&mut arguments, "--autobaud-timeout")?;
+                let millis = millis.parse()
+                    .map_err(|_| anyhow!("Invalid --autobaud-timeout \"{}\", expected milliseconds", millis))?;
+                autobaud_timeout = Some(Duration::from_millis(millis));
+            },
+            "--channel" => channels.push(demux::parse_channel(&option_value(&mut arguments, "--channel")?)?),
+            "--expect-crc32" => {
+                let crc = option_value(&mut arguments, "--expect-crc32")?;
+                expected_crc = Some(u32::from_str_radix(crc.trim_start_matches("0x"), 16)
+                    .map_err(|_| anyhow!("Invalid --expect-crc32 \"{}\", expected a hex CRC32 like 0xcbf43926", crc))?);
+            },
+            flag if flag.starts_with("--") => return Err(anyhow!("Unknown option: {}", flag)),
+            _ => {
+                supplied_arguments.push(argument);
+                // what cargo run passes on comes after the kernel, it's meant for the kernel
+                if runner && supplied_arguments.len() == 4 {
+                    let ignored: Vec<String> = arguments.by_ref().collect();
+                    if !ignored.is_empty() && running.is_none() {
+                        status!("[PUSHER] Ignoring the kernel's arguments: {}", ignored.join(" "));
+                    }
+                }
+            }
+        }
+    }
+    if let Some(training) = &mut autobaud {
+        if let Some(response) = autobaud_response {
+            training.response = response;
+        }
+        training.timeout = autobaud_timeout.unwrap_or(training.timeout);
+    }
+    if probe_after.is_none() && (probe_string.is_some() || probe_response.is_some() || probe_limit.is_some()) {
+        bail!("--probe-string, --probe-response and --probe-limit go with --probe-after");
+    }
+    let ready_probe = probe_after.map(|after| {
+        let defaults = ReadyProbe::default();
+        ReadyProbe {
+            after,
+            probe: probe_string.unwrap_or(defaults.probe),
+            response: probe_response,
+            limit: probe_limit.unwrap_or(defaults.limit),
+        }
+    });
+    let globals = GlobalOptions { show_banner, print_wire, record_path, capture_raw_path, steal_lock, open_retries,
+        open_retry_delay };
+    // `push` is the default, so naming it is optional
+    if supplied_arguments.get(1).map(String::as_str) == Some("push") {
+        supplied_arguments.remove(1);
+    }
+    let config = Config::load(config_path.as_deref())?;
+    if list_dtbs {
+        return Ok((Command::ListDtbs(config), globals));
+    }
+    if supplied_arguments.get(1).map(String::as_str) == Some("pull") {
+        if supplied_arguments.len() != 5 {
+            return Err(anyhow!("Usage: pusher pull <device> <baudrate> <output>"));
+        }
+        return Ok((Command::Pull(PullOptions {
+            target: parse_target(&supplied_arguments[2])?,
+            baud_rate: supplied_arguments[3].parse::<u32>()?,
+            output_path: PathBuf::from(&supplied_arguments[4]),
+            protocol
+        }), globals));
+    }
+    if supplied_arguments.get(1).map(String::as_str) == Some("verify") {
+        if supplied_arguments.len() != 5 {
+            return Err(anyhow!("Usage: pusher verify <device> <baudrate> <kernel>"));
+        }
+        return Ok((Command::Verify(VerifyOptions {
+            target: parse_target(&supplied_arguments[2])?,
+            baud_rate: supplied_arguments[3].parse::<u32>()?,
+            kernel_path: PathBuf::from(&supplied_arguments[4]),
+            protocol
+        }), globals));
+    }
+    if supplied_arguments.get(1).map(String::as_str) == Some("bench") {
+        if supplied_arguments.len() != 4 {
+            bail!("Usage: pusher bench [--size <n>] [--pattern random|zeros] [--json] <device> <baudrate>");
+        }
+        return Ok((Command::Bench(BenchOptions {
+            target: parse_target(&supplied_arguments[2])?,
+            baud_rate: supplied_arguments[3].parse::<u32>()?,
+            size: bench_size.unwrap_or(DEFAULT_BENCH_SIZE),
+            pattern: bench_pattern.unwrap_or(Pattern::Random),
+            json
+        }), globals));
+    } else if bench_size.is_some() || bench_pattern.is_some() || json {
+        bail!("--size, --pattern and --json only apply to pusher bench");
+    }
+    if supplied_arguments.get(1).map(String::as_str) == Some("lines") {
+        let baud_rate = match supplied_arguments.len() {
+            3 => DEFAULT_LINES_BAUD_RATE,
+            4 => supplied_arguments[3].parse::<u32>()?,
+            _ => bail!("Usage: pusher lines [--watch-lines] <device> [<baudrate>]")
+        };
+        return Ok((Command::Lines(LinesOptions {
+            target: parse_target(&supplied_arguments[2])?,
+            baud_rate,
+            watch: watch_lines
+        }), globals));
+    }
+    if supplied_arguments.get(1).map(String::as_str) == Some("capdump") {
+        if supplied_arguments.len() != 3 {
+            bail!("Usage: pusher capdump [--direction rx|tx] [--from <secs>] [--to <secs>] <capture>");
+        }
+        return Ok((Command::Capdump(PathBuf::from(&supplied_arguments[2]), capdump_filter), globals));
+    } else if capdump_filter.direction.is_some() || capdump_filter.from.is_some() || capdump_filter.to.is_some() {
+        bail!("--direction, --from and --to only apply to pusher capdump");
+    }
+    if supplied_arguments.get(1).map(String::as_str) == Some("completions") {
+        if supplied_arguments.len() != 3 {
+            bail!("Usage: pusher completions bash|zsh|fish");
+        }
+        return Ok((Command::Completions(Shell::parse(&supplied_arguments[2])?), globals));
+    }
+    // called by the completion scripts, quietly
+    if supplied_arguments.get(1).map(String::as_str) == Some("__complete") {
+        let dynamic = Dynamic::parse(supplied_arguments.get(2).map_or("", String::as_str))?;
+        return Ok((Command::Complete(dynamic, config), globals));
+    }
+    if supplied_arguments.get(1).map(String::as_str) == Some("ctl") {
+        let request = match (supplied_arguments.get(3).map(String::as_str), supplied_arguments.get(4)) {
+            (Some("status"), None) => Request::Status,
+            (Some("push"), None) => Request::Push,
+            (Some("set-kernel"), Some(path)) => Request::SetKernel { path: PathBuf::from(path) },
+            (Some("reset-board"), None) => Request::ResetBoard,
+            (Some("quit"), None) => Request::Quit,
+            _ => return Err(anyhow!("Usage: pusher ctl <socket> status|push|set-kernel <path>|reset-board|quit"))
+        };
+        if supplied_arguments.len() > 5 {
+            return Err(anyhow!("Usage: pusher ctl <socket> status|push|set-kernel <path>|reset-board|quit"));
+        }
+        return Ok((Command::Ctl(CtlOptions { socket_path: PathBuf::from(&supplied_arguments[2]), request }), globals));
+    }
+    if supplied_arguments.get(1).map(String::as_str) == Some("simulate") {
+        if supplied_arguments.len() != 4 {
+            return Err(anyhow!("Usage: pusher simulate [--checksum <alg>] [--negotiate] [--identify] \
+                [--verify-after-send] [--expect-crc32 <hex>] <device> <baudrate>"));
+        }
+        return Ok((Command::Simulate(SimulateOptions {
+            target: parse_target(&supplied_arguments[2])?,
+            baud_rate: supplied_arguments[3].parse::<u32>()?,
+            protocol,
+            expected_crc
+        }), globals));
+    }
+    // `pusher replay <journal> <kernel>` is a push to the recorded device, which has no baud rate
+    let replay = supplied_arguments.get(1).map(String::as_str) == Some("replay");
+    if replay {
+        if !(3..=4).contains(&supplied_arguments.len()) || !boards.is_empty() {
+            bail!("Usage: pusher replay [--speed <factor>|--lockstep] [options] <journal> <kernel>");
+        }
+        supplied_arguments.remove(1);
+        supplied_arguments.insert(2, "0".to_string());
+    } else if replay_speed.is_some() || lockstep {
+        bail!("--speed and --lockstep only apply to pusher replay");
+    }
+    if replay_speed.is_some() && lockstep {
+        bail!("--lockstep replays without the recorded timing, it can't be given a --speed");
+    }
+    if replay && daemon {
+        bail!("A replay ends with the journal, it can't be run with --daemon");
+    }
+    if once && (daemon || boards.len() > 1) {
+        bail!("--once exits after a push, it can't be used with --daemon or several --device");
+    }
+    if globals.record_path.is_some() && boards.len() > 1 {
+        bail!("--record journals a single device, it can't be used with several --device");
+    }
+    if globals.capture_raw_path.is_some() && boards.len() > 1 {
+        bail!("--capture-raw captures a single device, it can't be used with several --device");
+    }
+    // no device before the baud rate, it's the adapter plugged in
+    let baud_first = supplied_arguments.get(1).is_some_and(|argument| argument.parse::<u32>().is_ok());
+    if auto_device && boards.is_empty() && !runner && !replay && baud_first {
+        let target = match running {
+            Some(running) => running.target.clone(),
+            None => {
+                let path = autodevice::select()?;
+                status!("[PUSHER] Auto-selected {}", path.display());
+                Target::Serial(path)
+            }
+        };
+        boards.push((target.to_string(), target));
+    }
+    // the kernel is the last positional argument, unless given with --kernel, and so is the
+    // device unless given with --device
+    let device_arguments = if boards.is_empty() { 1 } else { 0 };
+    let kernel = match kernel {
+        Some(kernel) if supplied_arguments.len() == 2 + device_arguments => Some(kernel),
+        None if supplied_arguments.len() == 3 + device_arguments => supplied_arguments.pop(),
+        None if supplied_arguments.len() == 2 + device_arguments && profile_kernel.is_some() => profile_kernel
+            .map(|kernel| kernel.to_string_lossy().into_owned()),
+        // without a kernel it's just a console, cargo and replays always have one
+        None if supplied_arguments.len() == 2 + device_arguments && !runner && !replay => None,
+        _ => return Err(anyhow!(USAGE))
+    };
+    if interactive_only && kernel.is_some() {
+        bail!("--interactive-only pushes no kernel, leave it out");
+    }
+    if kernel.is_none() && push_on_connect {
+        bail!("--push-on-connect needs a kernel to push");
+    }
+    if kernel.is_none() && boards.len() > 1 {
+        bail!("Pushing to several --device needs a kernel");
+    }
+    if (hex || hex_diff) && defmt_path.is_some() {
+        bail!("--defmt decodes the console output, it can't be shown with --hex or --hex-diff too");
+    }
+    if idle_warn_always && idle_warn.is_none() {
+        bail!("--idle-warn-always needs --idle-warn");
+    }
+    if log_rotate_size.is_some() && event_log_path.is_none() {
+        bail!("--log-rotate-size needs --event-log");
+    }
+    if log_rotate_keep.is_some() && log_rotate_size.is_none() {
+        bail!("--log-rotate-keep needs --log-rotate-size");
+    }
+    let log_rotation = log_rotate_size.map(|size| Rotation {
+        size,
+        keep: log_rotate_keep.unwrap_or(events::DEFAULT_ROTATE_KEEP)
+    });
+    if raw && dtb.is_some() {
+        bail!("--raw sends the kernel alone, it can't be used with --dtb");
+    }
+    if raw && !pre_send_wait.is_zero() {
+        bail!("--raw has no handshake, --pre-send-wait can't wait after it");
+    }
+    if size_retries.is_some() && protocol.size_nak.is_none() {
+        bail!("--retries needs --nak-token");
+    }
+    if raw && protocol.size_nak.is_some() {
+        bail!("--raw has no handshake, --nak-token can't be used with it");
+    }
+    if raw && protocol.ack_status {
+        bail!("--raw has no handshake, --ack-status can't be used with it");
+    }
+    if raw && protocol.identify {
+        bail!("--raw leaves the protocol out, it can't be used with --identify");
+    }
+    if raw && protocol.verify_after_send {
+        bail!("--raw leaves the protocol out, it can't be used with --verify-after-send");
+    }
+    if forward_stdin && daemon {
+        bail!("A daemon leaves stdin alone, --forward-stdin can't be used with --daemon");
+    }
+    if let Some(path) = send_file_on_connect.as_ref().filter(|path| !path.is_file()) {
+        bail!("{} doesn't exist", path.display());
+    }
+    let dtb_path = match dtb {
+        Some(dtb) => Some(config.resolve_dtb(&dtb)?),
+        None => None
+    };
+    let target = match boards.first() {
+        Some((_, target)) => target.clone(),
+        None if replay => {
+            Target::Replay { journal: PathBuf::from(&supplied_arguments[1]), speed: replay_speed.unwrap_or(1.0), lockstep }
+        },
+        None => parse_target(&supplied_arguments[1])?
+    };
+    // a daemon (or --udev) waits for the device to show up, anything else needs a serial port now
+    if let Target::Serial(path) = &target {
+        if boards.len() <= 1 && !daemon && !udev && udev_match.is_none() && !dump_kernel_info && running.is_none() {
+            SerialDevice::probe(path)?;
+        }
+    }
+    let udev = match (udev_match, &target) {
+        (Some(selector), _) => Some(selector),
+        (None, Target::Serial(path)) if udev => Some(Selector::Path(path.clone())),
+        (None, _) if udev => bail!("--udev only works with serial devices"),
+        (None, _) => None
+    };
+    // a single --device is just another way to name the device
+    if boards.len() == 1 {
+        boards.clear();
+    }
+    let board_kernels = match config.board_kernels()? {
+        kernels if kernels.is_empty() => None,
+        kernels => {
+            let usb_serial = match &target {
+                Target::Serial(path) if boards.is_empty() => autodevice::port_usb_serial(path),
+                _ => None
+            };
+            if !protocol.identify && usb_serial.is_none() {
+                bail!("The config's [boards] are told apart by what the loader says it is, give --identify");
+            }
+            if raw {
+                bail!("--raw leaves the protocol out, it can't push the config's [boards] kernels");
+            }
+            Some(BoardKernels { kernels: kernels.clone(), usb_serial,
+                fallback: config.unknown_board == UnknownBoard::Default })
+        }
+    };
+    let remote = match &kernel {
+        Some(kernel) if fetch::is_url(kernel) => {
+            if let Some(dir) = cache_dir.as_ref().filter(|_| running.is_none()) {
+                fs::create_dir_all(dir).map_err(|err| anyhow!("Couldn't create {}: {}", dir.display(), err))?;
+            }
+            Some(RemoteImage::new(kernel, http_headers, expected_sha256, cache_dir.as_deref()))
+        },
+        // check the the binary to push exists
+        Some(kernel) if !Path::new(kernel).exists() => return Err(anyhow!(format!("{} doesn't exist", kernel))),
+        _ => None
+    };
+    // cargo builds ELF files, loaders take raw images
+    let kernel = match kernel {
+        Some(kernel) if runner && remote.is_none() && elf::is_elf(Path::new(&kernel)) => {
+            let raw_path = match running {
+                Some(_) => elf::raw_path(Path::new(&kernel)),
+                None => elf::convert(Path::new(&kernel))?
+            };
+            Some(raw_path.to_string_lossy().into_owned())
+        },
+        kernel => kernel
+    };
+    Ok((Command::Push(Box::new(Options {
+        target,
+        baud_rate: supplied_arguments[1 + device_arguments].parse::<u32>()?,
+        protocol,
+        kernel_path: match &remote {
+            Some(remote) => Some(remote.cache_path().to_path_buf()),
+            None => kernel.map(PathBuf::from)
+        },
+        remote,
+        refetch_each_push,
+        kernel_offset,
+        kernel_length,
+        control_socket,
+        daemon,
+        udev,
+        keepalive,
+        keepalive_byte,
+        idle_warn,
+        idle_warn_always,
+        watch_lines,
+        mirror,
+        boards,
+        reset_gpio,
+        reset_pulse,
+        power_cycle,
+        power_cycle_after,
+        console_server,
+        console_server_writable,
+        status_addr,
+        flush_input,
+        dtb_path,
+        board_kernels,
+        push_on_connect,
+        symbols_path,
+        symbol_patterns,
+        defmt_path,
+        demux,
+        channels,
+        cobs,
+        // --hex-diff alone means a diffed hex dump
+        hex: (hex || (hex_diff && !cobs)).then_some(hex_width),
+        hex_diff,
+        autobaud,
+        ready_probe,
+        ready_timeout,
+        break_reset_timeout,
+        probe_bauds,
+        probe_pattern,
+        local_echo,
+        enter_sends,
+        forward_stdin,
+        char_delay,
+        char_delay_push,
+        pre_send_wait,
+        size_retries: size_retries.unwrap_or(DEFAULT_SIZE_RETRIES),
+        write_timeout,
+        send_file_on_connect,
+        line_delay,
+        line_prompt,
+        event_log_path,
+        log_rotation,
+        dump_kernel_info,
+        no_drain,
+        raw,
+        device_to_stderr,
+        strip_ansi,
+        verbose,
+        trace,
+        status_line,
+        poll_events,
+        skip_unchanged,
+        skip_signal,
+        exit_on,
+        once,
+        wake,
+        apply_hard
+    })), globals))
+}
+
+/// Parse --from and --to, seconds into a capture
+fn parse_capture_time(secs: &str) -> Result<Duration> {
+    match secs.parse::<f64>() {
+        Ok(parsed) if parsed >= 0.0 && parsed.is_finite() => Ok(Duration::from_secs_f64(parsed)),
+        _ => bail!("Invalid time \"{}\", expected seconds into the capture like 12 or 0.5", secs)
+    }
+}
+
+/// Parse the device argument, a tty path or `unix:<socket_path>`.
+/// The baudrate is meaningless for a socket and ignored.
+fn parse_target(device: &str) -> Result<Target> {
+    match device.strip_prefix("unix:") {
+        Some(socket_path) => Ok(Target::UnixSocket(PathBuf::from(socket_path))),
+        None => Ok(Target::Serial(PathBuf::from(device)))
+    }
+}
+
+/// Parse a --device argument, `[name=]<device>`. Unnamed boards are named after the device file.
+fn parse_board(argument: &str) -> Result<(String, Target)> {
+    let (name, device) = match argument.split_once('=') {
+        Some((name, device)) => (name.to_string(), device),
+        None => {
+            let file_name = Path::new(argument).file_name().map(|name| name.to_string_lossy().into_owned());
+            (file_name.unwrap_or_else(|| argument.to_string()), argument)
+        }
+    };
+    Ok((name, parse_target(device)?))
+}
+
+/// Open the supplied device, checking it is a tty. `SerialDevice::probe` checked it exists.
+fn open_tty(device: &Path) -> io::Result<RawFd> {
+    let device_name = CString::new(device.as_os_str().as_bytes())?;
+    // check if device is a tty
+    let fd;
+    unsafe {
+        fd = open(device_name.as_ptr(), O_RDWR | O_NOCTTY | O_NONBLOCK);
+        if -1 == fd {
+            return Err(io::Error::last_os_error());
+        }
+        if 0 == isatty(fd) {
+            libc::close(fd);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Supplied device is not a tty!"));
+        }
+    }
+    Ok(fd)
+}
+
+/// The port being taken already (`err` says how), naming who has it when /proc tells
+fn port_busy(path: &Path, err: &io::Error) -> anyhow::Error {
+    let holders = tty::port_holders(path);
+    let held_by = if holders.is_empty() { String::new() } else { format!(" It's held by {}", holders.join(", ")) };
+    anyhow!("{} is already open ({}), is another pusher, screen, minicom or ModemManager running?{}", path.display(),
+        err, held_by)
+}
+
+/// The arguments of `pusher --runner [options] <kernel> [arguments]` as cargo runs it, with
+/// the options, device and baud rate of the config's `[runner]` table put in front
+fn runner_arguments(mut arguments: Vec<String>) -> Result<Vec<String>> {
+    let config_path = match arguments.iter().position(|argument| argument == "--config") {
+        Some(index) => PathBuf::from(arguments.get(index + 1).ok_or_else(|| anyhow!("--config requires a value"))?),
+        None => {
+            let Some(path) = env::current_dir().ok().and_then(|dir| Config::find(&dir)) else {
+                bail!("--runner needs a pusher.toml with a [runner] table, there's none here or in the parent \
+                    directories");
+            };
+            // the rest of the config is read with the other options
+            arguments.splice(1..1, ["--config".to_string(), path.to_string_lossy().into_owned()]);
+            path
+        }
+    };
+    let Some(runner) = Config::load(Some(&config_path))?.runner else {
+        bail!("--runner needs a [runner] table in {}", config_path.display());
+    };
+    // positional arguments go device, baud rate, kernel, options anywhere
+    let mut added = runner.options;
+    added.extend([runner.device, runner.baud.to_string()]);
+    arguments.splice(1..1, added);
+    Ok(arguments)
+}
+
+/// The board of `--profile` put in front of the arguments, its options, device and baud rate,
+/// returning its kernel to push when the command line gives none. The device is `running`'s,
+/// the session's, when reloading.
+fn profile_arguments(arguments: &mut Vec<String>, runner: bool, running: Option<&Target>) -> Result<Option<PathBuf>> {
+    let Some(index) = arguments.iter().position(|argument| argument == "--profile") else {
+        return Ok(None);
+    };
+    let name = arguments.get(index + 1).ok_or_else(|| anyhow!("--profile requires a value"))?.clone();
+    if runner {
+        bail!("--runner takes its board from the [runner] table, it can't be used with --profile");
+    }
+    let config_path = arguments.iter().position(|argument| argument == "--config")
+        .and_then(|index| arguments.get(index + 1)).map(PathBuf::from);
+    let config = Config::load(config_path.as_deref())?;
+    if config.profiles.is_empty() {
+        bail!("--profile needs [profiles.<name>] tables in the config, there are none");
+    }
+    let (profile, device) = match running {
+        // the session's port stays, `auto` is the profile of its adapter
+        Some(target) => {
+            let name = match (name.as_str(), target) {
+                ("auto", Target::Serial(path)) => {
+                    let serial = autodevice::port_usb_serial(path);
+                    let Some((name, _)) = config.profiles.iter()
+                        .find(|(_, profile)| serial.is_some() && profile.usb_serial == serial)
+                    else {
+                        bail!("No profile has the usb_serial of the adapter on {} anymore", target);
+                    };
+                    name.clone()
+                },
+                _ => name
+            };
+            let Some(profile) = config.profiles.get(&name) else {
+                bail!("Unknown profile \"{}\"", name);
+            };
+            (profile, target.to_string())
+        },
+        None if name == "auto" => {
+            let (name, path) = autodevice::select_profile(&config.profiles)?;
+            status!("[PUSHER] Auto-selected profile {} on {}", name, path.display());
+            (&config.profiles[name], path.to_string_lossy().into_owned())
+        },
+        None => {
+            let Some(profile) = config.profiles.get(&name) else {
+                bail!("Unknown profile \"{}\", available profiles: {}", name,
+                    config.profiles.keys().cloned().collect::<Vec<_>>().join(", "));
+            };
+            let device = autodevice::profile_device(&name, profile)?;
+            if profile.usb_serial.is_some() {
+                status!("[PUSHER] Profile {} on {}", name, device);
+            }
+            (profile, device)
+        }
+    };
+    // positional arguments go device, baud rate, kernel, options anywhere
+    let mut added = profile.options.clone();
+    added.extend([device, profile.baud.to_string()]);
+    arguments.splice(1..1, added);
+    Ok(profile.kernel.clone())
+}
+
+/// Take the value following an option that requires one
+fn option_value(arguments: &mut impl Iterator<Item = String>, option: &str) -> Result<String> {
+    arguments.next().ok_or_else(|| anyhow!("{} requires a value", option))
+}
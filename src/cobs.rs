@@ -0,0 +1,153 @@
+//! Consistent Overhead Byte Stuffing, for packetized device output delimited by zero bytes.
+
+use anyhow::{Result, bail};
+
+use crate::hexview::Differ;
+use crate::status;
+
+/// Longest packet kept while waiting for its delimiter
+const MAX_PACKET: usize = 64 * 1024;
+
+/// Decode one COBS encoded packet, without its zero delimiter
+pub fn decode(encoded: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut index = 0;
+    while index < encoded.len() {
+        let code = encoded[index] as usize;
+        if code == 0 {
+            bail!("Zero byte at offset {} inside the packet", index);
+        }
+        index += 1;
+        if index + code - 1 > encoded.len() {
+            bail!("Block at offset {} claims {} bytes but the packet ends first", index - 1, code - 1);
+        }
+        decoded.extend_from_slice(&encoded[index..index + code - 1]);
+        index += code - 1;
+        // a maximum length block (0xff) isn't followed by an implicit zero, neither is the last one
+        if code != 0xff && index < encoded.len() {
+            decoded.push(0);
+        }
+    }
+    Ok(decoded)
+}
+
+/// Collects device output into packets
+#[derive(Default)]
+pub struct CobsReader {
+    packet: Vec<u8>,
+    differ: Differ,
+}
+
+impl CobsReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A reader highlighting the bytes of each packet that differ from the packet before
+    pub fn diffing() -> Self {
+        Self { packet: Vec::new(), differ: Differ::new(true) }
+    }
+
+    /// Feed device output, returns a line for every packet it completed: the decoded packet as
+    /// hex, or the decode error along with the raw packet
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &byte in bytes {
+            if byte != 0 {
+                if self.packet.len() < MAX_PACKET {
+                    self.packet.push(byte);
+                }
+                continue;
+            }
+            let packet = std::mem::take(&mut self.packet);
+            if packet.is_empty() {
+                continue;
+            }
+            match decode(&packet) {
+                Ok(decoded) => lines.push(format!("[COBS] {}", self.differ.format(&decoded))),
+                Err(err) => lines.push(format!("[PUSHER] Bad COBS packet ({}): {}", err, hex(&packet)))
+            }
+        }
+        lines
+    }
+
+    /// Size of the packet still waiting for its delimiter
+    pub fn pending(&self) -> usize {
+        self.packet.len()
+    }
+}
+
+/// The session is over, however it ended: a packet still waiting for its delimiter is lost
+impl Drop for CobsReader {
+    fn drop(&mut self) {
+        if !self.packet.is_empty() {
+            status!("\r\n[PUSHER] Discarding a partial COBS packet of {} bytes", self.packet.len());
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_runs() {
+        assert_eq!(decode(&[0x01, 0x01]).unwrap(), [0]);
+        assert_eq!(decode(&[0x01, 0x01, 0x01]).unwrap(), [0, 0]);
+        assert_eq!(decode(&[0x03, 0x11, 0x22, 0x02, 0x33]).unwrap(), [0x11, 0x22, 0x00, 0x33]);
+        assert_eq!(decode(&[0x01, 0x02, 0x11, 0x01]).unwrap(), [0x00, 0x11, 0x00]);
+    }
+
+    #[test]
+    fn maximum_blocks() {
+        let data: Vec<u8> = (1..=254).collect();
+        // a trailing 0xff block, no implicit zero after it
+        let mut encoded = vec![0xff];
+        encoded.extend(&data);
+        assert_eq!(decode(&encoded).unwrap(), data);
+
+        // a 0xff block followed by another one isn't followed by a zero either
+        let mut longer = encoded.clone();
+        longer.extend([0x02, 0xaa]);
+        let mut expected = data.clone();
+        expected.push(0xaa);
+        assert_eq!(decode(&longer).unwrap(), expected);
+
+        // a zero right after a maximum block takes a block of its own
+        let mut zero_after = encoded.clone();
+        zero_after.extend([0x01, 0x01]);
+        let mut expected = data;
+        expected.push(0);
+        assert_eq!(decode(&zero_after).unwrap(), expected);
+    }
+
+    #[test]
+    fn code_past_the_end() {
+        let err = decode(&[0x05, 0x11, 0x22]).unwrap_err();
+        assert_eq!(err.to_string(), "Block at offset 0 claims 4 bytes but the packet ends first");
+        let mut short_max = vec![0xff];
+        short_max.extend([0x11; 253]);
+        assert!(decode(&short_max).is_err());
+    }
+
+    #[test]
+    fn zero_inside() {
+        assert!(decode(&[0x02, 0x11, 0x00, 0x01]).is_err());
+    }
+
+    #[test]
+    fn reader_splits_packets() {
+        let mut reader = CobsReader::new();
+        assert!(reader.feed(&[0x03, 0x11]).is_empty());
+        assert_eq!(reader.pending(), 2);
+        let lines = reader.feed(&[0x22, 0x00, 0x00, 0x05, 0x00]);
+        assert_eq!(lines[0], "[COBS] 11 22");
+        assert!(lines[1].starts_with("[PUSHER] Bad COBS packet (Block at offset 0"), "{}", lines[1]);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(reader.pending(), 0);
+    }
+}
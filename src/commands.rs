@@ -0,0 +1,263 @@
+//! Local commands typed after the escape byte (Ctrl-A) in an interactive session.
+//!
+//! - `Ctrl-A m`: read device memory and show a hex dump
+//! - `Ctrl-A w`: write bytes to device memory
+//! - `Ctrl-A e`: toggle local echo
+//! - `Ctrl-A Enter`: switch what Enter sends, CR, LF, CR LF or what the terminal sends
+//! - `Ctrl-A s`: show the session statistics, with how far the push in progress is
+//! - `Ctrl-A c`: cancel the push in progress
+//! - `Ctrl-A f`: push now, even if the image didn't change
+//! - `Ctrl-A P`: push another file now, once, instead of the kernel
+//! - `Ctrl-A b`: reset the board
+//! - `Ctrl-A p`: power cycle the board
+//! - `Ctrl-A d`, `Ctrl-A r`: toggle DTR, RTS (boot strap pins are often wired to one)
+//! - `Ctrl-A D`, `Ctrl-A R`: pulse DTR, RTS for as long as a board reset does
+//! - `Ctrl-A l`: show the modem control lines
+//! - `Ctrl-A t`: show or hide the status line
+//! - `Ctrl-A u`: send a local text file to the console line by line, Esc stops it
+//! - `Ctrl-A x`: exit pusher
+//! - `Ctrl-A Ctrl-A`: send a literal Ctrl-A to the device
+
+use std::fs;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow};
+
+use crate::enter::EnterSends;
+use crate::lines;
+use crate::memory::{self, Request, Response};
+use crate::output;
+use crate::protocol;
+use crate::transport::{ModemLines, Transport};
+use crate::tty::StdinDevice;
+use crate::status;
+
+/// Ctrl-A, starts a local command
+pub const ESCAPE_BYTE: u8 = 0x01;
+/// How long to wait for the loader to answer a memory command
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Settings of the interactive session that local commands can change
+#[derive(Debug, Default)]
+pub struct Session {
+    /// Show typed bytes locally, for devices that don't echo
+    pub local_echo: bool,
+    /// What the Enter key sends
+    pub enter_sends: EnterSends,
+    /// Bytes sent and total of the push in progress, if there's one
+    pub push_progress: Option<(u64, u64)>,
+    /// Bytes of the ready signal seen so far and its length, while connected
+    pub ready_signal: Option<(usize, usize)>,
+    /// Set when the push in progress should be given up
+    pub cancel_push: bool,
+    /// Set when a push should start right away, changed image or not
+    pub force_push: bool,
+    /// Set to a file to push right away instead of the kernel, this once
+    pub push_file: Option<PathBuf>,
+    /// Set when the board should be reset
+    pub reset_board: bool,
+    /// Set when the board should be power cycled
+    pub power_cycle: bool,
+    /// Whether DTR and RTS are asserted, `None` if the link has no modem control lines
+    pub modem_lines: Option<ModemLines>,
+    /// How long Ctrl-A D and Ctrl-A R hold a line
+    pub pulse: Duration,
+    /// Set when the status line should be shown or hidden
+    pub toggle_status_line: bool,
+    /// Set when the session statistics should be shown
+    pub show_stats: bool,
+    /// Set to the file to send to the console
+    pub send_file: Option<PathBuf>,
+    /// Set when the session should end
+    pub quit: bool,
+}
+
+/// Run the local command `command` typed after the escape byte
+pub fn run_escape_command(command: u8, serial_device: &mut dyn Transport, stdin_device: &mut StdinDevice,
+    session: &mut Session) -> Result<()>
+{
+    let result = match command {
+        // the loader is busy taking the payload, it can't answer memory commands
+        b'm' | b'w' if session.push_progress.is_some() => Err(anyhow!("Not while pushing")),
+        b'm' => peek(serial_device, stdin_device),
+        b'w' => poke(serial_device, stdin_device),
+        b's' => {
+            session.show_stats = true;
+            Ok(())
+        },
+        b'c' => match session.push_progress {
+            Some(_) => {
+                session.cancel_push = true;
+                Ok(())
+            },
+            None => Err(anyhow!("No push in progress"))
+        },
+        b'f' => match session.push_progress {
+            Some(_) => Err(anyhow!("Already pushing")),
+            None => {
+                session.force_push = true;
+                Ok(())
+            }
+        },
+        b'P' if session.push_progress.is_some() => Err(anyhow!("Already pushing")),
+        b'P' => match stdin_device.read_path("\r\n[PUSHER] push file: ")?.trim() {
+            "" => Err(anyhow!("No file given")),
+            path => match fs::metadata(path) {
+                Ok(metadata) if metadata.is_file() => {
+                    session.push_file = Some(PathBuf::from(path));
+                    Ok(())
+                },
+                Ok(_) => Err(anyhow!("{} isn't a file", path)),
+                Err(err) => Err(anyhow!("Can't push {}: {}", path, err))
+            }
+        },
+        b'b' => {
+            session.reset_board = true;
+            Ok(())
+        },
+        b'p' => {
+            session.power_cycle = true;
+            Ok(())
+        },
+        b't' => {
+            session.toggle_status_line = true;
+            Ok(())
+        },
+        b'd' | b'r' | b'D' | b'R' => modem_line(command, serial_device, session),
+        b'l' => lines::read(serial_device).map(|lines| {
+            session.modem_lines = Some(lines);
+            status!("\r\n[PUSHER] {}", lines);
+        }),
+        b'u' if session.push_progress.is_some() => Err(anyhow!("Not while pushing")),
+        b'u' => match stdin_device.read_path("\r\n[PUSHER] send file: ")?.trim() {
+            "" => Err(anyhow!("No file given")),
+            path => {
+                session.send_file = Some(PathBuf::from(path));
+                Ok(())
+            }
+        },
+        b'x' => {
+            session.quit = true;
+            Ok(())
+        },
+        b'e' => {
+            session.local_echo = !session.local_echo;
+            status!("\r\n[PUSHER] Local echo {}", if session.local_echo { "on" } else { "off" });
+            Ok(())
+        },
+        // the terminal may hand Enter over as either
+        b'\r' | b'\n' => {
+            session.enter_sends = session.enter_sends.next();
+            status!("\r\n[PUSHER] Enter sends {}", session.enter_sends.name());
+            Ok(())
+        },
+        ESCAPE_BYTE => {
+            protocol::write_byte(serial_device, ESCAPE_BYTE)?;
+            Ok(())
+        },
+        _ => Err(anyhow!("Unknown command Ctrl-A {}", command as char))
+    };
+    // a bad address or a loader without memory commands shouldn't end the session
+    if let Err(err) = result {
+        status!("\r\n[PUSHER] {}", err);
+    }
+    Ok(())
+}
+
+/// Toggle (`d`, `r`) or pulse (`D`, `R`) DTR or RTS and show the lines as they are now
+fn modem_line(command: u8, serial_device: &mut dyn Transport, session: &mut Session) -> Result<()> {
+    let dtr = command.eq_ignore_ascii_case(&b'd');
+    let name = if dtr { "DTR" } else { "RTS" };
+    let lines = serial_device.modem_lines().map_err(|err| anyhow!("Can't change {}: {}", name, err))?;
+    let asserted = if dtr { lines.dtr } else { lines.rts };
+    let mut set = |asserted| if dtr { serial_device.set_dtr(asserted) } else { serial_device.set_rts(asserted) };
+    set(!asserted)?;
+    if command.is_ascii_uppercase() {
+        sleep(session.pulse);
+        set(asserted)?;
+        status!("\r\n[PUSHER] Pulsed {} for {}ms", name, session.pulse.as_millis());
+    }
+    let lines = serial_device.modem_lines()?;
+    session.modem_lines = Some(lines);
+    status!("\r\n[PUSHER] {}", lines);
+    Ok(())
+}
+
+/// Ask for an address and a length, read that memory from the device and hex dump it
+fn peek(serial_device: &mut dyn Transport, stdin_device: &mut StdinDevice) -> Result<()> {
+    let address = parse_number(&stdin_device.read_line("\r\n[PUSHER] read address: ")?)?;
+    let len = parse_number(&stdin_device.read_line("[PUSHER] length: ")?)?;
+    let len = u16::try_from(len).map_err(|_| anyhow!("Can't read more than {} bytes at once", memory::MAX_LEN))?;
+    if let Some(Response::Read(data)) = transact(serial_device, &Request::Read { address, len })? {
+        output::status_text(&hexdump(address, &data));
+    }
+    Ok(())
+}
+
+/// Ask for an address and bytes, and write them to device memory
+fn poke(serial_device: &mut dyn Transport, stdin_device: &mut StdinDevice) -> Result<()> {
+    let address = parse_number(&stdin_device.read_line("\r\n[PUSHER] write address: ")?)?;
+    let data = stdin_device.read_line("[PUSHER] bytes (hex): ")?
+        .split_whitespace()
+        .map(|byte| u8::from_str_radix(byte.trim_start_matches("0x"), 16)
+            .map_err(|_| anyhow!("Invalid byte \"{}\"", byte)))
+        .collect::<Result<Vec<u8>>>()?;
+    let len = data.len();
+    if let Some(Response::WriteAck) = transact(serial_device, &Request::Write { address, data })? {
+        status!("[PUSHER] Wrote {} bytes at {:#x}", len, address);
+    }
+    Ok(())
+}
+
+/// Send `request` and wait for its response.
+/// Console output arriving before the response is shown as usual.
+/// Returns `None` if the loader didn't answer in time.
+fn transact(serial_device: &mut dyn Transport, request: &Request) -> Result<Option<Response>> {
+    protocol::write_bytes(serial_device, &request.encode()?)?;
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut waiter = protocol::ReadableWaiter::new(serial_device)?;
+    loop {
+        // anything before the response opcode is regular device output
+        if let Some(start) = buffer.iter().position(|&byte| byte == request.opcode()) {
+            output::device_bytes(&buffer[..start]);
+            buffer.drain(..start);
+            if let Some((response, _)) = memory::decode_response(request, &buffer)? {
+                return Ok(Some(response));
+            }
+        } else {
+            output::device_bytes(&buffer);
+            buffer.clear();
+        }
+        let now = Instant::now();
+        if now >= deadline || !waiter.wait(Some(deadline - now))? {
+            status!("[PUSHER] No response, does the loader support memory commands?");
+            return Ok(None);
+        }
+        buffer.extend(serial_device.read_all()?);
+    }
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal number
+pub fn parse_number(text: &str) -> Result<u64> {
+    let text = text.trim();
+    let number = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse::<u64>()
+    };
+    number.map_err(|_| anyhow!("Invalid number \"{}\"", text))
+}
+
+/// Format `data`, read from `address`, as a classic 16 bytes per row hex dump
+fn hexdump(address: u64, data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (row, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = chunk.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        dump += &format!("{:016x}  {:<47}  |{}|\r\n", address + row as u64 * 16, hex.join(" "), ascii);
+    }
+    dump
+}
@@ -0,0 +1,291 @@
+//! Tab completion scripts for bash, zsh and fish (`pusher completions <shell>`).
+//!
+//! The options are taken from the usage text, so the scripts keep up with new ones. Values
+//! that depend on the machine, the serial ports and the config's DTB variants, are asked from
+//! pusher itself through the hidden `pusher __complete devices|dtbs|profiles`, which prints one
+//! per line.
+
+use std::fmt::Write;
+use anyhow::{Result, bail};
+
+use crate::config::Config;
+
+/// Subcommands offered as the first word
+const SUBCOMMANDS: [&str; 9] = ["push", "pull", "verify", "replay", "bench", "lines", "capdump", "ctl", "completions"];
+/// Rates offered after the device
+const BAUD_RATES: [&str; 6] = ["9600", "57600", "115200", "230400", "460800", "921600"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => bail!("Unknown shell \"{}\", expected bash, zsh or fish", name)
+        }
+    }
+}
+
+/// Values completed by asking pusher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dynamic {
+    /// Serial ports found on the machine
+    Devices,
+    /// The DTB variants of the config
+    Dtbs,
+    /// The config's profiles, and `auto`
+    Profiles,
+}
+
+impl Dynamic {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "devices" => Ok(Dynamic::Devices),
+            "dtbs" => Ok(Dynamic::Dtbs),
+            "profiles" => Ok(Dynamic::Profiles),
+            _ => bail!("Nothing to complete for \"{}\"", name)
+        }
+    }
+
+    /// The values, one per line. Failing to find them is quietly no values.
+    pub fn values(self, config: &Config) -> Vec<String> {
+        match self {
+            Dynamic::Devices => serialport::available_ports()
+                .map(|ports| ports.into_iter().map(|port| port.port_name).collect())
+                .unwrap_or_default(),
+            Dynamic::Dtbs => config.dtbs.keys().cloned().collect(),
+            Dynamic::Profiles => config.profiles.keys().cloned().chain(["auto".to_string()]).collect(),
+        }
+    }
+}
+
+/// What an option's value is, for completing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    /// A flag without a value
+    None,
+    /// Anything, nothing to offer
+    Text,
+    File,
+    Ask(Dynamic),
+}
+
+/// An option from the usage text
+#[derive(Debug)]
+struct Flag {
+    name: String,
+    value: Value,
+    description: String,
+}
+
+/// The completion script for `shell`, from the `Options:` section of `usage`
+pub fn script(shell: Shell, usage: &str) -> String {
+    let flags = parse_usage(usage);
+    match shell {
+        Shell::Bash => bash(&flags),
+        Shell::Zsh => zsh(&flags),
+        Shell::Fish => fish(&flags),
+    }
+}
+
+/// Options are lines indented by 4 starting with `--` and their value placeholders, followed by
+/// the description, or with the description on the next line.
+fn parse_usage(usage: &str) -> Vec<Flag> {
+    let mut flags: Vec<Flag> = Vec::new();
+    let Some((_, options)) = usage.split_once("Options:") else {
+        return flags;
+    };
+    for line in options.lines() {
+        let trimmed = line.trim();
+        // descriptions on their own line are indented further, and may start with an option too
+        if !trimmed.starts_with("--") || line.len() - line.trim_start().len() > 4 {
+            // the description of the option on the line before
+            if let Some(flag) = flags.last_mut().filter(|flag| flag.description.is_empty()) {
+                flag.description = trimmed.to_string();
+            }
+            continue;
+        }
+        let (name, rest) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+        let (placeholder, description) = split_placeholder(rest.trim_start());
+        let value = match (name, placeholder) {
+            (_, "") => Value::None,
+            ("--dtb", _) => Value::Ask(Dynamic::Dtbs),
+            ("--device", _) => Value::Ask(Dynamic::Devices),
+            ("--profile", _) => Value::Ask(Dynamic::Profiles),
+            (_, placeholder) if ["path", "file", "elf", "journal"].iter().any(|kind| placeholder.contains(kind)) => {
+                Value::File
+            },
+            _ => Value::Text
+        };
+        flags.push(Flag { name: name.to_string(), value, description: description.to_string() });
+    }
+    flags
+}
+
+/// Split what follows an option into its value placeholders (`<...>` and `[...]`) and its description
+fn split_placeholder(text: &str) -> (&str, &str) {
+    let mut rest = text;
+    loop {
+        let close = match rest.chars().next() {
+            Some('<') => rest.find('>'),
+            Some('[') => rest.find(']'),
+            _ => None
+        };
+        match close {
+            // `<n>=<path>` is one value
+            Some(close) => rest = rest[close + 1..].trim_start_matches(|c: char| !c.is_whitespace()).trim_start(),
+            None => break
+        }
+    }
+    (text[..text.len() - rest.len()].trim_end(), rest)
+}
+
+fn bash(flags: &[Flag]) -> String {
+    let names: Vec<&str> = flags.iter().map(|flag| flag.name.as_str()).collect();
+    let with = |value: Value| flags.iter().filter(|flag| flag.value == value).map(|flag| flag.name.as_str())
+        .collect::<Vec<_>>().join("|");
+    format!(r#"# pusher completion for bash, e.g. `pusher completions bash > /etc/bash_completion.d/pusher`
+_pusher() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "$prev" in
+        {devices})
+            COMPREPLY=($(compgen -W "$(pusher __complete devices 2>/dev/null)" -- "$cur"))
+            return;;
+        {dtbs})
+            COMPREPLY=($(compgen -W "$(pusher __complete dtbs 2>/dev/null)" -- "$cur"))
+            return;;
+        {profiles})
+            COMPREPLY=($(compgen -W "$(pusher __complete profiles 2>/dev/null)" -- "$cur"))
+            compopt +o default
+            return;;
+        {files})
+            COMPREPLY=()
+            return;;
+        {text})
+            COMPREPLY=()
+            compopt +o default
+            return;;
+    esac
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "{names}" -- "$cur"))
+        return
+    fi
+    # positional arguments so far, options and their values left out
+    local word positionals=0 i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${{COMP_WORDS[i]}}"
+        case "$word" in
+            {text}|{files}|{devices}|{dtbs}|{profiles}) ((i++));;
+            -*) ;;
+            *) ((positionals++));;
+        esac
+    done
+    case "$positionals" in
+        0) COMPREPLY=($(compgen -W "{subcommands} $(pusher __complete devices 2>/dev/null)" -- "$cur"));;
+        1) COMPREPLY=($(compgen -W "$(pusher __complete devices 2>/dev/null)" -- "$cur"));;
+        2) COMPREPLY=($(compgen -W "{bauds}" -- "$cur"));;
+        *) COMPREPLY=();;
+    esac
+}}
+complete -o default -F _pusher pusher
+"#,
+        devices = with(Value::Ask(Dynamic::Devices)), dtbs = with(Value::Ask(Dynamic::Dtbs)),
+        profiles = with(Value::Ask(Dynamic::Profiles)),
+        files = with(Value::File), text = with(Value::Text), names = names.join(" "),
+        subcommands = SUBCOMMANDS.join(" "), bauds = BAUD_RATES.join(" "))
+}
+
+fn zsh(flags: &[Flag]) -> String {
+    let mut script = String::from("#compdef pusher\n\
+        # pusher completion for zsh, e.g. `pusher completions zsh > \"${fpath[1]}/_pusher\"`\n\n\
+        _pusher_devices() {\n    local -a devices\n    devices=(${(f)\"$(pusher __complete devices 2>/dev/null)\"})\n    \
+        _describe 'device' devices\n}\n\n\
+        _pusher_dtbs() {\n    local -a dtbs\n    dtbs=(${(f)\"$(pusher __complete dtbs 2>/dev/null)\"})\n    \
+        _describe 'DTB variant' dtbs\n    _files\n}\n\n\
+        _pusher_profiles() {\n    local -a profiles\n    \
+        profiles=(${(f)\"$(pusher __complete profiles 2>/dev/null)\"})\n    \
+        _describe 'profile' profiles\n}\n\n\
+        _pusher() {\n    _arguments -s \\\n");
+    for flag in flags {
+        let description = flag.description.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]")
+            .replace(':', "\\:");
+        let action = match flag.value {
+            Value::None => String::new(),
+            Value::Text => ":value: ".to_string(),
+            Value::File => ":file:_files".to_string(),
+            Value::Ask(Dynamic::Devices) => ":device:_pusher_devices".to_string(),
+            Value::Ask(Dynamic::Dtbs) => ":DTB:_pusher_dtbs".to_string(),
+            Value::Ask(Dynamic::Profiles) => ":profile:_pusher_profiles".to_string(),
+        };
+        let _ = writeln!(script, "        '*{}[{}]{}' \\", flag.name, description, action);
+    }
+    let _ = write!(script, "        '1:subcommand or device:({} $(pusher __complete devices 2>/dev/null))' \\\n        \
+        '2:device or baud rate:_pusher_devices' \\\n        '3:baud rate:({})' \\\n        '*:file:_files'\n}}\n\n\
+        _pusher \"$@\"\n", SUBCOMMANDS.join(" "), BAUD_RATES.join(" "));
+    script
+}
+
+fn fish(flags: &[Flag]) -> String {
+    let mut script = String::from("# pusher completion for fish, e.g. \
+        `pusher completions fish > ~/.config/fish/completions/pusher.fish`\n");
+    let _ = writeln!(script, "complete -c pusher -n __fish_use_subcommand -f -a '{}'", SUBCOMMANDS.join(" "));
+    let _ = writeln!(script, "complete -c pusher -f -a '(pusher __complete devices 2>/dev/null)'");
+    for flag in flags {
+        let description = flag.description.replace('\'', "\\'");
+        let value = match flag.value {
+            Value::None => String::new(),
+            Value::Text => " -x".to_string(),
+            Value::File => " -r -F".to_string(),
+            Value::Ask(Dynamic::Devices) => " -x -a '(pusher __complete devices 2>/dev/null)'".to_string(),
+            Value::Ask(Dynamic::Dtbs) => " -r -F -a '(pusher __complete dtbs 2>/dev/null)'".to_string(),
+            Value::Ask(Dynamic::Profiles) => " -x -a '(pusher __complete profiles 2>/dev/null)'".to_string(),
+        };
+        let _ = writeln!(script, "complete -c pusher -l {}{} -d '{}'", flag.name.trim_start_matches("--"), value,
+            description);
+    }
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USAGE: &str = "Usage: pusher [options] <device> <baud>
+
+Options:
+    --verbose           say more
+    --kernel <path>     the kernel
+    --profile <name|auto> push to a board of the config's [profiles]
+    --dtb <name|path>
+        a DTB variant of the config, or a file
+    --retries <n>       size header retries
+";
+
+    #[test]
+    fn values_from_usage() {
+        let flags = parse_usage(USAGE);
+        let values: Vec<(&str, Value)> = flags.iter().map(|flag| (flag.name.as_str(), flag.value)).collect();
+        assert_eq!(values, [("--verbose", Value::None), ("--kernel", Value::File),
+            ("--profile", Value::Ask(Dynamic::Profiles)), ("--dtb", Value::Ask(Dynamic::Dtbs)),
+            ("--retries", Value::Text)]);
+        assert_eq!(flags[3].description, "a DTB variant of the config, or a file");
+        assert_eq!(flags[2].description, "push to a board of the config's [profiles]");
+    }
+
+    #[test]
+    fn profiles() {
+        let config: Config = toml::from_str("[profiles.rack3]\n[profiles.bench]\n").unwrap();
+        assert_eq!(Dynamic::parse("profiles").unwrap().values(&config), ["bench", "rack3", "auto"]);
+        assert_eq!(Dynamic::Profiles.values(&Config::default()), ["auto"]);
+        let bash = script(Shell::Bash, USAGE);
+        assert!(bash.contains("--profile)\n            COMPREPLY=($(compgen -W \"$(pusher __complete profiles"));
+        assert!(script(Shell::Fish, USAGE).contains("-l profile -x -a '(pusher __complete profiles 2>/dev/null)'"));
+    }
+}
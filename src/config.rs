@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+use crate::status;
+
+/// Config file looked up in the current directory when `--config` isn't given
+pub const DEFAULT_CONFIG_PATH: &str = "pusher.toml";
+/// Baud rate of `[runner]` when it doesn't say
+const DEFAULT_RUNNER_BAUD: u32 = 115200;
+
+/// Settings read from `pusher.toml`
+///
+/// # Example:
+/// ```toml
+/// unknown_board = "refuse"
+///
+/// [boards]
+/// rpi3b = "kernel8-rpi3.img"
+/// rpi4 = "kernel8-rpi4.img"
+///
+/// [dtbs]
+/// hdmi = "dtbs/bcm2710-rpi-3-b-hdmi.dtb"
+/// uart = "dtbs/bcm2710-rpi-3-b-uart.dtb"
+///
+/// [runner]
+/// device = "/dev/ttyUSB0"
+/// baud = 115200
+/// options = ["--checksum", "crc32", "--exit-on", "tests passed"]
+///
+/// [profiles.rack3]
+/// usb_serial = "A7004abc"
+/// kernel = "build/rack3/kernel8.img"
+/// options = ["--reset-gpio", "gpiochip0:17"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Named device tree blobs that can be selected with `--dtb <name>`
+    #[serde(default)]
+    pub dtbs: BTreeMap<String, PathBuf>,
+    /// What `--runner` pushes to, since cargo only gives it the kernel
+    pub runner: Option<RunnerConfig>,
+    /// Boards selected with `--profile <name>`, or `--profile auto` by their adapter's serial
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Kernels picked per push by what the loader says it is (`--identify`) or by the USB serial
+    /// of the adapter, see `push::BoardKernels`
+    #[serde(default)]
+    pub boards: BTreeMap<String, PathBuf>,
+    /// What a push to a board that isn't in `boards` does
+    #[serde(default)]
+    pub unknown_board: UnknownBoard,
+}
+
+/// `unknown_board`: push the kernel given on the command line (or by the profile), or nothing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownBoard {
+    #[default]
+    Default,
+    Refuse,
+}
+
+/// The `[runner]` table
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunnerConfig {
+    /// Device to push to, anything the device argument takes
+    pub device: String,
+    #[serde(default = "default_runner_baud")]
+    pub baud: u32,
+    /// Options added before those on the command line
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// A `[profiles.<name>]` table, a board: how to reach it and what to push to it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// The USB serial number (iSerial) of the board's adapter, its port is looked up by it
+    pub usb_serial: Option<String>,
+    /// The device, for boards not found by `usb_serial`
+    pub device: Option<String>,
+    #[serde(default = "default_runner_baud")]
+    pub baud: u32,
+    /// Pushed when the command line gives no kernel
+    pub kernel: Option<PathBuf>,
+    /// Options added before those on the command line, like how the board is reset
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+fn default_runner_baud() -> u32 {
+    DEFAULT_RUNNER_BAUD
+}
+
+impl Config {
+    /// Load the config from `path`, or from `pusher.toml` if it exists.
+    /// A missing default config is not an error, an explicitly supplied one is.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(Self::default())
+        };
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read config {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// The `pusher.toml` closest to `dir`: in it or the nearest of its parents that has one,
+    /// e.g. the workspace root when cargo runs pusher from a member crate
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors().map(|dir| dir.join(DEFAULT_CONFIG_PATH)).find(|path| path.is_file())
+    }
+
+    /// Resolve a `--dtb` argument: a configured variant name first, then a path on disk.
+    pub fn resolve_dtb(&self, name_or_path: &str) -> Result<PathBuf> {
+        if let Some(path) = self.dtbs.get(name_or_path) {
+            if !path.exists() {
+                bail!("DTB \"{}\" points to {}, which doesn't exist", name_or_path, path.display());
+            }
+            return Ok(path.clone());
+        }
+        if Path::new(name_or_path).is_file() {
+            return Ok(PathBuf::from(name_or_path));
+        }
+        if self.dtbs.is_empty() {
+            bail!("Unknown DTB \"{}\" and no variants are configured", name_or_path);
+        }
+        bail!("Unknown DTB \"{}\", available variants: {}", name_or_path,
+            self.dtbs.keys().cloned().collect::<Vec<_>>().join(", "))
+    }
+
+    /// The `[boards]` kernels, failing if one of them doesn't exist
+    pub fn board_kernels(&self) -> Result<&BTreeMap<String, PathBuf>> {
+        if let Some((board, path)) = self.boards.iter().find(|(_, path)| !path.exists()) {
+            bail!("Board \"{}\" points to {}, which doesn't exist", board, path.display());
+        }
+        Ok(&self.boards)
+    }
+
+    /// Print the configured DTB variants, one per line
+    pub fn list_dtbs(&self) {
+        if self.dtbs.is_empty() {
+            status!("[PUSHER] No DTB variants configured");
+        }
+        for (name, path) in &self.dtbs {
+            status!("{}\t{}", name, path.display());
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Console sharing over TCP, so more than one person can watch the same board.
+//!
+//! Every client gets the device output, starting with the last `REPLAY_LIMIT` bytes so it
+//! doesn't join blind. When the server is writable, what clients send goes to the device as
+//! well, mixed with the local keyboard. A client that can't keep up is disconnected instead of
+//! holding up the serial loop.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::prelude::AsRawFd;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+
+use crate::tokens::{Source, Tokens};
+use crate::status;
+
+/// Recent output sent to clients as they connect
+pub const REPLAY_LIMIT: usize = 16 * 1024;
+/// Output queued for a client before it's considered too slow
+const QUEUE_LIMIT: usize = 256 * 1024;
+
+/// A connected client and the output it hasn't taken yet
+struct Client {
+    stream: TcpStream,
+    address: SocketAddr,
+    queued: VecDeque<u8>,
+}
+
+/// The listening socket and its clients
+pub struct ConsoleServer {
+    listener: TcpListener,
+    clients: HashMap<Token, Client>,
+    /// Forward what clients send to the device
+    writable: bool,
+    replay: VecDeque<u8>,
+}
+
+impl ConsoleServer {
+    /// Listen on `address` (`host:port`). Clients get their tokens as they connect.
+    pub fn bind(address: &str, writable: bool) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            clients: HashMap::new(),
+            writable,
+            replay: VecDeque::new(),
+        })
+    }
+
+    /// Register the listening socket
+    pub fn register(&self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.register(&mut SourceFd(&self.listener.as_raw_fd()), token, Interest::READABLE)
+    }
+
+    /// Take every waiting client, sending each the replay buffer
+    pub fn accept(&mut self, registry: &Registry, tokens: &mut Tokens) -> io::Result<()> {
+        loop {
+            let (stream, address) = match self.listener.accept() {
+                Ok(client) => client,
+                // edge triggered: keep accepting until there's nobody left
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err)
+            };
+            stream.set_nonblocking(true)?;
+            let token = tokens.allocate(Source::ConsoleClient);
+            registry.register(&mut SourceFd(&stream.as_raw_fd()), token, Interest::READABLE | Interest::WRITABLE)?;
+            status!("\r\n[PUSHER] Console client {} connected", address);
+            let mut client = Client { stream, address, queued: self.replay.clone() };
+            let flushed = client.flush();
+            self.clients.insert(token, client);
+            if flushed.is_err() {
+                self.disconnect(registry, tokens, token)?;
+            }
+        }
+    }
+
+    /// Send device output to every client, dropping those that fell too far behind
+    pub fn broadcast(&mut self, registry: &Registry, tokens: &mut Tokens, bytes: &[u8]) -> io::Result<()> {
+        self.replay.extend(bytes);
+        let excess = self.replay.len().saturating_sub(REPLAY_LIMIT);
+        self.replay.drain(..excess);
+
+        let mut gone = Vec::new();
+        for (&token, client) in &mut self.clients {
+            client.queued.extend(bytes);
+            if client.queued.len() > QUEUE_LIMIT {
+                status!("\r\n[PUSHER] Console client {} can't keep up, disconnecting it", client.address);
+                // best effort, its buffer is likely full anyway
+                let _ = client.stream.write(b"\r\n[PUSHER] Too slow, disconnected\r\n");
+                gone.push(token);
+            } else if client.flush().is_err() {
+                gone.push(token);
+            }
+        }
+        for token in gone {
+            self.disconnect(registry, tokens, token)?;
+        }
+        Ok(())
+    }
+
+    /// Handle readiness of the client with `token`: send what's queued and read what it sent.
+    /// Returns the bytes to forward to the device, always empty when the server isn't writable.
+    pub fn on_client_event(&mut self, registry: &Registry, tokens: &mut Tokens, token: Token) -> io::Result<Vec<u8>> {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return Ok(Vec::new());
+        };
+        let mut input = Vec::new();
+        let mut chunk = [0; 1024];
+        let mut closed = client.flush().is_err();
+        while !closed {
+            match client.stream.read(&mut chunk) {
+                Ok(0) => closed = true,
+                Ok(read) => input.extend_from_slice(&chunk[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => closed = true
+            }
+        }
+        if closed {
+            status!("\r\n[PUSHER] Console client {} disconnected", client.address);
+            self.disconnect(registry, tokens, token)?;
+        }
+        if !self.writable {
+            input.clear();
+        }
+        Ok(input)
+    }
+
+    fn disconnect(&mut self, registry: &Registry, tokens: &mut Tokens, token: Token) -> io::Result<()> {
+        if let Some(client) = self.clients.remove(&token) {
+            registry.deregister(&mut SourceFd(&client.stream.as_raw_fd()))?;
+            tokens.release(token);
+        }
+        Ok(())
+    }
+}
+
+impl Client {
+    /// Write as much of the queue as the socket takes without blocking
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.queued.is_empty() {
+            let (front, _) = self.queued.as_slices();
+            match self.stream.write(front) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.queued.drain(..written);
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(())
+    }
+}
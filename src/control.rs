@@ -0,0 +1,153 @@
+//! Control socket, letting scripts drive a running pusher instead of the keyboard.
+//!
+//! Clients connect to a Unix domain socket and send one JSON request per line, e.g.
+//! `{"command": "set-kernel", "path": "build/kernel8.img"}`, each answered by one JSON line:
+//! `{"ok": true, ...}` or `{"ok": false, "error": "..."}`. Clients are served one at a time.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::prelude::AsRawFd;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result, anyhow};
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// What a client can ask for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// Whether pusher is waiting or pushing, and how far
+    Status,
+    /// Push right away
+    Push,
+    /// Push another kernel from now on
+    SetKernel { path: PathBuf },
+    /// Reset the board through the link's reset line
+    ResetBoard,
+    /// End the session
+    Quit,
+}
+
+/// The listening socket and the client being served
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    client: Option<UnixStream>,
+    /// Start of a request line not complete yet
+    pending: Vec<u8>,
+    client_token: Token,
+}
+
+impl ControlServer {
+    /// Listen on `path`, replacing a socket left behind by an earlier session
+    pub fn bind(path: &Path, client_token: Token) -> io::Result<Self> {
+        if fs::symlink_metadata(path).is_ok() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, path: path.to_path_buf(), client: None, pending: Vec::new(), client_token })
+    }
+
+    /// Register the listening socket, clients get registered with the token given to `bind`
+    pub fn register(&self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.register(&mut SourceFd(&self.listener.as_raw_fd()), token, Interest::READABLE)
+    }
+
+    /// Take the next waiting client, unless one is being served
+    pub fn accept(&mut self, registry: &Registry) -> io::Result<()> {
+        if self.client.is_some() {
+            return Ok(());
+        }
+        match self.listener.accept() {
+            Ok((client, _)) => {
+                client.set_nonblocking(true)?;
+                registry.register(&mut SourceFd(&client.as_raw_fd()), self.client_token, Interest::READABLE)?;
+                self.client = Some(client);
+                Ok(())
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Read what the client sent, returning the complete request lines.
+    /// When the client is gone, the next waiting one is accepted.
+    pub fn read_requests(&mut self, registry: &Registry) -> io::Result<Vec<Result<Request, String>>> {
+        let Some(client) = &mut self.client else {
+            return Ok(Vec::new());
+        };
+        let mut chunk = [0; 1024];
+        let mut closed = false;
+        loop {
+            match client.read(&mut chunk) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                },
+                Ok(read) => self.pending.extend_from_slice(&chunk[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+        let mut requests = Vec::new();
+        while let Some(end) = self.pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                requests.push(serde_json::from_str(line.trim()).map_err(|err| format!("Invalid request: {}", err)));
+            }
+        }
+        if closed {
+            self.disconnect(registry)?;
+        }
+        Ok(requests)
+    }
+
+    /// Answer the client's request
+    pub fn respond(&mut self, response: &Value) -> io::Result<()> {
+        if let Some(client) = &mut self.client {
+            // the client may have left already, that's its business
+            let _ = client.set_nonblocking(false)
+                .and_then(|_| writeln!(client, "{}", response))
+                .and_then(|_| client.set_nonblocking(true));
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self, registry: &Registry) -> io::Result<()> {
+        if let Some(client) = self.client.take() {
+            registry.deregister(&mut SourceFd(&client.as_raw_fd()))?;
+        }
+        self.pending.clear();
+        // edge triggered: clients that connected meanwhile won't be signaled again
+        self.accept(registry)
+    }
+}
+
+impl Drop for ControlServer {
+    /// Don't leave the socket file behind
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Send one request to the pusher listening on `socket_path` and return its answer
+pub fn send_request(socket_path: &Path, request: &Request) -> Result<Value> {
+    let mut stream = UnixStream::connect(socket_path)
+        .with_context(|| format!("Couldn't connect to {}", socket_path.display()))?;
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    if response.is_empty() {
+        return Err(anyhow!("pusher closed the connection without answering"));
+    }
+    Ok(serde_json::from_str(&response)?)
+}
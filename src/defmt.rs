@@ -0,0 +1,167 @@
+//! Decoding of defmt log frames sent by Rust firmware, with `defmt_decoder`.
+//!
+//! defmt frames are rzCOBS encoded and terminated by a zero byte, plain text output never
+//! contains zeros. Device output is cut at zero bytes: a chunk that decodes to a frame of the
+//! firmware's table is a log frame, anything else is shown as plain text. A corrupt frame is
+//! shown as it came, decoding continues with the next one.
+
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, bail};
+use defmt_decoder::{Encoding, Table};
+
+/// Longest chunk kept while waiting for a frame delimiter
+const MAX_FRAME: usize = 16 * 1024;
+
+/// Splits device output into defmt frames and plain text
+pub struct Decoder {
+    /// Format strings of the firmware, by index
+    table: Table,
+    /// Bytes since the last delimiter
+    chunk: Vec<u8>,
+}
+
+impl Decoder {
+    /// Decode the frames logged by the firmware ELF at `elf_path`
+    pub fn load(elf_path: &Path) -> Result<Self> {
+        let Some(table) = Table::parse(&fs::read(elf_path)?)? else {
+            bail!("{} has no .defmt section", elf_path.display());
+        };
+        // raw frames have no delimiter to tell them from plain text
+        if table.encoding() != Encoding::Rzcobs {
+            bail!("{} logs raw defmt frames, only rzcobs encoded ones can be decoded", elf_path.display());
+        }
+        Ok(Self { table, chunk: Vec::new() })
+    }
+
+    /// Feed device output, returns what should be displayed
+    pub fn feed(&mut self, bytes: &[u8]) -> String {
+        let mut output = String::new();
+        for &byte in bytes {
+            if byte != 0 {
+                if self.chunk.len() < MAX_FRAME {
+                    self.chunk.push(byte);
+                }
+                continue;
+            }
+            let chunk = std::mem::take(&mut self.chunk);
+            match self.format_frame(&chunk) {
+                Some(line) => output += &format!("{}\r\n", line),
+                // not a frame we know, it was plain text (or a corrupt frame)
+                None => output += &String::from_utf8_lossy(&chunk)
+            }
+        }
+        // plain text without a delimiter yet is shown right away unless it may be a frame
+        if !self.chunk.is_empty() && self.chunk.iter().all(|&byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace())
+            && self.chunk.ends_with(b"\n")
+        {
+            output += &String::from_utf8_lossy(&std::mem::take(&mut self.chunk));
+        }
+        output
+    }
+
+    /// Format the frame `chunk` is, without its delimiter, `None` if it isn't one of the table
+    fn format_frame(&self, chunk: &[u8]) -> Option<String> {
+        let mut stream = self.table.new_stream_decoder();
+        stream.received(chunk);
+        stream.received(&[0]);
+        let frame = stream.decode().ok()?;
+        Some(frame.display(true).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::ansi::AnsiStripper;
+
+    /// rzCOBS encoding as the firmware does it: a decoded frame gets zeros appended up to the end
+    /// of its last group, the defmt frame format doesn't mind
+    fn rzcobs_encode(frame: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let (mut run, mut zeros) = (0, 0u8);
+        for &byte in frame {
+            if run < 7 {
+                if byte == 0 {
+                    zeros |= 1 << run;
+                } else {
+                    encoded.push(byte);
+                }
+                run += 1;
+                if run == 7 && zeros != 0 {
+                    encoded.push(zeros);
+                    (run, zeros) = (0, 0);
+                }
+            } else if byte == 0 {
+                encoded.push((run - 7) as u8 | 0x80);
+                (run, zeros) = (0, 0);
+            } else {
+                encoded.push(byte);
+                run += 1;
+                if run == 134 {
+                    encoded.push(0xff);
+                    (run, zeros) = (0, 0);
+                }
+            }
+        }
+        match run {
+            0 => {},
+            1..=6 => encoded.push((zeros | (0xff << run)) & 0x7f),
+            _ => encoded.push((run - 7) as u8 | 0x80)
+        }
+        encoded
+    }
+
+    /// `tests/fixtures/defmt.elf` holds just the `.defmt` symbols of a firmware logging
+    /// `info!("x={=u8}")` (index 1), `println!("{=str}!")` (2) and
+    /// `warn!("{} items left, {=usize} bytes")` (4), a bare `{}` of a `u32` is `{=u32}` (3)
+    fn decoder() -> Decoder {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/defmt.elf");
+        Decoder::load(&path).unwrap()
+    }
+
+    /// What `output` shows as, without the level's colors
+    fn shown(decoder: &mut Decoder, output: &[u8]) -> String {
+        let shown = decoder.feed(output);
+        String::from_utf8(AnsiStripper::new().strip(shown.as_bytes())).unwrap()
+    }
+
+    /// `decoded` rzCOBS encoded with its delimiter
+    fn frame(decoded: &[u8]) -> Vec<u8> {
+        [&rzcobs_encode(decoded)[..], &[0]].concat()
+    }
+
+    #[test]
+    fn frames_and_text() {
+        let mut decoder = decoder();
+        let mut output = frame(&[1, 0, 42]);
+        output.extend(b"plain\r\n");
+        assert_eq!(shown(&mut decoder, &output), "INFO x=42\r\nplain\r\n");
+        // a str's length is 4 bytes, so is a usize
+        assert_eq!(shown(&mut decoder, &frame(&[2, 0, 2, 0, 0, 0, b'h', b'i'])), "hi!\r\n");
+        // a bare {} sends the index of the value's format string first
+        let output = frame(&[4, 0, 3, 0, 7, 0, 0, 0, 0x2c, 0x01, 0, 0]);
+        assert_eq!(shown(&mut decoder, &output), "WARN 7 items left, 300 bytes\r\n");
+    }
+
+    #[test]
+    fn corrupt_frame_resyncs() {
+        let mut decoder = decoder();
+        let frame = frame(&[1, 0, 7]);
+        // a frame cut short by lost bytes is shown as it came, the next delimiter starts afresh
+        let mut output = shown(&mut decoder, &frame[1..]);
+        assert!(!output.contains("x=7"));
+        output = shown(&mut decoder, &frame);
+        assert_eq!(output, "INFO x=7\r\n");
+        // so does a frame with an unknown index
+        assert!(!shown(&mut decoder, &[&rzcobs_encode(&[9, 0, 7])[..], &[0]].concat()).contains("x="));
+        assert_eq!(shown(&mut decoder, &frame), "INFO x=7\r\n");
+    }
+
+    #[test]
+    fn not_defmt() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/kernel.bin");
+        assert!(Decoder::load(&path).is_err());
+    }
+}
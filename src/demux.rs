@@ -0,0 +1,99 @@
+//! Demultiplexing of logical channels sent over the one link.
+//!
+//! A frame is a channel byte, a length byte and `length` bytes of payload. Channel 0 is the
+//! console, other channels are written to their configured file or FIFO. Channel numbers are
+//! control characters, so plain text (e.g. a loader printing before the kernel starts framing its
+//! output) is recognized as unframed and passed to the console untouched.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use anyhow::{Result, Context, bail};
+
+use crate::status;
+
+/// The channel shown on the console
+pub const CONSOLE_CHANNEL: u8 = 0;
+
+/// Parse a `--channel` argument, `<number>=<path>`
+pub fn parse_channel(argument: &str) -> Result<(u8, PathBuf)> {
+    let Some((channel, path)) = argument.split_once('=') else {
+        bail!("Channels are given as <number>=<path>, got \"{}\"", argument);
+    };
+    let channel: u8 = channel.parse().with_context(|| format!("Invalid channel number \"{}\"", channel))?;
+    if channel == CONSOLE_CHANNEL {
+        bail!("Channel 0 is the console");
+    }
+    // these show up in plain text, they can't start a frame
+    if channel >= 0x20 || [b'\t', b'\n', b'\r'].contains(&channel) {
+        bail!("Channel {} can't be told apart from text, use 1-8, 11, 12 or 14-31", channel);
+    }
+    Ok((channel, PathBuf::from(path)))
+}
+
+enum State {
+    /// Between frames
+    Idle,
+    /// Got the channel byte of a frame
+    Length(u8),
+    /// Inside the payload of a frame
+    Payload { channel: u8, remaining: u8 },
+}
+
+/// Splits device output into the console stream and the other channels
+pub struct Demux {
+    channels: HashMap<u8, File>,
+    state: State,
+}
+
+impl Demux {
+    /// Open the sink of every channel. Opening a FIFO waits for its reader.
+    pub fn open(channels: &[(u8, PathBuf)]) -> Result<Self> {
+        let mut files = HashMap::new();
+        for (channel, path) in channels {
+            let file = OpenOptions::new().create(true).append(true).open(path)
+                .with_context(|| format!("Couldn't open {} for channel {}", path.display(), channel))?;
+            files.insert(*channel, file);
+        }
+        Ok(Self { channels: files, state: State::Idle })
+    }
+
+    /// Feed device output, returns the bytes meant for the console
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut console = Vec::new();
+        for &byte in bytes {
+            self.state = match self.state {
+                State::Idle if byte == CONSOLE_CHANNEL || self.channels.contains_key(&byte) => State::Length(byte),
+                State::Idle => {
+                    console.push(byte);
+                    State::Idle
+                },
+                State::Length(_) if byte == 0 => State::Idle,
+                State::Length(channel) => State::Payload { channel, remaining: byte },
+                State::Payload { channel, remaining } => {
+                    if channel == CONSOLE_CHANNEL {
+                        console.push(byte);
+                    } else {
+                        self.write_channel(channel, byte);
+                    }
+                    match remaining - 1 {
+                        0 => State::Idle,
+                        remaining => State::Payload { channel, remaining }
+                    }
+                }
+            };
+        }
+        console
+    }
+
+    /// Write to a channel's sink, a failing sink (e.g. a FIFO without reader) is closed
+    fn write_channel(&mut self, channel: u8, byte: u8) {
+        if let Some(file) = self.channels.get_mut(&channel) {
+            if let Err(err) = file.write_all(&[byte]) {
+                status!("\r\n[PUSHER] Closing channel {}: {}", channel, err);
+                self.channels.remove(&channel);
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Turning an ELF kernel into the raw image a loader expects, like `objcopy -O binary`: the
+//! allocated sections with contents laid out at their load (physical) addresses, gaps filled
+//! with zeros.
+//! Used by `--runner`, since cargo hands over the ELF it built.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow, bail};
+use object::elf::{FileHeader32, FileHeader64, PT_LOAD, SHF_ALLOC, SHT_NOBITS};
+use object::read::elf::{FileHeader, ProgramHeader, SectionHeader};
+use object::{Endianness, FileKind};
+
+use crate::status;
+
+/// Widest span of load addresses accepted, larger ones are most likely segments meant for
+/// different memories (e.g. flash and RAM) that a flat image can't describe
+const MAX_SPAN: u64 = 256 << 20;
+
+/// Whether the file at `path` starts like an ELF file
+pub fn is_elf(path: &Path) -> bool {
+    let mut magic = [0; 4];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && &magic == b"\x7fELF"
+}
+
+/// The raw image of an ELF file and where it's loaded
+#[derive(Debug)]
+pub struct RawImage {
+    /// Physical address of the first byte
+    pub load_address: u64,
+    pub data: Vec<u8>,
+}
+
+/// Lay the allocated sections of the ELF file `data` out as a raw image
+pub fn to_raw(data: &[u8]) -> Result<RawImage> {
+    let sections = match FileKind::parse(data)? {
+        FileKind::Elf32 => loaded_sections::<FileHeader32<Endianness>>(data)?,
+        FileKind::Elf64 => loaded_sections::<FileHeader64<Endianness>>(data)?,
+        _ => bail!("Not an ELF file")
+    };
+    let Some(start) = sections.iter().map(|&(address, _)| address).min() else {
+        bail!("The ELF file has nothing to load");
+    };
+    let end = sections.iter().map(|&(address, section)| address + section.len() as u64).max().unwrap_or(start);
+    if end - start > MAX_SPAN {
+        bail!("The ELF file's sections span {:#x} to {:#x}, too far apart for a raw image", start, end);
+    }
+    let mut image = vec![0; (end - start) as usize];
+    for (address, section) in sections {
+        let offset = (address - start) as usize;
+        image[offset..offset + section.len()].copy_from_slice(section);
+    }
+    Ok(RawImage { load_address: start, data: image })
+}
+
+/// The load address and contents of the allocated sections that have contents. A section's
+/// load address is its address moved like the segment holding it, e.g. `.data` kept in flash
+/// and copied to RAM by the startup code.
+fn loaded_sections<Elf: FileHeader<Endian = Endianness>>(data: &[u8]) -> Result<Vec<(u64, &[u8])>> {
+    let header = Elf::parse(data)?;
+    let endian = header.endian()?;
+    let segments: Vec<&Elf::ProgramHeader> = header.program_headers(endian, data)?.iter()
+        .filter(|segment| segment.p_type(endian) == PT_LOAD)
+        .collect();
+    let mut sections = Vec::new();
+    for section in header.section_headers(endian, data)? {
+        // .bss takes memory but has nothing to send, the kernel clears it itself
+        if section.sh_flags(endian).into() & u64::from(SHF_ALLOC) == 0 || section.sh_type(endian) == SHT_NOBITS
+            || section.sh_size(endian).into() == 0
+        {
+            continue;
+        }
+        let contents = section.data(endian, data)?;
+        let address: u64 = section.sh_addr(endian).into();
+        let offset: u64 = section.sh_offset(endian).into();
+        let load_address = segments.iter()
+            .find(|segment| (segment.p_offset(endian).into()..segment.p_offset(endian).into()
+                + segment.p_filesz(endian).into()).contains(&offset))
+            .map_or(address, |segment| {
+                address.wrapping_sub(segment.p_vaddr(endian).into()).wrapping_add(segment.p_paddr(endian).into())
+            });
+        sections.push((load_address, contents));
+    }
+    Ok(sections)
+}
+
+/// Where `convert` writes the raw image of the ELF file at `path`: next to it, as `<path>.bin`
+pub fn raw_path(path: &Path) -> PathBuf {
+    let mut raw_path = path.as_os_str().to_owned();
+    raw_path.push(".bin");
+    PathBuf::from(raw_path)
+}
+
+/// Convert the ELF file at `path` to a raw image written next to it, see `raw_path`.
+/// Returns the path of the image.
+pub fn convert(path: &Path) -> Result<PathBuf> {
+    let raw = to_raw(&fs::read(path)?).map_err(|err| anyhow!("Couldn't convert {}: {}", path.display(), err))?;
+    let raw_path = raw_path(path);
+    fs::write(&raw_path, &raw.data).map_err(|err| anyhow!("Couldn't write {}: {}", raw_path.display(), err))?;
+    status!("[PUSHER] Converted the ELF {} to a {} bytes raw image loaded at {:#x}", path.display(),
+        raw.data.len(), raw.load_address);
+    Ok(raw_path)
+}
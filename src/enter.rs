@@ -0,0 +1,92 @@
+//! What the Enter key sends to the device (--enter-sends, Ctrl-A Enter): loaders' command
+//! parsers often want a lone CR where a kernel's shell wants LF, and the terminal hands over
+//! whichever it's set up for.
+//!
+//! Only line endings are touched. A CR or an LF is an Enter, and so is a CR LF pair (pasted
+//! text from some terminals), which then sends a single line ending.
+
+use anyhow::{Result, bail};
+
+/// The line ending an Enter is sent as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnterSends {
+    Cr,
+    Lf,
+    CrLf,
+    /// Whatever the terminal delivered
+    #[default]
+    Raw,
+}
+
+impl EnterSends {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "cr" => Ok(EnterSends::Cr),
+            "lf" => Ok(EnterSends::Lf),
+            "crlf" => Ok(EnterSends::CrLf),
+            "raw" => Ok(EnterSends::Raw),
+            _ => bail!("Unknown Enter translation \"{}\", expected cr, lf, crlf or raw", name)
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EnterSends::Cr => "CR",
+            EnterSends::Lf => "LF",
+            EnterSends::CrLf => "CR LF",
+            EnterSends::Raw => "what the terminal sends",
+        }
+    }
+
+    /// The mode after this one, for Ctrl-A Enter
+    pub fn next(self) -> Self {
+        match self {
+            EnterSends::Cr => EnterSends::Lf,
+            EnterSends::Lf => EnterSends::CrLf,
+            EnterSends::CrLf => EnterSends::Raw,
+            EnterSends::Raw => EnterSends::Cr,
+        }
+    }
+
+    fn line_ending(self) -> Option<&'static [u8]> {
+        match self {
+            EnterSends::Cr => Some(b"\r"),
+            EnterSends::Lf => Some(b"\n"),
+            EnterSends::CrLf => Some(b"\r\n"),
+            EnterSends::Raw => None,
+        }
+    }
+}
+
+/// Translates the Enters in a stream of typed (or piped) bytes
+#[derive(Debug, Default)]
+pub struct EnterTranslator {
+    /// The last byte was a CR, an LF right after it belongs to the same Enter
+    after_cr: bool,
+}
+
+impl EnterTranslator {
+    /// What to send for `byte` with `mode`: `None` to send it as is, the bytes to send instead
+    /// otherwise (none for the LF of a CR LF pair)
+    pub fn translate(&mut self, mode: EnterSends, byte: u8) -> Option<&'static [u8]> {
+        let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+        let line_ending = mode.line_ending()?;
+        match byte {
+            b'\n' if after_cr => Some(b""),
+            b'\r' | b'\n' => Some(line_ending),
+            _ => None
+        }
+    }
+
+    /// `bytes` with their Enters translated
+    pub fn translate_all(&mut self, mode: EnterSends, bytes: &[u8]) -> Vec<u8> {
+        let mut translated = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            match self.translate(mode, byte) {
+                Some(line_ending) => translated.extend_from_slice(line_ending),
+                None => translated.push(byte)
+            }
+        }
+        translated
+    }
+}
@@ -0,0 +1,215 @@
+//! Timeline of what pusher did, kept apart from the raw device output.
+//!
+//! Each line is the wall clock time (unix seconds), the time since pusher started and the event:
+//! `1760600000.123 +12.345s handshake ok`
+//!
+//! A session starts with a `#` line saying what it's about, and so does every file the log is
+//! rotated into (--log-rotate-size): `<log>.1` is the newest of the rotated ones.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Something worth a line in the event log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Waiting for the device's ready signal
+    Waiting,
+    /// No ready signal came, so the loader was probed for it, the `attempt`th time
+    Probe { attempt: u32 },
+    /// The ready signal (or the push on connect) started a push
+    Trigger,
+    /// Who the loader said it is (--identify)
+    Identity(String),
+    /// Starting to send a kernel of `size` bytes
+    SendStart { size: u64 },
+    /// The device answered the size header, or didn't
+    Handshake { ok: bool },
+    /// What followed the device's answer to the size header (--ack-status)
+    AckStatus(String),
+    /// The device answered the size header with its NAK, so it's sent again, the `retry`th time
+    SizeNak { retry: u32 },
+    /// The whole payload was sent
+    SendEnd,
+    /// What the loader stored matched the kernel, or didn't (--verify-after-send)
+    Verified { ok: bool },
+    /// The image didn't change since the last push, so it wasn't sent again
+    Skipped,
+    /// The push was given up after `sent` of `total` payload bytes
+    Cancelled { sent: u64, total: u64 },
+    /// The device went away, it's reopened when running as a daemon
+    Disconnected(String),
+    /// The device printed nothing for `secs` (--idle-warn)
+    Idle { secs: u64 },
+    /// The device printed something again after `secs` of silence
+    Resumed { secs: u64 },
+    /// The board's power was cut and given back, and why
+    PowerCycle(String),
+    /// A line of the statistics dumped on SIGUSR1
+    Stats(String),
+    /// The configuration was read again (SIGHUP), with what it changed
+    Reloaded(String),
+    /// The session ended on an error
+    Error(String),
+    /// The session ended cleanly
+    Exit,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Waiting => write!(f, "waiting"),
+            Event::Probe { attempt } => write!(f, "probe {}", attempt),
+            Event::Trigger => write!(f, "trigger"),
+            Event::Identity(identity) => write!(f, "identity {}", identity),
+            Event::SendStart { size } => write!(f, "send start {} bytes", size),
+            Event::Handshake { ok: true } => write!(f, "handshake ok"),
+            Event::Handshake { ok: false } => write!(f, "handshake failed"),
+            Event::AckStatus(status) => write!(f, "ack status {}", status),
+            Event::SizeNak { retry } => write!(f, "size nak, retry {}", retry),
+            Event::SendEnd => write!(f, "send end"),
+            Event::Verified { ok: true } => write!(f, "verify ok"),
+            Event::Verified { ok: false } => write!(f, "verify failed"),
+            Event::Skipped => write!(f, "skipped, image unchanged"),
+            Event::Cancelled { sent, total } => write!(f, "cancelled after {}/{} bytes", sent, total),
+            Event::Disconnected(message) => write!(f, "disconnected: {}", message),
+            Event::Idle { secs } => write!(f, "idle, no output for {}s", secs),
+            Event::Resumed { secs } => write!(f, "output resumed after {}s", secs),
+            // stands out when skimming a long log
+            Event::PowerCycle(message) => write!(f, "POWER CYCLE: {}", message),
+            Event::Stats(line) => write!(f, "stats: {}", line),
+            Event::Reloaded(changes) => write!(f, "reloaded: {}", changes),
+            Event::Error(message) => write!(f, "error: {}", message),
+            Event::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+/// When the log is rotated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate before a line would take the file past this many bytes
+    pub size: u64,
+    /// Rotated files kept, older ones are deleted
+    pub keep: u32,
+}
+
+/// Rotated files kept when only the size is given
+pub const DEFAULT_ROTATE_KEEP: u32 = 5;
+
+/// Appends events to a file, or drops them when no log was asked for
+pub struct EventLog {
+    file: Option<File>,
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    /// The first line of every file, saying what the session is about
+    header: String,
+    /// Bytes in the file so far
+    written: u64,
+    /// The file has an event after its header, an event longer than the rotation size still
+    /// gets a file of its own rather than rotating forever
+    has_events: bool,
+    start: Instant,
+}
+
+impl EventLog {
+    /// Append events to `path`, creating it if needed. `context` says what the session is about
+    /// (the device, the kernel), for the header starting it and every rotated file.
+    pub fn open(path: &Path, context: &str, rotation: Option<Rotation>) -> io::Result<Self> {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut log = Self {
+            file: None,
+            path: path.to_path_buf(),
+            rotation,
+            header: format!("# pusher session, {}, started {}\n", context, started),
+            written: 0,
+            has_events: false,
+            start: Instant::now(),
+        };
+        log.open_file()?;
+        Ok(log)
+    }
+
+    /// A log that records nothing
+    pub fn disabled() -> Self {
+        Self { file: None, path: PathBuf::new(), rotation: None, header: String::new(), written: 0, has_events: false,
+            start: Instant::now() }
+    }
+
+    /// Write one line for `event` and flush it, so the log survives a crash. A line that doesn't
+    /// fit the file anymore goes whole into the next one.
+    pub fn record(&mut self, event: Event) -> io::Result<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = format!("{}.{:03} +{:.3}s {}\n", now.as_secs(), now.subsec_millis(),
+            self.start.elapsed().as_secs_f64(), event);
+        if self.rotation.is_some_and(|rotation| self.has_events && self.written + line.len() as u64 > rotation.size) {
+            self.rotate()?;
+        }
+        self.write(&line)?;
+        self.has_events = true;
+        Ok(())
+    }
+
+    /// Append to the file at `path`, starting with the header
+    fn open_file(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.written = file.metadata()?.len();
+        // what earlier sessions logged counts, it's rotated with the first event past the size
+        self.has_events = self.written > 0;
+        self.file = Some(file);
+        let header = self.header.clone();
+        self.write(&header)
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        file.write_all(text.as_bytes())?;
+        file.flush()?;
+        self.written += text.len() as u64;
+        Ok(())
+    }
+
+    /// Move the file to `<path>.1`, the older ones a number up, dropping the ones past `keep`,
+    /// and start a new one. Each step is a rename, the log is never half moved.
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.rotation.map_or(0, |rotation| rotation.keep);
+        self.file = None;
+        let rotated = |index: u32| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", index));
+            PathBuf::from(path)
+        };
+        match keep {
+            0 => remove_if_there(&self.path)?,
+            keep => {
+                remove_if_there(&rotated(keep))?;
+                for index in (1..keep).rev() {
+                    rename_if_there(&rotated(index), &rotated(index + 1))?;
+                }
+                fs::rename(&self.path, rotated(1))?;
+            }
+        }
+        self.open_file()
+    }
+}
+
+fn remove_if_there(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result
+    }
+}
+
+fn rename_if_there(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result
+    }
+}
@@ -0,0 +1,236 @@
+//! Pushing the same kernel to several boards from one pusher.
+//!
+//! Every board is watched on its own: its output is shown with a `[name] ` prefix on each line,
+//! and whichever board sends the ready signal gets the kernel, alongside any other push going
+//! on. A board failing a push, or going away, doesn't affect the others. Keystrokes aren't
+//! forwarded, there would be no telling which board they're for.
+//!
+//! A progress line can't be shared, so each board's push reports every `PROGRESS_STEP` percent
+//! on a status line of its own.
+
+use std::io;
+use std::time::Duration;
+use anyhow::Result;
+use mio::{Events, Interest, Poll, Registry, Token};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_mio::v0_8::Signals;
+
+use crate::capture::Capture;
+use crate::events::EventLog;
+use crate::output;
+use crate::pattern::RollingMatcher;
+use crate::protocol::Protocol;
+use crate::push::{PushOptions, Step, Transfer};
+use crate::transport::Transport;
+use crate::status;
+
+/// Most device output kept per board while its push is going on
+const CAPTURE_LIMIT: usize = 64 * 1024;
+/// Percent of a push between two of its progress lines
+const PROGRESS_STEP: u64 = 25;
+
+/// One of the boards pushed to, and how its pushes went
+pub struct Board {
+    name: String,
+    device: Box<dyn Transport>,
+    ready_signal: RollingMatcher,
+    transfer: Option<Transfer>,
+    /// The last progress step the push in progress reported
+    reported_percent: u64,
+    /// How long the last successful push took
+    last_push: Option<Duration>,
+    capture: Capture,
+    /// The device went away, the board stays listed in the summary
+    lost: bool,
+    pushes: u32,
+    failures: u32,
+}
+
+impl Board {
+    pub fn new(name: &str, device: Box<dyn Transport>, protocol: &Protocol) -> Self {
+        Self {
+            name: name.to_string(),
+            device,
+            ready_signal: RollingMatcher::new(protocol.ready_signal.clone()),
+            transfer: None,
+            reported_percent: 0,
+            last_push: None,
+            capture: Capture::new(CAPTURE_LIMIT),
+            lost: false,
+            pushes: 0,
+            failures: 0,
+        }
+    }
+}
+
+/// Keeps the boards' output apart: a board's line is only continued if nobody else printed
+/// since, otherwise a new prefixed line is started.
+struct Screen {
+    /// The board whose line was left unfinished
+    open_line: Option<usize>,
+}
+
+impl Screen {
+    fn show(&mut self, board: usize, name: &str, bytes: &[u8]) {
+        let mut shown = Vec::new();
+        for &byte in bytes {
+            if self.open_line != Some(board) {
+                if self.open_line.is_some() {
+                    shown.extend_from_slice(b"\r\n");
+                }
+                shown.extend_from_slice(format!("[{}] ", name).as_bytes());
+                self.open_line = Some(board);
+            }
+            shown.push(byte);
+            if byte == b'\n' {
+                self.open_line = None;
+            }
+        }
+        output::device_bytes(&shown);
+    }
+
+    fn status(&mut self, name: &str, message: &str) {
+        if self.open_line.take().is_some() {
+            output::device("\r\n");
+        }
+        status!("[PUSHER] [{}] {}", name, message);
+    }
+}
+
+/// Watch every board and push to those that ask, until SIGINT / SIGTERM or until every board
+/// is gone. Prints each board's push count and failures at the end. A wakeup takes up to
+/// `events_capacity` readiness events. Returns the signal that stopped it, if one did.
+pub fn fan_out(boards: &mut [Board], protocol: &Protocol, options: &PushOptions, events_capacity: usize)
+    -> Result<Option<i32>>
+{
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(events_capacity);
+    for (index, board) in boards.iter_mut().enumerate() {
+        poll.registry().register(board.device.as_mut(), Token(index), Interest::READABLE)?;
+    }
+    let signal_token = Token(boards.len());
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    poll.registry().register(&mut signals, signal_token, Interest::READABLE)?;
+    // the log has no notion of boards, lines of different boards couldn't be told apart
+    let mut event_log = EventLog::disabled();
+    let mut screen = Screen { open_line: None };
+
+    status!("[PUSHER] Waiting for {} boards", boards.len());
+    while boards.iter().any(|board| !board.lost) {
+        let timeout = boards.iter().filter_map(|board| board.transfer.as_ref()?.timeout()).min();
+        match poll.poll(&mut events, timeout) {
+            // a signal arrived, it's handled through its token
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => result?
+        }
+        for event in &events {
+            if event.token() == signal_token {
+                if let Some(signal) = signals.pending().next() {
+                    status!("\r\n[PUSHER] Got a signal, exiting");
+                    print_summary(boards);
+                    return Ok(Some(signal));
+                }
+                continue;
+            }
+            let index = event.token().0;
+            on_readable(&mut boards[index], index, poll.registry(), protocol, options, &mut event_log,
+                &mut screen);
+        }
+        for (index, board) in boards.iter_mut().enumerate() {
+            step(board, index, &mut event_log, &mut screen);
+        }
+    }
+    status!("[PUSHER] No boards left");
+    print_summary(boards);
+    Ok(None)
+}
+
+/// Show what the board sent, or hand it to its push, starting one on the ready signal
+fn on_readable(board: &mut Board, index: usize, registry: &Registry, protocol: &Protocol, options: &PushOptions,
+    event_log: &mut EventLog, screen: &mut Screen)
+{
+    let bytes = match board.device.read_all() {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            screen.status(&board.name, &format!("Lost the device: {}", err));
+            if board.transfer.take().is_some() {
+                board.failures += 1;
+            }
+            let _ = registry.deregister(board.device.as_mut());
+            board.lost = true;
+            return;
+        }
+    };
+    if let Some(transfer) = &mut board.transfer {
+        match transfer.on_received(&bytes, event_log, &mut board.capture) {
+            Ok(shown) => screen.show(index, &board.name, &shown),
+            Err(err) => {
+                board.transfer = None;
+                board.failures += 1;
+                screen.status(&board.name, &format!("Push failed: {}", err));
+            }
+        }
+        return;
+    }
+    screen.show(index, &board.name, &bytes);
+    if board.ready_signal.feed(&bytes).is_some() {
+        screen.status(&board.name, "Sending kernel!");
+        match Transfer::start(board.device.as_mut(), protocol, options, event_log) {
+            Ok(transfer) => {
+                board.transfer = Some(transfer);
+                board.reported_percent = 0;
+            },
+            Err(err) => {
+                board.failures += 1;
+                screen.status(&board.name, &format!("Push failed: {}", err));
+            }
+        }
+    }
+}
+
+/// Move the board's push along, if it has one
+fn step(board: &mut Board, index: usize, event_log: &mut EventLog, screen: &mut Screen) {
+    let Some(transfer) = &mut board.transfer else {
+        return;
+    };
+    let (sent, total) = transfer.progress();
+    let result = transfer.step(board.device.as_mut(), event_log, None);
+    if let Ok(Step::Pending) = result {
+        let (sent, total) = transfer.progress();
+        let percent = (sent * 100).checked_div(total).unwrap_or(100);
+        if percent / PROGRESS_STEP > board.reported_percent / PROGRESS_STEP && percent < 100 {
+            board.reported_percent = percent;
+            screen.status(&board.name, &format!("Sent {}/{} bytes ({}%)", sent, total, percent));
+        }
+        return;
+    }
+    let stats = transfer.stats();
+    board.transfer = None;
+    // what the board said during the push, it may explain a failure
+    let (captured, dropped) = board.capture.take();
+    screen.show(index, &board.name, &captured);
+    if dropped > 0 {
+        screen.status(&board.name, &format!("({} more bytes of output truncated)", dropped));
+    }
+    match result {
+        Ok(_) => {
+            board.pushes += 1;
+            board.last_push = Some(stats.duration);
+            screen.status(&board.name, &format!("Sent {} bytes in {:.1}s, done! booting now", total,
+                stats.duration.as_secs_f64()));
+        },
+        Err(err) => {
+            board.failures += 1;
+            screen.status(&board.name, &format!("Push failed after {}/{} bytes: {}", sent, total, err));
+        }
+    }
+}
+
+fn print_summary(boards: &[Board]) {
+    status!("[PUSHER] Summary:");
+    for board in boards {
+        status!("[PUSHER]   {}: {} pushed, {} failed{}{}", board.name, board.pushes, board.failures,
+            board.last_push.map_or(String::new(), |took| format!(", the last push took {:.1}s", took.as_secs_f64())),
+            if board.lost { ", lost" } else { "" });
+    }
+}
@@ -0,0 +1,145 @@
+//! Kernels served over HTTP(S), downloaded into a cache file and pushed from there.
+//! Failures are reported as network errors, so they can't be mistaken for serial ones.
+//!
+//! Downloading needs the `http` cargo feature, without it URLs are refused.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result, anyhow, bail};
+use sha2::{Digest, Sha256};
+
+use crate::status;
+
+/// Largest image accepted from a server
+#[cfg(feature = "http")]
+const MAX_IMAGE_SIZE: u64 = 1 << 30;
+
+/// Whether a kernel argument is a URL rather than a path
+pub fn is_url(kernel: &str) -> bool {
+    kernel.starts_with("http://") || kernel.starts_with("https://")
+}
+
+/// Parse a `--header "Name: value"` argument
+pub fn parse_header(header: &str) -> Result<(String, String)> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.trim().to_string())),
+        _ => bail!("Invalid header \"{}\", expected \"Name: value\"", header)
+    }
+}
+
+/// Parse a hex SHA-256 digest
+pub fn parse_sha256(hex: &str) -> Result<[u8; 32]> {
+    let invalid = || anyhow!("Invalid SHA-256 \"{}\", expected 64 hex digits", hex);
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut digest = [0; 32];
+    for (index, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * index..2 * index + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(digest)
+}
+
+/// An image on a server, mirrored in a local file
+#[derive(Debug)]
+pub struct RemoteImage {
+    url: String,
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    headers: Vec<(String, String)>,
+    expected_sha256: Option<[u8; 32]>,
+    cache_path: PathBuf,
+    /// ETag of the cached copy, to only download again when it changed
+    etag: Option<String>,
+    /// Whether the cached copy and its ETag are kept for the next pusher
+    persistent: bool,
+}
+
+impl RemoteImage {
+    /// Nothing is downloaded until `fetch`. With a `cache_dir`, the image downloaded by an
+    /// earlier pusher is reused if the server says it's still current (or it has the expected
+    /// digest), instead of downloading it again.
+    pub fn new(url: &str, headers: Vec<(String, String)>, expected_sha256: Option<[u8; 32]>,
+        cache_dir: Option<&Path>) -> Self {
+        let url_digest = Sha256::digest(url.as_bytes());
+        let name: String = url_digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+        let cache_path = cache_dir.map_or_else(env::temp_dir, Path::to_path_buf).join(format!("pusher-{}.img", name));
+        // a cached copy without an ETag can only be trusted through its digest
+        let etag = cache_dir.filter(|_| cache_path.exists())
+            .and_then(|_| fs::read_to_string(cache_path.with_extension("etag")).ok());
+        Self { url: url.to_string(), headers, expected_sha256, cache_path, etag, persistent: cache_dir.is_some() }
+    }
+
+    /// Where the downloaded image is kept
+    pub fn cache_path(&self) -> &Path {
+        &self.cache_path
+    }
+
+    /// Download the image into the cache file, unless the server says the cached copy is
+    /// still current. Returns whether a new image was downloaded.
+    pub fn fetch(&mut self) -> Result<bool> {
+        if self.persistent && self.etag.is_none() && self.cached_matches_digest() {
+            status!("[PUSHER] Using the cached copy of {}", self.url);
+            // only on the first fetch, --refetch-each-push still asks the server afterwards
+            self.persistent = false;
+            return Ok(false);
+        }
+        let Some((image, etag)) = self.download()? else {
+            status!("[PUSHER] {} didn't change", self.url);
+            return Ok(false);
+        };
+
+        if let Some(expected) = &self.expected_sha256 {
+            if Sha256::digest(&image).as_slice() != expected {
+                bail!("The image downloaded from {} doesn't match --expected-sha256", self.url);
+            }
+        }
+        // replace the cached copy in one go, a failed download keeps the previous one
+        let partial_path = self.cache_path.with_extension("part");
+        fs::write(&partial_path, &image).with_context(|| format!("Couldn't write {}", partial_path.display()))?;
+        fs::rename(&partial_path, &self.cache_path)?;
+        status!("[PUSHER] Downloaded {} ({} bytes)", self.url, image.len());
+        if self.persistent {
+            let etag_path = self.cache_path.with_extension("etag");
+            let _ = match &etag {
+                Some(etag) => fs::write(etag_path, etag),
+                None => fs::remove_file(etag_path),
+            };
+        }
+        self.etag = etag;
+        Ok(true)
+    }
+
+    /// Whether there's a cached copy with the --expected-sha256 digest
+    fn cached_matches_digest(&self) -> bool {
+        self.expected_sha256.as_ref().is_some_and(|expected| {
+            fs::read(&self.cache_path).is_ok_and(|image| Sha256::digest(image).as_slice() == expected)
+        })
+    }
+
+    /// The image and its ETag, `None` if the cached copy is still current
+    #[cfg(feature = "http")]
+    fn download(&self) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        let mut request = ureq::get(&self.url);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        if let Some(etag) = &self.etag {
+            request = request.header("If-None-Match", etag);
+        }
+        let mut response = request.call().map_err(|err| anyhow!("Network error fetching {}: {}", self.url, err))?;
+        if response.status() == 304 {
+            return Ok(None);
+        }
+        let etag = response.headers().get("ETag").and_then(|etag| etag.to_str().ok()).map(String::from);
+        let image = response.body_mut().with_config().limit(MAX_IMAGE_SIZE).read_to_vec()
+            .map_err(|err| anyhow!("Network error fetching {}: {}", self.url, err))?;
+        Ok(Some((image, etag)))
+    }
+
+    #[cfg(not(feature = "http"))]
+    fn download(&self) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        bail!("Can't download {}, this pusher was built without HTTP support, rebuild it with `--features http`",
+            self.url)
+    }
+}
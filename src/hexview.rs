@@ -0,0 +1,72 @@
+//! Showing binary device output as a hex dump (--hex), and with --hex-diff the bytes that
+//! changed since the row (or COBS packet) before highlighted, to spot a toggling status byte in
+//! a stream of similar frames.
+
+/// Bytes per row, by default
+pub const DEFAULT_WIDTH: usize = 16;
+/// Changed bytes are bold red
+const CHANGED: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+/// Formats bytes as hex, highlighting the ones that differ from the same offset of the
+/// bytes it formatted last, if diffing
+#[derive(Debug, Default)]
+pub struct Differ {
+    diff: bool,
+    previous: Vec<u8>,
+}
+
+impl Differ {
+    pub fn new(diff: bool) -> Self {
+        Self { diff, previous: Vec::new() }
+    }
+
+    /// `bytes` as space separated hex. Bytes past the end of the previous ones are new, not changed.
+    pub fn format(&mut self, bytes: &[u8]) -> String {
+        let hex: Vec<String> = bytes.iter().enumerate().map(|(offset, byte)| {
+            match self.previous.get(offset) {
+                Some(previous) if self.diff && previous != byte => format!("{}{:02x}{}", CHANGED, byte, RESET),
+                _ => format!("{:02x}", byte)
+            }
+        }).collect();
+        if self.diff {
+            self.previous = bytes.to_vec();
+        }
+        hex.join(" ")
+    }
+}
+
+/// Cuts device output into rows of `width` bytes and formats them like a classic hex dump,
+/// the offset in the output first and the printable bytes last. A row is shown once it's full.
+#[derive(Debug)]
+pub struct HexView {
+    width: usize,
+    differ: Differ,
+    row: Vec<u8>,
+    /// Offset of the row in the output
+    offset: u64,
+}
+
+impl HexView {
+    pub fn new(width: usize, diff: bool) -> Self {
+        Self { width, differ: Differ::new(diff), row: Vec::with_capacity(width), offset: 0 }
+    }
+
+    /// Feed device output, returns the rows it completed, each ending with `\r\n`
+    pub fn feed(&mut self, bytes: &[u8]) -> String {
+        let mut rows = String::new();
+        for &byte in bytes {
+            self.row.push(byte);
+            if self.row.len() < self.width {
+                continue;
+            }
+            let ascii: String = self.row.iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            rows += &format!("{:08x}  {}  |{}|\r\n", self.offset, self.differ.format(&self.row), ascii);
+            self.offset += self.width as u64;
+            self.row.clear();
+        }
+        rows
+    }
+}
@@ -0,0 +1,139 @@
+//! Noticing the device being plugged in and out through udev, instead of finding out by a read
+//! failing or by trying to open it every so often.
+//!
+//! udev support needs the `udev` cargo feature, Linux and libudev. Without it, or when the
+//! monitor can't be set up (no udev in a container), pusher falls back to polling.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+use mio::{Registry, Token};
+
+/// Which tty is the board's
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    /// The device node or one of its links, like `/dev/serial/by-id/...`
+    Path(PathBuf),
+    /// The USB adapter's vendor and product IDs
+    UsbId { vendor: u16, product: u16 },
+    /// The USB adapter's serial number
+    UsbSerial(String),
+}
+
+impl Selector {
+    /// Parse a `--udev-match` argument: `<vid>:<pid>` in hex, or `serial=<serial number>`
+    pub fn parse(text: &str) -> Result<Self> {
+        if let Some(serial) = text.strip_prefix("serial=") {
+            return Ok(Selector::UsbSerial(serial.to_string()));
+        }
+        let invalid = || anyhow!("Invalid udev match \"{}\", expected <vid>:<pid> like 0403:6001 \
+            or serial=<serial number>", text);
+        let (vendor, product) = text.split_once(':').ok_or_else(invalid)?;
+        Ok(Selector::UsbId {
+            vendor: u16::from_str_radix(vendor, 16).map_err(|_| invalid())?,
+            product: u16::from_str_radix(product, 16).map_err(|_| invalid())?,
+        })
+    }
+
+    /// Whether a tty with these udev properties is the one selected
+    #[cfg_attr(not(all(feature = "udev", target_os = "linux")), allow(dead_code))]
+    fn matches(&self, devnode: &Path, property: impl Fn(&str) -> Option<String>) -> bool {
+        match self {
+            Selector::Path(path) => devnode == path || property("DEVLINKS")
+                .is_some_and(|links| links.split(' ').any(|link| Path::new(link) == path)),
+            Selector::UsbId { vendor, product } => {
+                let id = |name| property(name).and_then(|id| u16::from_str_radix(&id, 16).ok());
+                id("ID_VENDOR_ID") == Some(*vendor) && id("ID_MODEL_ID") == Some(*product)
+            },
+            Selector::UsbSerial(serial) => property("ID_SERIAL_SHORT").as_deref() == Some(serial.as_str()),
+        }
+    }
+}
+
+/// The selected device coming or going
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hotplug {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+/// Listens to udev for the selected tty
+#[cfg(all(feature = "udev", target_os = "linux"))]
+pub struct HotplugMonitor {
+    socket: udev::MonitorSocket,
+    selector: Selector,
+}
+
+#[cfg(all(feature = "udev", target_os = "linux"))]
+impl HotplugMonitor {
+    pub fn open(selector: Selector) -> Result<Self> {
+        let socket = udev::MonitorBuilder::new()
+            .and_then(|builder| builder.match_subsystem("tty"))
+            .and_then(|builder| builder.listen())
+            .map_err(|err| anyhow!("Couldn't listen to udev: {}", err))?;
+        Ok(Self { socket, selector })
+    }
+
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.register(&mut self.socket, token, mio::Interest::READABLE)
+    }
+
+    /// The device node of the selected tty, if it's plugged in already
+    pub fn find(&self) -> Option<PathBuf> {
+        let mut enumerator = udev::Enumerator::new().ok()?;
+        enumerator.match_subsystem("tty").ok()?;
+        enumerator.scan_devices().ok()?
+            .filter_map(|device| Some((device.devnode()?.to_path_buf(), device)))
+            .find(|(devnode, device)| self.selector.matches(devnode, |name| property(device, name)))
+            .map(|(devnode, _)| devnode)
+    }
+
+    /// Take what udev said since the last call, keeping what's about the selected tty
+    pub fn events(&mut self) -> Vec<Hotplug> {
+        let mut hotplugs = Vec::new();
+        for event in self.socket.iter() {
+            let Some(devnode) = event.devnode().map(Path::to_path_buf) else {
+                continue;
+            };
+            if !self.selector.matches(&devnode, |name| property(&event, name)) {
+                continue;
+            }
+            match event.event_type() {
+                udev::EventType::Add => hotplugs.push(Hotplug::Added(devnode)),
+                udev::EventType::Remove => hotplugs.push(Hotplug::Removed(devnode)),
+                _ => {}
+            }
+        }
+        hotplugs
+    }
+}
+
+#[cfg(all(feature = "udev", target_os = "linux"))]
+fn property(device: &udev::Device, name: &str) -> Option<String> {
+    Some(device.property_value(name)?.to_string_lossy().into_owned())
+}
+
+/// Stand-in for builds without udev support, it can't be opened
+#[cfg(not(all(feature = "udev", target_os = "linux")))]
+pub struct HotplugMonitor {
+    _unconstructible: (),
+}
+
+#[cfg(not(all(feature = "udev", target_os = "linux")))]
+impl HotplugMonitor {
+    pub fn open(_selector: Selector) -> Result<Self> {
+        Err(anyhow!("This pusher was built without udev support, rebuild it with `--features udev` (Linux only)"))
+    }
+
+    pub fn register(&mut self, _registry: &Registry, _token: Token) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn find(&self) -> Option<PathBuf> {
+        None
+    }
+
+    pub fn events(&mut self) -> Vec<Hotplug> {
+        Vec::new()
+    }
+}
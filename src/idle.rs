@@ -0,0 +1,56 @@
+//! Noticing a board that went quiet (--idle-warn): a hung kernel prints nothing, which is easy
+//! to miss for minutes.
+
+use std::time::{Duration, Instant};
+
+use crate::events::Event;
+use crate::status;
+
+/// Warns once when the device printed nothing for `period`, and again when it talks again
+#[derive(Debug)]
+pub struct IdleWatch {
+    period: Duration,
+    /// Watching the silence. A loader waiting for the first push is quiet on purpose, so unless
+    /// told to always watch this starts with a kernel pushed.
+    armed: bool,
+    last_output: Instant,
+    /// When the silence warned about started
+    silent_since: Option<Instant>,
+}
+
+impl IdleWatch {
+    pub fn new(period: Duration, always: bool) -> Self {
+        Self { period, armed: always, last_output: Instant::now(), silent_since: None }
+    }
+
+    /// Start watching, a kernel was pushed (or the device was told to boot the one it has)
+    pub fn arm(&mut self) {
+        self.armed = true;
+        self.last_output = Instant::now();
+    }
+
+    /// The device printed something. Returns the event of the silence ending, if it was warned about.
+    pub fn on_output(&mut self) -> Option<Event> {
+        self.last_output = Instant::now();
+        let silent_since = self.silent_since.take()?;
+        let secs = silent_since.elapsed().as_secs();
+        status!("\r\n[PUSHER] The device is talking again after {}s of silence", secs);
+        Some(Event::Resumed { secs })
+    }
+
+    /// How long until the silence is worth a warning, `None` if it's not watched or warned already
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.armed && self.silent_since.is_none()).then(|| self.period.saturating_sub(self.last_output.elapsed()))
+    }
+
+    /// Warn if the silence went on for the period. Returns the event of it, once per silence.
+    pub fn check(&mut self) -> Option<Event> {
+        if self.timeout()? > Duration::ZERO {
+            return None;
+        }
+        self.silent_since = Some(self.last_output);
+        let secs = self.last_output.elapsed().as_secs();
+        status!("\r\n[PUSHER] Warning: nothing from the device for {}s, is it hung?", secs);
+        Some(Event::Idle { secs })
+    }
+}
@@ -0,0 +1,605 @@
+//! Journals of the bytes on the wire, written with --record (or --capture-raw) and played back
+//! with `pusher replay` to debug the console and the protocol without the hardware, or shown
+//! with `pusher capdump`.
+//!
+//! A journal starts with the magic `PUSHJRNL` and a version byte (currently 1), followed by a
+//! record per chunk of bytes that went either way:
+//!
+//! | bytes  | field                                                           |
+//! |--------|-----------------------------------------------------------------|
+//! | 1      | direction, 0 received from the device (RX) or 1 sent to it (TX) |
+//! | 8      | microseconds since the journal was created, little endian       |
+//! | 4      | length of the data, little endian                               |
+//! | length | the data                                                        |
+//!
+//! An RX record without data marks the link being closed. Records are written whole as they
+//! happen, so a journal cut short by a crash is only missing its last record. A --capture-raw
+//! journal is buffered instead, flushed every `FLUSH_INTERVAL` and on exit.
+//!
+//! A journal of a real board kept as a regression fixture goes by the `.pusher-cast` extension.
+//! `Lockstep` plays its RX side back in step with what's sent rather than with the clock, so the
+//! ready signal detection and the handshake see the same thing on every run, on any machine.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow, bail};
+use mio::{event, Registry, Token, Interest};
+
+use crate::transport::{ErrorCounts, ModemLines, Transport, UnixSocketDevice};
+
+const MAGIC: &[u8; 8] = b"PUSHJRNL";
+const VERSION: u8 = 1;
+/// Sent bytes gathered into one record at most, they're written one at a time
+const MAX_TX_RECORD: usize = 4096;
+/// Longest a buffered journal keeps records to itself
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which way bytes went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+/// Bytes that went one way at once
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub direction: Direction,
+    /// Since the journal was created
+    pub time: Duration,
+    pub data: Vec<u8>,
+}
+
+/// The journal file, shared by the writers of every connection
+#[derive(Debug)]
+struct Sink {
+    file: BufWriter<File>,
+    /// Flushed on a timer rather than after every record
+    buffered: bool,
+    flushed: Instant,
+}
+
+/// Appends records to a journal. Clones write to the same journal with the same clock, one per
+/// connection.
+#[derive(Debug, Clone)]
+pub struct JournalWriter {
+    sink: Arc<Mutex<Sink>>,
+    created: Instant,
+}
+
+impl JournalWriter {
+    /// Create the journal at `path`, replacing what was there
+    pub fn create(path: &Path) -> Result<Self> {
+        Self::open(path, false)
+    }
+
+    /// Create the journal at `path` with its records buffered, see `flush`
+    pub fn buffered(path: &Path) -> Result<Self> {
+        Self::open(path, true)
+    }
+
+    fn open(path: &Path, buffered: bool) -> Result<Self> {
+        let file = File::create(path).map_err(|err| anyhow!("Couldn't create {}: {}", path.display(), err))?;
+        let mut file = BufWriter::new(file);
+        file.write_all(MAGIC)?;
+        file.write_all(&[VERSION])?;
+        file.flush()?;
+        let sink = Sink { file, buffered, flushed: Instant::now() };
+        Ok(Self { sink: Arc::new(Mutex::new(sink)), created: Instant::now() })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Sink> {
+        self.sink.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// How long until a buffered journal is due a flush, `None` if it isn't buffered
+    pub fn flush_timeout(&self) -> Option<Duration> {
+        let sink = self.lock();
+        sink.buffered.then(|| FLUSH_INTERVAL.saturating_sub(sink.flushed.elapsed()))
+    }
+
+    /// Write out the buffered records
+    pub fn flush(&self) -> io::Result<()> {
+        let mut sink = self.lock();
+        sink.flushed = Instant::now();
+        sink.file.flush()
+    }
+
+    /// Record `data` going `direction` at `time`
+    pub fn record(&mut self, direction: Direction, time: Instant, data: &[u8]) -> io::Result<()> {
+        let micros = time.saturating_duration_since(self.created).as_micros() as u64;
+        let length = u32::try_from(data.len()).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "record too long"))?;
+        let mut record = Vec::with_capacity(13 + data.len());
+        record.push(match direction {
+            Direction::Rx => 0,
+            Direction::Tx => 1,
+        });
+        record.extend_from_slice(&micros.to_le_bytes());
+        record.extend_from_slice(&length.to_le_bytes());
+        record.extend_from_slice(data);
+        let mut sink = self.lock();
+        sink.file.write_all(&record)?;
+        if !sink.buffered {
+            sink.file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a journal's records in order
+#[derive(Debug)]
+pub struct JournalReader<R> {
+    reader: R,
+}
+
+impl JournalReader<BufReader<File>> {
+    /// Open the journal at `path`, checking it is one
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).map_err(|err| anyhow!("Couldn't open {}: {}", path.display(), err))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> JournalReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; 9];
+        if reader.read_exact(&mut header).is_err() || &header[..8] != MAGIC {
+            bail!("Not a pusher journal");
+        }
+        if header[8] != VERSION {
+            bail!("Journal version {} isn't supported, this pusher reads version {}", header[8], VERSION);
+        }
+        Ok(Self { reader })
+    }
+
+    /// The next record, `None` at the end of the journal
+    pub fn next_entry(&mut self) -> Result<Option<Entry>> {
+        let mut direction = [0; 1];
+        match self.reader.read_exact(&mut direction) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?
+        }
+        let direction = match direction[0] {
+            0 => Direction::Rx,
+            1 => Direction::Tx,
+            other => bail!("Invalid direction {} in the journal", other)
+        };
+        let mut fields = [0; 12];
+        let mut data = Vec::new();
+        // the length isn't trusted with an allocation, a corrupt one just finds the end of the file
+        self.reader.read_exact(&mut fields)
+            .and_then(|()| {
+                let length = u32::from_le_bytes([fields[8], fields[9], fields[10], fields[11]]) as u64;
+                match (&mut self.reader).take(length).read_to_end(&mut data)? as u64 == length {
+                    true => Ok(()),
+                    false => Err(ErrorKind::UnexpectedEof.into())
+                }
+            })
+            .map_err(|_| anyhow!("The journal's last record is cut short"))?;
+        let micros = u64::from_le_bytes(fields[..8].try_into().unwrap_or_default());
+        Ok(Some(Entry { direction, time: Duration::from_micros(micros), data }))
+    }
+}
+
+/// Every record of the journal at `path`, in order
+pub fn load(path: &Path) -> Result<Vec<Entry>> {
+    let mut reader = JournalReader::open(path)?;
+    let mut entries = Vec::new();
+    while let Some(entry) = reader.next_entry()? {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Journals everything going through a link
+pub struct Recorder {
+    inner: Box<dyn Transport>,
+    journal: JournalWriter,
+    /// Sent but not recorded yet, and when the first of them went
+    pending: Vec<u8>,
+    pending_since: Instant,
+}
+
+impl Recorder {
+    pub fn new(inner: Box<dyn Transport>, journal: JournalWriter) -> Self {
+        Self { inner, journal, pending: Vec::new(), pending_since: Instant::now() }
+    }
+
+    fn record_pending(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.journal.record(Direction::Tx, self.pending_since, &self.pending)?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Recorder {
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let bytes = self.inner.read_all()?;
+        if !bytes.is_empty() {
+            self.record_pending()?;
+            self.journal.record(Direction::Rx, Instant::now(), &bytes)?;
+        }
+        Ok(bytes)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<usize> {
+        let written = self.inner.write_byte(byte)?;
+        if written == 1 {
+            if self.pending.is_empty() {
+                self.pending_since = Instant::now();
+            }
+            self.pending.push(byte);
+            if self.pending.len() == MAX_TX_RECORD {
+                self.record_pending()?;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.record_pending()?;
+        self.inner.flush()
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.inner.clear_input()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+
+    fn reset(&mut self, pulse: Duration) -> io::Result<()> {
+        self.inner.reset(pulse)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_dtr(&mut self, asserted: bool) -> io::Result<()> {
+        self.inner.set_dtr(asserted)
+    }
+
+    fn set_rts(&mut self, asserted: bool) -> io::Result<()> {
+        self.inner.set_rts(asserted)
+    }
+
+    fn modem_lines(&mut self) -> io::Result<ModemLines> {
+        self.inner.modem_lines()
+    }
+
+    fn error_counts(&mut self) -> io::Result<ErrorCounts> {
+        self.inner.error_counts()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.record_pending();
+        let _ = self.journal.record(Direction::Rx, Instant::now(), &[]);
+    }
+}
+
+impl event::Source for Recorder {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
+
+/// A link whose device plays back the RX side of the journal at `path`, with the recorded
+/// timing sped up `speed` times (no waiting at all if 0), and takes whatever is sent to it.
+/// It's closed by the device at the end of the journal, once the last recorded close is due.
+pub fn replay(path: &Path, speed: f64) -> Result<UnixSocketDevice> {
+    let received: Vec<Entry> = load(path)?.into_iter().filter(|entry| entry.direction == Direction::Rx).collect();
+    let (ours, theirs) = UnixStream::pair()?;
+    let mut drain = theirs.try_clone()?;
+    thread::spawn(move || {
+        let mut buffer = [0; 1024];
+        while matches!(drain.read(&mut buffer), Ok(read) if read > 0) {}
+    });
+    let mut device = theirs;
+    thread::spawn(move || {
+        let started = Instant::now();
+        for entry in received {
+            if speed > 0.0 {
+                sleep(entry.time.div_f64(speed).saturating_sub(started.elapsed()));
+            }
+            if device.write_all(&entry.data).is_err() {
+                return;
+            }
+        }
+        let _ = device.shutdown(Shutdown::Write);
+    });
+    Ok(UnixSocketDevice::from_stream(ours)?)
+}
+
+/// A link whose device plays back the RX side of a journal in step with what's sent to it: a
+/// received record is played once as many bytes have been sent as had been when it was received.
+/// The timing is left out, what was sent is only counted (`sent` has it). The device closes the
+/// link after the last record, or at the first recorded close.
+pub struct Lockstep {
+    link: UnixSocketDevice,
+    device: UnixStream,
+    /// Records to play, each with how many bytes were sent before it
+    records: VecDeque<(usize, Vec<u8>)>,
+    /// Played but not taken by the socket yet
+    playing: Vec<u8>,
+    sent: Vec<u8>,
+}
+
+impl Lockstep {
+    /// Play back the journal at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::new(load(path)?)
+    }
+
+    /// Play back a journal's `entries`
+    pub fn new(entries: Vec<Entry>) -> Result<Self> {
+        let mut records = VecDeque::new();
+        let mut sent = 0;
+        for entry in entries {
+            match entry.direction {
+                Direction::Tx => sent += entry.data.len(),
+                Direction::Rx => {
+                    let closed = entry.data.is_empty();
+                    records.push_back((sent, entry.data));
+                    if closed {
+                        break;
+                    }
+                }
+            }
+        }
+        let (ours, device) = UnixStream::pair()?;
+        device.set_nonblocking(true)?;
+        let mut lockstep = Self { link: UnixSocketDevice::from_stream(ours)?, device, records, playing: Vec::new(),
+            sent: Vec::new() };
+        // what came before anything was sent, like the banner and the ready signal
+        lockstep.play()?;
+        Ok(lockstep)
+    }
+
+    /// Everything sent so far
+    pub fn sent(&self) -> &[u8] {
+        &self.sent
+    }
+
+    /// Play the records that are due, closing the link after the last one
+    fn play(&mut self) -> io::Result<()> {
+        while let Some((sent_before, _)) = self.records.front() {
+            if *sent_before > self.sent.len() {
+                break;
+            }
+            let (_, data) = self.records.pop_front().unwrap_or_default();
+            if data.is_empty() {
+                self.records.clear();
+            }
+            self.playing.extend(data);
+        }
+        while !self.playing.is_empty() {
+            match self.device.write(&self.playing) {
+                Ok(written) => {
+                    self.playing.drain(..written);
+                },
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                // the rest goes once the link has read some
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err)
+            }
+        }
+        if self.records.is_empty() {
+            let _ = self.device.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Lockstep {
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut received = Vec::new();
+        loop {
+            self.play()?;
+            match self.link.read_all() {
+                Ok(bytes) if bytes.is_empty() => return Ok(received),
+                Ok(bytes) => received.extend(bytes),
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof && !received.is_empty() => return Ok(received),
+                Err(err) => return Err(err)
+            }
+            if self.playing.is_empty() {
+                return Ok(received);
+            }
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<usize> {
+        self.sent.push(byte);
+        self.play()?;
+        Ok(1)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.link.clear_input()
+    }
+}
+
+impl event::Source for Lockstep {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.link.register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.link.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.link.deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A journal file of its own for each test
+    fn journal_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pusher-journal-{}-{}", std::process::id(), name))
+    }
+
+    fn header() -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = journal_path("round-trip");
+        let mut writer = JournalWriter::create(&path).unwrap();
+        let start = Instant::now();
+        writer.record(Direction::Rx, start, &[3, 3, 3]).unwrap();
+        writer.record(Direction::Tx, start + Duration::from_millis(5), &[72, 0, 0, 0]).unwrap();
+        writer.record(Direction::Rx, start + Duration::from_millis(7), b"OK").unwrap();
+        writer.record(Direction::Rx, start + Duration::from_millis(9), &[]).unwrap();
+        drop(writer);
+
+        let entries = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let directions: Vec<Direction> = entries.iter().map(|entry| entry.direction).collect();
+        assert_eq!(directions, [Direction::Rx, Direction::Tx, Direction::Rx, Direction::Rx]);
+        let data: Vec<&[u8]> = entries.iter().map(|entry| entry.data.as_slice()).collect();
+        assert_eq!(data, [&[3, 3, 3][..], &[72, 0, 0, 0], b"OK", &[]]);
+        // the writer's clock started after `start`, so every time is a little early
+        assert!(entries[1].time <= Duration::from_millis(5));
+        assert!(entries.windows(2).all(|pair| pair[0].time <= pair[1].time));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let err = JournalReader::new(&b"PUSHJRNX\x01"[..]).unwrap_err();
+        assert_eq!(err.to_string(), "Not a pusher journal");
+        assert!(JournalReader::new(&b"PUSH"[..]).is_err());
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION + 1);
+        let err = JournalReader::new(bytes.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("version 2 isn't supported"), "{}", err);
+    }
+
+    #[test]
+    fn cut_short_record() {
+        let mut bytes = header();
+        bytes.push(0);
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        // claims the largest length there is, only 2 bytes follow
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(b"OK");
+        let mut reader = JournalReader::new(bytes.as_slice()).unwrap();
+        let err = reader.next_entry().unwrap_err();
+        assert_eq!(err.to_string(), "The journal's last record is cut short");
+
+        // the header of a record alone
+        let mut bytes = header();
+        bytes.extend_from_slice(&[1, 0, 0]);
+        assert!(JournalReader::new(bytes.as_slice()).unwrap().next_entry().is_err());
+    }
+
+    /// The fixture recordings, `tests/fixtures/<name>`
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    #[test]
+    fn lockstep_handshake() {
+        push_recording(false, 0, None, b"pusher fixture!\n");
+    }
+
+    #[test]
+    fn lockstep_handshake_drained() {
+        // nothing comes after the ready signal until the size header, the drain just waits it out
+        push_recording(true, 0, None, b"pusher fixture!\n");
+    }
+
+    #[test]
+    fn lockstep_kernel_window() {
+        // the size header is the window's, the loader's OK comes after any 4 bytes
+        push_recording(false, 2, Some(5), b"sher ");
+    }
+
+    #[test]
+    fn lockstep_kernel_length_clamped() {
+        push_recording(false, 10, Some(100), b"ture!\n");
+    }
+
+    /// Push the `offset`, `length` window of the fixture kernel to the recorded handshake once
+    /// its ready signal came, `kernel` is what should go out after the size header
+    fn push_recording(drain: bool, offset: u64, length: Option<u64>, kernel: &[u8]) {
+        use crate::capture::Capture;
+        use crate::events::EventLog;
+        use crate::pattern::RollingMatcher;
+        use crate::protocol::Protocol;
+        use crate::push::{self, PushOptions};
+
+        let protocol = Protocol::v1();
+        let mut device = Lockstep::open(&fixture("handshake.pusher-cast")).unwrap();
+        let mut ready_signal = RollingMatcher::new(protocol.ready_signal.clone());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ready_signal.feed(&device.read_all().unwrap()).is_none() {
+            assert!(Instant::now() < deadline, "the recorded ready signal never came");
+        }
+        assert!(device.sent().is_empty());
+
+        let kernel_path = fixture("kernel.bin");
+        let options = PushOptions {
+            kernel_path,
+            kernel_offset: offset,
+            kernel_length: length,
+            dtb_path: None,
+            byte_delay: Duration::ZERO,
+            pre_send_wait: Duration::ZERO,
+            size_retries: 0,
+            drain,
+            raw: false,
+            boards: None,
+        };
+        let stats = push::send_kernel(&mut device, &protocol, &options, &mut EventLog::disabled(),
+            &mut Capture::new(1024), None).unwrap();
+
+        let mut expected = protocol.encode_size(kernel.len() as u64).unwrap();
+        expected.extend(kernel);
+        assert_eq!(device.sent(), expected.as_slice());
+        assert_eq!(stats.bytes_sent, expected.len() as u64);
+        assert_eq!(stats.retries, 0);
+    }
+
+    #[test]
+    fn end_of_journal() {
+        let bytes = header();
+        assert_eq!(JournalReader::new(bytes.as_slice()).unwrap().next_entry().unwrap(), None);
+    }
+}
@@ -0,0 +1,52 @@
+//! The pieces pusher is built from: transports to the device, the wire protocol and the
+//! console helpers. `cli` glues them together into the `pusher` command.
+
+pub mod ansi;
+pub mod autobaud;
+pub mod autodevice;
+pub mod bench;
+pub mod capdump;
+pub mod capture;
+pub mod cli;
+pub mod cobs;
+pub mod commands;
+pub mod completions;
+pub mod config;
+pub mod console;
+pub mod control;
+pub mod defmt;
+pub mod demux;
+pub mod elf;
+pub mod enter;
+pub mod events;
+pub mod fanout;
+pub mod fetch;
+pub mod hexview;
+pub mod hotplug;
+pub mod idle;
+pub mod journal;
+pub mod lines;
+pub mod lock;
+pub mod memory;
+pub mod mirror;
+pub mod output;
+pub mod pattern;
+pub mod power;
+pub mod probe;
+pub mod protocol;
+pub mod pull;
+pub mod push;
+pub mod readyprobe;
+pub mod reset;
+pub mod sendfile;
+pub mod simulate;
+pub mod stats;
+pub mod statushttp;
+pub mod statusline;
+pub mod symbols;
+pub mod tokens;
+pub mod trace;
+pub mod transport;
+pub mod tty;
+pub mod uarterrors;
+pub mod verify;
@@ -0,0 +1,75 @@
+//! Showing the modem control lines (`pusher lines`, Ctrl-A l) and watching them change
+//! (--watch-lines): a flapping CTS explains dropped bytes, a DCD that never comes up a bad cable.
+
+use std::io;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{Result, anyhow};
+
+use crate::transport::{ModemLines, Transport};
+use crate::status;
+
+/// How often --watch-lines looks at the lines
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The lines as they are now, for showing them
+pub fn read(serial_device: &mut dyn Transport) -> Result<ModemLines> {
+    serial_device.modem_lines().map_err(|err| anyhow!("Can't read the modem lines: {}", err))
+}
+
+/// Polls the lines every `POLL_INTERVAL` and prints each one that changed, with the time
+#[derive(Debug)]
+pub struct LineWatch {
+    last: ModemLines,
+    next: Instant,
+}
+
+impl LineWatch {
+    /// Start from the lines as they are now
+    pub fn new(serial_device: &mut dyn Transport) -> Result<Self> {
+        Ok(Self { last: read(serial_device)?, next: Instant::now() + POLL_INTERVAL })
+    }
+
+    /// How long until the lines are due a look
+    pub fn timeout(&self) -> Duration {
+        self.next.saturating_duration_since(Instant::now())
+    }
+
+    /// Look at the lines if it's time, printing the transitions. Returns the lines if any changed.
+    pub fn check(&mut self, serial_device: &mut dyn Transport) -> io::Result<Option<ModemLines>> {
+        if !self.timeout().is_zero() {
+            return Ok(None);
+        }
+        self.next = Instant::now() + POLL_INTERVAL;
+        let lines = serial_device.modem_lines()?;
+        if lines == self.last {
+            return Ok(None);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        for ((name, was), (_, is)) in self.last.lines().into_iter().zip(lines.lines()) {
+            if was != is {
+                status!("\r\n[PUSHER] {}.{:03} {} {}", now.as_secs(), now.subsec_millis(), name,
+                    if is { "on" } else { "off" });
+            }
+        }
+        self.last = lines;
+        Ok(Some(lines))
+    }
+}
+
+/// Show the lines for `pusher lines`, then with `watch` their transitions until interrupted
+pub fn show(serial_device: &mut dyn Transport, watch: bool) -> Result<()> {
+    let lines = read(serial_device)?;
+    for (name, asserted) in lines.lines() {
+        status!("[PUSHER] {:<3} {}", name, if asserted { "on" } else { "off" });
+    }
+    if !watch {
+        return Ok(());
+    }
+    status!("[PUSHER] Watching the lines, Ctrl-C stops");
+    let mut line_watch = LineWatch::new(serial_device)?;
+    loop {
+        sleep(line_watch.timeout());
+        line_watch.check(serial_device)?;
+    }
+}
@@ -0,0 +1,119 @@
+//! Lockfiles for the ports pusher opens, in the UUCP style minicom and friends use: the PID in
+//! `LCK..ttyUSB0` under /var/lock, or /tmp where that isn't writable. The exclusive open only
+//! covers the open itself, a lockfile keeps a second pusher off a port someone else is pushing
+//! through, whoever runs it.
+//!
+//! A lockfile whose process is gone, left by a crash, is taken over. One that can't be told
+//! gone (a PID used again, another PID namespace) is only taken with --steal-lock.
+
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use anyhow::{Result, anyhow, bail};
+
+use crate::stats::format_duration;
+use crate::status;
+
+/// Where lockfiles go, the first one writable
+const LOCK_DIRS: [&str; 2] = ["/var/lock", "/tmp"];
+
+static STEAL: AtomicBool = AtomicBool::new(false);
+
+/// Take the lockfiles of ports held by a process that's still there too, --steal-lock
+pub fn set_steal(steal: bool) {
+    STEAL.store(steal, Ordering::Relaxed);
+}
+
+/// The lockfile of an open port, removed when it's dropped
+#[derive(Debug)]
+pub struct DeviceLock {
+    path: PathBuf,
+}
+
+impl DeviceLock {
+    /// Lock the port at `device`, failing with who holds it if another process does
+    pub fn acquire(device: &Path) -> Result<Self> {
+        // /dev/serial/by-id names lock the tty they point to
+        let device = fs::canonicalize(device).unwrap_or_else(|_| device.to_path_buf());
+        // named after the path under /dev, `pts_3` for /dev/pts/3
+        let name = device.strip_prefix("/dev").unwrap_or(&device).to_string_lossy()
+            .trim_start_matches('/').replace('/', "_");
+        if name.is_empty() {
+            bail!("{} can't be locked", device.display());
+        }
+        let dir = LOCK_DIRS.iter().map(Path::new).find(|dir| writable(dir)).unwrap_or(Path::new("/tmp"));
+        let path = dir.join(format!("LCK..{}", name));
+        // a second try after removing a stale lockfile, unless someone took it in between
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    // the HDB UUCP format: the PID right aligned in 10 characters
+                    file.write_all(format!("{:>10}\n", std::process::id()).as_bytes())
+                        .map_err(|err| anyhow!("Couldn't write the lockfile {}: {}", path.display(), err))?;
+                    return Ok(Self { path });
+                },
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {},
+                Err(err) => bail!("Couldn't create the lockfile {}: {}", path.display(), err)
+            }
+            let holder = fs::read_to_string(&path).ok().and_then(|content| content.trim().parse::<i32>().ok());
+            let since = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .map(|age| format!(" for {}", format_duration(age))).unwrap_or_default();
+            match holder {
+                Some(pid) if !alive(pid) => {
+                    status!("[PUSHER] Taking over the lock of {}, its process {} is gone", device.display(), pid);
+                },
+                _ if STEAL.load(Ordering::Relaxed) => {
+                    status!("[PUSHER] Stealing the lock of {} from {}", device.display(), describe(holder));
+                },
+                _ => bail!("{} is locked by {}{} ({}). If it's gone, take the lock with --steal-lock",
+                    device.display(), describe(holder), since, path.display())
+            }
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    bail!("Couldn't remove the lockfile {}: {}", path.display(), err);
+                },
+                _ => {}
+            }
+        }
+        bail!("{} was locked again by someone else ({})", device.display(), path.display())
+    }
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        // unless it was stolen meanwhile
+        let ours = fs::read_to_string(&self.path)
+            .is_ok_and(|content| content.trim().parse::<u32>().ok() == Some(std::process::id()));
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Whether the process `pid` is there, even if it's another user's
+fn alive(pid: i32) -> bool {
+    pid > 0 && (unsafe { libc::kill(pid, 0) } == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+/// `pid 1234 (pusher)`, or what's known of the holder
+fn describe(pid: Option<i32>) -> String {
+    match pid {
+        Some(pid) => match fs::read_to_string(format!("/proc/{}/comm", pid)) {
+            Ok(name) => format!("pid {} ({})", pid, name.trim()),
+            Err(_) => format!("pid {}", pid)
+        },
+        None => "someone (the lockfile doesn't give a PID)".to_string()
+    }
+}
+
+fn writable(dir: &Path) -> bool {
+    let Ok(dir) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(dir.as_ptr(), libc::W_OK) == 0 }
+}
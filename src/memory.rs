@@ -0,0 +1,147 @@
+//! Framing of the loader's memory peek / poke commands.
+//!
+//! All integers are little endian, addresses are 8 bytes wide.
+//!
+//! | request | host -> device                                  | device -> host                        |
+//! |---------|-------------------------------------------------|---------------------------------------|
+//! | read    | `'r'` addr(8) len(2)                            | `'r'` len(2) data(len)                |
+//! | write   | `'w'` addr(8) len(2) data(len)                  | `'w'` `OK`                            |
+
+use anyhow::{Result, bail};
+
+/// Opcode of a read request and its response
+pub const READ_OPCODE: u8 = b'r';
+/// Opcode of a write request and its response
+pub const WRITE_OPCODE: u8 = b'w';
+/// Largest amount of memory a single request can cover
+pub const MAX_LEN: usize = u16::MAX as usize;
+
+/// A request to the loader
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request {
+    Read { address: u64, len: u16 },
+    Write { address: u64, data: Vec<u8> },
+}
+
+/// A response from the loader
+#[derive(Debug, PartialEq, Eq)]
+pub enum Response {
+    Read(Vec<u8>),
+    WriteAck,
+}
+
+impl Request {
+    /// Serialize the request to its wire format
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        match self {
+            Request::Read { address, len } => {
+                frame.push(READ_OPCODE);
+                frame.extend_from_slice(&address.to_le_bytes());
+                frame.extend_from_slice(&len.to_le_bytes());
+            },
+            Request::Write { address, data } => {
+                if data.len() > MAX_LEN {
+                    bail!("Can't write {} bytes in one request, the maximum is {}", data.len(), MAX_LEN);
+                }
+                frame.push(WRITE_OPCODE);
+                frame.extend_from_slice(&address.to_le_bytes());
+                frame.extend_from_slice(&(data.len() as u16).to_le_bytes());
+                frame.extend_from_slice(data);
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Opcode the response to this request starts with
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Request::Read { .. } => READ_OPCODE,
+            Request::Write { .. } => WRITE_OPCODE,
+        }
+    }
+}
+
+/// Try to decode the response to `request` from the start of `buffer`.
+/// Returns the response and how many bytes it took, or `None` if more bytes are needed.
+pub fn decode_response(request: &Request, buffer: &[u8]) -> Result<Option<(Response, usize)>> {
+    let Some(&opcode) = buffer.first() else {
+        return Ok(None);
+    };
+    if opcode != request.opcode() {
+        bail!("Unexpected response opcode {:#04x}", opcode);
+    }
+    match request {
+        Request::Read { .. } => {
+            if buffer.len() < 3 {
+                return Ok(None);
+            }
+            let len = u16::from_le_bytes([buffer[1], buffer[2]]) as usize;
+            if buffer.len() < 3 + len {
+                return Ok(None);
+            }
+            Ok(Some((Response::Read(buffer[3..3 + len].to_vec()), 3 + len)))
+        },
+        Request::Write { .. } => {
+            if buffer.len() < 3 {
+                return Ok(None);
+            }
+            if &buffer[1..3] != b"OK" {
+                bail!("Write wasn't acknowledged, got {:02x?}", &buffer[1..3]);
+            }
+            Ok(Some((Response::WriteAck, 3)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_read() {
+        let request = Request::Read { address: 0x8_0000, len: 0x10 };
+        assert_eq!(request.encode().unwrap(), [b'r', 0x00, 0x00, 0x08, 0, 0, 0, 0, 0, 0x10, 0x00]);
+    }
+
+    #[test]
+    fn encode_write() {
+        let request = Request::Write { address: 0x1234, data: vec![0xde, 0xad] };
+        assert_eq!(request.encode().unwrap(), [b'w', 0x34, 0x12, 0, 0, 0, 0, 0, 0, 0x02, 0x00, 0xde, 0xad]);
+        let too_long = Request::Write { address: 0, data: vec![0; MAX_LEN + 1] };
+        assert!(too_long.encode().is_err());
+    }
+
+    #[test]
+    fn decode_read() {
+        let request = Request::Read { address: 0, len: 3 };
+        let buffer = [b'r', 3, 0, 1, 2, 3, b'x'];
+        let (response, used) = decode_response(&request, &buffer).unwrap().unwrap();
+        assert_eq!(response, Response::Read(vec![1, 2, 3]));
+        assert_eq!(used, 6);
+    }
+
+    #[test]
+    fn decode_write() {
+        let request = Request::Write { address: 0, data: vec![1] };
+        assert_eq!(decode_response(&request, b"wOK").unwrap(), Some((Response::WriteAck, 3)));
+        assert!(decode_response(&request, b"wNO").is_err());
+    }
+
+    #[test]
+    fn short_buffer() {
+        let read = Request::Read { address: 0, len: 3 };
+        for buffer in [&b""[..], b"r", b"r\x03", b"r\x03\x00\x01\x02"] {
+            assert_eq!(decode_response(&read, buffer).unwrap(), None, "{:02x?}", buffer);
+        }
+        let write = Request::Write { address: 0, data: vec![1] };
+        assert_eq!(decode_response(&write, b"wO").unwrap(), None);
+    }
+
+    #[test]
+    fn wrong_opcode() {
+        let read = Request::Read { address: 0, len: 1 };
+        let err = decode_response(&read, b"wOK").unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected response opcode 0x77");
+    }
+}
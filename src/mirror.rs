@@ -0,0 +1,41 @@
+//! Copying device output to a second port (--mirror), for an instrument on a test rig that
+//! captures the boot log. The mirror is write only and never worth ending the session over.
+
+use std::time::Duration;
+
+use crate::protocol;
+use crate::transport::Transport;
+use crate::status;
+
+/// Longest a byte waits for the mirror to take it, a stuck mirror mustn't hold up the session
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The second port and whether writing to it fails
+pub struct Mirror {
+    device: Box<dyn Transport>,
+    name: String,
+    failing: bool,
+}
+
+impl Mirror {
+    pub fn new(mut device: Box<dyn Transport>, name: String) -> Self {
+        device.set_write_timeout(Some(WRITE_TIMEOUT));
+        Self { device, name, failing: false }
+    }
+
+    /// Copy `bytes` to the mirror. A failure is reported once, and again when it works again.
+    pub fn write(&mut self, bytes: &[u8]) {
+        match protocol::write_bytes(self.device.as_mut(), bytes) {
+            Ok(()) if self.failing => {
+                self.failing = false;
+                status!("\r\n[PUSHER] Mirroring to {} again", self.name);
+            },
+            Ok(()) => {},
+            Err(err) if !self.failing => {
+                self.failing = true;
+                status!("\r\n[PUSHER] Couldn't mirror to {}, dropping output until it works again: {}", self.name, err);
+            },
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Where the output goes. pusher's own `[PUSHER]` status lines go to stdout, the device output
+//! follows them unless it's sent to stderr so the two can be redirected apart.
+//!
+//! A run shows them on the `Output` it's given (`with`), so embedders and tests can capture what
+//! it shows (see `SharedBuffer`). `status!` is `println!` to the status writer.
+//!
+//! Status lines come from every layer (the transfer, the link, the reconnect loop...), so rather
+//! than being handed down to each, the `Output` is the current thread's for the run. Runs on
+//! other threads, parallel tests among them, show on their own. Outside of a run it's stdout.
+//!
+//! With --strip-ansi the device output is shown without its CSI sequences (see `ansi`).
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::ansi::AnsiStripper;
+
+/// `println!` to the status writer
+#[macro_export]
+macro_rules! status {
+    () => { $crate::output::status_text("\n") };
+    ($($arg:tt)*) => { $crate::output::status_fmt(format_args!("{}\n", format_args!($($arg)*))) };
+}
+
+/// Showing one read taking longer than this means the terminal can't keep up, while the
+/// kernel's tty buffer (4 KiB) fills up and then drops what comes next
+const SLOW_DISPLAY: Duration = Duration::from_millis(200);
+/// Least time between two overrun warnings
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where a run shows the device output and its status lines
+pub struct Output {
+    pub device: Box<dyn Write>,
+    pub status: Box<dyn Write>,
+}
+
+impl Default for Output {
+    /// Both to stdout
+    fn default() -> Self {
+        Self { device: Box::new(io::stdout()), status: Box::new(io::stdout()) }
+    }
+}
+
+/// The `Output` of the run on this thread, and how it's shown
+struct Shown {
+    output: Output,
+    device_to_stderr: bool,
+    status_to_stderr: bool,
+    /// Strips the device output, with --strip-ansi
+    stripper: Option<AnsiStripper>,
+    verbose: bool,
+}
+
+impl Shown {
+    fn new(output: Output) -> Self {
+        Self { output, device_to_stderr: false, status_to_stderr: false, stripper: None, verbose: false }
+    }
+}
+
+thread_local! {
+    static SHOWN: RefCell<Shown> = RefCell::new(Shown::new(Output::default()));
+}
+
+/// Run `run` showing its output on `output`, then go back to what was shown on before
+pub fn with<T>(output: Output, run: impl FnOnce() -> T) -> T {
+    /// Puts the previous output back even if `run` panics
+    struct Restore(Option<Shown>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if let Some(previous) = self.0.take() {
+                SHOWN.with(|shown| shown.replace(previous));
+            }
+        }
+    }
+
+    let previous = SHOWN.with(|shown| shown.replace(Shown::new(output)));
+    let _restore = Restore(Some(previous));
+    run()
+}
+
+/// Send the device output to stderr instead of the run's device writer
+pub fn set_device_to_stderr(to_stderr: bool) {
+    SHOWN.with(|shown| shown.borrow_mut().device_to_stderr = to_stderr);
+}
+
+/// Send the status lines to stderr instead of the run's status writer
+pub fn set_status_to_stderr(to_stderr: bool) {
+    SHOWN.with(|shown| shown.borrow_mut().status_to_stderr = to_stderr);
+}
+
+/// Show the device output without its CSI sequences
+pub fn set_strip_ansi(strip: bool) {
+    SHOWN.with(|shown| shown.borrow_mut().stripper = strip.then(AnsiStripper::new));
+}
+
+/// Show the `verbose` status lines
+pub fn set_verbose(verbose: bool) {
+    SHOWN.with(|shown| shown.borrow_mut().verbose = verbose);
+}
+
+/// Whether the `verbose` status lines are shown
+pub fn is_verbose() -> bool {
+    SHOWN.with(|shown| shown.borrow().verbose)
+}
+
+/// A status line only shown with --verbose, about what pusher does with the link
+pub fn verbose(message: &str) {
+    if is_verbose() {
+        status!("\r\n[PUSHER] {}", message);
+    }
+}
+
+/// Show status text as is, flushed right away
+pub fn status_text(text: &str) {
+    SHOWN.with(|shown| {
+        let shown = &mut *shown.borrow_mut();
+        match shown.status_to_stderr {
+            true => write(&mut io::stderr(), text.as_bytes()),
+            false => write(&mut shown.output.status, text.as_bytes())
+        }
+    });
+}
+
+/// Show formatted status text, see `status!`
+pub fn status_fmt(args: fmt::Arguments) {
+    match args.as_str() {
+        Some(text) => status_text(text),
+        None => status_text(&args.to_string()),
+    }
+}
+
+/// Show output received from the device, flushed right away
+pub fn device(text: &str) {
+    typed(text.as_bytes());
+}
+
+/// Show what was typed along with the device output, as is
+pub fn typed(bytes: &[u8]) {
+    SHOWN.with(|shown| {
+        let shown = &mut *shown.borrow_mut();
+        match shown.device_to_stderr {
+            true => write(&mut io::stderr(), bytes),
+            false => write(&mut shown.output.device, bytes)
+        }
+    });
+}
+
+/// Show raw bytes received from the device, stripped with --strip-ansi
+pub fn device_bytes(bytes: &[u8]) {
+    let stripped = SHOWN.with(|shown| shown.borrow_mut().stripper.as_mut().map(|stripper| stripper.strip(bytes)));
+    device(&String::from_utf8_lossy(stripped.as_deref().unwrap_or(bytes)));
+}
+
+fn write(writer: &mut impl Write, bytes: &[u8]) {
+    // a closed pipe shouldn't take the session down with it
+    let _ = writer.write_all(bytes).and_then(|_| writer.flush());
+}
+
+/// A writer into memory that stays readable after being handed to an `Output`, through a clone
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything written so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Warns, at most every `WARNING_INTERVAL`, when the device output comes in faster than it's
+/// shown, since the bytes that don't fit in the OS buffers meanwhile are lost
+#[derive(Debug, Default)]
+pub struct OverrunWarning {
+    last_warning: Option<Instant>,
+}
+
+impl OverrunWarning {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check how long showing the last read took
+    pub fn check(&mut self, display_time: Duration) {
+        if display_time < SLOW_DISPLAY {
+            return;
+        }
+        if self.last_warning.is_some_and(|last| last.elapsed() < WARNING_INTERVAL) {
+            return;
+        }
+        self.last_warning = Some(Instant::now());
+        status!("\r\n[PUSHER] Warning: the device output arrives faster than it can be shown, some may be lost. \
+            Try --echo-received-to-stderr 2>file to capture it to a file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture() {
+        let (status, device) = (SharedBuffer::new(), SharedBuffer::new());
+        let output = Output { device: Box::new(device.clone()), status: Box::new(status.clone()) };
+        with(output, || {
+            status!("[PUSHER] capture test {}", 42);
+            device_bytes(b"captured device output\r\n");
+        });
+        status!("[PUSHER] after the capture test");
+
+        assert_eq!(status.contents(), b"[PUSHER] capture test 42\n");
+        assert_eq!(device.contents(), b"captured device output\r\n");
+    }
+
+    #[test]
+    fn capture_stripped() {
+        let device = SharedBuffer::new();
+        let output = Output { device: Box::new(device.clone()), status: Box::new(io::sink()) };
+        with(output, || {
+            set_strip_ansi(true);
+            device_bytes(b"\x1b[1;32mgreen\x1b[0m\r\n");
+        });
+        assert_eq!(device.contents(), b"green\r\n");
+    }
+}
@@ -0,0 +1,82 @@
+//! Byte patterns given on the command line and matching them in a stream.
+
+use anyhow::{Result, bail};
+
+/// Parse a byte pattern: text where `\xNN`, `\r`, `\n`, `\t`, `\0` and `\\` are escapes,
+/// e.g. `<<READY>>` or `\x03\x03\x03`
+pub fn parse_pattern(text: &str) -> Result<Vec<u8>> {
+    let mut pattern = Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            pattern.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let hex = String::from_utf8_lossy(&hex).into_owned();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 => pattern.push(byte),
+                    _ => bail!("Invalid escape \\x{} in \"{}\"", hex, text)
+                }
+            },
+            Some(b'r') => pattern.push(b'\r'),
+            Some(b'n') => pattern.push(b'\n'),
+            Some(b't') => pattern.push(b'\t'),
+            Some(b'0') => pattern.push(0),
+            Some(b'\\') => pattern.push(b'\\'),
+            Some(other) => bail!("Invalid escape \\{} in \"{}\"", other as char, text),
+            None => bail!("Trailing \\ in \"{}\"", text)
+        }
+    }
+    if pattern.is_empty() {
+        bail!("Empty pattern");
+    }
+    Ok(pattern)
+}
+
+/// Looks for a pattern in a stream fed in arbitrary chunks
+pub struct RollingMatcher {
+    pattern: Vec<u8>,
+    /// The last `pattern.len()` bytes seen
+    window: Vec<u8>,
+}
+
+impl RollingMatcher {
+    pub fn new(pattern: Vec<u8>) -> Self {
+        Self { window: Vec::with_capacity(pattern.len()), pattern }
+    }
+
+    /// Feed bytes, returns the index in `bytes` right after the first match, if any.
+    /// The matcher starts over after a match.
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<usize> {
+        for (index, &byte) in bytes.iter().enumerate() {
+            if self.window.len() == self.pattern.len() {
+                self.window.remove(0);
+            }
+            self.window.push(byte);
+            if self.window == self.pattern {
+                self.window.clear();
+                return Some(index + 1);
+            }
+        }
+        None
+    }
+
+    /// How many bytes of the pattern the last bytes fed are the start of
+    pub fn partial_match(&self) -> usize {
+        (0..=self.window.len()).rev()
+            .find(|&len| self.window.ends_with(&self.pattern[..len]))
+            .unwrap_or(0)
+    }
+
+    /// Forget the bytes seen so far
+    pub fn reset(&mut self) {
+        self.window.clear();
+    }
+
+    pub fn pattern(&self) -> &[u8] {
+        &self.pattern
+    }
+}
@@ -0,0 +1,101 @@
+//! Power cycling the board, for when nothing short of cutting its power brings it back.
+//!
+//! Either a user command does it (e.g. `uhubctl -l 1-1.4 -p 2 -a cycle`), or pusher switches a
+//! USB hub port off and on itself through the port's `disable` attribute in sysfs, the per-port
+//! power control uhubctl uses on Linux 6.0 and later. Both need a hub that really switches
+//! port power, many only pretend to.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+use anyhow::{Result, anyhow, bail};
+
+/// Failed pushes in a row after which the board is power cycled, by default
+pub const DEFAULT_FAILURES: u32 = 3;
+/// How long a hub port is left off, enough for the board's capacitors to drain
+const POWER_OFF_TIME: Duration = Duration::from_secs(2);
+
+/// How to power cycle the board
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PowerCycle {
+    /// A shell command that does it all
+    Command(String),
+    /// A port of a USB hub switched off and on again
+    HubPort(HubPort),
+}
+
+/// A `--hub-location` argument: `<hub>:port=<n>`, the hub as uhubctl names it (e.g. `1-1.4`,
+/// or just the bus number for a root hub)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HubPort {
+    hub: String,
+    port: u32,
+}
+
+impl HubPort {
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid hub location \"{}\", expected <hub>:port=<n> like 1-1.4:port=2", text);
+        let (hub, port) = text.split_once(":port=").ok_or_else(invalid)?;
+        if hub.is_empty() || !hub.chars().all(|c| c.is_ascii_digit() || c == '-' || c == '.') {
+            return Err(invalid());
+        }
+        let port = port.parse().map_err(|_| invalid())?;
+        Ok(Self { hub: hub.to_string(), port })
+    }
+
+    /// The port's `disable` attribute, under the hub's first interface
+    fn disable_path(&self) -> PathBuf {
+        let (interface, port) = if self.hub.contains('-') {
+            (format!("{}:1.0", self.hub), format!("{}-port{}", self.hub, self.port))
+        } else {
+            // root hubs are `usbN` but their interface is `N-0:1.0`
+            (format!("{}-0:1.0", self.hub), format!("usb{}-port{}", self.hub, self.port))
+        };
+        PathBuf::from("/sys/bus/usb/devices").join(interface).join(port).join("disable")
+    }
+
+    fn set_disabled(&self, disabled: bool) -> Result<()> {
+        let path = self.disable_path();
+        fs::write(&path, if disabled { "1" } else { "0" }).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => anyhow!("{} doesn't exist, is the hub there? Switching ports needs \
+                Linux 6.0 or later, use --power-cycle-cmd with uhubctl otherwise", path.display()),
+            io::ErrorKind::PermissionDenied => anyhow!("Permission denied writing {}, it takes root or a \
+                udev rule granting access to it", path.display()),
+            _ => anyhow!("Couldn't write {}: {}", path.display(), err)
+        })
+    }
+}
+
+impl PowerCycle {
+    /// Cut the board's power and give it back, the device node goes away meanwhile
+    pub fn run(&self) -> Result<()> {
+        match self {
+            PowerCycle::Command(command) => {
+                let status = Command::new("sh").arg("-c").arg(command).status()
+                    .map_err(|err| anyhow!("Couldn't run `{}`: {}", command, err))?;
+                if !status.success() {
+                    bail!("`{}` failed ({})", command, status);
+                }
+                Ok(())
+            },
+            PowerCycle::HubPort(hub_port) => {
+                hub_port.set_disabled(true)?;
+                sleep(POWER_OFF_TIME);
+                hub_port.set_disabled(false)
+            }
+        }
+    }
+}
+
+impl fmt::Display for PowerCycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowerCycle::Command(command) => write!(f, "running `{}`", command),
+            PowerCycle::HubPort(HubPort { hub, port }) => write!(f, "hub {} port {}", hub, port),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Finding the baud rate a loader talks at by trying each candidate in turn.
+
+use std::time::{Duration, Instant};
+use anyhow::{Result, bail};
+
+use crate::pattern::RollingMatcher;
+use crate::protocol;
+use crate::transport::Transport;
+use crate::status;
+
+/// How many times the list of rates is tried before giving up
+const PROBE_ROUNDS: usize = 3;
+
+/// What locked the probe on a rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeMatch {
+    /// The device sent its ready signal, it's waiting for a kernel
+    ReadySignal,
+    /// The device sent the known good pattern
+    KnownGood,
+}
+
+/// Switch the device through `baud_rates`, listening `dwell` at each one, until the ready signal
+/// or `known_good` shows up. The device is left at the rate that produced it.
+pub fn probe_bauds(serial_device: &mut dyn Transport, baud_rates: &[u32], dwell: Duration,
+    ready_signal: &[u8], known_good: Option<&[u8]>) -> Result<(u32, ProbeMatch)>
+{
+    let mut waiter = protocol::ReadableWaiter::new(serial_device)?;
+    for _ in 0..PROBE_ROUNDS {
+        for &baud_rate in baud_rates {
+            serial_device.set_baud_rate(baud_rate)?;
+            // whatever arrived at the previous rate is garbage
+            serial_device.clear_input()?;
+            let mut ready = RollingMatcher::new(ready_signal.to_vec());
+            let mut good = known_good.map(|pattern| RollingMatcher::new(pattern.to_vec()));
+            let deadline = Instant::now() + dwell;
+            loop {
+                let now = Instant::now();
+                if now >= deadline || !waiter.wait(Some(deadline - now))? {
+                    break;
+                }
+                let received = serial_device.read_all()?;
+                if ready.feed(&received).is_some() {
+                    status!("[PUSHER] Got the ready signal at {} baud", baud_rate);
+                    return Ok((baud_rate, ProbeMatch::ReadySignal));
+                }
+                if good.as_mut().and_then(|good| good.feed(&received)).is_some() {
+                    status!("[PUSHER] Got the known good pattern at {} baud", baud_rate);
+                    return Ok((baud_rate, ProbeMatch::KnownGood));
+                }
+            }
+            status!("[PUSHER] Nothing recognizable at {} baud", baud_rate);
+        }
+    }
+    bail!("None of the baud rates produced the ready signal or the known good pattern")
+}
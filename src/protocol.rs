@@ -0,0 +1,752 @@
+//! Wire format shared by the push and pull directions, described by `Protocol`.
+//!
+//! # Push (host -> device)
+//! 1. The device sends `ready_signal` when it's ready to receive.
+//! 2. The host sends the image size, `size_width` bytes in `byte_order`.
+//! 3. The device answers `ack`. Or, if the loader was given a `size_nak`, it answers that to have
+//!    the size header sent again (a few times at most) rather than letting the host time out.
+//!    With `ack_status`, the `ack` may be followed by a status for the host to report, e.g. the
+//!    memory bank the loader picked: spaces or tabs, then the status up to the end of the line
+//!    (CR or LF), `OK 2\r\n`. The status is trimmed and at most `MAX_ACK_STATUS` bytes. A bare
+//!    `ack` (anything else right after it, or nothing within `ACK_STATUS_WAIT`) has no status.
+//! 4. The host sends the image, followed by its `checksum` if there is one.
+//!
+//! # Identify (optional, right after the ready signal)
+//! With `identify`, the host asks the loader who it is before anything else of a push:
+//! 1. The host sends `identify_request`, one RS (0x1e) byte.
+//! 2. The loader answers `identify_request`, one length byte, then that many bytes of identity:
+//!    whatever it wants to say about itself, like `bootx 1.4 rev C 32M` for its name, version,
+//!    board revision and how much it can load.
+//! 3. The push goes on with the negotiation or the size header.
+//!
+//! A loader that doesn't answer within `IDENTIFY_TIMEOUT` gets the push as if it wasn't asked.
+//! It must ignore the request byte then, a v1 loader takes it for the start of the size header.
+//!
+//! # Negotiation (optional, at the start of a push)
+//! With `negotiate`, the host finds out what the loader supports instead of both ends being
+//! configured alike. Between steps 1 and 2 of a push:
+//! 1. The host sends `negotiate_request` (three SYN, 0x16, bytes) followed by one capabilities
+//!    byte: bit 0 (`CAP_CRC16`) for a CRC-16/CCITT-FALSE trailer, bit 1 (`CAP_CRC32`) for a
+//!    CRC-32/ISO-HDLC trailer. Bits 2 to 7 are reserved (for compression and chunked transfers)
+//!    and sent as 0.
+//! 2. The loader answers `negotiate_request` followed by its selection byte: at most one of
+//!    the offered checksum bits, 0 for no checksum, reserved bits clear.
+//! 3. The push goes on with the size header, the payload ending with the selected checksum.
+//!
+//! A loader that doesn't answer within `NEGOTIATE_TIMEOUT` gets the v1 push, with `checksum`.
+//! The probe is four bytes, so a v1 loader with a 4 bytes size header reads it as a size of
+//! about 50 MB (370 MB big endian): loaders that refuse it and signal again, or that ignore SYN
+//! bytes, still work.
+//! One that takes it and answers `ack` is stuck waiting for a payload, the push fails then.
+//!
+//! # Verify after send (optional, at the end of a push)
+//! With `verify_after_send`, the host checks what actually landed in the loader's memory or
+//! flash rather than what went over the wire. After step 4 of a push:
+//! 1. The host sends `verify_request` (three GS, 0x1d, bytes).
+//! 2. The loader answers `verify_request` followed by the CRC32 (ISO-HDLC) of the kernel as it
+//!    stored it, without the checksum trailer or the DTB, 4 bytes in `byte_order`.
+//! 3. The push fails if the CRC isn't the kernel's, or if the answer doesn't come in time.
+//!
+//! # Pull (device -> host)
+//! 1. The device sends `pull_magic`.
+//! 2. The device sends the file size, `size_width` bytes in `byte_order`.
+//! 3. The host answers `ack`.
+//! 4. The device sends the file followed by its CRC32 (ISO-HDLC), 4 bytes in `byte_order`.
+//! 5. The host answers `ack` if the CRC matches, `nak` otherwise.
+//!
+//! # Read back (device -> host, asked by the host)
+//! Lets `pusher verify` check what a loader holds, for loaders that keep the last image.
+//! 1. The device sends `ready_signal`.
+//! 2. Instead of a size header, the host sends `readback_request`.
+//! 3. The device sends the size of the image it holds, `size_width` bytes in `byte_order`
+//!    (0 if it holds none).
+//! 4. The host answers `ack`.
+//! 5. The device sends the image followed by its CRC32 (ISO-HDLC), 4 bytes in `byte_order`.
+//! 6. The host answers `ack` if the CRC matches, `nak` otherwise. The device then waits for a
+//!    push again, starting over from 1.
+
+use std::io;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{Result, bail};
+use crc::{Crc, CRC_16_IBM_3740, CRC_32_ISO_HDLC};
+use mio::{Events, Interest, Poll, Token};
+
+use crate::output;
+use crate::transport::Transport;
+
+/// CRC used to verify pulled files
+pub const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+/// Width of the CRC32 trailer of a pulled file
+pub const CRC32_WIDTH: usize = 4;
+/// CRC16-CCITT as most small bootloaders implement it (poly 0x1021, init 0xffff, aka CCITT-FALSE)
+pub const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+/// Capability bits of the negotiation
+pub const CAP_CRC16: u8 = 1 << 0;
+pub const CAP_CRC32: u8 = 1 << 1;
+/// Everything pusher can do, offered in the capabilities byte
+pub const CAPABILITIES: u8 = CAP_CRC16 | CAP_CRC32;
+/// How long the loader has to answer the capabilities probe before it's taken for a v1 loader
+pub const NEGOTIATE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long the loader has to answer the identify request before the push goes on without it
+pub const IDENTIFY_TIMEOUT: Duration = Duration::from_millis(300);
+/// Writes in a row that may take nothing before a byte is given up on
+const SHORT_WRITE_RETRIES: u32 = 100;
+/// Wait between two of them
+const SHORT_WRITE_WAIT: Duration = Duration::from_millis(1);
+
+/// Checksum sent after the pushed image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    None,
+    /// CRC-16/CCITT-FALSE, 2 bytes
+    Crc16,
+    /// CRC-32/ISO-HDLC (the zlib / ethernet one), 4 bytes
+    Crc32,
+}
+
+impl Checksum {
+    /// Parse a `--checksum` argument
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "none" => Ok(Checksum::None),
+            "crc16" => Ok(Checksum::Crc16),
+            "crc32" => Ok(Checksum::Crc32),
+            _ => bail!("Unknown checksum \"{}\", expected crc16, crc32 or none", name)
+        }
+    }
+
+    /// Width of the checksum on the wire
+    pub fn width(self) -> usize {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => 2,
+            Checksum::Crc32 => 4,
+        }
+    }
+
+    /// The checksum a loader selected in the negotiation.
+    /// Fails on reserved bits or on more than one checksum.
+    pub fn from_selection(selection: u8) -> Result<Self> {
+        match selection {
+            0 => Ok(Checksum::None),
+            CAP_CRC16 => Ok(Checksum::Crc16),
+            CAP_CRC32 => Ok(Checksum::Crc32),
+            _ => bail!("The loader selected unsupported capabilities {:#04x}", selection)
+        }
+    }
+
+    /// Its bit in the negotiation's capabilities byte, 0 for none
+    pub fn capability(self) -> u8 {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => CAP_CRC16,
+            Checksum::Crc32 => CAP_CRC32,
+        }
+    }
+
+    /// Compute the checksum of `data`
+    pub fn compute(self, data: &[u8]) -> u32 {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => CRC16.checksum(data) as u32,
+            Checksum::Crc32 => CRC32.checksum(data),
+        }
+    }
+}
+
+/// Byte order of multi-byte integers on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+/// Everything that defines the wire format.
+/// Use `Protocol::v1()` for the format pusher always spoke, then adjust fields as needed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Protocol {
+    /// What the device sends to signal it's ready for a kernel
+    pub ready_signal: Vec<u8>,
+    /// Sent by whoever receives a size header, once it's ready for the payload
+    pub ack: Vec<u8>,
+    /// Sent by the host when a pulled file fails verification
+    pub nak: Vec<u8>,
+    /// Sent by the device instead of `ack` when it wants the size header again
+    pub size_nak: Option<Vec<u8>>,
+    /// The device's `ack` may be followed by a status, see the module docs
+    pub ack_status: bool,
+    /// Width of the size header, in bytes (at most 8)
+    pub size_width: usize,
+    /// Byte order of the size header and the CRC trailer
+    pub byte_order: ByteOrder,
+    /// Sequence the device sends to start a pull
+    pub pull_magic: Vec<u8>,
+    /// Sent by the host instead of a size header to read back the device's image
+    pub readback_request: Vec<u8>,
+    /// Ask the loader who it is right after its ready signal
+    pub identify: bool,
+    /// Starts both the identify request and the loader's answer
+    pub identify_request: u8,
+    /// Checksum appended to the pushed image, unless the negotiation picks another
+    pub checksum: Checksum,
+    /// Negotiate the checksum with the loader before the size header
+    pub negotiate: bool,
+    /// Starts both the capabilities probe and the loader's answer
+    pub negotiate_request: Vec<u8>,
+    /// Ask the loader for the CRC32 of what it stored once the payload is sent
+    pub verify_after_send: bool,
+    /// Starts both the verify request and the loader's answer
+    pub verify_request: Vec<u8>,
+}
+
+impl Protocol {
+    /// The original protocol: three ETX (0x03) bytes in a row, 4 bytes little endian size, `OK`.
+    /// Pulls start with three STX (0x02) bytes, mirroring the push break, read backs are asked
+    /// for with three ENQ (0x05) bytes, what the loader stored with three GS (0x1d) bytes, who it is
+    /// with an RS (0x1e) byte.
+    pub fn v1() -> Self {
+        Self {
+            ready_signal: vec![3, 3, 3],
+            ack: b"OK".to_vec(),
+            nak: b"NO".to_vec(),
+            size_nak: None,
+            ack_status: false,
+            size_width: 4,
+            byte_order: ByteOrder::Little,
+            pull_magic: vec![2, 2, 2],
+            readback_request: vec![5, 5, 5],
+            identify: false,
+            identify_request: 0x1e,
+            checksum: Checksum::None,
+            negotiate: false,
+            negotiate_request: vec![0x16, 0x16, 0x16],
+            verify_after_send: false,
+            verify_request: vec![0x1d, 0x1d, 0x1d],
+        }
+    }
+
+    /// Encode a size header, failing if `size` doesn't fit in `size_width` bytes
+    pub fn encode_size(&self, size: u64) -> Result<Vec<u8>> {
+        self.encode_int(size, self.size_width)
+    }
+
+    /// Decode a size header from the start of `bytes`
+    pub fn decode_size(&self, bytes: &[u8]) -> u64 {
+        self.decode_int(&bytes[..self.size_width])
+    }
+
+    /// Encode a CRC32 trailer
+    pub fn encode_crc(&self, crc: u32) -> Vec<u8> {
+        self.encode_int(crc as u64, CRC32_WIDTH).expect("a u32 always fits in 4 bytes")
+    }
+
+    /// Decode a CRC32 trailer from the start of `bytes`
+    pub fn decode_crc(&self, bytes: &[u8]) -> u32 {
+        self.decode_int(&bytes[..CRC32_WIDTH]) as u32
+    }
+
+    /// Compute and encode the checksum trailer of `data`, empty without a checksum
+    pub fn encode_checksum(&self, data: &[u8]) -> Vec<u8> {
+        let checksum = self.checksum.compute(data) as u64;
+        self.encode_int(checksum, self.checksum.width()).expect("a checksum always fits its width")
+    }
+
+    /// Decode a checksum trailer from the start of `bytes`
+    pub fn decode_checksum(&self, bytes: &[u8]) -> u32 {
+        self.decode_int(&bytes[..self.checksum.width()]) as u32
+    }
+
+    fn encode_int(&self, value: u64, width: usize) -> Result<Vec<u8>> {
+        if width < 8 && value >> (8 * width) != 0 {
+            bail!("{} doesn't fit in {} bytes", value, width);
+        }
+        let bytes = value.to_le_bytes()[..width].to_vec();
+        Ok(match self.byte_order {
+            ByteOrder::Little => bytes,
+            ByteOrder::Big => bytes.into_iter().rev().collect()
+        })
+    }
+
+    fn decode_int(&self, bytes: &[u8]) -> u64 {
+        let fold = |value: u64, &byte: &u8| value << 8 | byte as u64;
+        match self.byte_order {
+            ByteOrder::Little => bytes.iter().rev().fold(0, fold),
+            ByteOrder::Big => bytes.iter().fold(0, fold)
+        }
+    }
+}
+
+/// Write `byte` to the device, trying again while the link takes nothing. A byte silently left
+/// out would corrupt what's sent, so one the link never takes is a `WriteZero` error.
+pub fn write_byte(serial_device: &mut dyn Transport, byte: u8) -> io::Result<()> {
+    for _ in 0..SHORT_WRITE_RETRIES {
+        if serial_device.write_byte(byte)? > 0 {
+            return Ok(());
+        }
+        sleep(SHORT_WRITE_WAIT);
+    }
+    Err(io::Error::new(io::ErrorKind::WriteZero,
+        format!("the link took none of byte {:#04x} in {} writes", byte, SHORT_WRITE_RETRIES)))
+}
+
+/// Write every byte of `bytes` to the device
+pub fn write_bytes(serial_device: &mut dyn Transport, bytes: &[u8]) -> Result<()> {
+    for &byte in bytes {
+        write_byte(serial_device, byte)?;
+    }
+    Ok(())
+}
+
+/// Write every byte of `bytes` to the device, pausing `delay` after each one for receivers
+/// that can't take bytes back to back
+pub fn write_paced(serial_device: &mut dyn Transport, bytes: &[u8], delay: Duration) -> Result<()> {
+    if delay.is_zero() {
+        return write_bytes(serial_device, bytes);
+    }
+    for &byte in bytes {
+        write_byte(serial_device, byte)?;
+        sleep(delay);
+    }
+    Ok(())
+}
+
+/// Show device output until `sequence` arrives.
+/// Returns the bytes received after the sequence.
+pub fn wait_for_sequence(serial_device: &mut dyn Transport, sequence: &[u8]) -> Result<Vec<u8>> {
+    let mut window: Vec<u8> = Vec::new();
+    let mut waiter = ReadableWaiter::new(serial_device)?;
+    loop {
+        waiter.wait(None)?;
+        let output_bytes = serial_device.read_all()?;
+        for (i, &byte) in output_bytes.iter().enumerate() {
+            window.push(byte);
+            if window.len() > sequence.len() {
+                window.remove(0);
+            }
+            if window == sequence {
+                return Ok(output_bytes[i + 1..].to_vec());
+            }
+        }
+        output::device_bytes(&output_bytes);
+    }
+}
+
+/// Waits for the device to become readable again and again with the same poll, one for each
+/// loop that waits on the device. Read everything available between waits, the device is
+/// watched edge triggered. Dropping it drops the registration.
+pub struct ReadableWaiter {
+    poll: Poll,
+    events: Events,
+}
+
+impl ReadableWaiter {
+    pub fn new(serial_device: &mut dyn Transport) -> Result<Self> {
+        let poll = Poll::new()?;
+        poll.registry().register(serial_device, Token(0), Interest::READABLE)?;
+        Ok(Self { poll, events: Events::with_capacity(1) })
+    }
+
+    /// Wait at most `timeout`, returns false on timeout. A signal or a wakeup with nothing to
+    /// show only gets the rest of the timeout waited out.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<bool> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match self.poll.poll(&mut self.events, remaining) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                result => result?
+            }
+            if !self.events.is_empty() {
+                return Ok(true);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// Read from the device into `buffer` until it holds at least `len` bytes.
+/// Fails if no data arrives for `timeout`, leaving whatever was received in `buffer`.
+/// `progress` is called with the buffer length after every read.
+pub fn receive_exact(serial_device: &mut dyn Transport, buffer: &mut Vec<u8>, len: usize,
+    timeout: Duration, mut progress: impl FnMut(usize)) -> Result<()>
+{
+    let mut last_data = Instant::now();
+    let mut waiter = ReadableWaiter::new(serial_device)?;
+    while buffer.len() < len {
+        let received = serial_device.read_all()?;
+        if !received.is_empty() {
+            buffer.extend_from_slice(&received);
+            last_data = Instant::now();
+            progress(buffer.len().min(len));
+            continue;
+        }
+        let elapsed = last_data.elapsed();
+        if elapsed >= timeout || !waiter.wait(Some(timeout - elapsed))? {
+            bail!("Timed out after receiving {} of {} bytes", buffer.len(), len);
+        }
+    }
+    Ok(())
+}
+
+/// The device's answer to a size header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeReply {
+    Ack,
+    /// The size header is wanted again
+    Nak,
+}
+
+/// How long the rest of the ack's line has to come, with `Protocol::ack_status`
+pub const ACK_STATUS_WAIT: Duration = Duration::from_millis(100);
+/// Longest ack status kept, in bytes, a longer one is cut
+pub const MAX_ACK_STATUS: usize = 64;
+
+/// Picks the ack (or the size NAK) out of device output.
+/// The device may keep printing while the handshake is going on, so everything around the
+/// ack is handed back to be shown instead of being mistaken for a bad response.
+#[derive(Debug, Clone)]
+pub struct AckScanner {
+    ack: Vec<u8>,
+    nak: Option<Vec<u8>>,
+    pending: Vec<u8>,
+    /// Read the status following the ack, see `with_status`
+    read_status: bool,
+    /// The ack was found, this is what came after it on its line so far
+    status_line: Option<Vec<u8>>,
+    status: Option<String>,
+}
+
+impl AckScanner {
+    pub fn new(ack: &[u8], nak: Option<&[u8]>) -> Self {
+        Self { ack: ack.to_vec(), nak: nak.map(<[u8]>::to_vec), pending: Vec::new(), read_status: false,
+            status_line: None, status: None }
+    }
+
+    /// Also read the status that may follow the ack (`Protocol::ack_status`): the ack is only
+    /// answered once the end of its line came, or `finish` gives up waiting for it
+    pub fn with_status(mut self) -> Self {
+        self.read_status = true;
+        self
+    }
+
+    /// Scan received bytes. Returns the bytes that aren't (and can't become) part of an answer,
+    /// and the answer if one was found. Once it is, everything after it is returned as well.
+    pub fn feed(&mut self, bytes: &[u8]) -> (Vec<u8>, Option<SizeReply>) {
+        if self.status_line.is_some() {
+            return self.feed_status(bytes);
+        }
+        self.pending.extend_from_slice(bytes);
+        let find = |pattern: &[u8]| self.pending.windows(pattern.len()).position(|window| window == pattern);
+        let ack = find(&self.ack).map(|start| (start, self.ack.len(), SizeReply::Ack));
+        let nak = self.nak.as_deref().and_then(|nak| find(nak).map(|start| (start, nak.len(), SizeReply::Nak)));
+        // the first answer counts
+        if let Some((start, len, reply)) = [ack, nak].into_iter().flatten().min_by_key(|&(start, ..)| start) {
+            let mut shown: Vec<u8> = self.pending.drain(..).collect();
+            let after = shown.split_off(start + len);
+            shown.truncate(start);
+            if reply == SizeReply::Ack && self.read_status {
+                self.status_line = Some(Vec::new());
+                let (rest, reply) = self.feed_status(&after);
+                shown.extend(rest);
+                return (shown, reply);
+            }
+            shown.extend(after);
+            return (shown, Some(reply));
+        }
+        // keep a possible partial answer at the end, hand back the rest
+        let longest = self.ack.len().max(self.nak.as_ref().map_or(0, Vec::len));
+        let keep = self.pending.len().min(longest - 1);
+        (self.pending.drain(..self.pending.len() - keep).collect(), None)
+    }
+
+    /// Read the rest of the ack's line, the ack is answered once it ended
+    fn feed_status(&mut self, bytes: &[u8]) -> (Vec<u8>, Option<SizeReply>) {
+        let Some(line) = &mut self.status_line else {
+            return (bytes.to_vec(), None);
+        };
+        let Some(&first) = line.first().or(bytes.first()) else {
+            return (Vec::new(), None);
+        };
+        // no status, what follows is output
+        if first != b' ' && first != b'\t' {
+            self.status_line = None;
+            return (bytes.to_vec(), Some(SizeReply::Ack));
+        }
+        match bytes.iter().position(|&byte| byte == b'\r' || byte == b'\n') {
+            Some(end) => {
+                line.extend_from_slice(&bytes[..end]);
+                self.finish();
+                (bytes[end..].to_vec(), Some(SizeReply::Ack))
+            },
+            None => {
+                line.extend_from_slice(bytes);
+                // a status never ending, the rest of it goes to the output
+                if line.len() > MAX_ACK_STATUS {
+                    return (Vec::new(), self.finish());
+                }
+                (Vec::new(), None)
+            }
+        }
+    }
+
+    /// Stop waiting for the end of the ack's line, returning the ack if it came. Whatever came
+    /// after it is its status.
+    pub fn finish(&mut self) -> Option<SizeReply> {
+        let line = self.status_line.take()?;
+        let status = String::from_utf8_lossy(&line[..line.len().min(MAX_ACK_STATUS)]).trim().to_string();
+        self.status = Some(status).filter(|status| !status.is_empty());
+        Some(SizeReply::Ack)
+    }
+
+    /// Whether the ack came and the rest of its line is awaited
+    pub fn reading_status(&self) -> bool {
+        self.status_line.is_some()
+    }
+
+    /// The status that followed the ack, once it's answered
+    pub fn status(&self) -> Option<&str> {
+        self.status.as_deref()
+    }
+}
+
+/// Picks the loader's answer to a request, the request itself followed by `len` bytes, out of
+/// device output: the selection byte answering the capabilities probe, the CRC answering the
+/// verify request. Like `AckScanner`, everything around it is handed back.
+#[derive(Debug, Clone)]
+pub struct ReplyScanner {
+    request: Vec<u8>,
+    len: usize,
+    pending: Vec<u8>,
+}
+
+impl ReplyScanner {
+    pub fn new(request: &[u8], len: usize) -> Self {
+        Self { request: request.to_vec(), len, pending: Vec::new() }
+    }
+
+    /// Scan received bytes. Returns the bytes that aren't part of the answer, and the `len`
+    /// bytes after the request once they all arrived.
+    pub fn feed(&mut self, bytes: &[u8]) -> (Vec<u8>, Option<Vec<u8>>) {
+        self.pending.extend_from_slice(bytes);
+        let request_len = self.request.len();
+        match self.pending.windows(request_len).position(|window| window == self.request.as_slice()) {
+            Some(start) if self.pending.len() >= start + request_len + self.len => {
+                let mut shown: Vec<u8> = self.pending.drain(..).collect();
+                let reply = shown.drain(start..start + request_len + self.len).skip(request_len).collect();
+                (shown, Some(reply))
+            },
+            // the rest of the answer is still to come
+            Some(start) => (self.pending.drain(..start).collect(), None),
+            None => {
+                let keep = self.pending.len().min(request_len - 1);
+                (self.pending.drain(..self.pending.len() - keep).collect(), None)
+            }
+        }
+    }
+}
+
+/// Finds the loader's answer to the identify request: the request byte, a length byte, then that
+/// many bytes of identity. Like `ReplyScanner`, everything around it is handed back.
+#[derive(Debug, Clone)]
+pub struct IdentityScanner {
+    request: u8,
+    pending: Vec<u8>,
+}
+
+impl IdentityScanner {
+    pub fn new(request: u8) -> Self {
+        Self { request, pending: Vec::new() }
+    }
+
+    /// Scan received bytes. Returns the bytes that aren't part of the answer, and the identity
+    /// once it all arrived, trimmed and with control characters as spaces.
+    pub fn feed(&mut self, bytes: &[u8]) -> (Vec<u8>, Option<String>) {
+        self.pending.extend_from_slice(bytes);
+        let Some(start) = self.pending.iter().position(|&byte| byte == self.request) else {
+            return (self.pending.drain(..).collect(), None);
+        };
+        match self.pending.get(start + 1) {
+            Some(&len) if self.pending.len() >= start + 2 + len as usize => {
+                let mut shown: Vec<u8> = self.pending.drain(..).collect();
+                let identity: Vec<u8> = shown.drain(start..start + 2 + len as usize).skip(2).collect();
+                let identity = String::from_utf8_lossy(&identity).chars()
+                    .map(|c| if c.is_control() { ' ' } else { c })
+                    .collect::<String>();
+                (shown, Some(identity.trim().to_string()))
+            },
+            // the rest of the answer is still to come
+            _ => (self.pending.drain(..start).collect(), None)
+        }
+    }
+}
+
+/// Wait at most `timeout` for the protocol's ack, showing the device output around it
+pub fn wait_for_ack(serial_device: &mut dyn Transport, protocol: &Protocol, timeout: Duration) -> Result<()> {
+    let mut scanner = AckScanner::new(&protocol.ack, None);
+    let deadline = Instant::now() + timeout;
+    let mut waiter = ReadableWaiter::new(serial_device)?;
+    loop {
+        let (shown, reply) = scanner.feed(&serial_device.read_all()?);
+        output::device_bytes(&shown);
+        if reply.is_some() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if now >= deadline || !waiter.wait(Some(deadline - now))? {
+            bail!("Didn't receive {} after sending the size, aborting now", String::from_utf8_lossy(&protocol.ack));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::prelude::AsRawFd;
+    use mio::unix::SourceFd;
+    use mio::{event, Registry};
+
+    const CHECK: &[u8] = b"123456789";
+
+    fn protocol(checksum: Checksum, byte_order: ByteOrder) -> Protocol {
+        Protocol { checksum, byte_order, ..Protocol::v1() }
+    }
+
+    /// A link that takes each byte only after refusing it `refusals` times, with nothing to read
+    struct Stingy {
+        link: UnixStream,
+        _device: UnixStream,
+        refusals: u32,
+        refused: u32,
+        written: Vec<u8>,
+    }
+
+    impl Stingy {
+        fn new(refusals: u32) -> Self {
+            let (link, device) = UnixStream::pair().unwrap();
+            Self { link, _device: device, refusals, refused: 0, written: Vec::new() }
+        }
+    }
+
+    impl Transport for Stingy {
+        fn read_all(&mut self) -> io::Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn write_byte(&mut self, byte: u8) -> io::Result<usize> {
+            if self.refused < self.refusals {
+                self.refused += 1;
+                return Ok(0);
+            }
+            self.refused = 0;
+            self.written.push(byte);
+            Ok(1)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn clear_input(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl event::Source for Stingy {
+        fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            SourceFd(&self.link.as_raw_fd()).register(registry, token, interests)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            SourceFd(&self.link.as_raw_fd()).reregister(registry, token, interests)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            SourceFd(&self.link.as_raw_fd()).deregister(registry)
+        }
+    }
+
+    #[test]
+    fn checksum_check_values() {
+        assert_eq!(Checksum::Crc16.compute(CHECK), 0x29b1);
+        assert_eq!(Checksum::Crc32.compute(CHECK), 0xcbf4_3926);
+        assert_eq!(Checksum::None.compute(CHECK), 0);
+    }
+
+    #[test]
+    fn crc16_trailer() {
+        let little = protocol(Checksum::Crc16, ByteOrder::Little);
+        assert_eq!(little.encode_checksum(CHECK), [0xb1, 0x29]);
+        assert_eq!(little.decode_checksum(&[0xb1, 0x29, 0xff]), 0x29b1);
+        let big = protocol(Checksum::Crc16, ByteOrder::Big);
+        assert_eq!(big.encode_checksum(CHECK), [0x29, 0xb1]);
+        assert_eq!(big.decode_checksum(&[0x29, 0xb1]), 0x29b1);
+    }
+
+    #[test]
+    fn crc32_trailer() {
+        let little = protocol(Checksum::Crc32, ByteOrder::Little);
+        assert_eq!(little.encode_checksum(CHECK), [0x26, 0x39, 0xf4, 0xcb]);
+        assert_eq!(little.decode_checksum(&[0x26, 0x39, 0xf4, 0xcb]), 0xcbf4_3926);
+        let big = protocol(Checksum::Crc32, ByteOrder::Big);
+        assert_eq!(big.encode_checksum(CHECK), [0xcb, 0xf4, 0x39, 0x26]);
+        assert_eq!(big.decode_checksum(&[0xcb, 0xf4, 0x39, 0x26]), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn no_checksum_no_trailer() {
+        assert!(protocol(Checksum::None, ByteOrder::Little).encode_checksum(CHECK).is_empty());
+        assert_eq!(Checksum::None.width(), 0);
+        assert_eq!(Checksum::Crc16.width(), 2);
+        assert_eq!(Checksum::Crc32.width(), 4);
+    }
+
+    #[test]
+    fn short_writes_are_retried() {
+        let mut link = Stingy::new(3);
+        write_bytes(&mut link, CHECK).unwrap();
+        assert_eq!(link.written, CHECK);
+        write_paced(&mut link, b"ab", Duration::from_millis(1)).unwrap();
+        assert_eq!(link.written, b"123456789ab");
+    }
+
+    #[test]
+    fn short_write_retry_cap() {
+        let mut link = Stingy::new(SHORT_WRITE_RETRIES - 1);
+        write_byte(&mut link, 0x55).unwrap();
+        assert_eq!(link.written, [0x55]);
+        let mut link = Stingy::new(SHORT_WRITE_RETRIES);
+        let err = write_byte(&mut link, 0x55).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert!(link.written.is_empty());
+    }
+
+    #[test]
+    fn wait_outlasts_signals() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        // a handler, so the signal interrupts the poll instead of being ignored
+        let delivered = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(libc::SIGUSR2, delivered.clone()).unwrap();
+        let waiting = unsafe { libc::pthread_self() } as usize;
+        let signaller = std::thread::spawn(move || {
+            for _ in 0..5 {
+                sleep(Duration::from_millis(40));
+                unsafe { libc::pthread_kill(waiting as libc::pthread_t, libc::SIGUSR2) };
+            }
+        });
+        let mut link = Stingy::new(0);
+        let mut waiter = ReadableWaiter::new(&mut link).unwrap();
+        let started = Instant::now();
+        assert!(!waiter.wait(Some(Duration::from_millis(400))).unwrap());
+        let waited = started.elapsed();
+        signaller.join().unwrap();
+        assert!(delivered.load(Ordering::Relaxed));
+        assert!(waited >= Duration::from_millis(400), "woke up after {:?}", waited);
+        assert!(waited < Duration::from_secs(2), "woke up after {:?}", waited);
+    }
+}
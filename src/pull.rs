@@ -0,0 +1,61 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Result, bail};
+
+use crate::output;
+use crate::protocol::{self, Protocol, CRC32, CRC32_WIDTH};
+use crate::transport::Transport;
+use crate::status;
+
+/// How long to wait for more data once a pull started
+const PULL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait for the device to offer a file and save it to `output_path`.
+/// Device output before the pull starts is shown as usual.
+/// If the transfer stops midway, what was received is saved to `<output_path>.partial`.
+pub fn pull(serial_device: &mut dyn Transport, protocol: &Protocol, output_path: &Path) -> Result<()> {
+    let mut received = protocol::wait_for_sequence(serial_device, &protocol.pull_magic)?;
+    status!("\n[PUSHER] Device started a pull");
+
+    protocol::receive_exact(serial_device, &mut received, protocol.size_width, PULL_TIMEOUT, |_| {})?;
+    let size = protocol.decode_size(&received) as usize;
+    received.drain(..protocol.size_width);
+    status!("[PUSHER] File size: {}", size);
+    protocol::write_bytes(serial_device, &protocol.ack)?;
+
+    let result = protocol::receive_exact(serial_device, &mut received, size + CRC32_WIDTH,
+        PULL_TIMEOUT, |count| print_progress(count.min(size), size));
+    status!();
+    if let Err(err) = result {
+        received.truncate(size);
+        save_partial(output_path, &received)?;
+        return Err(err);
+    }
+
+    let expected_crc = protocol.decode_crc(&received[size..]);
+    received.truncate(size);
+    let crc = CRC32.checksum(&received);
+    if crc != expected_crc {
+        protocol::write_bytes(serial_device, &protocol.nak)?;
+        save_partial(output_path, &received)?;
+        bail!("CRC mismatch: device sent {:#010x}, received data has {:#010x}", expected_crc, crc);
+    }
+    protocol::write_bytes(serial_device, &protocol.ack)?;
+    fs::write(output_path, &received)?;
+    status!("[PUSHER] Saved {} bytes to {}", size, output_path.display());
+    Ok(())
+}
+
+fn print_progress(count: usize, size: usize) {
+    let percent = (count * 100).checked_div(size).unwrap_or(100);
+    output::status_text(&format!("\r[PUSHER] Received {}/{} bytes ({}%)", count, size, percent));
+}
+
+fn save_partial(output_path: &Path, data: &[u8]) -> Result<()> {
+    let mut partial_path = PathBuf::from(output_path).into_os_string();
+    partial_path.push(".partial");
+    fs::write(&partial_path, data)?;
+    status!("[PUSHER] Saved {} received bytes to {}", data.len(), Path::new(&partial_path).display());
+    Ok(())
+}
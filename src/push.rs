@@ -0,0 +1,784 @@
+//! Sending a kernel (and optionally a DTB) to a device that signaled it's ready.
+//! UI-agnostic: status goes through the event log and the progress callback, so embedders
+//! can show the transfer however they like.
+//!
+//! A push is a `Transfer` that the caller drives: hand it what the device sends with
+//! `on_received` and call `step` whenever `timeout` says so. This way an event loop keeps
+//! serving the keyboard during a long push. `send_kernel` drives one to completion.
+//!
+//! A raw push leaves the protocol out: the kernel alone is streamed right away, with no size
+//! header, handshake, checksum or DTB, for loaders that don't speak it yet.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow, bail};
+use sha2::{Digest, Sha256};
+use tracing::{Span, field, info_span};
+
+use crate::capture::Capture;
+use crate::events::{Event, EventLog};
+use crate::hexview::Differ;
+use crate::output;
+use crate::protocol::{self, AckScanner, Checksum, IdentityScanner, Protocol, ReplyScanner, SizeReply, ACK_STATUS_WAIT,
+    CAPABILITIES, CRC32, CRC32_WIDTH, IDENTIFY_TIMEOUT, NEGOTIATE_TIMEOUT};
+use crate::trace::PhaseSpan;
+use crate::transport::Transport;
+use crate::status;
+
+/// How long the device has to acknowledge the size header
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the loader has to answer the verify request, hashing a large image in flash is slow
+pub const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the device has to be quiet before the size header is sent
+pub const DRAIN_SETTLE: Duration = Duration::from_millis(50);
+/// Most bytes sent by one `step`
+pub const CHUNK_SIZE: usize = 256;
+/// A `step` stops sending once it took this long, so slow links and paced pushes don't hold up
+/// the caller's event loop
+pub const STEP_TIME: Duration = Duration::from_millis(20);
+
+/// SHA-256 of an image
+pub type ImageDigest = [u8; 32];
+
+/// Progress callback, called with the bytes sent so far and the total
+pub type Progress<'a> = &'a mut dyn FnMut(u64, u64);
+
+/// What to push and how
+#[derive(Debug, Clone)]
+pub struct PushOptions {
+    pub kernel_path: PathBuf,
+    /// Bytes of the kernel file to skip, e.g. a header the loader doesn't want
+    pub kernel_offset: u64,
+    /// Send only this many bytes from `kernel_offset` (at most the rest of the file)
+    pub kernel_length: Option<u64>,
+    /// Sent right after the kernel, prefixed with its own size record
+    pub dtb_path: Option<PathBuf>,
+    /// Pause after each byte, for receivers that can't take bytes back to back
+    pub byte_delay: Duration,
+    /// Pause once between the ack and the first payload byte, for loaders setting up their buffers
+    pub pre_send_wait: Duration,
+    /// How many times the size header is sent again when the loader answers it with its NAK
+    pub size_retries: u32,
+    /// Read (and show) what the device sent before the ready signal, before sending the size
+    pub drain: bool,
+    /// Send the kernel bytes alone, see the module docs
+    pub raw: bool,
+    /// Push the board's kernel rather than `kernel_path`
+    pub boards: Option<BoardKernels>,
+}
+
+/// Kernels picked per push by the board pushed to (the config's `[boards]`). A board is one of
+/// the words of what the loader says it is (split at whitespace, commas, semicolons and equal
+/// signs, see `Protocol::identify`), or the USB serial of its adapter; the first known one wins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoardKernels {
+    pub kernels: BTreeMap<String, PathBuf>,
+    /// The serial number of the device's USB adapter, if it has one
+    pub usb_serial: Option<String>,
+    /// Boards that aren't in `kernels` get `PushOptions::kernel_path`, rather than no push
+    pub fallback: bool,
+}
+
+/// The kernel `BoardKernels` picked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoardChoice {
+    /// The board found in `BoardKernels::kernels`, `None` for one that isn't, pushed the default kernel
+    pub board: Option<String>,
+    pub kernel: PathBuf,
+}
+
+impl BoardKernels {
+    /// The kernel of the board the loader says it is (`identity`) or the adapter's serial names,
+    /// `default` for another board if falling back, an error otherwise
+    pub fn choose(&self, identity: Option<&str>, default: &Path) -> Result<BoardChoice> {
+        let words = identity.into_iter()
+            .flat_map(|identity| identity.split(|c: char| c.is_whitespace() || matches!(c, ',' | ';' | '=')));
+        let found = words.chain(self.usb_serial.as_deref()).find_map(|board| self.kernels.get_key_value(board));
+        match found {
+            Some((board, kernel)) => Ok(BoardChoice { board: Some(board.clone()), kernel: kernel.clone() }),
+            None if self.fallback => Ok(BoardChoice { board: None, kernel: default.to_path_buf() }),
+            None => bail!("No kernel in [boards] for this board ({}), not pushing. Known boards: {}",
+                self.describe(identity), self.kernels.keys().cloned().collect::<Vec<_>>().join(", "))
+        }
+    }
+
+    /// What's known of the board, for when it isn't in `kernels`
+    pub fn describe(&self, identity: Option<&str>) -> String {
+        let mut said = match identity {
+            Some(identity) => format!("the loader said \"{}\"", identity),
+            None => "the loader didn't say who it is".to_string()
+        };
+        if let Some(serial) = &self.usb_serial {
+            said += &format!(", the adapter's serial is {}", serial);
+        }
+        said
+    }
+
+    /// Hash of every image a push may send, `default` included: which one it sends is only
+    /// known once the loader said who it is
+    pub fn digest(&self, default: &Path) -> Result<ImageDigest> {
+        let mut hasher = Sha256::new();
+        for path in std::iter::once(default).chain(self.kernels.values().map(PathBuf::as_path)) {
+            hasher.update(image_digest(path)?);
+        }
+        Ok(hasher.finalize().into())
+    }
+}
+
+/// Hash the image at `path`, to tell whether it changed since it was last pushed
+pub fn image_digest(path: &Path) -> Result<ImageDigest> {
+    Ok(Sha256::digest(fs::read(path)?).into())
+}
+
+/// The `[offset, offset + length)` window of a `file_size` bytes kernel file, up to the end of
+/// the file without a length or if the length goes past it. Fails if the offset is past the end.
+pub fn kernel_window(file_size: u64, offset: u64, length: Option<u64>) -> Result<Range<u64>> {
+    if offset > file_size {
+        bail!("The kernel offset {} is past the end of the {} bytes file", offset, file_size);
+    }
+    let end = length.map_or(file_size, |length| offset.saturating_add(length).min(file_size));
+    Ok(offset..end)
+}
+
+/// Read the window of the kernel file described by `offset` and `length`, see `kernel_window`
+pub fn read_kernel(path: &Path, offset: u64, length: Option<u64>) -> Result<Vec<u8>> {
+    let mut image = fs::read(path)?;
+    let window = kernel_window(image.len() as u64, offset, length)?;
+    image.truncate(window.end as usize);
+    image.drain(..window.start as usize);
+    Ok(image)
+}
+
+/// Bytes shown from each end of the kernel by `kernel_info`
+const INFO_EDGE: usize = 8;
+
+/// A line saying what a push of the kernel at `path` sends (the window of it given by `offset`
+/// and `length`): the path, where it resolves to, the size, CRC32 and the bytes at both ends
+pub fn kernel_info(path: &Path, offset: u64, length: Option<u64>) -> Result<String> {
+    let image = read_kernel(path, offset, length)?;
+    let resolved = fs::canonicalize(path)?;
+    let mut hex = Differ::new(false);
+    Ok(format!("{} ({}): {} bytes, crc32 {:08x}, first {}, last {}", path.display(), resolved.display(),
+        image.len(), CRC32.checksum(&image), hex.format(&image[..image.len().min(INFO_EDGE)]),
+        hex.format(&image[image.len().saturating_sub(INFO_EDGE)..])))
+}
+
+/// Where a transfer is at
+#[derive(Debug)]
+enum State {
+    /// Device output from before the ready signal is shown, the push begins once there's been
+    /// none until `quiet_until`
+    Draining { quiet_until: Instant },
+    /// The identify request is sent, the loader has until `deadline` to answer
+    Identifying { scanner: IdentityScanner, deadline: Instant },
+    /// The loader said who it is, the handshake goes on with the next step
+    Identified,
+    /// The capabilities probe is sent, the loader has until `deadline` to answer
+    Negotiating { scanner: ReplyScanner, deadline: Instant },
+    /// The loader selected `checksum`, the size header goes out on the next step
+    Negotiated { checksum: Checksum },
+    /// The size header is sent, the device has until `deadline` to answer
+    AwaitingAck { scanner: AckScanner, deadline: Instant },
+    /// The loader answered the size header with its NAK, it goes out again on the next step
+    ResendSize,
+    /// The ack came, the payload goes out from `until`
+    Settling { until: Instant },
+    /// Sending the payload
+    Streaming,
+    /// The verify request is sent, the loader has until `deadline` to answer
+    Verifying { scanner: ReplyScanner, deadline: Instant },
+    /// The loader answered the verify request with the CRC32 of what it stored
+    Verified { crc: u32 },
+    Finished,
+}
+
+/// Whether a transfer needs more steps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Pending,
+    Done,
+}
+
+/// What a finished push took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferStats {
+    /// Everything sent: the handshake, the payload and the verify request
+    pub bytes_sent: u64,
+    /// From the first byte of the handshake until the push was done
+    pub duration: Duration,
+    /// Times the size header was sent again
+    pub retries: u32,
+    /// The checksum trailer sent after the kernel, if the protocol has one
+    pub checksum: Option<u32>,
+}
+
+/// A push in progress
+#[derive(Debug)]
+pub struct Transfer {
+    state: State,
+    /// Everything sent after the ack: kernel, checksum trailer, DTB size record and DTB
+    payload: Vec<u8>,
+    sent: usize,
+    byte_delay: Duration,
+    pre_send_wait: Duration,
+    /// NAKs of the size header answered by sending it again, at most
+    size_retries: u32,
+    /// Times the size header was sent again
+    size_resent: u32,
+    /// The checksum is the negotiated one once the negotiation is over
+    protocol: Protocol,
+    /// The payload starts with the kernel, then comes the checksum trailer
+    kernel_size: usize,
+    /// What to push, the payload is put together from it once the loader said who it is
+    options: PushOptions,
+    /// The kernel picked for the board, with `PushOptions::boards`
+    board: Option<BoardChoice>,
+    /// What followed the loader's ack, with `Protocol::ack_status`
+    ack_status: Option<String>,
+    /// Who the loader said it is, with `Protocol::identify`
+    identity: Option<String>,
+    /// Bytes written to the device, all phases together
+    written: u64,
+    started: Instant,
+    /// How long it took, once it's done
+    took: Option<Duration>,
+    /// The span of the push, and of the phase it's in, see `trace`
+    span: Span,
+    phase: Option<PhaseSpan>,
+}
+
+impl Transfer {
+    /// Start pushing: drain the device if asked to, then send the identify request if asked to,
+    /// else go on with the handshake. The device must have just sent its ready signal.
+    pub fn start(serial_device: &mut dyn Transport, protocol: &Protocol, options: &PushOptions,
+        event_log: &mut EventLog) -> Result<Self>
+    {
+        let mut transfer = Self {
+            state: State::Finished,
+            payload: Vec::new(),
+            sent: 0,
+            byte_delay: options.byte_delay,
+            pre_send_wait: options.pre_send_wait,
+            size_retries: options.size_retries,
+            size_resent: 0,
+            protocol: protocol.clone(),
+            kernel_size: 0,
+            options: options.clone(),
+            board: None,
+            ack_status: None,
+            identity: None,
+            written: 0,
+            started: Instant::now(),
+            took: None,
+            span: info_span!("push", file = field::Empty, size = field::Empty),
+            phase: None,
+        };
+        // whatever the device printed before the ready signal mustn't be taken for the ack, it's
+        // shown as it comes through the caller's poll until the device is quiet
+        if options.drain {
+            transfer.state = State::Draining { quiet_until: Instant::now() + DRAIN_SETTLE };
+        } else {
+            transfer.begin(serial_device, event_log)?;
+        }
+        Ok(transfer)
+    }
+
+    /// The device is drained: send a raw push right away, or the identify request if asked to,
+    /// else go on with the handshake
+    fn begin(&mut self, serial_device: &mut dyn Transport, event_log: &mut EventLog) -> Result<()> {
+        self.started = Instant::now();
+        if self.options.raw {
+            let kernel_image = read_kernel(&self.options.kernel_path, self.options.kernel_offset,
+                self.options.kernel_length)?;
+            let kernel_size = kernel_image.len() as u64;
+            self.span.record("file", field::display(self.options.kernel_path.display()));
+            self.span.record("size", kernel_size);
+            status!("[PUSHER] Sending {} raw bytes, no size header or handshake", kernel_size);
+            event_log.record(Event::SendStart { size: kernel_size })?;
+            self.payload = kernel_image;
+            self.kernel_size = kernel_size as usize;
+            self.pre_send_wait = Duration::ZERO;
+            self.size_retries = 0;
+            self.state = State::Streaming;
+            return Ok(());
+        }
+        self.phase = Some(PhaseSpan::new(info_span!(parent: &self.span, "handshake", ms = field::Empty,
+            bytes = field::Empty)));
+        // the kernel may be the board's, read it once the loader said who it is
+        if self.protocol.identify {
+            protocol::write_paced(serial_device, &[self.protocol.identify_request], self.byte_delay)?;
+            self.count(1);
+            flush(serial_device, "the identify request")?;
+            self.state = State::Identifying {
+                scanner: IdentityScanner::new(self.protocol.identify_request),
+                deadline: Instant::now() + IDENTIFY_TIMEOUT,
+            };
+        } else {
+            self.load(event_log)?;
+            self.handshake(serial_device)?;
+        }
+        Ok(())
+    }
+
+    /// Read the kernel (the board's, with `PushOptions::boards`) and put the payload together:
+    /// the kernel, its checksum trailer, then the DTB if there's one
+    fn load(&mut self, event_log: &mut EventLog) -> Result<()> {
+        let mut kernel_path = self.options.kernel_path.clone();
+        if let Some(boards) = &self.options.boards {
+            let choice = boards.choose(self.identity.as_deref(), &kernel_path)?;
+            match &choice.board {
+                Some(board) => status!("[PUSHER] Board {}: pushing {}", board, choice.kernel.display()),
+                None => status!("[PUSHER] No kernel in [boards] for this board ({}), pushing the default {}",
+                    boards.describe(self.identity.as_deref()), choice.kernel.display())
+            }
+            kernel_path = choice.kernel.clone();
+            self.board = Some(choice);
+        }
+        let kernel_image = read_kernel(&kernel_path, self.options.kernel_offset, self.options.kernel_length)?;
+        let kernel_size = kernel_image.len() as u64;
+        self.span.record("file", field::display(kernel_path.display()));
+        self.span.record("size", kernel_size);
+        status!("[PUSHER] Kernel size: {}", kernel_size);
+        let mut payload = self.protocol.encode_checksum(&kernel_image);
+        payload.splice(0..0, kernel_image);
+
+        // the DTB goes right after the kernel, prefixed with its own size record
+        if let Some(dtb_path) = &self.options.dtb_path {
+            let dtb = fs::read(dtb_path)?;
+            status!("[PUSHER] Sending DTB {} ({} bytes)", dtb_path.display(), dtb.len());
+            payload.extend(self.protocol.encode_size(dtb.len() as u64)?);
+            payload.extend(dtb);
+        }
+
+        event_log.record(Event::SendStart { size: kernel_size })?;
+        if !self.byte_delay.is_zero() {
+            let paced = self.byte_delay * u32::try_from(payload.len()).unwrap_or(u32::MAX);
+            status!("[PUSHER] Pacing the payload at {}us per byte, this takes at least {:.1}s",
+                self.byte_delay.as_micros(), paced.as_secs_f64());
+        }
+        self.payload = payload;
+        self.kernel_size = kernel_size as usize;
+        Ok(())
+    }
+
+    /// Send the capabilities probe if negotiating, the size header otherwise
+    fn handshake(&mut self, serial_device: &mut dyn Transport) -> Result<()> {
+        if self.protocol.negotiate {
+            let mut probe = self.protocol.negotiate_request.clone();
+            probe.push(CAPABILITIES);
+            protocol::write_paced(serial_device, &probe, self.byte_delay)?;
+            self.count(probe.len());
+            flush(serial_device, "the negotiation request")?;
+            self.state = State::Negotiating {
+                scanner: ReplyScanner::new(&self.protocol.negotiate_request, 1),
+                deadline: Instant::now() + NEGOTIATE_TIMEOUT,
+            };
+        } else {
+            self.announce_checksum();
+            self.send_size(serial_device)?;
+        }
+        Ok(())
+    }
+
+    /// Send the size header, the device then has `ACK_TIMEOUT` to answer
+    fn send_size(&mut self, serial_device: &mut dyn Transport) -> Result<()> {
+        // first, send the size of the kernel as the device expects it
+        let size = self.protocol.encode_size(self.kernel_size as u64)?;
+        protocol::write_paced(serial_device, &size, self.byte_delay)?;
+        flush(serial_device, "the size header")?;
+        self.count(size.len());
+        let scanner = AckScanner::new(&self.protocol.ack, self.protocol.size_nak.as_deref());
+        self.state = State::AwaitingAck {
+            scanner: if self.protocol.ack_status { scanner.with_status() } else { scanner },
+            deadline: Instant::now() + ACK_TIMEOUT,
+        };
+        Ok(())
+    }
+
+    /// The loader acknowledged the size header, `status` is what followed its ack
+    fn on_ack(&mut self, status: Option<&str>, event_log: &mut EventLog) -> Result<()> {
+        self.phase = None;
+        event_log.record(Event::Handshake { ok: true })?;
+        let ack = String::from_utf8_lossy(&self.protocol.ack);
+        match status {
+            Some(status) => {
+                event_log.record(Event::AckStatus(status.to_string()))?;
+                status!("[PUSHER] Got response: \"{}\" with status \"{}\", sending image now!", ack, status);
+            },
+            None => status!("[PUSHER] Got response: \"{}\", sending image now!", ack)
+        }
+        self.ack_status = status.map(str::to_string);
+        self.state = match self.pre_send_wait.is_zero() {
+            true => State::Streaming,
+            false => State::Settling { until: Instant::now() + self.pre_send_wait }
+        };
+        Ok(())
+    }
+
+    /// Count bytes sent, in the phase the push is in
+    fn count(&mut self, bytes: usize) {
+        self.written += bytes as u64;
+        if let Some(phase) = &mut self.phase {
+            phase.add(bytes);
+        }
+    }
+
+    /// Switch the payload's trailer to the checksum the loader selected
+    fn use_checksum(&mut self, checksum: Checksum) {
+        let trailer = self.kernel_size..self.kernel_size + self.protocol.checksum.width();
+        self.protocol.checksum = checksum;
+        let encoded = self.protocol.encode_checksum(&self.payload[..self.kernel_size]);
+        self.payload.splice(trailer, encoded);
+    }
+
+    fn announce_checksum(&self) {
+        if self.protocol.checksum != Checksum::None {
+            let width = self.protocol.checksum.width();
+            status!("[PUSHER] Sending {:?} checksum {:02x?}", self.protocol.checksum,
+                &self.payload[self.kernel_size..self.kernel_size + width]);
+        }
+    }
+
+    /// Handle bytes received from the device. Returns the device output to show now: until the
+    /// ack that's everything around it, after it output goes to `capture` so it doesn't
+    /// interleave with the progress output.
+    pub fn on_received(&mut self, bytes: &[u8], event_log: &mut EventLog, capture: &mut Capture) -> Result<Vec<u8>> {
+        match &mut self.state {
+            State::Draining { quiet_until } => {
+                if !bytes.is_empty() {
+                    *quiet_until = Instant::now() + DRAIN_SETTLE;
+                }
+                Ok(bytes.to_vec())
+            },
+            State::Identifying { scanner, .. } => {
+                let (shown, identity) = scanner.feed(bytes);
+                if let Some(identity) = identity {
+                    status!("[PUSHER] Loader identity: \"{}\"", identity);
+                    event_log.record(Event::Identity(identity.clone()))?;
+                    self.identity = Some(identity);
+                    self.state = State::Identified;
+                }
+                Ok(shown)
+            },
+            State::Identified => Ok(bytes.to_vec()),
+            State::Negotiating { scanner, .. } => {
+                let (shown, selection) = scanner.feed(bytes);
+                // the selection scanner may hold the start of the ack back, look at it all
+                if bytes.windows(self.protocol.ack.len()).any(|window| window == self.protocol.ack.as_slice()) {
+                    bail!("The loader took the capabilities probe for a size header (it answered {}), reset it \
+                        and push without --negotiate", String::from_utf8_lossy(&self.protocol.ack));
+                }
+                if let Some(selection) = selection.map(|reply| reply[0]) {
+                    let checksum = Checksum::from_selection(selection)?;
+                    status!("[PUSHER] Negotiated checksum: {:?}", checksum);
+                    self.state = State::Negotiated { checksum };
+                }
+                Ok(shown)
+            },
+            State::Negotiated { .. } => Ok(bytes.to_vec()),
+            State::AwaitingAck { scanner, deadline } => {
+                let (shown, reply) = scanner.feed(bytes);
+                match reply {
+                    Some(SizeReply::Ack) => {
+                        let status = scanner.status().map(str::to_string);
+                        self.on_ack(status.as_deref(), event_log)?;
+                    },
+                    Some(SizeReply::Nak) => {
+                        let nak = String::from_utf8_lossy(self.protocol.size_nak.as_deref().unwrap_or_default());
+                        if self.size_resent == self.size_retries {
+                            event_log.record(Event::Handshake { ok: false })?;
+                            bail!("The loader answered the size header with \"{}\" {} times, aborting now", nak,
+                                self.size_resent + 1);
+                        }
+                        self.size_resent += 1;
+                        event_log.record(Event::SizeNak { retry: self.size_resent })?;
+                        status!("[PUSHER] Got \"{}\", sending the size again (retry {} of {})", nak, self.size_resent,
+                            self.size_retries);
+                        self.state = State::ResendSize;
+                    },
+                    // the ack came, the rest of its line doesn't have long
+                    None if scanner.reading_status() => *deadline = (*deadline).min(Instant::now() + ACK_STATUS_WAIT),
+                    None => {}
+                }
+                Ok(shown)
+            },
+            State::ResendSize => Ok(bytes.to_vec()),
+            State::Verifying { scanner, .. } => {
+                let (shown, reply) = scanner.feed(bytes);
+                capture.feed(&shown);
+                if let Some(reply) = reply {
+                    self.state = State::Verified { crc: self.protocol.decode_crc(&reply) };
+                }
+                Ok(Vec::new())
+            },
+            State::Settling { .. } | State::Streaming | State::Verified { .. } | State::Finished => {
+                capture.feed(bytes);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// How long the caller may wait for device output before calling `step`
+    pub fn timeout(&self) -> Option<Duration> {
+        match &self.state {
+            State::Draining { quiet_until: deadline } | State::Identifying { deadline, .. }
+                | State::Negotiating { deadline, .. }
+                | State::AwaitingAck { deadline, .. } | State::Verifying { deadline, .. } | State::Settling { until: deadline }
+                => Some(deadline.saturating_duration_since(Instant::now())),
+            State::Identified | State::Negotiated { .. } | State::ResendSize | State::Streaming | State::Verified { .. }
+                => Some(Duration::ZERO),
+            State::Finished => None,
+        }
+    }
+
+    /// Move the transfer along: go on with the handshake once the loader said who it is (or had
+    /// its time to), send the size header once the negotiation is over, send up to
+    /// `CHUNK_SIZE` more bytes of the payload (less if that takes longer than `STEP_TIME`), ask
+    /// the loader for the CRC of what it stored once it's all sent, or fail if the ack or that
+    /// CRC is overdue, or if the CRC doesn't match.
+    /// `progress` is called after every step that sent something.
+    pub fn step(&mut self, serial_device: &mut dyn Transport, event_log: &mut EventLog,
+        progress: Option<Progress>) -> Result<Step>
+    {
+        match &self.state {
+            State::Draining { quiet_until } => {
+                if Instant::now() >= *quiet_until {
+                    self.begin(serial_device, event_log)?;
+                }
+                Ok(Step::Pending)
+            },
+            State::Identifying { deadline, .. } => {
+                if Instant::now() >= *deadline {
+                    status!("[PUSHER] The loader didn't say who it is, pushing anyway");
+                    self.load(event_log)?;
+                    self.handshake(serial_device)?;
+                }
+                Ok(Step::Pending)
+            },
+            State::Identified => {
+                self.load(event_log)?;
+                self.handshake(serial_device)?;
+                Ok(Step::Pending)
+            },
+            State::Negotiating { deadline, .. } => {
+                if Instant::now() >= *deadline {
+                    status!("[PUSHER] No answer to the capabilities probe, falling back to the v1 protocol");
+                    self.announce_checksum();
+                    self.send_size(serial_device)?;
+                }
+                Ok(Step::Pending)
+            },
+            &State::Negotiated { checksum } => {
+                self.use_checksum(checksum);
+                self.announce_checksum();
+                self.send_size(serial_device)?;
+                Ok(Step::Pending)
+            },
+            State::AwaitingAck { deadline, .. } => {
+                if Instant::now() < *deadline {
+                    return Ok(Step::Pending);
+                }
+                // the ack came but the end of its line didn't, what came of it is the status
+                if let State::AwaitingAck { scanner, .. } = &mut self.state {
+                    if scanner.finish().is_some() {
+                        let status = scanner.status().map(str::to_string);
+                        self.on_ack(status.as_deref(), event_log)?;
+                        return Ok(Step::Pending);
+                    }
+                }
+                event_log.record(Event::Handshake { ok: false })?;
+                bail!("Didn't receive {} after sending the size, aborting now",
+                    String::from_utf8_lossy(&self.protocol.ack));
+            },
+            State::ResendSize => {
+                self.send_size(serial_device)?;
+                Ok(Step::Pending)
+            },
+            State::Settling { until } => {
+                if Instant::now() >= *until {
+                    self.state = State::Streaming;
+                }
+                Ok(Step::Pending)
+            },
+            State::Streaming => {
+                if self.phase.is_none() {
+                    self.phase = Some(PhaseSpan::new(info_span!(parent: &self.span, "transfer", ms = field::Empty,
+                        bytes = field::Empty)));
+                }
+                let started = Instant::now();
+                let before = self.sent;
+                let end = (self.sent + CHUNK_SIZE).min(self.payload.len());
+                while self.sent < end && started.elapsed() < STEP_TIME {
+                    protocol::write_paced(serial_device, &self.payload[self.sent..=self.sent], self.byte_delay)
+                        .map_err(|err| anyhow!("Couldn't send byte {} of {} of the payload: {}", self.sent,
+                            self.payload.len(), err))?;
+                    self.sent += 1;
+                }
+                self.count(self.sent - before);
+                if let Some(progress) = progress {
+                    progress(self.sent as u64, self.payload.len() as u64);
+                }
+                if self.sent < self.payload.len() {
+                    return Ok(Step::Pending);
+                }
+                flush(serial_device, "the end of the payload")?;
+                event_log.record(Event::SendEnd)?;
+                self.phase = None;
+                if !self.protocol.verify_after_send {
+                    self.finish();
+                    return Ok(Step::Done);
+                }
+                protocol::write_paced(serial_device, &self.protocol.verify_request, self.byte_delay)?;
+                flush(serial_device, "the verify request")?;
+                self.phase = Some(PhaseSpan::new(info_span!(parent: &self.span, "verify", ms = field::Empty,
+                    bytes = field::Empty)));
+                self.count(self.protocol.verify_request.len());
+                self.state = State::Verifying {
+                    scanner: ReplyScanner::new(&self.protocol.verify_request, CRC32_WIDTH),
+                    deadline: Instant::now() + VERIFY_TIMEOUT,
+                };
+                Ok(Step::Pending)
+            },
+            State::Verifying { deadline, .. } => {
+                if Instant::now() >= *deadline {
+                    event_log.record(Event::Verified { ok: false })?;
+                    bail!("The loader didn't answer the verify request within {}s", VERIFY_TIMEOUT.as_secs());
+                }
+                Ok(Step::Pending)
+            },
+            &State::Verified { crc } => {
+                self.phase = None;
+                let expected = CRC32.checksum(&self.payload[..self.kernel_size]);
+                event_log.record(Event::Verified { ok: crc == expected })?;
+                if crc != expected {
+                    bail!("The loader stored an image with CRC32 {:#010x}, the kernel's is {:#010x}", crc, expected);
+                }
+                status!("\n[PUSHER] The loader's CRC32 {:#010x} matches the kernel", crc);
+                self.finish();
+                Ok(Step::Done)
+            },
+            State::Finished => Ok(Step::Done),
+        }
+    }
+
+    fn finish(&mut self) {
+        self.state = State::Finished;
+        self.took = Some(self.started.elapsed());
+    }
+
+    /// What the push took so far, all of it once `step` said it's done
+    pub fn stats(&self) -> TransferStats {
+        let width = self.protocol.checksum.width();
+        let checksum = (width > 0 && self.payload.len() >= self.kernel_size + width)
+            .then(|| self.protocol.decode_checksum(&self.payload[self.kernel_size..self.kernel_size + width]));
+        TransferStats {
+            bytes_sent: self.written,
+            duration: self.took.unwrap_or_else(|| self.started.elapsed()),
+            retries: self.size_resent,
+            checksum,
+        }
+    }
+
+    /// What followed the loader's ack, with `Protocol::ack_status`
+    pub fn ack_status(&self) -> Option<&str> {
+        self.ack_status.as_deref()
+    }
+
+    /// Who the loader said it is, with `Protocol::identify`
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// The kernel picked for the board, with `PushOptions::boards`, once the loader said who it is
+    pub fn board(&self) -> Option<&BoardChoice> {
+        self.board.as_ref()
+    }
+
+    /// Size headers sent again so far
+    pub fn retries(&self) -> u32 {
+        self.size_resent
+    }
+
+    /// Payload bytes sent so far and the payload size
+    pub fn progress(&self) -> (u64, u64) {
+        (self.sent as u64, self.payload.len() as u64)
+    }
+
+    /// Give up on the transfer. The device is left waiting for the rest of the payload,
+    /// it has to be reset (or time out) before it can take another push.
+    pub fn cancel(self, event_log: &mut EventLog) -> Result<()> {
+        let (sent, total) = self.progress();
+        event_log.record(Event::Cancelled { sent, total })?;
+        Ok(())
+    }
+}
+
+/// Flush `what` was just written, a failure means the device may not have all of it
+fn flush(serial_device: &mut dyn Transport, what: &str) -> Result<()> {
+    serial_device.flush().map_err(|err| anyhow!("Couldn't flush {} to the device: {}", what, err))
+}
+
+/// Push the kernel described by `options` and wait until it's all sent, the device must have
+/// just sent its ready signal. Device output during the payload ends up in `capture`.
+/// `progress` is called after every `CHUNK_SIZE` bytes of the payload (kernel, checksum trailer
+/// and DTB with its size record), or every `STEP_TIME` if sending that many takes longer.
+/// Returns what the push took.
+pub fn send_kernel(serial_device: &mut dyn Transport, protocol: &Protocol, options: &PushOptions,
+    event_log: &mut EventLog, capture: &mut Capture, mut progress: Option<Progress>) -> Result<TransferStats>
+{
+    let mut waiter = protocol::ReadableWaiter::new(serial_device)?;
+    let mut transfer = Transfer::start(serial_device, protocol, options, event_log)?;
+    loop {
+        let progress: Option<Progress> = match &mut progress {
+            Some(progress) => Some(*progress),
+            None => None
+        };
+        if transfer.step(serial_device, event_log, progress)? == Step::Done {
+            return Ok(transfer.stats());
+        }
+        if let Some(timeout) = transfer.timeout().filter(|timeout| !timeout.is_zero()) {
+            waiter.wait(Some(timeout))?;
+        }
+        let received = serial_device.read_all()?;
+        output::device_bytes(&transfer.on_received(&received, event_log, capture)?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 16 bytes, "pusher fixture!\n"
+    fn kernel() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/kernel.bin")
+    }
+
+    #[test]
+    fn offset_and_length_inside() {
+        assert_eq!(kernel_window(16, 2, Some(5)).unwrap(), 2..7);
+        assert_eq!(read_kernel(&kernel(), 2, Some(5)).unwrap(), b"sher ");
+        assert_eq!(read_kernel(&kernel(), 7, None).unwrap(), b"fixture!\n");
+        assert_eq!(read_kernel(&kernel(), 0, Some(6)).unwrap(), b"pusher");
+    }
+
+    #[test]
+    fn length_past_the_end() {
+        // clamped to the end of the file, shorter than asked for, which is what the warning checks
+        let window = kernel_window(16, 10, Some(100)).unwrap();
+        assert_eq!(window, 10..16);
+        assert!(window.end - window.start < 100);
+        assert_eq!(read_kernel(&kernel(), 10, Some(100)).unwrap(), b"ture!\n");
+        assert_eq!(kernel_window(16, 10, Some(u64::MAX)).unwrap(), 10..16);
+    }
+
+    #[test]
+    fn offset_at_and_past_the_end() {
+        assert_eq!(kernel_window(16, 16, Some(4)).unwrap(), 16..16);
+        assert_eq!(kernel_window(16, 17, None).unwrap_err().to_string(),
+            "The kernel offset 17 is past the end of the 16 bytes file");
+        assert!(read_kernel(&kernel(), 17, Some(1)).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! Asking for the ready signal (--probe-after): loaders that announce themselves once at power
+//! on leave a pusher attached later waiting forever, unless it pokes them.
+
+use std::time::{Duration, Instant};
+use anyhow::Result;
+
+use crate::events::Event;
+use crate::pattern::RollingMatcher;
+use crate::protocol;
+use crate::transport::Transport;
+use crate::status;
+
+/// Longest wait between two probes, however far the backoff got
+const MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How to probe for the ready signal
+#[derive(Debug, Clone)]
+pub struct ReadyProbe {
+    /// Silence before the first probe, doubled before each next one
+    pub after: Duration,
+    /// Sent to the loader
+    pub probe: Vec<u8>,
+    /// The loader's answer, as good as the ready signal. `None` when it answers with the ready signal.
+    pub response: Option<Vec<u8>>,
+    /// Probes sent at most, then the ready signal is waited for without them
+    pub limit: u32,
+}
+
+impl Default for ReadyProbe {
+    fn default() -> Self {
+        Self { after: Duration::from_secs(5), probe: b"\n".to_vec(), response: None, limit: 5 }
+    }
+}
+
+/// Sends the probes while waiting for the ready signal
+pub struct Prober {
+    probe: ReadyProbe,
+    response: Option<RollingMatcher>,
+    sent: u32,
+    started: Instant,
+    interval: Duration,
+    next: Instant,
+}
+
+impl Prober {
+    /// Start counting the silence
+    pub fn new(probe: ReadyProbe) -> Self {
+        let response = probe.response.clone().map(RollingMatcher::new);
+        Self { interval: probe.after, started: Instant::now(), next: Instant::now() + probe.after, sent: 0, probe,
+            response }
+    }
+
+    /// How long until the next probe, `None` once they're all sent
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.sent < self.probe.limit).then(|| self.next.saturating_duration_since(Instant::now()))
+    }
+
+    /// Send the next probe if it's due. Returns the event of it and the bytes sent.
+    pub fn step(&mut self, serial_device: &mut dyn Transport) -> Result<Option<(Event, usize)>> {
+        if self.timeout().is_none_or(|timeout| !timeout.is_zero()) {
+            return Ok(None);
+        }
+        self.sent += 1;
+        status!("\r\n[PUSHER] No ready signal after {}s, probing the loader ({} of {})",
+            self.started.elapsed().as_secs(), self.sent, self.probe.limit);
+        protocol::write_bytes(serial_device, &self.probe.probe)?;
+        self.interval = (self.interval * 2).min(MAX_INTERVAL);
+        self.next = Instant::now() + self.interval;
+        if self.sent == self.probe.limit {
+            status!("[PUSHER] That was the last probe, waiting for the ready signal");
+        }
+        Ok(Some((Event::Probe { attempt: self.sent }, self.probe.probe.len())))
+    }
+
+    /// Look for the probe's response in device output
+    pub fn feed(&mut self, bytes: &[u8]) -> bool {
+        self.response.as_mut().is_some_and(|response| response.feed(bytes).is_some())
+    }
+}
@@ -0,0 +1,137 @@
+//! Resetting the board, through a host GPIO line wired to the SoC reset or through the link's
+//! own reset line (DTR on serial ports).
+//!
+//! GPIO support needs the `gpio` cargo feature and Linux' GPIO character devices.
+
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+
+use crate::transport::Transport;
+
+/// How long the reset line is held by default
+pub const DEFAULT_PULSE: Duration = Duration::from_millis(100);
+
+/// A `--reset-gpio` argument: `<chip>:<line>[:active_low]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpioSpec {
+    /// `/dev/gpiochipN`, or just `gpiochipN`
+    pub chip: PathBuf,
+    /// Line offset on the chip
+    pub line: u32,
+    /// The board is held in reset while the line is low
+    pub active_low: bool,
+}
+
+impl GpioSpec {
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid GPIO \"{}\", expected <chip>:<line>[:active_low]", text);
+        let mut parts = text.split(':');
+        let chip = parts.next().filter(|chip| !chip.is_empty()).ok_or_else(invalid)?;
+        let line = parts.next().and_then(|line| line.parse().ok()).ok_or_else(invalid)?;
+        let active_low = match parts.next() {
+            None => false,
+            Some("active_low") => true,
+            Some(_) => return Err(invalid())
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        let chip = if chip.starts_with('/') { PathBuf::from(chip) } else { PathBuf::from("/dev").join(chip) };
+        Ok(Self { chip, line, active_low })
+    }
+}
+
+/// A GPIO line held by pusher to reset the board, released (not asserted) between pulses
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+pub struct GpioReset {
+    handle: gpio_cdev::LineHandle,
+}
+
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+impl GpioReset {
+    /// Take the line as an output, not asserting it.
+    /// Permission problems and lines used by someone else get their own error messages.
+    pub fn open(spec: &GpioSpec) -> Result<Self> {
+        use gpio_cdev::{Chip, LineRequestFlags};
+        let mut chip = Chip::new(&spec.chip).map_err(|err| match io_error_kind(&err) {
+            Some(io::ErrorKind::PermissionDenied) => anyhow!("Permission denied opening {}, the user may need to \
+                be in the group owning it (often gpio)", spec.chip.display()),
+            Some(io::ErrorKind::NotFound) => anyhow!("{} doesn't exist", spec.chip.display()),
+            _ => anyhow!("Couldn't open {}: {}", spec.chip.display(), err)
+        })?;
+        let line = chip.get_line(spec.line)
+            .map_err(|_| anyhow!("{} has no line {}, it has {}", spec.chip.display(), spec.line, chip.num_lines()))?;
+        if let Ok(info) = line.info() {
+            if info.is_used() {
+                return Err(anyhow!("Line {} of {} is busy, used by {}", spec.line, spec.chip.display(),
+                    info.consumer().unwrap_or("the kernel")));
+            }
+        }
+        let mut flags = LineRequestFlags::OUTPUT;
+        if spec.active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW;
+        }
+        let handle = line.request(flags, 0, "pusher")
+            .map_err(|err| anyhow!("Couldn't take line {} of {}: {}", spec.line, spec.chip.display(), err))?;
+        Ok(Self { handle })
+    }
+
+    /// Assert the line for `width`, then release it
+    pub fn pulse(&mut self, width: Duration) -> io::Result<()> {
+        let set = |value| self.handle.set_value(value).map_err(|err| io::Error::other(err.to_string()));
+        set(1)?;
+        std::thread::sleep(width);
+        set(0)
+    }
+}
+
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+fn io_error_kind(err: &gpio_cdev::Error) -> Option<io::ErrorKind> {
+    std::error::Error::source(err)?.downcast_ref::<io::Error>().map(io::Error::kind)
+}
+
+/// Stand-in for builds without GPIO support, it can't be opened
+#[cfg(not(all(feature = "gpio", target_os = "linux")))]
+pub struct GpioReset {
+    _unconstructible: (),
+}
+
+#[cfg(not(all(feature = "gpio", target_os = "linux")))]
+impl GpioReset {
+    pub fn open(_spec: &GpioSpec) -> Result<Self> {
+        Err(anyhow!("This pusher was built without GPIO support, rebuild it with `--features gpio` (Linux only)"))
+    }
+
+    pub fn pulse(&mut self, _width: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How the board gets reset: the GPIO line if there's one, the link's reset line otherwise
+pub struct BoardReset {
+    gpio: Option<GpioReset>,
+    pulse: Duration,
+}
+
+impl BoardReset {
+    /// Hold the reset for `pulse` each time
+    pub fn new(gpio: Option<GpioReset>, pulse: Duration) -> Self {
+        Self { gpio, pulse }
+    }
+
+    /// Whether a GPIO line does the resetting, it works without the device connected
+    pub fn has_gpio(&self) -> bool {
+        self.gpio.is_some()
+    }
+
+    /// Pulse the reset. Without a GPIO line, this needs the device (`serial_device`) connected.
+    pub fn reset(&mut self, serial_device: Option<&mut dyn Transport>) -> io::Result<()> {
+        match (&mut self.gpio, serial_device) {
+            (Some(gpio), _) => gpio.pulse(self.pulse),
+            (None, Some(serial_device)) => serial_device.reset(self.pulse),
+            (None, None) => Err(io::Error::new(io::ErrorKind::NotConnected, "the device is disconnected"))
+        }
+    }
+}
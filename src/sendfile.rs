@@ -0,0 +1,125 @@
+//! Sending a local text file to the console line by line (Ctrl-A u, --send-file-on-connect), for
+//! scripts of loader commands too long to paste.
+//!
+//! Lines go out ending with `\r`, like Enter, paced by a delay after each one and, for
+//! interpreters that drop what comes while they're busy, by waiting for their prompt.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow, bail};
+use regex::bytes::Regex;
+
+use crate::protocol;
+use crate::push::Step;
+use crate::transport::Transport;
+use crate::status;
+
+/// Esc, stops a file being sent
+pub const ABORT_BYTE: u8 = 0x1b;
+/// How long the prompt may take to show up after a line
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most device output kept to look for the prompt in
+const PROMPT_WINDOW: usize = 4096;
+
+/// A file being sent
+#[derive(Debug)]
+pub struct FileSend {
+    path: PathBuf,
+    lines: Vec<Vec<u8>>,
+    /// Lines sent so far
+    sent: usize,
+    /// Bytes sent so far, line endings included
+    bytes_sent: u64,
+    char_delay: Duration,
+    line_delay: Duration,
+    prompt: Option<Regex>,
+    /// Device output since the last line, while waiting for the prompt
+    output: Vec<u8>,
+    /// When the next line may go
+    next_line: Instant,
+    /// Give up if the prompt didn't show up by then
+    prompt_deadline: Option<Instant>,
+}
+
+impl FileSend {
+    /// Read the file at `path`. Each byte is followed by `char_delay`, each line by `line_delay`
+    /// and, if given, by `prompt` showing up in the device output.
+    pub fn open(path: &Path, char_delay: Duration, line_delay: Duration, prompt: Option<Regex>) -> Result<Self> {
+        let content = fs::read(path).map_err(|err| anyhow!("Couldn't read {}: {}", path.display(), err))?;
+        let mut lines: Vec<Vec<u8>> = content.split(|&byte| byte == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line).to_vec())
+            .collect();
+        // the newline ending the last line doesn't start another one
+        if lines.last().is_some_and(Vec::is_empty) {
+            lines.pop();
+        }
+        if lines.is_empty() {
+            bail!("{} is empty, nothing to send", path.display());
+        }
+        Ok(Self { path: path.to_path_buf(), lines, sent: 0, bytes_sent: 0, char_delay, line_delay, prompt,
+            output: Vec::new(), next_line: Instant::now(), prompt_deadline: None })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Lines sent so far and the number of lines
+    pub fn progress(&self) -> (usize, usize) {
+        (self.sent, self.lines.len())
+    }
+
+    /// Bytes written to the device so far
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Look for the prompt in device output
+    pub fn on_received(&mut self, bytes: &[u8]) {
+        let Some(prompt) = self.prompt.as_ref().filter(|_| self.prompt_deadline.is_some()) else {
+            return;
+        };
+        self.output.extend_from_slice(bytes);
+        if self.output.len() > PROMPT_WINDOW {
+            self.output.drain(..self.output.len() - PROMPT_WINDOW);
+        }
+        if prompt.is_match(&self.output) {
+            self.prompt_deadline = None;
+            self.output.clear();
+        }
+    }
+
+    /// How long the caller may wait for device output before calling `step`
+    pub fn timeout(&self) -> Duration {
+        self.prompt_deadline.unwrap_or(self.next_line).saturating_duration_since(Instant::now())
+    }
+
+    /// Send the next line if it's time to. Fails if the prompt didn't show up in time.
+    pub fn step(&mut self, serial_device: &mut dyn Transport) -> Result<Step> {
+        if let Some(deadline) = self.prompt_deadline {
+            if Instant::now() >= deadline {
+                bail!("No prompt within {}s of line {} of {}, stopped sending {}", PROMPT_TIMEOUT.as_secs(),
+                    self.sent, self.lines.len(), self.path.display());
+            }
+            return Ok(Step::Pending);
+        }
+        if Instant::now() < self.next_line {
+            return Ok(Step::Pending);
+        }
+        let mut line = self.lines[self.sent].clone();
+        line.push(b'\r');
+        status!("\r\n[PUSHER] Line {} of {}", self.sent + 1, self.lines.len());
+        protocol::write_paced(serial_device, &line, self.char_delay)?;
+        self.sent += 1;
+        self.bytes_sent += line.len() as u64;
+        if self.sent == self.lines.len() {
+            return Ok(Step::Done);
+        }
+        self.next_line = Instant::now() + self.line_delay;
+        if self.prompt.is_some() {
+            self.prompt_deadline = Some(Instant::now() + PROMPT_TIMEOUT);
+        }
+        Ok(Step::Pending)
+    }
+}
@@ -0,0 +1,100 @@
+//! The loader's side of the push protocol, for people writing their own loader:
+//! run `pusher simulate` on one end of a link and the loader's host side on the other.
+
+use std::time::Duration;
+use anyhow::{Result, bail};
+
+use crate::protocol::{self, Checksum, Protocol, CRC32};
+use crate::transport::Transport;
+use crate::status;
+
+/// How long to wait for the host once the ready signal was sent
+const SIMULATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Signal readiness, receive one image and describe it.
+/// Fails if the image doesn't match the protocol's checksum trailer, or if `expected_crc` is
+/// given and doesn't match the image's CRC32. When negotiating, the protocol's checksum is
+/// selected if the host offers it, none otherwise. With `identify`, the host's identify request
+/// is answered with the simulator's name and version. With `verify_after_send`, the image's CRC32
+/// is sent back when the host asks for it.
+pub fn simulate(serial_device: &mut dyn Transport, protocol: &Protocol, expected_crc: Option<u32>) -> Result<()> {
+    status!("[SIMULATOR] Sending ready signal");
+    protocol::write_bytes(serial_device, &protocol.ready_signal)?;
+
+    let mut received = Vec::new();
+    let mut protocol = protocol.clone();
+    if protocol.identify {
+        protocol::receive_exact(serial_device, &mut received, 1, SIMULATE_TIMEOUT, |_| {})?;
+        if received[0] != protocol.identify_request {
+            bail!("Expected the identify request, got {:#04x}", received[0]);
+        }
+        received.drain(..1);
+        let identity = concat!("pusher simulate ", env!("CARGO_PKG_VERSION"));
+        status!("[SIMULATOR] Got the identify request, answering \"{}\"", identity);
+        let mut answer = vec![protocol.identify_request, identity.len() as u8];
+        answer.extend(identity.as_bytes());
+        protocol::write_bytes(serial_device, &answer)?;
+    }
+    if protocol.negotiate {
+        let probe_len = protocol.negotiate_request.len() + 1;
+        protocol::receive_exact(serial_device, &mut received, probe_len, SIMULATE_TIMEOUT, |_| {})?;
+        if !received.starts_with(&protocol.negotiate_request) {
+            bail!("Expected the capabilities probe, got {:02x?}", &received[..probe_len]);
+        }
+        let offered = received[probe_len - 1];
+        received.drain(..probe_len);
+        let selection = protocol.checksum.capability() & offered;
+        protocol.checksum = Checksum::from_selection(selection)?;
+        status!("[SIMULATOR] Offered capabilities {:#04x}, selecting checksum {:?}", offered, protocol.checksum);
+        let mut answer = protocol.negotiate_request.clone();
+        answer.push(selection);
+        protocol::write_bytes(serial_device, &answer)?;
+    }
+    protocol::receive_exact(serial_device, &mut received, protocol.size_width, SIMULATE_TIMEOUT, |_| {})?;
+    let size = protocol.decode_size(&received) as usize;
+    received.drain(..protocol.size_width);
+    status!("[SIMULATOR] Got size header: {} bytes, answering {}", size, String::from_utf8_lossy(&protocol.ack));
+    protocol::write_bytes(serial_device, &protocol.ack)?;
+
+    let checksum_width = protocol.checksum.width();
+    protocol::receive_exact(serial_device, &mut received, size + checksum_width, SIMULATE_TIMEOUT, |_| {})?;
+    if protocol.checksum != Checksum::None {
+        let sent = protocol.decode_checksum(&received[size..]);
+        let computed = protocol.checksum.compute(&received[..size]);
+        status!("[SIMULATOR] {:?} trailer {:#x}, computed {:#x}", protocol.checksum, sent, computed);
+        if sent != computed {
+            bail!("Checksum trailer doesn't match the image");
+        }
+    }
+    let mut following = received.split_off(size + checksum_width);
+    received.truncate(size);
+    let crc = CRC32.checksum(&received);
+    status!("[SIMULATOR] Received {} bytes, CRC32 {:#010x}", received.len(), crc);
+    status!("[SIMULATOR] First bytes: {:02x?}", &received[..received.len().min(16)]);
+    if protocol.verify_after_send {
+        // whatever else follows the image, like a DTB, comes before the request
+        let request = &protocol.verify_request;
+        let start = loop {
+            if let Some(start) = following.windows(request.len()).position(|window| window == request.as_slice()) {
+                break start;
+            }
+            let len = following.len() + 1;
+            protocol::receive_exact(serial_device, &mut following, len, SIMULATE_TIMEOUT, |_| {})?;
+        };
+        following.drain(start..start + request.len());
+        status!("[SIMULATOR] Got the verify request, answering CRC32 {:#010x}", crc);
+        let mut answer = request.clone();
+        answer.extend(protocol.encode_crc(crc));
+        protocol::write_bytes(serial_device, &answer)?;
+    }
+    if !following.is_empty() {
+        status!("[SIMULATOR] {} bytes followed the image", following.len());
+    }
+    if let Some(expected_crc) = expected_crc {
+        if crc != expected_crc {
+            bail!("CRC32 mismatch, expected {:#010x}", expected_crc);
+        }
+        status!("[SIMULATOR] CRC32 matches");
+    }
+    Ok(())
+}
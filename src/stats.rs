@@ -0,0 +1,385 @@
+//! Counters of an interactive session, shown with Ctrl-A s and in the control socket's status,
+//! and with the history of its pushes on SIGUSR1.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde_json::{Value, json};
+
+use crate::push::{BoardChoice, TransferStats};
+use crate::transport::ErrorCounts;
+use crate::status;
+
+/// Pushes kept in the history, older ones are only counted
+const HISTORY_LIMIT: usize = 1000;
+
+/// When a push ended and how
+#[derive(Debug, Clone)]
+pub struct PushRecord {
+    pub time: SystemTime,
+    /// `ok`, `skipped`, `cancelled` or `failed`
+    pub result: &'static str,
+    pub error: Option<String>,
+    /// How long the push took, if it got going
+    pub duration: Option<Duration>,
+    /// The file pushed, the kernel unless another one was pushed with Ctrl-A P
+    pub file: Option<PathBuf>,
+    /// What the UART counted during the push, if the link counts errors
+    pub uart_errors: Option<ErrorCounts>,
+    /// What followed the loader's ack, with --ack-status
+    pub ack_status: Option<String>,
+    /// Who the loader said it is, with --identify
+    pub identity: Option<String>,
+    /// The kernel picked for the board, with the config's `[boards]`
+    pub board: Option<BoardChoice>,
+    /// What the transfer took, for a push that went through
+    pub transfer: Option<TransferStats>,
+}
+
+impl PushRecord {
+    pub fn new(result: &'static str, error: Option<String>) -> Self {
+        Self { time: SystemTime::now(), result, error, duration: None, file: None, uart_errors: None, ack_status: None,
+            identity: None, board: None, transfer: None }
+    }
+
+    /// The record of a push that started at `started`
+    pub fn took(mut self, started: Option<Instant>) -> Self {
+        self.duration = started.map(|started| started.elapsed());
+        self
+    }
+
+    /// The record of a push of `file`
+    pub fn of(mut self, file: Option<PathBuf>) -> Self {
+        self.file = file;
+        self
+    }
+
+    /// The record of a push the UART counted `uart_errors` during
+    pub fn with_uart_errors(mut self, uart_errors: Option<ErrorCounts>) -> Self {
+        self.uart_errors = uart_errors;
+        self
+    }
+
+    /// The record of a push the loader acknowledged with `ack_status`
+    pub fn with_ack_status(mut self, ack_status: Option<&str>) -> Self {
+        self.ack_status = ack_status.map(str::to_string);
+        self
+    }
+
+    /// The record of a push to the loader that said it's `identity`
+    pub fn with_identity(mut self, identity: Option<&str>) -> Self {
+        self.identity = identity.map(str::to_string);
+        self
+    }
+
+    /// The record of a push of the kernel picked for the board
+    pub fn with_board(mut self, board: Option<BoardChoice>) -> Self {
+        self.board = board;
+        self
+    }
+
+    /// The record of a push whose transfer took `stats`, which is how long it took
+    pub fn with_transfer(mut self, stats: TransferStats) -> Self {
+        self.duration = Some(stats.duration);
+        self.transfer = Some(stats);
+        self
+    }
+
+    /// The record as reported by the status request, the time in unix seconds
+    pub fn to_json(&self) -> Value {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut record = json!({ "time": time, "result": self.result, "error": self.error, "file": self.file,
+            "uart_errors": self.uart_errors, "ack_status": self.ack_status, "identity": self.identity });
+        if let Some(transfer) = &self.transfer {
+            record["bytes_sent"] = json!(transfer.bytes_sent);
+            record["duration_ms"] = json!(transfer.duration.as_millis() as u64);
+            record["retries"] = json!(transfer.retries);
+            record["checksum"] = json!(transfer.checksum);
+        }
+        // null for a board that isn't in [boards]
+        if let Some(board) = &self.board {
+            record["board"] = json!(board.board);
+        }
+        record
+    }
+}
+
+/// `1760600000 ok kernel.bin in 2.3s, 1048580 bytes`, then the retries, the board, the loader's
+/// identity, the ack status, the UART errors and the error if any
+impl fmt::Display for PushRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(), self.result)?;
+        if let Some(file) = &self.file {
+            write!(f, " {}", file.display())?;
+        }
+        if let Some(duration) = self.duration {
+            write!(f, " in {}", format_duration(duration))?;
+        }
+        if let Some(transfer) = &self.transfer {
+            write!(f, ", {} bytes", transfer.bytes_sent)?;
+            if transfer.retries > 0 {
+                write!(f, ", {} retries", transfer.retries)?;
+            }
+        }
+        match self.board.as_ref().map(|board| &board.board) {
+            Some(Some(board)) => write!(f, ", board {}", board)?,
+            Some(None) => write!(f, ", board not in [boards]")?,
+            None => {}
+        }
+        if let Some(identity) = &self.identity {
+            write!(f, ", loader {}", identity)?;
+        }
+        if let Some(ack_status) = &self.ack_status {
+            write!(f, ", ack status {}", ack_status)?;
+        }
+        if let Some(uart_errors) = self.uart_errors.filter(|uart_errors| !uart_errors.is_zero()) {
+            write!(f, ", UART errors: {}", uart_errors)?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, ": {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the session went so far, kept across reconnects
+#[derive(Debug)]
+pub struct SessionStats {
+    started: Instant,
+    /// Bytes read from the device
+    received: u64,
+    /// Bytes written to the device: keystrokes, console input, keepalives and payloads
+    sent: u64,
+    last_output: Option<Instant>,
+    pushed: u32,
+    failed: u32,
+    skipped: u32,
+    cancelled: u32,
+    /// Size headers sent again because the loader answered them with its NAK
+    retries: u32,
+    /// Times the device was opened again after it went away or couldn't be opened
+    reconnects: u32,
+    /// The last `HISTORY_LIMIT` pushes, the last one last
+    history: VecDeque<PushRecord>,
+    /// What the UART counted, if the link counts errors
+    uart_errors: Option<ErrorCounts>,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self { started: Instant::now(), received: 0, sent: 0, last_output: None, pushed: 0, failed: 0,
+            skipped: 0, cancelled: 0, retries: 0, reconnects: 0, history: VecDeque::new(), uart_errors: None }
+    }
+
+    /// Count bytes read from the device
+    pub fn on_received(&mut self, count: usize) {
+        if count > 0 {
+            self.received += count as u64;
+            self.last_output = Some(Instant::now());
+        }
+    }
+
+    /// Count bytes written to the device
+    pub fn on_sent(&mut self, count: u64) {
+        self.sent += count;
+    }
+
+    /// Count size headers sent again
+    pub fn on_retries(&mut self, count: u32) {
+        self.retries += count;
+    }
+
+    /// Count a try at opening the device again
+    pub fn on_reconnect(&mut self) {
+        self.reconnects += 1;
+    }
+
+    /// Count errors the UART counted, none to say the link counts them
+    pub fn on_uart_errors(&mut self, new: ErrorCounts) {
+        *self.uart_errors.get_or_insert_default() += new;
+    }
+
+    /// Count a push that ended, keeping its record
+    pub fn on_push(&mut self, record: PushRecord) {
+        match record.result {
+            "ok" => self.pushed += 1,
+            "failed" => self.failed += 1,
+            "skipped" => self.skipped += 1,
+            "cancelled" => self.cancelled += 1,
+            _ => {}
+        }
+        if self.history.len() == HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history.push_back(record);
+    }
+
+    /// How the last push ended
+    pub fn last_push(&self) -> Option<&PushRecord> {
+        self.history.back()
+    }
+
+    /// Pushes that made it
+    pub fn pushed(&self) -> u32 {
+        self.pushed
+    }
+
+    /// Show the statistics, `ready` saying where the device is at
+    pub fn report(&self, ready: &str) {
+        status!("\r\n[PUSHER] Session statistics:");
+        for line in self.summary(ready) {
+            status!("[PUSHER]   {}", line);
+        }
+    }
+
+    /// The lines of the report, `ready` saying where the device is at
+    pub fn summary(&self, ready: &str) -> Vec<String> {
+        let mut lines = vec![
+            format!("up {}", format_duration(self.started.elapsed())),
+            format!("{} bytes received, {} bytes sent", self.received, self.sent),
+            format!("pushes: {} ok, {} failed, {} skipped, {} cancelled", self.pushed, self.failed, self.skipped,
+                self.cancelled),
+        ];
+        if let Some(PushRecord { result, file: Some(file), .. }) = self.last_push() {
+            lines.push(format!("last push {} ({})", result, file.display()));
+        }
+        if let Some(uart_errors) = &self.uart_errors {
+            lines.push(format!("UART errors: {}", uart_errors));
+        }
+        lines.push(ready.to_string());
+        lines.push(match self.last_output {
+            Some(last_output) => format!("last device output {} ago", format_duration(last_output.elapsed())),
+            None => "no device output yet".to_string()
+        });
+        lines
+    }
+
+    /// The pushes of the session, the first one first. Only the last `HISTORY_LIMIT` are kept.
+    pub fn history(&self) -> impl Iterator<Item = &PushRecord> {
+        self.history.iter()
+    }
+
+    /// The statistics as reported by the status request, durations in seconds
+    pub fn to_json(&self) -> Value {
+        json!({
+            "uptime": self.started.elapsed().as_secs_f64(),
+            "received": self.received,
+            "sent": self.sent,
+            "pushes": { "ok": self.pushed, "failed": self.failed, "skipped": self.skipped, "cancelled": self.cancelled },
+            "since_output": self.last_output.map(|last_output| last_output.elapsed().as_secs_f64()),
+            "uart_errors": self.uart_errors,
+            "retries": self.retries,
+            "reconnects": self.reconnects,
+        })
+    }
+
+    /// The counters in the Prometheus text format, for /metrics. `connected` says whether the
+    /// device is open.
+    pub fn to_metrics(&self, connected: bool) -> String {
+        let mut metrics = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(metrics, "# HELP pusher_{} {}\n# TYPE pusher_{} {}", name, help, name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(metrics, "pusher_{}{} {}", name, labels, value);
+            }
+        };
+        metric("up_seconds", "gauge", "Seconds since the session started.", &[("", self.started.elapsed().as_secs())]);
+        metric("connected", "gauge", "Whether the device is open.", &[("", connected as u64)]);
+        metric("received_bytes_total", "counter", "Bytes read from the device.", &[("", self.received)]);
+        metric("sent_bytes_total", "counter", "Bytes written to the device.", &[("", self.sent)]);
+        metric("pushes_total", "counter", "Pushes by how they ended.", &[
+            ("{result=\"ok\"}", self.pushed.into()),
+            ("{result=\"failed\"}", self.failed.into()),
+            ("{result=\"skipped\"}", self.skipped.into()),
+            ("{result=\"cancelled\"}", self.cancelled.into()),
+        ]);
+        metric("size_retries_total", "counter", "Size headers sent again after the loader's NAK.", &[("", self.retries.into())]);
+        metric("reconnects_total", "counter", "Tries at opening the device again after losing it.", &[("", self.reconnects.into())]);
+        if let Some(uart_errors) = &self.uart_errors {
+            metric("uart_errors_total", "counter", "Errors counted by the UART.", &[
+                ("{kind=\"framing\"}", uart_errors.framing.into()),
+                ("{kind=\"parity\"}", uart_errors.parity.into()),
+                ("{kind=\"overrun\"}", uart_errors.overrun.into()),
+                ("{kind=\"buffer_overrun\"}", uart_errors.buffer_overrun.into()),
+            ]);
+        }
+        metrics
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `1h02m03s`, `2m03s` or `3.4s`
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{:.1}s", duration.as_secs_f64()),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_counters() {
+        let mut stats = SessionStats::new();
+        stats.on_received(10);
+        stats.on_received(0);
+        stats.on_received(5);
+        stats.on_sent(3);
+        stats.on_sent(0);
+        stats.on_sent(1024);
+        let json = stats.to_json();
+        assert_eq!(json["received"], 15);
+        assert_eq!(json["sent"], 1027);
+        assert_eq!(stats.summary("ready")[1], "15 bytes received, 1027 bytes sent");
+        assert!(stats.to_metrics(true).contains("pusher_received_bytes_total 15\n"));
+    }
+
+    #[test]
+    fn push_outcomes() {
+        let mut stats = SessionStats::new();
+        assert!(stats.last_push().is_none());
+        for result in ["ok", "failed", "ok", "skipped", "cancelled", "ok"] {
+            stats.on_push(PushRecord::new(result, None));
+        }
+        stats.on_push(PushRecord::new("failed", Some("No ack".to_string())).of(Some(PathBuf::from("Image"))));
+        assert_eq!(stats.pushed(), 3);
+        assert_eq!(stats.to_json()["pushes"], json!({ "ok": 3, "failed": 2, "skipped": 1, "cancelled": 1 }));
+        assert_eq!(stats.last_push().unwrap().error.as_deref(), Some("No ack"));
+        assert_eq!(stats.history().count(), 7);
+        assert!(stats.summary("ready").contains(&"last push failed (Image)".to_string()));
+    }
+
+    #[test]
+    fn history_is_capped() {
+        let mut stats = SessionStats::new();
+        for _ in 0..HISTORY_LIMIT + 5 {
+            stats.on_push(PushRecord::new("ok", None));
+        }
+        assert_eq!(stats.history().count(), HISTORY_LIMIT);
+        assert_eq!(stats.pushed() as usize, HISTORY_LIMIT + 5);
+    }
+
+    #[test]
+    fn time_since_last_output() {
+        let mut stats = SessionStats::new();
+        stats.on_received(0);
+        assert!(stats.to_json()["since_output"].is_null());
+        assert_eq!(stats.summary("ready").last().unwrap(), "no device output yet");
+        stats.on_received(1);
+        assert!(stats.to_json()["since_output"].as_f64().unwrap() < 1.0);
+        // empty reads don't count as output
+        stats.last_output = Instant::now().checked_sub(Duration::from_secs(125));
+        stats.on_received(0);
+        assert_eq!(stats.summary("ready").last().unwrap(), "last device output 2m05s ago");
+    }
+}
@@ -0,0 +1,244 @@
+//! A tiny HTTP listener for dashboards (--status-addr): `GET /status` answers the session's
+//! state as JSON and `GET /metrics` its counters in the Prometheus text format.
+//!
+//! It's served from the event loop like the console server: clients are non blocking, send one
+//! request and are disconnected once answered. At most `MAX_CLIENTS` are kept, a new one pushes
+//! out the oldest, so a client that never finishes its request can't pile up.
+//!
+//! Serving needs the `status-http` cargo feature, without it --status-addr is refused.
+
+#[cfg(not(feature = "status-http"))]
+use std::io;
+#[cfg(not(feature = "status-http"))]
+use anyhow::{Result, anyhow};
+#[cfg(not(feature = "status-http"))]
+use mio::{Registry, Token};
+
+#[cfg(not(feature = "status-http"))]
+use crate::tokens::Tokens;
+
+/// What a client asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    /// `/status`
+    Status,
+    /// `/metrics`
+    Metrics,
+}
+
+#[cfg(feature = "status-http")]
+mod server {
+    use std::collections::HashMap;
+    use std::io::{self, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::os::unix::prelude::AsRawFd;
+    use anyhow::{Result, anyhow};
+    use mio::unix::SourceFd;
+    use mio::{Interest, Registry, Token};
+
+    use crate::tokens::{Source, Tokens};
+    use super::Endpoint;
+
+    /// Clients kept at once
+    const MAX_CLIENTS: usize = 16;
+    /// Longest request taken, headers included
+    const MAX_REQUEST: usize = 8 * 1024;
+
+    struct Client {
+        stream: TcpStream,
+        /// The request so far
+        request: Vec<u8>,
+        /// The response not written yet, once there is one
+        response: Option<Vec<u8>>,
+        /// Order the clients connected in, the oldest goes first
+        serial: u64,
+    }
+
+    /// The listening socket and its clients
+    pub struct StatusServer {
+        listener: TcpListener,
+        address: SocketAddr,
+        clients: HashMap<Token, Client>,
+        accepted: u64,
+    }
+
+    impl StatusServer {
+        /// Listen on `address` (`host:port`). Clients get their tokens as they connect.
+        pub fn bind(address: &str) -> Result<Self> {
+            let listener = TcpListener::bind(address)
+                .map_err(|err| anyhow!("Couldn't listen on {} for --status-addr: {}", address, err))?;
+            listener.set_nonblocking(true)?;
+            let address = listener.local_addr()?;
+            Ok(Self { listener, address, clients: HashMap::new(), accepted: 0 })
+        }
+
+        /// Where it listens, with the port picked if it was given as 0
+        pub fn address(&self) -> String {
+            self.address.to_string()
+        }
+
+        pub fn register(&self, registry: &Registry, token: Token) -> io::Result<()> {
+            registry.register(&mut SourceFd(&self.listener.as_raw_fd()), token, Interest::READABLE)
+        }
+
+        /// Take every waiting client
+        pub fn accept(&mut self, registry: &Registry, tokens: &mut Tokens) -> io::Result<()> {
+            loop {
+                let stream = match self.listener.accept() {
+                    Ok((stream, _)) => stream,
+                    // edge triggered: keep accepting until there's nobody left
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err)
+                };
+                stream.set_nonblocking(true)?;
+                if self.clients.len() == MAX_CLIENTS {
+                    let oldest = self.clients.iter().min_by_key(|(_, client)| client.serial).map(|(&token, _)| token);
+                    if let Some(token) = oldest {
+                        self.disconnect(registry, tokens, token)?;
+                    }
+                }
+                let token = tokens.allocate(Source::StatusClient);
+                registry.register(&mut SourceFd(&stream.as_raw_fd()), token, Interest::READABLE | Interest::WRITABLE)?;
+                self.accepted += 1;
+                self.clients.insert(token, Client { stream, request: Vec::new(), response: None, serial: self.accepted });
+            }
+        }
+
+        /// Handle readiness of the client with `token`: write what's left of its response, or
+        /// read its request. Returns what it asked for once the request is complete, to be
+        /// answered with `respond`. Requests for anything else are answered here.
+        pub fn on_client_event(&mut self, registry: &Registry, tokens: &mut Tokens, token: Token)
+            -> io::Result<Option<Endpoint>>
+        {
+            let Some(client) = self.clients.get_mut(&token) else {
+                return Ok(None);
+            };
+            if client.response.is_some() {
+                return self.flush(registry, tokens, token).map(|()| None);
+            }
+            let mut chunk = [0; 1024];
+            loop {
+                match client.stream.read(&mut chunk) {
+                    Ok(0) => return self.disconnect(registry, tokens, token).map(|()| None),
+                    Ok(read) => client.request.extend_from_slice(&chunk[..read]),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => return self.disconnect(registry, tokens, token).map(|()| None)
+                }
+                if client.request.len() > MAX_REQUEST {
+                    break;
+                }
+            }
+            let Some(end) = client.request.windows(4).position(|window| window == b"\r\n\r\n") else {
+                if client.request.len() > MAX_REQUEST {
+                    self.respond(registry, tokens, token, 431, "text/plain", "Request too large\n")?;
+                }
+                return Ok(None);
+            };
+            let head = String::from_utf8_lossy(&client.request[..end]).into_owned();
+            let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+            let (method, target) = (request_line.next().unwrap_or_default(), request_line.next().unwrap_or_default());
+            // the query string doesn't change what's served
+            let path = target.split('?').next().unwrap_or_default();
+            match (method, path) {
+                ("GET", "/status") => Ok(Some(Endpoint::Status)),
+                ("GET", "/metrics") => Ok(Some(Endpoint::Metrics)),
+                ("GET", _) => self.respond(registry, tokens, token, 404, "text/plain", "Not found, try /status or /metrics\n")
+                    .map(|()| None),
+                _ => self.respond(registry, tokens, token, 405, "text/plain", "Only GET is served\n").map(|()| None)
+            }
+        }
+
+        /// Answer the client with `token` and disconnect it once it's all written
+        pub fn respond(&mut self, registry: &Registry, tokens: &mut Tokens, token: Token, status: u16,
+            content_type: &str, body: &str) -> io::Result<()>
+        {
+            let Some(client) = self.clients.get_mut(&token) else {
+                return Ok(());
+            };
+            let reason = match status {
+                200 => "OK",
+                404 => "Not Found",
+                405 => "Method Not Allowed",
+                431 => "Request Header Fields Too Large",
+                _ => "Error",
+            };
+            let mut response = format!("HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status, reason, content_type, body.len()).into_bytes();
+            response.extend_from_slice(body.as_bytes());
+            client.response = Some(response);
+            self.flush(registry, tokens, token)
+        }
+
+        /// Write as much of the response as the socket takes, disconnecting once it's written
+        fn flush(&mut self, registry: &Registry, tokens: &mut Tokens, token: Token) -> io::Result<()> {
+            let Some(client) = self.clients.get_mut(&token) else {
+                return Ok(());
+            };
+            let Some(response) = &mut client.response else {
+                return Ok(());
+            };
+            while !response.is_empty() {
+                match client.stream.write(response) {
+                    Ok(0) => break,
+                    Ok(written) => {
+                        response.drain(..written);
+                    },
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break
+                }
+            }
+            self.disconnect(registry, tokens, token)
+        }
+
+        fn disconnect(&mut self, registry: &Registry, tokens: &mut Tokens, token: Token) -> io::Result<()> {
+            if let Some(client) = self.clients.remove(&token) {
+                registry.deregister(&mut SourceFd(&client.stream.as_raw_fd()))?;
+                tokens.release(token);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "status-http")]
+pub use server::StatusServer;
+
+/// Stand-in for builds without the `status-http` feature, it can't be bound
+#[cfg(not(feature = "status-http"))]
+pub struct StatusServer {
+    _unconstructible: (),
+}
+
+#[cfg(not(feature = "status-http"))]
+impl StatusServer {
+    pub fn bind(_address: &str) -> Result<Self> {
+        Err(anyhow!("This pusher was built without the status endpoint, rebuild it with `--features status-http`"))
+    }
+
+    pub fn address(&self) -> String {
+        String::new()
+    }
+
+    pub fn register(&self, _registry: &Registry, _token: Token) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn accept(&mut self, _registry: &Registry, _tokens: &mut Tokens) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn on_client_event(&mut self, _registry: &Registry, _tokens: &mut Tokens, _token: Token)
+        -> io::Result<Option<Endpoint>>
+    {
+        Ok(None)
+    }
+
+    pub fn respond(&mut self, _registry: &Registry, _tokens: &mut Tokens, _token: Token, _status: u16,
+        _content_type: &str, _body: &str) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
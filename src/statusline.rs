@@ -0,0 +1,134 @@
+//! A status line pinned to the bottom row of the terminal, so pusher's state stays in sight
+//! while the console scrolls by.
+//!
+//! Everything else is kept above it with a scroll region (`ESC [ top ; bottom r`), the line
+//! itself is drawn with the cursor saved and restored around it. Only shown when stdout is a
+//! terminal.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Least time between two redraws caused by device output alone
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct StatusLine {
+    /// stdout is a terminal, so the line can be shown at all
+    available: bool,
+    shown: bool,
+    rows: u16,
+    cols: u16,
+    text: String,
+    last_draw: Instant,
+}
+
+impl StatusLine {
+    /// A status line, shown right away if `show` and stdout is a terminal
+    pub fn new(show: bool) -> Self {
+        let available = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        let mut line = Self { available, shown: false, rows: 0, cols: 0, text: String::new(), last_draw: Instant::now() };
+        if show && available {
+            line.show();
+        }
+        line
+    }
+
+    pub fn is_shown(&self) -> bool {
+        self.shown
+    }
+
+    /// Show the line if it's hidden, hide it otherwise. Returns false if stdout isn't a terminal.
+    pub fn toggle(&mut self) -> bool {
+        if !self.available {
+            return false;
+        }
+        if self.shown {
+            self.hide();
+        } else {
+            self.show();
+        }
+        true
+    }
+
+    /// Change what the line says, redrawing it if that's new
+    pub fn set(&mut self, text: String) {
+        if text != self.text {
+            self.text = text;
+            self.draw();
+        }
+    }
+
+    /// Redraw after device output, in case it drew over the line (clearing the screen, moving
+    /// the cursor around). At most every `REFRESH_INTERVAL`.
+    pub fn refresh(&mut self) {
+        if self.last_draw.elapsed() >= REFRESH_INTERVAL {
+            self.draw();
+        }
+    }
+
+    /// Fit the scroll region and the line to the terminal's new size, on SIGWINCH
+    pub fn resize(&mut self) {
+        if !self.shown {
+            return;
+        }
+        let old_rows = self.rows;
+        let Some((rows, cols)) = window_size().filter(|&(rows, _)| rows >= 2) else {
+            return;
+        };
+        self.rows = rows;
+        self.cols = cols;
+        let mut sequence = String::from("\x1b7");
+        // a taller terminal leaves the old line in the middle of the region
+        if old_rows != 0 && old_rows < rows {
+            sequence += &format!("\x1b[{};1H\x1b[2K", old_rows);
+        }
+        // setting the region moves the cursor home, hence the save and restore around it
+        sequence += &format!("\x1b[1;{}r\x1b8", rows - 1);
+        write_terminal(&sequence);
+        self.draw();
+    }
+
+    fn show(&mut self) {
+        self.shown = true;
+        // make room for the line, in case the cursor is on the last row
+        write_terminal("\n\x1b[A");
+        self.resize();
+    }
+
+    fn hide(&mut self) {
+        self.shown = false;
+        write_terminal(&format!("\x1b7\x1b[r\x1b[{};1H\x1b[2K\x1b8", self.rows));
+    }
+
+    fn draw(&mut self) {
+        self.last_draw = Instant::now();
+        if !self.shown {
+            return;
+        }
+        let text: String = self.text.chars().take(self.cols as usize).collect();
+        write_terminal(&format!("\x1b7\x1b[{};1H\x1b[2K\x1b[7m{:width$}\x1b[0m\x1b8", self.rows, text,
+            width = self.cols as usize));
+    }
+}
+
+impl Drop for StatusLine {
+    fn drop(&mut self) {
+        if self.shown {
+            self.hide();
+        }
+    }
+}
+
+/// Rows and columns of the terminal on stdout
+fn window_size() -> Option<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0 {
+        return None;
+    }
+    Some((size.ws_row, size.ws_col))
+}
+
+fn write_terminal(sequence: &str) {
+    let mut stdout = io::stdout();
+    // a terminal gone away isn't worth failing over
+    let _ = stdout.write_all(sequence.as_bytes()).and_then(|_| stdout.flush());
+}
@@ -0,0 +1,119 @@
+use std::borrow::Cow;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use regex::bytes::Regex;
+
+/// Matches 8 to 16 hex digits, optionally prefixed with 0x
+pub const DEFAULT_ADDRESS_PATTERN: &str = r"\b(?:0x)?([0-9a-fA-F]{8,16})\b";
+/// Longest partial line kept while waiting for its end
+const MAX_LINE: usize = 4096;
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+/// Annotates addresses printed by the device with the kernel's symbols
+pub struct Symbolizer {
+    /// Function and object symbols, sorted by address
+    symbols: Vec<Symbol>,
+    /// Addresses covered by the image's allocated sections
+    image: Range<u64>,
+    /// DWARF line info, if the ELF has it
+    dwarf: Option<addr2line::Loader>,
+    /// Patterns finding addresses in a line. Capture group 1 is the address if present,
+    /// otherwise the whole match
+    patterns: Vec<Regex>,
+    /// The current, unfinished, line of device output
+    line: Vec<u8>,
+}
+
+impl Symbolizer {
+    /// Load the symbol table (and line info) of `elf_path`.
+    /// With no `patterns`, `DEFAULT_ADDRESS_PATTERN` is used.
+    pub fn load(elf_path: &Path, patterns: &[String]) -> Result<Self> {
+        let data = fs::read(elf_path)?;
+        let elf = object::File::parse(&*data)?;
+
+        let mut symbols: Vec<Symbol> = elf.symbols()
+            .filter(|symbol| matches!(symbol.kind(), SymbolKind::Text | SymbolKind::Data))
+            .filter_map(|symbol| Some(Symbol {
+                address: symbol.address(),
+                size: symbol.size(),
+                name: addr2line::demangle_auto(Cow::from(symbol.name().ok()?), None).into_owned()
+            }))
+            .collect();
+        symbols.sort_by_key(|symbol| symbol.address);
+
+        let loaded: Vec<Range<u64>> = elf.sections()
+            .filter(|section| section.address() != 0 && section.size() != 0)
+            .map(|section| section.address()..section.address() + section.size())
+            .collect();
+        let start = loaded.iter().map(|section| section.start).min().unwrap_or(0);
+        let end = loaded.iter().map(|section| section.end).max().unwrap_or(0);
+        let image = start..end;
+
+        // no line info isn't fatal, symbol names alone are still useful
+        let dwarf = addr2line::Loader::new(elf_path).ok();
+
+        let patterns = if patterns.is_empty() { vec![DEFAULT_ADDRESS_PATTERN.to_string()] } else { patterns.to_vec() };
+        let patterns = patterns.iter()
+            .map(|pattern| Regex::new(pattern).map_err(|err| anyhow!("Invalid symbol pattern: {}", err)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { symbols, image, dwarf, patterns, line: Vec::new() })
+    }
+
+    /// Feed device output. Returns an annotation for every address found in the lines it completed.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut annotations = Vec::new();
+        for &byte in bytes {
+            if byte == b'\n' {
+                let line = std::mem::take(&mut self.line);
+                annotations.extend(self.annotate_line(&line));
+            } else if self.line.len() < MAX_LINE {
+                self.line.push(byte);
+            }
+        }
+        annotations
+    }
+
+    fn annotate_line(&self, line: &[u8]) -> Vec<String> {
+        let mut annotations = Vec::new();
+        for pattern in &self.patterns {
+            for captures in pattern.captures_iter(line) {
+                let matched = captures.get(1).or_else(|| captures.get(0)).unwrap();
+                let text = String::from_utf8_lossy(matched.as_bytes());
+                if let Ok(address) = u64::from_str_radix(text.trim_start_matches("0x"), 16) {
+                    annotations.push(format!("    [PUSHER] {:#x} = {}", address, self.describe(address)));
+                }
+            }
+        }
+        annotations
+    }
+
+    /// `function+offset (file:line)` for `address`
+    pub fn describe(&self, address: u64) -> String {
+        if !self.image.contains(&address) {
+            return "outside the kernel image".to_string();
+        }
+        let index = self.symbols.partition_point(|symbol| symbol.address <= address);
+        let mut description = match index.checked_sub(1).map(|index| &self.symbols[index]) {
+            Some(symbol) if symbol.size == 0 || address < symbol.address + symbol.size => {
+                format!("{}+{:#x}", symbol.name, address - symbol.address)
+            },
+            _ => "unknown symbol".to_string()
+        };
+        let location = self.dwarf.as_ref().and_then(|dwarf| dwarf.find_location(address).ok().flatten());
+        if let Some(location) = location {
+            if let (Some(file), Some(line)) = (location.file, location.line) {
+                description += &format!(" ({}:{})", file, line);
+            }
+        }
+        description
+    }
+}
@@ -0,0 +1,57 @@
+//! Handing out the poll's tokens: every event source registered gets one of its own, and the
+//! event loop looks up which source an event is for.
+
+use std::collections::HashMap;
+use mio::Token;
+
+/// What a token was handed out for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The device, the same token for every connection to it
+    Serial,
+    Stdin,
+    Signals,
+    /// The control socket's listener and its client
+    Control,
+    ControlClient,
+    /// The console server's listener and each of its clients
+    Console,
+    ConsoleClient,
+    /// The udev monitor
+    Hotplug,
+    /// The --status-addr listener and each of its clients
+    Status,
+    StatusClient,
+}
+
+/// The tokens handed out so far and their sources. Tokens are never reused, an event still
+/// queued for a source that's gone can't be taken for another one.
+#[derive(Debug, Default)]
+pub struct Tokens {
+    sources: HashMap<Token, Source>,
+    next: usize,
+}
+
+impl Tokens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A new token for `source`
+    pub fn allocate(&mut self, source: Source) -> Token {
+        let token = Token(self.next);
+        self.next += 1;
+        self.sources.insert(token, source);
+        token
+    }
+
+    /// Forget `token`, its source was deregistered
+    pub fn release(&mut self, token: Token) {
+        self.sources.remove(&token);
+    }
+
+    /// What `token` was handed out for, `None` once it's released
+    pub fn source(&self, token: Token) -> Option<Source> {
+        self.sources.get(&token).copied()
+    }
+}
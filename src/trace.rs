@@ -0,0 +1,57 @@
+//! Tracing where the time of a push goes (--trace): each push is a `push` span holding a
+//! `handshake`, a `transfer` and, with --verify-after-send, a `verify` span. Closing a phase
+//! records how long it took and the bytes it sent, which tells a slow link from a slow loader.
+//!
+//! The spans cost next to nothing until `init` installs the subscriber printing them to stderr,
+//! filtered with `RUST_LOG` (`pusher=info` shows them all).
+
+use std::time::Instant;
+use anyhow::Result;
+use tracing::{Span, field};
+
+/// A phase of a push, its span is closed on drop with the time it took and the bytes it sent
+#[derive(Debug)]
+pub struct PhaseSpan {
+    span: Span,
+    started: Instant,
+    bytes: u64,
+}
+
+impl PhaseSpan {
+    /// Start timing `span`, which has empty `ms` and `bytes` fields for the totals
+    pub fn new(span: Span) -> Self {
+        Self { span, started: Instant::now(), bytes: 0 }
+    }
+
+    /// Count bytes the phase sent
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for PhaseSpan {
+    fn drop(&mut self) {
+        self.span.record("ms", field::display(format_args!("{:.2}", self.started.elapsed().as_secs_f64() * 1000.0)));
+        self.span.record("bytes", self.bytes);
+    }
+}
+
+/// Print the spans to stderr as they close, with the `RUST_LOG` filter (by default `pusher=info`)
+#[cfg(feature = "trace")]
+pub fn init() -> Result<()> {
+    use tracing_subscriber::EnvFilter;
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("pusher=info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .try_init()
+        .map_err(|err| anyhow::anyhow!("Couldn't set up tracing: {}", err))
+}
+
+#[cfg(not(feature = "trace"))]
+pub fn init() -> Result<()> {
+    anyhow::bail!("This pusher was built without tracing support, rebuild it with `--features trace`")
+}
@@ -0,0 +1,384 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::AddAssign;
+use std::os::unix::net::UnixStream;
+use std::os::unix::prelude::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use mio::unix::SourceFd;
+use mio::{event, Registry, Token, Interest};
+
+/// A link to the device: anything pusher can poll, read from and write to.
+/// `SerialDevice` is the usual one, `UnixSocketDevice` is used for emulators.
+pub trait Transport: event::Source {
+    /// Read everything currently available, return vector of bytes read.
+    fn read_all(&mut self) -> io::Result<Vec<u8>>;
+
+    /// Write one byte, return how many bytes were written.
+    fn write_byte(&mut self, byte: u8) -> io::Result<usize>;
+
+    /// Flush
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Discard any data received but not yet read.
+    fn clear_input(&mut self) -> io::Result<()>;
+
+    /// Change the baud rate without reopening, the mio registration stays valid.
+    /// Links without a baud rate don't support this.
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "this link has no baud rate"))
+    }
+
+    /// Reset the board through the link's reset line, holding it for `pulse`.
+    /// Links without one don't support this.
+    fn reset(&mut self, _pulse: Duration) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "this link has no reset line"))
+    }
+
+    /// Make a write that can't go on for `timeout` fail with `TimedOut` instead of waiting for
+    /// the link to take the byte forever, see `wait_writable`. `None` (the default) waits forever.
+    fn set_write_timeout(&mut self, _timeout: Option<Duration>) {}
+
+    /// Assert or release DTR. Links without modem control lines don't support this.
+    fn set_dtr(&mut self, _asserted: bool) -> io::Result<()> {
+        Err(no_modem_lines())
+    }
+
+    /// Assert or release RTS. Links without modem control lines don't support this.
+    fn set_rts(&mut self, _asserted: bool) -> io::Result<()> {
+        Err(no_modem_lines())
+    }
+
+    /// Which modem control lines are asserted. Links without them don't support this.
+    fn modem_lines(&mut self) -> io::Result<ModemLines> {
+        Err(no_modem_lines())
+    }
+
+    /// The errors the UART counted since the port came up. Links that don't count them don't
+    /// support this.
+    fn error_counts(&mut self) -> io::Result<ErrorCounts> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "this link doesn't count UART errors"))
+    }
+}
+
+fn no_modem_lines() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "this link has no modem control lines")
+}
+
+/// The modem control lines: DTR and RTS are driven by pusher, boards often have them wired to
+/// reset and boot strap pins, the others by the other end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModemLines {
+    pub dtr: bool,
+    pub rts: bool,
+    pub cts: bool,
+    pub dsr: bool,
+    pub dcd: bool,
+    pub ri: bool,
+}
+
+impl ModemLines {
+    /// Each line's name and whether it's asserted, the ones pusher drives first
+    pub fn lines(&self) -> [(&'static str, bool); 6] {
+        [("DTR", self.dtr), ("RTS", self.rts), ("CTS", self.cts), ("DSR", self.dsr), ("DCD", self.dcd),
+            ("RI", self.ri)]
+    }
+
+    /// Just the lines pusher drives, the others change behind its back
+    pub fn outputs(&self) -> String {
+        format_lines(&self.lines()[..2])
+    }
+}
+
+impl fmt::Display for ModemLines {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&format_lines(&self.lines()))
+    }
+}
+
+/// Receive errors a UART counts, bytes that were garbled or lost on the way in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct ErrorCounts {
+    /// A byte without its stop bit, often a baud rate that's off
+    pub framing: u32,
+    pub parity: u32,
+    /// The UART got a byte before the driver took the one before
+    pub overrun: u32,
+    /// The driver's buffer was full
+    pub buffer_overrun: u32,
+}
+
+impl ErrorCounts {
+    /// The errors counted since `earlier`
+    pub fn since(&self, earlier: &ErrorCounts) -> ErrorCounts {
+        ErrorCounts {
+            framing: self.framing.wrapping_sub(earlier.framing),
+            parity: self.parity.wrapping_sub(earlier.parity),
+            overrun: self.overrun.wrapping_sub(earlier.overrun),
+            buffer_overrun: self.buffer_overrun.wrapping_sub(earlier.buffer_overrun),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == ErrorCounts::default()
+    }
+}
+
+impl AddAssign for ErrorCounts {
+    fn add_assign(&mut self, other: ErrorCounts) {
+        self.framing += other.framing;
+        self.parity += other.parity;
+        self.overrun += other.overrun;
+        self.buffer_overrun += other.buffer_overrun;
+    }
+}
+
+impl fmt::Display for ErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} framing, {} parity, {} overrun, {} buffer overrun", self.framing, self.parity,
+            self.overrun, self.buffer_overrun)
+    }
+}
+
+fn format_lines(lines: &[(&str, bool)]) -> String {
+    lines.iter()
+        .map(|(name, asserted)| format!("{} {}", name, if *asserted { "on" } else { "off" }))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Wait at most `timeout` (forever if `None`) for `fd` to take more bytes, for writes that
+/// would block: flow control holding the output back or a link that stopped draining. Fails
+/// with `TimedOut` if it doesn't, the transfer stalled.
+pub fn wait_writable(fd: RawFd, timeout: Option<Duration>) -> io::Result<()> {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLOUT, revents: 0 };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let millis = match deadline {
+            // rounded up, or a wait for less than a millisecond would spin
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).as_micros().div_ceil(1000)
+                .min(i32::MAX as u128) as i32,
+            None => -1
+        };
+        match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            0 => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("transfer stalled, the link took \
+                nothing for {}ms", timeout.unwrap_or_default().as_millis()))),
+            _ => return Ok(())
+        }
+    }
+}
+
+/// Bytes per line of the --print-wire hex dump
+const WIRE_LINE: usize = 16;
+
+/// Logs every byte going either way to stderr as a hex dump, with the time since the link was
+/// opened (--print-wire). Bytes are written one at a time, so the sent ones are gathered into
+/// lines, logged once full or when the device answers.
+pub struct WireTap {
+    inner: Box<dyn Transport>,
+    opened: Instant,
+    /// Sent but not logged yet, and when the first of them went
+    pending: Vec<u8>,
+    pending_since: Instant,
+}
+
+impl WireTap {
+    pub fn new(inner: Box<dyn Transport>) -> Self {
+        Self { inner, opened: Instant::now(), pending: Vec::new(), pending_since: Instant::now() }
+    }
+
+    fn log(&self, direction: &str, since: Instant, bytes: &[u8]) {
+        let time = since.duration_since(self.opened).as_secs_f64();
+        let mut dump = String::new();
+        for chunk in bytes.chunks(WIRE_LINE) {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let ascii: String = chunk.iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            dump += &format!("[{:11.6}] {}: {:<47}  |{}|\n", time, direction, hex.join(" "), ascii);
+        }
+        // the log isn't worth failing the link over
+        let _ = io::stderr().write_all(dump.as_bytes());
+    }
+
+    fn log_pending(&mut self) {
+        if !self.pending.is_empty() {
+            self.log("TX", self.pending_since, &self.pending);
+            self.pending.clear();
+        }
+    }
+}
+
+impl Transport for WireTap {
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let bytes = self.inner.read_all()?;
+        if !bytes.is_empty() {
+            self.log_pending();
+            self.log("RX", Instant::now(), &bytes);
+        }
+        Ok(bytes)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<usize> {
+        let written = self.inner.write_byte(byte)?;
+        if written == 1 {
+            if self.pending.is_empty() {
+                self.pending_since = Instant::now();
+            }
+            self.pending.push(byte);
+            if self.pending.len() == WIRE_LINE {
+                self.log_pending();
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.log_pending();
+        self.inner.flush()
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.inner.clear_input()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.log_pending();
+        self.inner.set_baud_rate(baud_rate)
+    }
+
+    fn reset(&mut self, pulse: Duration) -> io::Result<()> {
+        self.log_pending();
+        self.inner.reset(pulse)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_dtr(&mut self, asserted: bool) -> io::Result<()> {
+        self.log_pending();
+        self.inner.set_dtr(asserted)
+    }
+
+    fn set_rts(&mut self, asserted: bool) -> io::Result<()> {
+        self.log_pending();
+        self.inner.set_rts(asserted)
+    }
+
+    fn modem_lines(&mut self) -> io::Result<ModemLines> {
+        self.inner.modem_lines()
+    }
+
+    fn error_counts(&mut self) -> io::Result<ErrorCounts> {
+        self.inner.error_counts()
+    }
+}
+
+impl Drop for WireTap {
+    fn drop(&mut self) {
+        self.log_pending();
+    }
+}
+
+impl event::Source for WireTap {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
+
+/// Represents a serial port exposed as a UNIX domain socket, e.g. by QEMU's
+/// `-serial unix:/path/to/socket,server`
+pub struct UnixSocketDevice {
+    stream: UnixStream,
+    write_timeout: Option<Duration>,
+}
+
+impl UnixSocketDevice {
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        Self::from_stream(UnixStream::connect(path)?)
+    }
+
+    /// Use a connected socket, e.g. one end of a `UnixStream::pair`
+    pub fn from_stream(stream: UnixStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self { stream, write_timeout: None })
+    }
+}
+
+impl Transport for UnixSocketDevice {
+    /// Read until the socket would block.
+    /// The other end closing the socket is reported as an `UnexpectedEof` error.
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) if buffer.is_empty() => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "socket closed by the other end"));
+                },
+                Ok(0) => return Ok(buffer),
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(buffer),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    /// Write one byte, waiting for room while the socket buffer is full
+    fn write_byte(&mut self, byte: u8) -> io::Result<usize> {
+        loop {
+            match self.stream.write(&[byte]) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    wait_writable(self.stream.as_raw_fd(), self.write_timeout)?;
+                },
+                result => return result
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.read_all()?;
+        Ok(())
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+}
+
+/// Implement event source for UnixSocketDevice to be able to register it
+/// in the Registry and Poll
+impl event::Source for UnixSocketDevice {
+   fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
+        -> io::Result<()>
+    {
+        SourceFd(&self.stream.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest)
+        -> io::Result<()>
+    {
+        SourceFd(&self.stream.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.stream.as_raw_fd()).deregister(registry)
+    }
+}
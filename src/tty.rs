@@ -1,6 +1,10 @@
-use std::fs::File;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::{self, Read, stdin, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::os::unix::prelude::{RawFd, FromRawFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::thread::sleep;
 use std::time::Duration;
 use anyhow::Result;
@@ -8,16 +12,141 @@ use mio::unix::SourceFd;
 use termios::*;
 use mio::{event, Registry, Token, Interest};
 
+use crate::lock::DeviceLock;
+use crate::output;
+use crate::status;
+use crate::transport::{self, ErrorCounts, ModemLines, Transport};
+
+/// Longest wait between two tries at opening a port, the delay doubles up to it
+const MAX_OPEN_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Times a port that's busy or not accessible yet is tried again, --open-retries
+static OPEN_RETRIES: AtomicU32 = AtomicU32::new(0);
+/// First wait before trying again in milliseconds, --open-retry-delay
+static OPEN_RETRY_DELAY: AtomicU64 = AtomicU64::new(500);
+
+/// Try opening a port that's busy or not accessible `retries` more times, waiting `delay` before
+/// the first retry and twice as long before each next one
+pub fn set_open_retries(retries: u32, delay: Duration) {
+    OPEN_RETRIES.store(retries, Ordering::Relaxed);
+    OPEN_RETRY_DELAY.store(delay.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Open the port at `path` with `open`, trying again as `set_open_retries` says while it fails
+/// with the port busy or not accessible, like while ModemManager probes an adapter just plugged
+/// in. Says so once, and the last error how many tries it took.
+pub fn retry_open<T>(path: &Path, mut open: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let retries = OPEN_RETRIES.load(Ordering::Relaxed);
+    let mut delay = Duration::from_millis(OPEN_RETRY_DELAY.load(Ordering::Relaxed));
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let err = match open() {
+            Err(err) if matches!(err.kind(), io::ErrorKind::ResourceBusy | io::ErrorKind::PermissionDenied) => err,
+            result => return result
+        };
+        if attempt > retries {
+            return match retries {
+                0 => Err(err),
+                _ => Err(io::Error::new(err.kind(), format!("{}, gave up after {} attempts", err, attempt)))
+            };
+        }
+        if attempt == 1 {
+            status!("[PUSHER] {} is busy ({}), retrying...", path.display(), err);
+        }
+        sleep(delay);
+        delay = (delay * 2).min(MAX_OPEN_RETRY_DELAY);
+    }
+}
+
 /// Represents a serial / UART port
 pub struct SerialDevice {
     device: File,
-    _baudrate: u32
+    baudrate: u32,
+    write_timeout: Option<Duration>,
+    /// Its lockfile, removed once it's closed
+    lock: Option<DeviceLock>,
+}
+
+/// What `SerialDevice::probe` found out about a port without opening it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortInfo {
+    pub path: PathBuf,
+    /// The device file `path` resolves to, e.g. /dev/ttyUSB0 for a /dev/serial/by-id link
+    pub device: PathBuf,
+    /// The kernel driver of the port, like `ftdi_sio`, `cdc_acm` or `serial8250` (Linux only)
+    pub driver: Option<String>,
+    /// Vendor and product ID of the port's USB adapter (Linux only)
+    pub usb_id: Option<(u16, u16)>,
+}
+
+/// `/dev/serial/by-id/usb-FTDI_FT232R-if00-port0 (/dev/ttyUSB0, driver ftdi_sio, USB 0403:6001)`
+impl fmt::Display for PortInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut details = Vec::new();
+        if self.device != self.path {
+            details.push(self.device.display().to_string());
+        }
+        if let Some(driver) = &self.driver {
+            details.push(format!("driver {}", driver));
+        }
+        if let Some((vid, pid)) = self.usb_id {
+            details.push(format!("USB {:04x}:{:04x}", vid, pid));
+        }
+        match details.is_empty() {
+            true => write!(f, "{}", self.path.display()),
+            false => write!(f, "{} ({})", self.path.display(), details.join(", "))
+        }
+    }
 }
 
-pub struct StdinDevice(RawFd);
+/// Represents the local terminal. The original terminal settings are restored on drop.
+/// When stdin isn't a terminal (a pipe, /dev/null in CI) or pusher runs as a daemon, it's left
+/// alone and never read, unless a pipe is to be forwarded (see `watch_pipe`).
+pub struct StdinDevice {
+    fd: RawFd,
+    original: Option<Termios>,
+    /// The file status flags of a watched pipe before it was made non blocking
+    pipe_flags: Option<libc::c_int>,
+}
 
 impl SerialDevice {
+    /// Check `path` is a serial port without opening it: that it exists, that it's a character
+    /// device and, on Linux, that the kernel has it for a tty, with its driver and USB IDs.
+    /// A port in use is probed all the same.
+    pub fn probe(path: &Path) -> io::Result<PortInfo> {
+        let metadata = fs::metadata(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => io::Error::new(err.kind(), format!("{} doesn't exist", path.display())),
+            _ => io::Error::new(err.kind(), format!("Couldn't look at {}: {}", path.display(), err))
+        })?;
+        if !metadata.file_type().is_char_device() {
+            let kind = match metadata.file_type() {
+                kind if kind.is_dir() => "a directory",
+                kind if kind.is_file() => "a regular file",
+                kind if kind.is_block_device() => "a block device",
+                kind if kind.is_fifo() => "a FIFO",
+                kind if kind.is_socket() => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("{} is a socket, not a serial port. Give it as unix:{}", path.display(),
+                            path.display())));
+                },
+                _ => "not a character device"
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{} is {}, not a serial port", path.display(), kind)));
+        }
+        let device = fs::canonicalize(path)?;
+        let mut port = PortInfo { path: path.to_path_buf(), device, driver: None, usb_id: None };
+        sysfs_info(&mut port, metadata.rdev())?;
+        Ok(port)
+    }
+
+    /// Set the port up and take it for this process alone, see `claim`. A port that's taken
+    /// already fails with `ResourceBusy`.
     pub fn init(serial_fd: RawFd, baudrate: u32) -> io::Result<Self> {
+        // closed again if anything below fails
+        let device = unsafe { File::from_raw_fd(serial_fd) };
+        claim(serial_fd)?;
         let mut termios = Termios::from_fd(serial_fd)?;
         termios.c_cc[VTIME] = 0;
         termios.c_cc[VMIN] = 0;
@@ -27,34 +156,276 @@ impl SerialDevice {
         termios.c_cflag = CS8 | CREAD | CLOCAL; // 8n1
         termios.c_lflag = !ICANON;
 
-        // set baudrate
-        cfsetspeed(&mut termios, baudrate)?;
-        tcsetattr(serial_fd, TCSANOW, &termios)?;
+        set_speed(serial_fd, &mut termios, baudrate, TCSANOW)?;
         Ok(Self {
-            device: unsafe { File::from_raw_fd(serial_fd) },
-           _baudrate: baudrate
+            device,
+           baudrate,
+           write_timeout: None,
+           lock: None,
         } )
     }
 
+    /// Hold `lock` for as long as the port is open
+    pub fn with_lock(mut self, lock: DeviceLock) -> Self {
+        self.lock = Some(lock);
+        self
+    }
+
+    /// Assert or release the modem control line `line`, an ioctl on the open fd that leaves its
+    /// registration with the poll alone
+    fn set_modem_line(&mut self, line: libc::c_int, asserted: bool) -> io::Result<()> {
+        let request = if asserted { libc::TIOCMBIS } else { libc::TIOCMBIC };
+        if unsafe { libc::ioctl(self.device.as_raw_fd(), request, &line) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The kernel's `struct serial_icounter_struct`, filled by TIOCGICOUNT
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct SerialIcounter {
+    cts: libc::c_int,
+    dsr: libc::c_int,
+    rng: libc::c_int,
+    dcd: libc::c_int,
+    rx: libc::c_int,
+    tx: libc::c_int,
+    frame: libc::c_int,
+    overrun: libc::c_int,
+    parity: libc::c_int,
+    brk: libc::c_int,
+    buf_overrun: libc::c_int,
+    reserved: [libc::c_int; 9],
+}
+
+/// Apply `termios` to the port with the speed set to `baud_rate`. A rate termios doesn't know
+/// (250000, 1500000 on some systems) is set as a custom one, see `set_custom_speed`.
+fn set_speed(fd: RawFd, termios: &mut Termios, baud_rate: u32, action: libc::c_int) -> io::Result<()> {
+    match cfsetspeed(termios, baud_rate) {
+        Ok(()) => tcsetattr(fd, action, termios),
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            // everything but the speed, which the custom one then replaces
+            tcsetattr(fd, action, termios)?;
+            set_custom_speed(fd, baud_rate)
+        },
+        Err(err) => Err(err)
+    }
+}
+
+/// Most a UART's rate can be off before bytes get garbled, as a fraction of it
+const RATE_TOLERANCE: f64 = 0.03;
+
+/// Set any rate with termios2 and BOTHER, the driver works out the divisor. It may only get
+/// close, further off than `RATE_TOLERANCE` fails.
+#[cfg(all(target_os = "linux", not(any(target_arch = "powerpc", target_arch = "powerpc64"))))]
+fn set_custom_speed(fd: RawFd, baud_rate: u32) -> io::Result<()> {
+    let unsupported = |err: io::Error| io::Error::new(err.kind(),
+        format!("the adapter can't do a custom rate of {} baud: {}", baud_rate, err));
+    let mut termios2: libc::termios2 = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TCGETS2, &mut termios2) } != 0 {
+        return Err(unsupported(io::Error::last_os_error()));
+    }
+    termios2.c_cflag = (termios2.c_cflag & !(libc::CBAUD | libc::CIBAUD)) | libc::BOTHER;
+    termios2.c_ispeed = baud_rate;
+    termios2.c_ospeed = baud_rate;
+    if unsafe { libc::ioctl(fd, libc::TCSETS2, &termios2) } != 0 {
+        return Err(unsupported(io::Error::last_os_error()));
+    }
+    // the driver writes back the rate it got
+    if unsafe { libc::ioctl(fd, libc::TCGETS2, &mut termios2) } != 0 {
+        return Err(unsupported(io::Error::last_os_error()));
+    }
+    let off = (termios2.c_ospeed as f64 - baud_rate as f64).abs() / baud_rate as f64;
+    if off > RATE_TOLERANCE {
+        return Err(io::Error::new(io::ErrorKind::Unsupported,
+            format!("the adapter can't do {} baud, the closest it gets is {}", baud_rate, termios2.c_ospeed)));
+    }
+    Ok(())
+}
+
+#[cfg(not(all(target_os = "linux", not(any(target_arch = "powerpc", target_arch = "powerpc64")))))]
+fn set_custom_speed(_fd: RawFd, baud_rate: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+        format!("{} baud isn't a standard rate, custom rates need Linux", baud_rate)))
+}
+
+/// Keep the port to ourselves: another pusher fails to lock it and other programs (screen,
+/// minicom) to open it, unless they run as root
+fn claim(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.kind() {
+            io::ErrorKind::WouldBlock => io::Error::from_raw_os_error(libc::EBUSY),
+            _ => err
+        });
+    }
+    if unsafe { libc::ioctl(fd, libc::TIOCEXCL) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Fill in the driver of `port`, the character device numbered `rdev`, and the IDs of its USB
+/// adapter from sysfs. Fails if sysfs knows the device and it isn't a tty. Pseudo terminals
+/// aren't in it.
+#[cfg(target_os = "linux")]
+fn sysfs_info(port: &mut PortInfo, rdev: u64) -> io::Result<()> {
+    // glibc's encoding of device numbers
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0xfff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0xff);
+    let Ok(class) = fs::canonicalize(format!("/sys/dev/char/{}:{}", major, minor)) else {
+        return Ok(());
+    };
+    let link_name = |link: PathBuf| fs::read_link(link).ok()
+        .and_then(|target| Some(target.file_name()?.to_string_lossy().into_owned()));
+    if let Some(subsystem) = link_name(class.join("subsystem")).filter(|subsystem| subsystem != "tty") {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{} is a {} device, not a serial port", port.path.display(), subsystem)));
+    }
+    port.driver = link_name(class.join("device/driver"));
+    port.usb_id = class.ancestors().find_map(|dir| {
+        let id = |name: &str| u16::from_str_radix(fs::read_to_string(dir.join(name)).ok()?.trim(), 16).ok();
+        Some((id("idVendor")?, id("idProduct")?))
+    });
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sysfs_info(_port: &mut PortInfo, _rdev: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Processes other than this one that have `path` open, as `pid (name)`, found through /proc.
+/// Those of other users can't be looked into without root.
+pub fn port_holders(path: &Path) -> Vec<String> {
+    let (Ok(target), Ok(processes)) = (fs::canonicalize(path), fs::read_dir("/proc")) else {
+        return Vec::new();
+    };
+    let own_pid = std::process::id().to_string();
+    let mut holders = Vec::new();
+    for process in processes.flatten() {
+        let pid = process.file_name().to_string_lossy().into_owned();
+        if pid == own_pid || !pid.bytes().all(|byte| byte.is_ascii_digit()) {
+            continue;
+        }
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        if fds.flatten().any(|fd| fs::read_link(fd.path()).is_ok_and(|link| link == target)) {
+            let name = fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+            holders.push(format!("{} ({})", pid, name.trim()));
+        }
+    }
+    holders
+}
+
+impl Transport for SerialDevice {
     /// Read until EOF from device, return vector of bytes read.
-    pub fn read_all(&mut self) -> Result<Vec<u8>, io::Error> {
+    fn read_all(&mut self) -> Result<Vec<u8>, io::Error> {
         let mut buffer = Vec::new();
         self.device.read_to_end(&mut buffer)?;
         Ok(buffer)
     }
 
     /// Flush
-    pub fn flush(&mut self) -> Result<(), io::Error> {
+    fn flush(&mut self) -> Result<(), io::Error> {
         self.device.flush()?;
         Ok(())
     }
 
-    /// Write one byte to serial device, and flush
-    pub fn write_byte(&mut self, byte: u8) -> io::Result<usize> {
-        let bytes_written = self.device.write(&[byte])?;
+    /// Discard any data received by the OS but not yet read.
+    /// Anything the device sent before this call (stale boot output, leftover break bytes) is lost.
+    fn clear_input(&mut self) -> io::Result<()> {
+        tcflush(self.device.as_raw_fd(), TCIFLUSH)
+    }
+
+    /// Reconfigure the baud rate of the open port, the fd (and so its registration) doesn't change
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        let fd = self.device.as_raw_fd();
+        let mut termios = Termios::from_fd(fd)?;
+        set_speed(fd, &mut termios, baud_rate, TCSADRAIN)?;
+        self.baudrate = baud_rate;
+        Ok(())
+    }
+
+    /// Pulse DTR, which USB serial adapters commonly have wired to the board's reset
+    fn reset(&mut self, pulse: Duration) -> io::Result<()> {
+        self.set_dtr(true)?;
+        sleep(pulse);
+        self.set_dtr(false)
+    }
+
+    fn set_dtr(&mut self, asserted: bool) -> io::Result<()> {
+        self.set_modem_line(libc::TIOCM_DTR, asserted)
+    }
+
+    fn set_rts(&mut self, asserted: bool) -> io::Result<()> {
+        self.set_modem_line(libc::TIOCM_RTS, asserted)
+    }
+
+    fn modem_lines(&mut self) -> io::Result<ModemLines> {
+        let mut lines: libc::c_int = 0;
+        if unsafe { libc::ioctl(self.device.as_raw_fd(), libc::TIOCMGET, &mut lines) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ModemLines {
+            dtr: lines & libc::TIOCM_DTR != 0,
+            rts: lines & libc::TIOCM_RTS != 0,
+            cts: lines & libc::TIOCM_CTS != 0,
+            dsr: lines & libc::TIOCM_DSR != 0,
+            dcd: lines & libc::TIOCM_CAR != 0,
+            ri: lines & libc::TIOCM_RNG != 0,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn error_counts(&mut self) -> io::Result<ErrorCounts> {
+        let mut counters = SerialIcounter::default();
+        if unsafe { libc::ioctl(self.device.as_raw_fd(), libc::TIOCGICOUNT, &mut counters) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ErrorCounts {
+            framing: counters.frame as u32,
+            parity: counters.parity as u32,
+            overrun: counters.overrun as u32,
+            buffer_overrun: counters.buf_overrun as u32,
+        })
+    }
+
+    /// Write one byte to serial device, and flush. While the output queue is full (flow
+    /// control holding it back) wait for room, at most the write timeout.
+    fn write_byte(&mut self, byte: u8) -> io::Result<usize> {
+        let bytes_written = loop {
+            match self.device.write(&[byte]) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    transport::wait_writable(self.device.as_raw_fd(), self.write_timeout)?;
+                },
+                result => break result?
+            }
+        };
         sleep(Duration::from_millis(2));
         Ok(bytes_written)
     }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+}
+
+/// Let what's still queued reach the device before the port is closed
+impl Drop for SerialDevice {
+    fn drop(&mut self) {
+        output::verbose("Closing serial port");
+        let fd = self.device.as_raw_fd();
+        // a device that went away has nothing left to drain to
+        let _ = tcdrain(fd);
+        // a tty someone else keeps open (a pty's other end) would stay exclusive after closing
+        unsafe { libc::ioctl(fd, libc::TIOCNXCL) };
+    }
 }
 
 /// Implement event source for SerialDevice to be able to register it 
@@ -81,42 +452,203 @@ impl StdinDevice {
     /// Setup stdin for serial communication:
     /// - Turn terminal echo off. Unless the "otherside" returns the output, nothing will be shown.
     /// - Turn off canonical mode. This means read doesn't wait for NL to proceed.
+    ///
+    /// Nothing is changed if stdin isn't a terminal.
     pub fn init() -> io::Result<Self> {
-        let mut termios = Termios::from_fd(stdin().as_raw_fd())?;
+        let fd = stdin().as_raw_fd();
+        if unsafe { libc::isatty(fd) } == 0 {
+            return Ok(Self { fd, original: None, pipe_flags: None });
+        }
+        let original = Termios::from_fd(fd)?;
+        let mut termios = original;
 
         // disable canonical mode and turn echo off
         termios.c_lflag &= !(ECHO | ICANON);
 
-        tcsetattr(stdin().as_raw_fd(), TCSANOW, &termios)?;
-        Ok(Self(stdin().as_raw_fd()))
+        tcsetattr(fd, TCSANOW, &termios)?;
+        Ok(Self { fd, original: Some(original), pipe_flags: None })
+    }
+
+    /// Stdin as a daemon sees it: never touched, even if it happens to be a terminal
+    pub fn detached() -> Self {
+        Self { fd: stdin().as_raw_fd(), original: None, pipe_flags: None }
+    }
+
+    /// Whether keystrokes are read and forwarded, only from a terminal that isn't detached
+    pub fn is_interactive(&self) -> bool {
+        self.original.is_some()
+    }
+
+    /// Get a stdin that isn't a terminal ready to be polled and read with `read_piped`.
+    /// It's made non blocking until dropped.
+    pub fn watch_pipe(&mut self) -> io::Result<()> {
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
+        if flags == -1 || unsafe { libc::fcntl(self.fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        self.pipe_flags = Some(flags);
+        Ok(())
+    }
+
+    /// Read everything that came through the watched pipe so far, and whether it ended
+    pub fn read_piped(&mut self) -> io::Result<(Vec<u8>, bool)> {
+        let mut bytes = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            match unsafe { libc::read(self.fd, chunk.as_mut_ptr() as *mut libc::c_void, chunk.len()) } {
+                0 => return Ok((bytes, true)),
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    match err.kind() {
+                        io::ErrorKind::WouldBlock => return Ok((bytes, false)),
+                        io::ErrorKind::Interrupted => continue,
+                        _ => return Err(err)
+                    }
+                },
+                read => bytes.extend_from_slice(&chunk[..read as usize])
+            }
+        }
+    }
+
+    /// Show `prompt` and read a line, echoing it locally since echo is off.
+    /// Backspace works, the line ends on Enter.
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<String> {
+        self.read_input(prompt, false)
+    }
+
+    /// Like `read_line`, for a file path: Tab completes it as far as it's unambiguous
+    pub fn read_path(&mut self, prompt: &str) -> io::Result<String> {
+        self.read_input(prompt, true)
+    }
+
+    fn read_input(&mut self, prompt: &str, complete_paths: bool) -> io::Result<String> {
+        output::status_text(prompt);
+        let mut line = String::new();
+        loop {
+            match self.read()? {
+                '\r' | '\n' => break,
+                '\x7f' | '\x08' => {
+                    if line.pop().is_some() {
+                        output::status_text("\x08 \x08");
+                    }
+                },
+                '\t' if complete_paths => {
+                    let completion = complete_path(&line);
+                    output::status_text(&completion);
+                    line += &completion;
+                },
+                character => {
+                    line.push(character);
+                    output::status_text(character.encode_utf8(&mut [0; 4]));
+                }
+            }
+        }
+        output::status_text("\r\n");
+        Ok(line)
+    }
+
+    /// Show a typed byte, with the device output, the way a terminal with echo on would.
+    /// Enter moves to the start of the next line and backspace erases.
+    pub fn echo(&self, byte: u8) {
+        match byte {
+            b'\r' | b'\n' => output::typed(b"\r\n"),
+            0x7f | 0x08 => output::typed(b"\x08 \x08"),
+            _ => output::typed(&[byte]),
+        }
+    }
+
+    /// Read every byte already typed (at least one, waiting for it if needed).
+    /// Readiness is edge triggered, whatever isn't read now wouldn't be signaled again.
+    pub fn read_pending(&mut self) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![self.read()? as u8];
+        let mut available: libc::c_int = 0;
+        if unsafe { libc::ioctl(self.fd, libc::FIONREAD, &mut available) } == 0 {
+            for _ in 0..available {
+                bytes.push(self.read()? as u8);
+            }
+        }
+        Ok(bytes)
     }
 
     /// Read from stdin one byte.
+    /// Straight from the fd: std's buffered stdin would keep the next bytes of a burst
+    /// (Ctrl-A and its command) to itself, where polling doesn't see them.
     pub fn read(&mut self) -> Result<char, io::Error> {
-        let mut buffer = Vec::new();
-        buffer.resize(1, 0);
-        stdin().lock().read_exact(&mut buffer)?;
-        Ok(buffer[0] as char)
+        let mut byte = 0u8;
+        loop {
+            match unsafe { libc::read(self.fd, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+                1 => return Ok(byte as char),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
     }
 }
 
+/// What Tab adds to the path typed so far: the rest of the name all the matching entries of its
+/// directory start with, and a `/` if that's a whole directory name
+fn complete_path(typed: &str) -> String {
+    let (directory, prefix) = match typed.rfind('/') {
+        Some(slash) => (&typed[..=slash], &typed[slash + 1..]),
+        None => ("", typed)
+    };
+    let Ok(entries) = fs::read_dir(if directory.is_empty() { "." } else { directory }) else {
+        return String::new();
+    };
+    let names: Vec<String> = entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    let Some(first) = names.first() else {
+        return String::new();
+    };
+    let common = names.iter().fold(first.len(), |common, name| {
+        first.bytes().zip(name.bytes()).take(common).take_while(|(a, b)| a == b).count()
+    });
+    // a cut in the middle of a multibyte character completes nothing
+    let Some(completed) = first.get(..common) else {
+        return String::new();
+    };
+    let mut completion = completed[prefix.len()..].to_string();
+    if names.len() == 1 && Path::new(directory).join(completed).is_dir() {
+        completion.push('/');
+    }
+    completion
+}
+
 /// Implement event source for StdinDevice to be able to register it 
 /// in the Registry and Poll
 impl event::Source for StdinDevice {
    fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
         -> io::Result<()>
     {
-        SourceFd(&self.0).register(registry, token, interests)
+        SourceFd(&self.fd).register(registry, token, interests)
     }
 
     fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest)
         -> io::Result<()>
     {
-        SourceFd(&self.0).reregister(registry, token, interests)
+        SourceFd(&self.fd).reregister(registry, token, interests)
     }
 
     fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
-        SourceFd(&self.0).deregister(registry)
+        SourceFd(&self.fd).deregister(registry)
     }
 }
 
+impl Drop for StdinDevice {
+    /// Give the terminal back the way we found it: canonical mode, echo on. A watched pipe gets
+    /// blocking again.
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            let _ = tcsetattr(self.fd, TCSANOW, original);
+        }
+        if let Some(flags) = self.pipe_flags {
+            unsafe { libc::fcntl(self.fd, libc::F_SETFL, flags) };
+        }
+    }
+}
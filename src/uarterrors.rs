@@ -0,0 +1,56 @@
+//! Watching the UART's error counters: framing, parity and overrun errors the driver drops
+//! bytes for without a word. They're the sign to lower the baud rate or fix the wiring.
+
+use std::time::{Duration, Instant};
+
+use crate::transport::{ErrorCounts, Transport};
+use crate::status;
+
+/// How often the counters are read between pushes, a push reads them at every step
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads the counters of a connection's port and keeps what they counted during the push in progress
+#[derive(Debug)]
+pub struct ErrorWatch {
+    last: ErrorCounts,
+    during_push: ErrorCounts,
+    next: Instant,
+}
+
+impl ErrorWatch {
+    /// Start from the counters as they are now, `None` if the link doesn't count errors
+    pub fn new(serial_device: &mut dyn Transport) -> Option<Self> {
+        let last = serial_device.error_counts().ok()?;
+        Some(Self { last, during_push: ErrorCounts::default(), next: Instant::now() + CHECK_INTERVAL })
+    }
+
+    /// How long until the counters are due a read
+    pub fn timeout(&self) -> Duration {
+        self.next.saturating_duration_since(Instant::now())
+    }
+
+    /// Read the counters if it's time, or right away while `pushing`, warning about errors
+    /// during a push. Returns the errors counted since the last read.
+    pub fn check(&mut self, serial_device: &mut dyn Transport, pushing: bool) -> ErrorCounts {
+        if !pushing && !self.timeout().is_zero() {
+            return ErrorCounts::default();
+        }
+        self.next = Instant::now() + CHECK_INTERVAL;
+        let Ok(counts) = serial_device.error_counts() else {
+            return ErrorCounts::default();
+        };
+        let new = counts.since(&self.last);
+        self.last = counts;
+        if pushing && !new.is_zero() {
+            self.during_push += new;
+            status!("\r\n[PUSHER] Warning: the UART counted {} errors during the push, a lower baud rate or \
+                better wiring may help", new);
+        }
+        new
+    }
+
+    /// The errors counted during the push that just ended
+    pub fn end_push(&mut self) -> ErrorCounts {
+        std::mem::take(&mut self.during_push)
+    }
+}
@@ -0,0 +1,69 @@
+//! Checking what the loader holds against the local kernel, see the read back part of
+//! `protocol` for the wire format.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Result, bail};
+
+use crate::output;
+use crate::protocol::{self, Protocol, CRC32, CRC32_WIDTH};
+use crate::transport::Transport;
+use crate::status;
+
+/// How long to wait for more data once the read back was asked for
+const READBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait for the device to be ready, read back its image and compare it to `kernel_path`.
+/// Fails on a mismatch, naming the first offset that differs.
+pub fn verify(serial_device: &mut dyn Transport, protocol: &Protocol, kernel_path: &Path) -> Result<()> {
+    let local = fs::read(kernel_path)?;
+    protocol::wait_for_sequence(serial_device, &protocol.ready_signal)?;
+    status!("\n[PUSHER] Device is ready, reading back its image");
+    protocol::write_bytes(serial_device, &protocol.readback_request)?;
+
+    let mut received = Vec::new();
+    protocol::receive_exact(serial_device, &mut received, protocol.size_width, READBACK_TIMEOUT, |_| {})?;
+    let size = protocol.decode_size(&received) as usize;
+    received.drain(..protocol.size_width);
+    if size == 0 {
+        bail!("The device holds no image");
+    }
+    status!("[PUSHER] Device holds {} bytes", size);
+    protocol::write_bytes(serial_device, &protocol.ack)?;
+
+    let result = protocol::receive_exact(serial_device, &mut received, size + CRC32_WIDTH,
+        READBACK_TIMEOUT, |count| print_progress(count.min(size), size));
+    status!();
+    result?;
+    let expected_crc = protocol.decode_crc(&received[size..]);
+    received.truncate(size);
+    let crc = CRC32.checksum(&received);
+    if crc != expected_crc {
+        protocol::write_bytes(serial_device, &protocol.nak)?;
+        bail!("CRC mismatch: device sent {:#010x}, received data has {:#010x}, the read back is corrupt",
+            expected_crc, crc);
+    }
+    protocol::write_bytes(serial_device, &protocol.ack)?;
+
+    if let Some(offset) = first_difference(&received, &local) {
+        bail!("The device's image ({} bytes) differs from {} ({} bytes) at offset {:#x}",
+            received.len(), kernel_path.display(), local.len(), offset);
+    }
+    status!("[PUSHER] The device's image matches {}", kernel_path.display());
+    Ok(())
+}
+
+/// Offset of the first byte that differs, a length mismatch differs where the shorter one ends
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(offset) => Some(offset),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None
+    }
+}
+
+fn print_progress(count: usize, size: usize) {
+    let percent = (count * 100).checked_div(size).unwrap_or(100);
+    output::status_text(&format!("\r[PUSHER] Received {}/{} bytes ({}%)", count, size, percent));
+}